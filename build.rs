@@ -0,0 +1,43 @@
+use std::path::Path;
+use std::process::Command;
+
+// Plugins are only loaded if they were built by the same compiler from the same sources, so remember both
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .unwrap_or_default();
+
+    let mut hash = FNV_OFFSET;
+    hash_dir(Path::new("src"), &mut hash);
+
+    println!("cargo:rustc-env=KELP_RUSTC_VERSION={}", version);
+    println!("cargo:rustc-env=KELP_SOURCE_HASH={:016x}", hash);
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src");
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// FNV-1a over the path and contents of every file, in a fixed order so the same tree always hashes the same
+fn hash_dir(dir: &Path, hash: &mut u64) {
+    let mut paths: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(_) => return,
+    };
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            hash_dir(&path, hash);
+        } else if let Ok(contents) = std::fs::read(&path) {
+            let name = path.to_string_lossy().replace('\\', "/");
+            for byte in name.bytes().chain([0]).chain(contents) {
+                *hash = (*hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// A native plugin showing the plugin API. Build it with `cargo build --example hello_plugin` and copy the library
+// into a plugins/ directory next to the kelp executable
+use crossterm::event::{Event, KeyCode};
+use kelp::declare_plugin;
+use kelp::editor::history::{EditDiff, UndoRedo};
+use kelp::editor::highlight::{Decoration, Token};
+use kelp::editor::plugin::{Plugin, PluginAction};
+use kelp::editor::prelude::{Editor, KelpKeyEvent, KelpKeyModifiers, TextDocument};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Default)]
+struct HelloPlugin {
+    keys: AtomicUsize,
+    edits: AtomicUsize,
+}
+
+impl Plugin for HelloPlugin {
+    fn on_load(&self, editor: &mut Editor) {
+        editor.status_msg = "hello_plugin loaded".to_owned();
+    }
+
+    fn on_event(&self, editor: &mut Editor, event: &Event) {
+        if let Event::Key(key) = event {
+            let keys = self.keys.fetch_add(1, Ordering::Relaxed) + 1;
+
+            if key.code == KeyCode::F(9) {
+                let edits = self.edits.load(Ordering::Relaxed);
+                editor.status_msg = format!("hello_plugin has seen {} keys and {} edits", keys, edits);
+            }
+        }
+    }
+
+    // Trims trailing whitespace, and refuses to save anything still marked as not ready
+    fn before_save(&self, editor: &mut Editor, doc: usize) -> Result<(), String> {
+        if !editor.docs[doc].is_text_doc() {
+            return Ok(());
+        }
+
+        let doc = editor.docs[doc].as_mut_text_doc();
+        let contents = doc.contents();
+        if contents.contains("DO NOT SAVE") {
+            return Err("hello_plugin found DO NOT SAVE".to_owned());
+        }
+
+        let trimmed: Vec<&str> = contents.split('\n').map(|line| line.trim_end()).collect();
+        doc.replace_contents(&trimmed.join("\n"));
+        Ok(())
+    }
+
+    fn on_change(&self, _editor: &mut Editor, _doc: usize, _diff: &EditDiff, _direction: UndoRedo) {
+        self.edits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // "hello" in the command prompt, or F7
+    fn actions(&self) -> Vec<PluginAction> {
+        vec![PluginAction {
+            name: "hello".to_owned(),
+            key: Some(KelpKeyEvent::new(KeyCode::F(7), KelpKeyModifiers::NoModifier)),
+        }]
+    }
+
+    fn run_action(&self, editor: &mut Editor, _name: &str, args: &[String]) {
+        let name = if args.is_empty() { "world".to_owned() } else { args.join(" ") };
+        editor.status_msg = format!("Hello, {}!", name);
+    }
+
+    // A word count for the open document
+    fn status_segments(&self, editor: &Editor) -> Vec<String> {
+        match editor.open_doc.map(|doc| &editor.docs[doc]) {
+            Some(doc) if doc.is_text_doc() => {
+                let words: usize = doc.as_text_doc().rows.iter().map(|row| row.buf.split_whitespace().count()).sum();
+                vec![format!("{} words", words)]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Highlights .hello files on its own, capitalised words are types and numbers are numbers
+    fn tokenize(&self, doc: &TextDocument, from: usize, count: usize) -> Option<Vec<Vec<Token>>> {
+        if doc.extension() != "hello" {
+            return None;
+        }
+
+        let rows = doc.rows.iter().skip(from).take(count).map(|row| {
+            let mut tokens = Vec::new();
+            let mut start = 0;
+
+            for word in row.buf.split(' ') {
+                let range = start..start + word.len();
+                if word.starts_with(char::is_uppercase) {
+                    tokens.push(Token::TypeName(range));
+                } else if !word.is_empty() && word.chars().all(|c| c.is_ascii_digit()) {
+                    tokens.push(Token::Number(range));
+                }
+                start += word.len() + 1;
+            }
+
+            tokens
+        });
+
+        Some(rows.collect())
+    }
+
+    // Marks every TODO in the gutter and says how many there are at the end of the line
+    fn decorations(&self, doc: &TextDocument, from: usize, count: usize) -> Vec<Decoration> {
+        let mut decorations = Vec::new();
+
+        for (row, content) in doc.rows.iter().enumerate().skip(from).take(count) {
+            let todos: Vec<usize> = content.buf.match_indices("TODO").map(|(i, _)| i).collect();
+            if todos.is_empty() {
+                continue;
+            }
+
+            for i in &todos {
+                decorations.push(Decoration::Style {
+                    row,
+                    range: *i..*i + 4,
+                    style: format!("{}", crossterm::style::Attribute::Reverse),
+                });
+            }
+            decorations.push(Decoration::GutterSign {
+                row,
+                sign: '!',
+                color: (230, 180, 60),
+            });
+            decorations.push(Decoration::VirtualText {
+                row,
+                text: format!("{} left to do", todos.len()),
+                color: (120, 120, 120),
+            });
+        }
+
+        decorations
+    }
+}
+
+declare_plugin!(HelloPlugin, HelloPlugin::default);
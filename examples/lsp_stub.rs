@@ -0,0 +1,334 @@
+// A tiny language server for trying out kelp's lsp client without installing a real one.
+//
+// Build it with `cargo build --example lsp_stub` and point a language at it in config.json:
+//     "language_server": { "command": "path/to/target/debug/examples/lsp_stub" }
+//
+// Every message it receives is appended to the file in $LSP_STUB_LOG if that is set.
+// Each TODO in an open document is reported back as a warning diagnostic.
+// Completion offers every word in the document, and hover and signature help answer with the same made up text
+// wherever they are asked for.
+// Rename replaces every whole word match in the document, and there are two code actions:
+// one that edits directly and one that runs a command which sends the edit back with workspace/applyEdit.
+
+use serde_json::json;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::Write;
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = 0;
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok()?;
+        }
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn write_message(message: Value) {
+    let body = message.to_string();
+    let mut stdout = std::io::stdout();
+    let _ = write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = stdout.flush();
+}
+
+fn log(message: &Value) {
+    if let Ok(path) = std::env::var("LSP_STUB_LOG") {
+        if let Ok(mut file) = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+        {
+            let _ = writeln!(file, "{}", message);
+        }
+    }
+}
+
+fn publish_todos(params: &Value) {
+    let document = &params["textDocument"];
+    let text = match params["contentChanges"].as_array() {
+        Some(changes) => changes.last().map(|change| &change["text"]),
+        None => Some(&document["text"]),
+    };
+
+    let mut diagnostics = Vec::new();
+    for (line, text) in text.and_then(Value::as_str).unwrap_or_default().lines().enumerate() {
+        for (column, _) in text.match_indices("TODO") {
+            // Positions are in utf 16 code units
+            let character = text[..column].encode_utf16().count();
+            diagnostics.push(json!({
+                "range": {
+                    "start": { "line": line, "character": character },
+                    "end": { "line": line, "character": character + 4 }
+                },
+                "severity": 2,
+                "source": "lsp_stub",
+                "message": "TODO left in code"
+            }));
+        }
+    }
+
+    write_message(json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": document["uri"], "diagnostics": diagnostics }
+    }));
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Edits replacing each whole word occurrence of the word at (line, character) with new_name
+fn rename_edits(text: &str, line: usize, character: usize, new_name: &str) -> Vec<Value> {
+    let chars: Vec<char> = text.lines().nth(line).unwrap_or_default().chars().collect();
+    let character = character.min(chars.len());
+    let start = chars[..character].iter().rev().take_while(|c| is_word_char(**c)).count();
+    let end = chars[character..].iter().take_while(|c| is_word_char(**c)).count();
+    let word: String = chars[character - start..character + end].iter().collect();
+
+    let mut edits = Vec::new();
+    if word.is_empty() {
+        return edits;
+    }
+
+    for (line, text) in text.lines().enumerate() {
+        for (column, _) in text.match_indices(&word[..]) {
+            let before = text[..column].chars().next_back();
+            let after = text[column + word.len()..].chars().next();
+            if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+                continue;
+            }
+
+            let character = text[..column].encode_utf16().count();
+            edits.push(json!({
+                "range": {
+                    "start": { "line": line, "character": character },
+                    "end": { "line": line, "character": character + word.encode_utf16().count() }
+                },
+                "newText": new_name
+            }));
+        }
+    }
+
+    edits
+}
+
+// Every distinct word in the text, in the order they first appear
+fn word_completions(text: &str) -> Vec<Value> {
+    let mut words: Vec<&str> = Vec::new();
+    for word in text.split(|c: char| !is_word_char(c)) {
+        if !word.is_empty() && !words.contains(&word) {
+            words.push(word);
+        }
+    }
+
+    words.iter().map(|word| json!({ "label": word, "kind": 1 })).collect()
+}
+
+// A Function DocumentSymbol for every line starting with "fn name("
+fn function_symbols(text: &str) -> Vec<Value> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(line, content)| {
+            let rest = content.trim_start().strip_prefix("fn ")?;
+            let name = &rest[..rest.find('(')?];
+            let start = content.len() - rest.len();
+            let range = json!({
+                "start": { "line": line, "character": start },
+                "end": { "line": line, "character": start + name.len() }
+            });
+
+            Some(json!({ "name": name, "kind": 12, "range": range, "selectionRange": range }))
+        })
+        .collect()
+}
+
+fn main() {
+    let stdin = std::io::stdin();
+    let mut reader = stdin.lock();
+    let mut documents: HashMap<String, String> = HashMap::new();
+    let mut next_id = 1000;
+
+    while let Some(message) = read_message(&mut reader) {
+        log(&message);
+
+        let id = message.get("id").cloned();
+        match message["method"].as_str().unwrap_or_default() {
+            "initialize" => write_message(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": {
+                    "capabilities": { "textDocumentSync": 1 },
+                    "serverInfo": { "name": "lsp_stub" }
+                }
+            })),
+            "initialized" => write_message(json!({
+                "jsonrpc": "2.0",
+                "method": "window/showMessage",
+                "params": { "type": 3, "message": "lsp_stub ready" }
+            })),
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let params = &message["params"];
+                let text = match params["contentChanges"].as_array() {
+                    Some(changes) => changes.last().map(|change| &change["text"]),
+                    None => Some(&params["textDocument"]["text"]),
+                };
+                if let (Some(uri), Some(text)) = (params["textDocument"]["uri"].as_str(), text.and_then(Value::as_str)) {
+                    documents.insert(uri.to_owned(), text.to_owned());
+                }
+
+                publish_todos(params)
+            }
+            "textDocument/rename" => {
+                let params = &message["params"];
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let edits = rename_edits(
+                    documents.get(uri).map(String::as_str).unwrap_or_default(),
+                    params["position"]["line"].as_u64().unwrap_or(0) as usize,
+                    params["position"]["character"].as_u64().unwrap_or(0) as usize,
+                    params["newName"].as_str().unwrap_or_default(),
+                );
+
+                write_message(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": { "changes": { uri: edits } }
+                }))
+            }
+            "textDocument/completion" => {
+                let uri = message["params"]["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = documents.get(uri).map(String::as_str).unwrap_or_default();
+
+                write_message(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": { "isIncomplete": false, "items": word_completions(text) }
+                }))
+            }
+            "textDocument/documentSymbol" => {
+                let uri = message["params"]["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = documents.get(uri).map(String::as_str).unwrap_or_default();
+
+                write_message(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": function_symbols(text)
+                }))
+            }
+            "textDocument/codeAction" => {
+                let params = &message["params"];
+                let uri = &params["textDocument"]["uri"];
+                let line = &params["range"]["start"]["line"];
+
+                write_message(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": [
+                        {
+                            "title": "Add a TODO above this line",
+                            "kind": "quickfix",
+                            "edit": {
+                                "documentChanges": [{
+                                    "textDocument": { "uri": uri, "version": null },
+                                    "edits": [{
+                                        "range": {
+                                            "start": { "line": line, "character": 0 },
+                                            "end": { "line": line, "character": 0 }
+                                        },
+                                        "newText": "// TODO\n"
+                                    }]
+                                }]
+                            }
+                        },
+                        {
+                            "title": "Uppercase this line",
+                            "command": "lsp_stub.uppercase",
+                            "arguments": [uri, line]
+                        }
+                    ]
+                }))
+            }
+            "workspace/executeCommand" => {
+                let arguments = &message["params"]["arguments"];
+                let uri = arguments[0].as_str().unwrap_or_default();
+                let line = arguments[1].as_u64().unwrap_or(0) as usize;
+                let text = documents
+                    .get(uri)
+                    .and_then(|text| text.lines().nth(line))
+                    .unwrap_or_default()
+                    .to_owned();
+
+                write_message(json!({
+                    "jsonrpc": "2.0",
+                    "id": next_id,
+                    "method": "workspace/applyEdit",
+                    "params": {
+                        "edit": {
+                            "changes": {
+                                uri: [{
+                                    "range": {
+                                        "start": { "line": line, "character": 0 },
+                                        "end": { "line": line, "character": text.encode_utf16().count() }
+                                    },
+                                    "newText": text.to_uppercase()
+                                }]
+                            }
+                        }
+                    }
+                }));
+                next_id += 1;
+
+                write_message(json!({ "jsonrpc": "2.0", "id": id, "result": null }))
+            }
+            "textDocument/hover" => write_message(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": {
+                    "contents": {
+                        "kind": "markdown",
+                        "value": format!(
+                            "```rust\nfn example(first: i32, second: &str) -> bool\n```\n\nHovered at line {}, character {}.",
+                            message["params"]["position"]["line"],
+                            message["params"]["position"]["character"]
+                        )
+                    }
+                }
+            })),
+            "textDocument/signatureHelp" => write_message(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": {
+                    "signatures": [{
+                        "label": "fn example(first: i32, second: &str) -> bool",
+                        "parameters": [{ "label": "first: i32" }, { "label": [23, 35] }]
+                    }],
+                    "activeSignature": 0,
+                    "activeParameter": 0
+                }
+            })),
+            "shutdown" => write_message(json!({ "jsonrpc": "2.0", "id": id, "result": null })),
+            "exit" => break,
+            _ => {
+                if let Some(id) = id {
+                    write_message(json!({ "jsonrpc": "2.0", "id": id, "result": null }));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+;; A WebAssembly plugin in the text format, copy it into the plugins/ directory next to the kelp executable.
+;; shout uppercases the open document, so it needs the write capability in config.json:
+;;     "plugin_capabilities": { "shout": ["read", "write", "status", "commands"] }
+;; spin never returns, to show a runaway plugin being stopped.
+(module
+  (import "kelp" "text_len" (func $text_len (result i32)))
+  (import "kelp" "read_text" (func $read_text (param i32 i32) (result i32)))
+  (import "kelp" "set_text" (func $set_text (param i32 i32)))
+  (import "kelp" "status" (func $status (param i32 i32)))
+  (import "kelp" "register_command" (func $register_command (param i32 i32) (result i32)))
+
+  (memory (export "memory") 2)
+
+  (data (i32.const 0) "shout")
+  (data (i32.const 8) "spin")
+  (data (i32.const 16) "shout loaded")
+  (data (i32.const 32) "Too long to shout")
+
+  ;; The text is read in after the strings
+  (global $buffer i32 (i32.const 1024))
+
+  (func (export "on_load")
+    (drop (call $register_command (i32.const 0) (i32.const 5)))
+    (drop (call $register_command (i32.const 8) (i32.const 4)))
+    (call $status (i32.const 16) (i32.const 12)))
+
+  (func $shout (local $len i32) (local $i i32) (local $c i32)
+    (local.set $len (call $text_len))
+
+    ;; Only what fits in memory
+    (if (i32.gt_u (local.get $len) (i32.sub (i32.const 131072) (global.get $buffer)))
+      (then
+        (call $status (i32.const 32) (i32.const 17))
+        (return)))
+
+    (drop (call $read_text (global.get $buffer) (local.get $len)))
+
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+        (local.set $c (i32.load8_u (i32.add (global.get $buffer) (local.get $i))))
+        (if (i32.and
+              (i32.ge_u (local.get $c) (i32.const 97))
+              (i32.le_u (local.get $c) (i32.const 122)))
+          (then
+            (i32.store8
+              (i32.add (global.get $buffer) (local.get $i))
+              (i32.sub (local.get $c) (i32.const 32)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+
+    (call $set_text (global.get $buffer) (local.get $len)))
+
+  (func (export "run_command") (param $id i32)
+    (if (i32.eqz (local.get $id))
+      (then (call $shout))
+      (else
+        (loop $forever
+          (br $forever))))))
@@ -0,0 +1,504 @@
+// Everything a key can be bound to. The names are what goes in the keybinds of config.json,
+// next to the names of actions registered by plugins.
+//
+// Actions missing from the config get their default key, unless something else already has it.
+// Characters that aren't bound to anything are typed.
+//
+// A keybind can be a chord, the keys after the first go in then:
+//     "comment": { "key": { "Char": "k" }, "modifiers": "Control", "then": [{ "key": { "Char": "c" }, "modifiers": "Control" }] }
+// and "leader": true puts the leader key from the config in front of it.
+use crate::editor::prelude::{Editor, EditorConfig, KelpKey, KelpKeyEvent, KelpKeyModifiers};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Copy,
+    Paste,
+    Undo,
+    Redo,
+    StartCommand,
+    NewFile,
+    OpenFile,
+    SaveFile,
+    CloseFile,
+    JumpToBracket,
+    ShowDiagnostics,
+    Outline,
+    WorkspaceSymbols,
+    Complete,
+    Hover,
+    Format,
+    GotoDefinition,
+    FindReferences,
+    JumpBack,
+    Rename,
+    CodeActions,
+    RunTask,
+    NextError,
+    PreviousError,
+
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    WordLeft,
+    WordRight,
+    SelectUp,
+    SelectDown,
+    SelectLeft,
+    SelectRight,
+    SelectWordLeft,
+    SelectWordRight,
+    LineStart,
+    LineEnd,
+
+    Backspace,
+    Delete,
+    NewLine,
+    Indent,
+
+    SetMark,
+    KillLine,
+    KillRegion,
+    KillRingSave,
+    Yank,
+    YankPop,
+
+    NextDoc,
+    PreviousDoc,
+    Menu,
+    Quit,
+
+    // Binary documents only
+    InsertByte,
+    AppendByte,
+}
+
+use Action::*;
+use KelpKeyModifiers::*;
+
+type DefaultKey = Option<(KeyCode, KelpKeyModifiers)>;
+
+// Name and default key of every action
+const ACTIONS: &[(Action, &str, DefaultKey)] = &[
+    (Copy, "copy", Some((KeyCode::Char('c'), Control))),
+    (Paste, "paste", Some((KeyCode::Char('v'), Control))),
+    (Undo, "undo", Some((KeyCode::Char('z'), Control))),
+    (Redo, "redo", Some((KeyCode::Char('y'), Control))),
+    (StartCommand, "start_command", Some((KeyCode::Char('g'), Control))),
+    (NewFile, "new_file", Some((KeyCode::Char('n'), Control))),
+    (OpenFile, "open_file", Some((KeyCode::Char('o'), Control))),
+    (SaveFile, "save_file", Some((KeyCode::Char('s'), Control))),
+    (CloseFile, "close_file", Some((KeyCode::Char('w'), Control))),
+    (JumpToBracket, "jump_to_bracket", Some((KeyCode::Char('b'), Control))),
+    (ShowDiagnostics, "show_diagnostics", Some((KeyCode::Char('e'), Control))),
+    (Outline, "outline", Some((KeyCode::Char('r'), Control))),
+    (WorkspaceSymbols, "workspace_symbols", Some((KeyCode::Char('t'), Control))),
+    (Complete, "complete", Some((KeyCode::Char(' '), Control))),
+    (Hover, "hover", Some((KeyCode::Char('k'), Control))),
+    (Format, "format", Some((KeyCode::Char('f'), Alt))),
+    (GotoDefinition, "goto_definition", Some((KeyCode::F(12), NoModifier))),
+    (FindReferences, "find_references", Some((KeyCode::F(12), Shift))),
+    (JumpBack, "jump_back", Some((KeyCode::Left, Alt))),
+    (Rename, "rename", Some((KeyCode::F(2), NoModifier))),
+    (CodeActions, "code_actions", Some((KeyCode::Enter, Alt))),
+    (RunTask, "run_task", Some((KeyCode::F(5), NoModifier))),
+    (NextError, "next_error", Some((KeyCode::F(8), NoModifier))),
+    (PreviousError, "previous_error", Some((KeyCode::F(8), Shift))),
+    (MoveUp, "move_up", Some((KeyCode::Up, NoModifier))),
+    (MoveDown, "move_down", Some((KeyCode::Down, NoModifier))),
+    (MoveLeft, "move_left", Some((KeyCode::Left, NoModifier))),
+    (MoveRight, "move_right", Some((KeyCode::Right, NoModifier))),
+    (WordLeft, "word_left", Some((KeyCode::Left, Control))),
+    (WordRight, "word_right", Some((KeyCode::Right, Control))),
+    (SelectUp, "select_up", Some((KeyCode::Up, Shift))),
+    (SelectDown, "select_down", Some((KeyCode::Down, Shift))),
+    (SelectLeft, "select_left", Some((KeyCode::Left, Shift))),
+    (SelectRight, "select_right", Some((KeyCode::Right, Shift))),
+    (SelectWordLeft, "select_word_left", Some((KeyCode::Left, ShiftAndControl))),
+    (SelectWordRight, "select_word_right", Some((KeyCode::Right, ShiftAndControl))),
+    (LineStart, "line_start", Some((KeyCode::Home, NoModifier))),
+    (LineEnd, "line_end", Some((KeyCode::End, NoModifier))),
+    (Backspace, "backspace", Some((KeyCode::Backspace, NoModifier))),
+    (Delete, "delete", Some((KeyCode::Delete, NoModifier))),
+    (NewLine, "new_line", Some((KeyCode::Enter, NoModifier))),
+    (Indent, "indent", Some((KeyCode::Tab, NoModifier))),
+    (SetMark, "set_mark", None),
+    (KillLine, "kill_line", None),
+    (KillRegion, "kill_region", None),
+    (KillRingSave, "kill_ring_save", None),
+    (Yank, "yank", None),
+    (YankPop, "yank_pop", None),
+    (NextDoc, "next_doc", Some((KeyCode::BackTab, Shift))),
+    (PreviousDoc, "previous_doc", None),
+    (Menu, "menu", Some((KeyCode::Esc, NoModifier))),
+    (Quit, "quit", Some((KeyCode::F(1), NoModifier))),
+    (InsertByte, "insert_byte", Some((KeyCode::Char('i'), NoModifier))),
+    (AppendByte, "append_byte", Some((KeyCode::Char('I'), Shift))),
+];
+
+// "keybind_preset": "emacs", more than one key being a chord. Actions whose default keys
+// these take get new ones here too, as close to what Emacs has for them as there is
+const EMACS: &[(&str, &[(KeyCode, KelpKeyModifiers)])] = &[
+    ("line_start", &[(KeyCode::Char('a'), Control)]),
+    ("line_end", &[(KeyCode::Char('e'), Control)]),
+    ("move_up", &[(KeyCode::Char('p'), Control)]),
+    ("move_down", &[(KeyCode::Char('n'), Control)]),
+    ("move_left", &[(KeyCode::Char('b'), Control)]),
+    ("move_right", &[(KeyCode::Char('f'), Control)]),
+    ("word_left", &[(KeyCode::Char('b'), Alt)]),
+    ("word_right", &[(KeyCode::Char('f'), Alt)]),
+    ("delete", &[(KeyCode::Char('d'), Control)]),
+    ("set_mark", &[(KeyCode::Char(' '), Control)]),
+    ("kill_line", &[(KeyCode::Char('k'), Control)]),
+    ("kill_region", &[(KeyCode::Char('w'), Control)]),
+    ("kill_ring_save", &[(KeyCode::Char('w'), Alt)]),
+    ("yank", &[(KeyCode::Char('y'), Control)]),
+    ("yank_pop", &[(KeyCode::Char('y'), Alt)]),
+    ("start_command", &[(KeyCode::Char('x'), Alt)]),
+    ("complete", &[(KeyCode::Char('/'), Alt)]),
+    ("format", &[(KeyCode::Char('q'), Alt)]),
+    ("jump_to_bracket", &[(KeyCode::Char(']'), Alt)]),
+    ("save_file", &[(KeyCode::Char('x'), Control), (KeyCode::Char('s'), Control)]),
+    ("open_file", &[(KeyCode::Char('x'), Control), (KeyCode::Char('f'), Control)]),
+    ("new_file", &[(KeyCode::Char('x'), Control), (KeyCode::Char('n'), NoModifier)]),
+    ("close_file", &[(KeyCode::Char('x'), Control), (KeyCode::Char('k'), NoModifier)]),
+    ("next_doc", &[(KeyCode::Char('x'), Control), (KeyCode::Char('b'), NoModifier)]),
+    ("undo", &[(KeyCode::Char('x'), Control), (KeyCode::Char('u'), NoModifier)]),
+    ("redo", &[(KeyCode::Char('x'), Control), (KeyCode::Char('r'), NoModifier)]),
+    ("hover", &[(KeyCode::Char('x'), Control), (KeyCode::Char('h'), NoModifier)]),
+    ("show_diagnostics", &[(KeyCode::Char('x'), Control), (KeyCode::Char('d'), NoModifier)]),
+    ("quit", &[(KeyCode::Char('x'), Control), (KeyCode::Char('c'), Control)]),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTIONS
+            .iter()
+            .find(|(_, action_name, _)| *action_name == name)
+            .map(|(action, _, _)| *action)
+    }
+
+    pub fn name(self) -> &'static str {
+        ACTIONS
+            .iter()
+            .find(|(action, _, _)| *action == self)
+            .map(|(_, name, _)| *name)
+            .unwrap()
+    }
+
+    // Not allowed in read only documents
+    pub fn edits(self) -> bool {
+        matches!(
+            self,
+            Paste
+                | Undo
+                | Redo
+                | SaveFile
+                | Complete
+                | Format
+                | Rename
+                | CodeActions
+                | Backspace
+                | Delete
+                | NewLine
+                | Indent
+                | KillLine
+                | KillRegion
+                | Yank
+                | YankPop
+        )
+    }
+
+    // Does what typing does, so modal editing keeps it to insert mode
+    pub fn types(self) -> bool {
+        matches!(
+            self,
+            Paste | Complete | Backspace | Delete | NewLine | Indent | KillLine | KillRegion | Yank | YankPop
+        )
+    }
+
+    // Bound to plain characters, which are typed in text documents
+    pub fn binary_only(self) -> bool {
+        matches!(self, InsertByte | AppendByte)
+    }
+
+    pub fn by_word(self) -> bool {
+        matches!(self, WordLeft | WordRight | SelectWordLeft | SelectWordRight)
+    }
+
+    pub fn selects(self) -> bool {
+        matches!(
+            self,
+            SelectUp | SelectDown | SelectLeft | SelectRight | SelectWordLeft | SelectWordRight
+        )
+    }
+}
+
+impl EditorConfig {
+    // Binds name to key unless it's already bound or a chord it'd clash with is
+    pub fn bind(&mut self, name: &str, key: KelpKeyEvent) {
+        let sequence = key.sequence(self.leader);
+        let taken = self.keybinds.values().any(|bound| match (bound.sequence(self.leader), &sequence) {
+            (Some(bound), Some(sequence)) => bound.iter().zip(sequence).all(|(a, b)| a == b),
+            _ => false,
+        });
+
+        if !self.keybinds.contains_key(name) && !taken {
+            self.keybinds.insert(name.to_owned(), key);
+        }
+    }
+
+    pub fn bind_defaults(&mut self) {
+        for (_, name, key) in ACTIONS {
+            if let Some((key, modifiers)) = key {
+                self.bind(name, KelpKeyEvent::new(*key, *modifiers));
+            }
+        }
+    }
+
+    // Binds over whatever is there, unbinding anything the keys clash with
+    pub fn apply_preset(&mut self, preset: &str) -> Result<(), String> {
+        let keys = match preset {
+            "emacs" => EMACS,
+            _ => return Err(format!("Unknown keybind preset {}", preset)),
+        };
+
+        for (name, keys) in keys {
+            let mut key = KelpKeyEvent::new(keys[0].0, keys[0].1);
+            key.then = keys[1..]
+                .iter()
+                .map(|&(key, modifiers)| KelpKey { key, modifiers })
+                .collect();
+
+            let sequence = key.sequence(self.leader);
+            let leader = self.leader;
+            self.keybinds.retain(|_, bound| match (bound.sequence(leader), &sequence) {
+                (Some(bound), Some(sequence)) => !bound.iter().zip(sequence).all(|(a, b)| a == b),
+                _ => true,
+            });
+            self.keybinds.insert((*name).to_owned(), key);
+        }
+        Ok(())
+    }
+}
+
+pub enum KeyPress {
+    Action(String),
+    Chord, // Part of a chord, there's nothing to do yet
+    Unbound,
+}
+
+impl Editor {
+    // Keys that start a chord are held on to until the rest of it is pressed.
+    // If a key is both bound and the start of a chord, the chord wins
+    pub fn resolve_key(&mut self, key: &KeyEvent, binary: bool) -> KeyPress {
+        if self.chord_timed_out() {
+            self.pending_keys.clear();
+        }
+
+        let mut keys = std::mem::take(&mut self.pending_keys);
+        let in_chord = !keys.is_empty();
+        keys.push(*key);
+
+        let mut exact: Option<&String> = None;
+        let mut longer = false;
+        for (name, bound) in &self.config.keybinds {
+            if !binary && Action::from_name(name).is_some_and(Action::binary_only) {
+                continue;
+            }
+
+            let sequence = match bound.sequence(self.config.leader) {
+                Some(sequence) if sequence.len() >= keys.len() => sequence,
+                _ => continue,
+            };
+
+            if sequence.iter().zip(&keys).all(|(bound, pressed)| bound.equals(pressed)) {
+                if sequence.len() > keys.len() {
+                    longer = true;
+                } else if exact.is_none_or(|exact| name < exact) {
+                    // Picks by name if a key is bound twice so it's at least the same one every time
+                    exact = Some(name);
+                }
+            }
+        }
+
+        if longer {
+            self.pending_keys = keys;
+            self.pending_since = Some(Instant::now());
+            KeyPress::Chord
+        } else if let Some(name) = exact {
+            KeyPress::Action(name.clone())
+        } else if in_chord {
+            self.status_msg = format!("{} isn't bound to anything", describe_keys(&keys));
+            KeyPress::Chord
+        } else {
+            KeyPress::Unbound
+        }
+    }
+
+    pub fn chord_timed_out(&self) -> bool {
+        match self.pending_since {
+            Some(since) if !self.pending_keys.is_empty() => {
+                since.elapsed() > Duration::from_millis(self.config.chord_timeout)
+            }
+            _ => false,
+        }
+    }
+}
+
+// As shown in the status bar, e.g. Ctrl+K Ctrl+C
+pub fn describe_keys(keys: &[KeyEvent]) -> String {
+    keys.iter()
+        .map(|key| {
+            let mut name = String::new();
+            if key.modifiers.contains(KeyModifiers::CONTROL) {
+                name.push_str("Ctrl+");
+            }
+            if key.modifiers.contains(KeyModifiers::ALT) {
+                name.push_str("Alt+");
+            }
+            if key.modifiers.contains(KeyModifiers::SHIFT) {
+                name.push_str("Shift+");
+            }
+
+            match key.code {
+                KeyCode::Char(' ') => name.push_str("Space"),
+                KeyCode::Char(c) if key.modifiers.is_empty() => name.push(c),
+                KeyCode::Char(c) => name.extend(c.to_uppercase()),
+                KeyCode::F(n) => name.push_str(&format!("F{}", n)),
+                code => name.push_str(&format!("{:?}", code)),
+            }
+            name
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    fn bind(editor: &mut Editor, name: &str, keys: &[char]) {
+        let mut key = KelpKeyEvent::new(KeyCode::Char(keys[0]), KelpKeyModifiers::Control);
+        key.then = keys[1..]
+            .iter()
+            .map(|&c| KelpKey {
+                key: KeyCode::Char(c),
+                modifiers: KelpKeyModifiers::Control,
+            })
+            .collect();
+        editor.config.keybinds.insert(name.to_owned(), key);
+    }
+
+    fn press(editor: &mut Editor, key: KeyEvent) -> Option<String> {
+        match editor.resolve_key(&key, false) {
+            KeyPress::Action(name) => Some(name),
+            KeyPress::Chord => Some("...".to_owned()),
+            KeyPress::Unbound => None,
+        }
+    }
+
+    fn editor() -> Editor {
+        let mut editor = Editor::default();
+        editor.config.chord_timeout = 1000;
+        editor
+    }
+
+    #[test]
+    fn single_keys() {
+        let mut editor = editor();
+        bind(&mut editor, "copy", &['c']);
+
+        assert_eq!(press(&mut editor, ctrl('c')).as_deref(), Some("copy"));
+        assert_eq!(press(&mut editor, ctrl('v')), None);
+        assert!(editor.pending_keys.is_empty());
+    }
+
+    #[test]
+    fn chords() {
+        let mut editor = editor();
+        bind(&mut editor, "comment", &['k', 'c']);
+        bind(&mut editor, "uncomment", &['k', 'u']);
+
+        assert_eq!(press(&mut editor, ctrl('k')).as_deref(), Some("..."));
+        assert_eq!(editor.pending_keys, [ctrl('k')]);
+        assert_eq!(press(&mut editor, ctrl('u')).as_deref(), Some("uncomment"));
+        assert!(editor.pending_keys.is_empty());
+
+        // The rest of a chord on its own is nothing
+        assert_eq!(press(&mut editor, ctrl('c')), None);
+    }
+
+    #[test]
+    fn unfinished_chords() {
+        let mut editor = editor();
+        bind(&mut editor, "comment", &['k', 'c']);
+
+        press(&mut editor, ctrl('k'));
+        assert_eq!(press(&mut editor, ctrl('x')).as_deref(), Some("..."));
+        assert_eq!(editor.status_msg, "Ctrl+K Ctrl+X isn't bound to anything");
+        assert!(editor.pending_keys.is_empty());
+    }
+
+    #[test]
+    fn chords_win_prefix_conflicts() {
+        let mut editor = editor();
+        bind(&mut editor, "kill_line", &['k']);
+        bind(&mut editor, "comment", &['k', 'c']);
+
+        assert_eq!(press(&mut editor, ctrl('k')).as_deref(), Some("..."));
+        assert_eq!(press(&mut editor, ctrl('c')).as_deref(), Some("comment"));
+    }
+
+    #[test]
+    fn keys_bound_twice_pick_the_first_name() {
+        let mut editor = editor();
+        bind(&mut editor, "paste", &['y']);
+        bind(&mut editor, "yank", &['y']);
+
+        for _ in 0..10 {
+            assert_eq!(press(&mut editor, ctrl('y')).as_deref(), Some("paste"));
+        }
+    }
+
+    #[test]
+    fn chords_time_out() {
+        let mut editor = editor();
+        bind(&mut editor, "comment", &['k', 'c']);
+        bind(&mut editor, "copy", &['c']);
+
+        press(&mut editor, ctrl('k'));
+        assert!(!editor.chord_timed_out());
+
+        editor.pending_since = Some(Instant::now() - Duration::from_millis(1500));
+        assert!(editor.chord_timed_out());
+
+        // The key after a timeout starts afresh instead of finishing the chord
+        assert_eq!(press(&mut editor, ctrl('c')).as_deref(), Some("copy"));
+        assert!(editor.pending_keys.is_empty());
+    }
+
+    #[test]
+    fn leader_keys() {
+        let mut editor = editor();
+        let mut key = KelpKeyEvent::new(KeyCode::Char('f'), KelpKeyModifiers::NoModifier);
+        key.leader = true;
+        editor.config.keybinds.insert("find".to_owned(), key);
+
+        // Without a leader in the config the keybind can't be pressed
+        assert_eq!(press(&mut editor, KeyEvent::new(KeyCode::Char('f'), KeyModifiers::NONE)), None);
+
+        editor.config.leader = Some(KelpKey {
+            key: KeyCode::Char(' '),
+            modifiers: KelpKeyModifiers::Control,
+        });
+        assert_eq!(press(&mut editor, ctrl(' ')).as_deref(), Some("..."));
+        assert_eq!(press(&mut editor, KeyEvent::new(KeyCode::Char('f'), KeyModifiers::NONE)).as_deref(), Some("find"));
+    }
+}
@@ -0,0 +1,380 @@
+use crate::editor::highlight::Token;
+use crate::editor::history::EditDiff;
+use crate::editor::prelude::*;
+use crate::editor::utils::fuzzy_score;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Most candidates shown in the popup at once
+pub const COMPLETION_HEIGHT: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompletionSource {
+    Word,
+    Lsp,
+    Snippet,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletionItem {
+    pub label: String,
+    pub insert: String,
+    pub source: CompletionSource,
+}
+
+// An open completion popup for the word being typed at (row, start_col)
+pub struct Completion {
+    pub doc: usize,
+    pub row: usize,
+    pub start_col: usize,
+
+    pub candidates: Vec<CompletionItem>,
+    pub matches: Vec<usize>, // Indices into candidates that fit what's typed, best first
+    pub selected: usize,
+}
+
+impl CompletionSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompletionSource::Word => "word",
+            CompletionSource::Lsp => "lsp",
+            CompletionSource::Snippet => "snip",
+        }
+    }
+}
+
+impl Completion {
+    pub fn new(doc_index: usize, doc: &TextDocument, candidates: Vec<CompletionItem>) -> Self {
+        let start_col = word_start(&doc.rows[doc.cursor_row], doc.cursor_col);
+
+        let mut completion = Completion {
+            doc: doc_index,
+            row: doc.cursor_row,
+            start_col,
+            candidates,
+            matches: Vec::new(),
+            selected: 0,
+        };
+        completion.update(doc);
+        completion
+    }
+
+    // Refilters against what's typed now, false if the popup should close
+    pub fn update(&mut self, doc: &TextDocument) -> bool {
+        if doc.cursor_row != self.row || doc.cursor_col < self.start_col {
+            return false;
+        }
+
+        let query = doc.rows[self.row].substring(self.start_col, doc.cursor_col);
+        if !query.chars().all(is_word_char) {
+            return false;
+        }
+
+        let mut matches: Vec<(i64, usize)> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.label != query)
+            .filter_map(|(i, item)| fuzzy_score(query, &item.label).map(|score| (score, i)))
+            .collect();
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        self.matches = matches.into_iter().map(|(_, i)| i).collect();
+        self.selected = std::cmp::min(self.selected, self.matches.len().saturating_sub(1));
+
+        !self.matches.is_empty()
+    }
+
+    pub fn select_next(&mut self) {
+        self.selected = (self.selected + 1) % self.matches.len();
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = if self.selected == 0 { self.matches.len() - 1 } else { self.selected - 1 };
+    }
+
+    pub fn selected_item(&self) -> Option<&CompletionItem> {
+        self.matches.get(self.selected).map(|i| &self.candidates[*i])
+    }
+
+    // Replaces the typed word with the selected item as a single undo step
+    pub fn accept(&self, doc: &mut TextDocument, config: &FileConfig) {
+        let item = match self.selected_item() {
+            Some(item) => item,
+            None => return,
+        };
+
+        let mut text = item.insert.clone();
+        let mut cursor = None;
+
+        if item.source == CompletionSource::Snippet {
+            // Later lines of a snippet keep the indentation of the line it's expanded on
+            let indent: String = doc.rows[self.row]
+                .buf
+                .chars()
+                .take_while(|c| c.is_whitespace())
+                .collect();
+            text = text
+                .replace("\t", &config.tab_str)
+                .replace('\n', &format!("\n{}", indent));
+
+            if let Some(marker) = text.find("$0") {
+                text.replace_range(marker..marker + 2, "");
+                cursor = Some(&text[..marker]);
+            }
+        }
+
+        let (diffs, end) = doc.replace_range(
+            (self.row, self.start_col),
+            (self.row, doc.cursor_col),
+            &text,
+        );
+
+        // Work out where $0 ended up from the text before it
+        let (row, col) = match cursor {
+            Some(before) => match before.rfind('\n') {
+                Some(newline) => (
+                    self.row + before.matches('\n').count(),
+                    before[newline + 1..].chars().count(),
+                ),
+                None => (self.row, self.start_col + before.chars().count()),
+            },
+            None => end,
+        };
+
+        doc.cursor_row = row;
+        doc.cursor_col = col;
+        doc.dirty += 1;
+        doc.add_diff(EditDiff::Compound(diffs));
+    }
+}
+
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Start of the word that ends at col
+pub fn word_start(row: &Row, col: usize) -> usize {
+    let before = row.substring(0, std::cmp::min(col, row.len()));
+    col - before.chars().rev().take_while(|c| is_word_char(*c)).count()
+}
+
+// The word touching col, if any
+pub fn word_at(row: &Row, col: usize) -> &str {
+    let col = std::cmp::min(col, row.len());
+    let start = word_start(row, col);
+    let end = col + row.substring(col, row.len()).chars().take_while(|c| is_word_char(*c)).count();
+    row.substring(start, end)
+}
+
+// Every distinct identifier in the given documents
+pub fn buffer_words<'a>(
+    docs: impl Iterator<Item = (&'a mut TextDocument, &'a FileConfig)>,
+    languages: &HashMap<String, FileConfig>,
+) -> Vec<CompletionItem> {
+    let mut words: Vec<String> = Vec::new();
+
+    for (doc, config) in docs {
+        let len = doc.rows.len();
+        Token::tokenize(
+            &mut doc.rows,
+            HighlightingInfo {
+                selection: None,
+                matching_brackets: None,
+                languages: Some(languages),
+                diagnostics: None,
+                plugin_tokens: None,
+                decorations: None,
+            },
+            0,
+            len,
+            config,
+        );
+
+        for row in &doc.rows {
+            for token in &row.tokens {
+                if let Token::Identifier(range) | Token::FnCall(range) | Token::TypeName(range) = token {
+                    words.push(row.buf[range.clone()].to_owned());
+                }
+            }
+        }
+    }
+
+    words.sort();
+    words.dedup();
+
+    words
+        .into_iter()
+        .map(|word| CompletionItem {
+            label: word.clone(),
+            insert: word,
+            source: CompletionSource::Word,
+        })
+        .collect()
+}
+
+pub fn snippets(config: &FileConfig) -> Vec<CompletionItem> {
+    let mut snippets: Vec<CompletionItem> = config
+        .snippets
+        .iter()
+        .map(|(trigger, body)| CompletionItem {
+            label: trigger.clone(),
+            insert: body.clone(),
+            source: CompletionSource::Snippet,
+        })
+        .collect();
+    snippets.sort_by(|a, b| a.label.cmp(&b.label));
+    snippets
+}
+
+// Reads a textDocument/completion response, either a list of items or a CompletionList
+pub fn from_lsp(response: &Value) -> Vec<CompletionItem> {
+    let items = match response {
+        Value::Array(items) => items,
+        _ => match response["items"].as_array() {
+            Some(items) => items,
+            None => return Vec::new(),
+        },
+    };
+
+    items
+        .iter()
+        .filter_map(|item| {
+            let label = item["label"].as_str()?.to_owned();
+            let insert = item["textEdit"]["newText"]
+                .as_str()
+                .or_else(|| item["insertText"].as_str())
+                .unwrap_or(&label)
+                .to_owned();
+
+            Some(CompletionItem {
+                label,
+                insert,
+                source: CompletionSource::Lsp,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::history::UndoRedo;
+    use serde_json::json;
+
+    fn doc(lines: &[&str], cursor: (usize, usize)) -> TextDocument {
+        let mut doc = TextDocument::new("main.rs".to_owned());
+        doc.rows = lines.iter().map(|line| Row::from_string(line.to_string())).collect();
+        doc.cursor_row = cursor.0;
+        doc.cursor_col = cursor.1;
+        doc
+    }
+
+    fn item(label: &str, insert: &str, source: CompletionSource) -> CompletionItem {
+        CompletionItem {
+            label: label.to_owned(),
+            insert: insert.to_owned(),
+            source,
+        }
+    }
+
+    fn text(doc: &TextDocument) -> Vec<&str> {
+        doc.rows.iter().map(|row| row.buf.as_str()).collect()
+    }
+
+    fn labels(completion: &Completion) -> Vec<&str> {
+        completion.matches.iter().map(|i| completion.candidates[*i].label.as_str()).collect()
+    }
+
+    fn undo(doc: &mut TextDocument) {
+        let diff = doc.history[doc.history_index.unwrap()].clone();
+        diff.apply(UndoRedo::Undo, doc);
+    }
+
+    #[test]
+    fn update_filters_by_what_is_typed() {
+        let mut doc = doc(&["let x = pr"], (0, 10));
+        let candidates = vec![
+            item("print", "print", CompletionSource::Word),
+            item("pr", "pr", CompletionSource::Word),
+            item("parse_args", "parse_args", CompletionSource::Word),
+            item("value", "value", CompletionSource::Word),
+        ];
+        let mut completion = Completion::new(0, &doc, candidates);
+
+        // The word itself isn't offered back
+        assert_eq!(completion.start_col, 8);
+        assert_eq!(labels(&completion), ["print", "parse_args"]);
+
+        doc.rows[0] = Row::from_string("let x = pri".to_owned());
+        doc.cursor_col = 11;
+        assert!(completion.update(&doc));
+        assert_eq!(labels(&completion), ["print"]);
+
+        doc.rows[0] = Row::from_string("let x = pri.".to_owned());
+        doc.cursor_col = 12;
+        assert!(!completion.update(&doc));
+
+        doc.cursor_col = 7;
+        assert!(!completion.update(&doc));
+    }
+
+    #[test]
+    fn accept_replaces_the_word_as_one_undo_step() {
+        let mut doc = doc(&["fn main() {", "    pri", "}"], (1, 7));
+        let completion = Completion::new(0, &doc, vec![item("println", "println", CompletionSource::Word)]);
+
+        completion.accept(&mut doc, &FileConfig::default());
+        assert_eq!(text(&doc), ["fn main() {", "    println", "}"]);
+        assert_eq!((doc.cursor_row, doc.cursor_col), (1, 11));
+        assert_eq!(doc.history.len(), 1);
+
+        undo(&mut doc);
+        assert_eq!(text(&doc), ["fn main() {", "    pri", "}"]);
+    }
+
+    #[test]
+    fn snippet_cursor_goes_to_the_end_marker() {
+        let mut doc = doc(&["fn main() {", "    pri", "}"], (1, 7));
+        let snippet = item("print", "println!(\"$0\");", CompletionSource::Snippet);
+        let completion = Completion::new(0, &doc, vec![snippet]);
+
+        completion.accept(&mut doc, &FileConfig::default());
+        assert_eq!(text(&doc), ["fn main() {", "    println!(\"\");", "}"]);
+        assert_eq!((doc.cursor_row, doc.cursor_col), (1, 14));
+    }
+
+    #[test]
+    fn multi_line_snippets_keep_the_indentation() {
+        let mut doc = doc(&["fn main() {", "    fo", "}"], (1, 6));
+        let snippet = item("for", "for x in y {\n\t$0\n}", CompletionSource::Snippet);
+        let completion = Completion::new(0, &doc, vec![snippet]);
+
+        completion.accept(&mut doc, &FileConfig::default());
+        assert_eq!(text(&doc), ["fn main() {", "    for x in y {", "        ", "    }", "}"]);
+        assert_eq!((doc.cursor_row, doc.cursor_col), (2, 8));
+        assert_eq!(doc.history.len(), 1);
+
+        undo(&mut doc);
+        assert_eq!(text(&doc), ["fn main() {", "    fo", "}"]);
+    }
+
+    #[test]
+    fn lsp_items_prefer_the_text_edit() {
+        let items = json!([
+            {"label": "push", "textEdit": {"newText": "push()", "range": {}}, "insertText": "push_back"},
+            {"label": "pop", "insertText": "pop()"},
+            {"label": "len"},
+            {"insertText": "no label"},
+        ]);
+
+        let items = from_lsp(&items);
+        let inserts: Vec<(&str, &str)> = items.iter().map(|item| (item.label.as_str(), item.insert.as_str())).collect();
+        assert_eq!(inserts, [("push", "push()"), ("pop", "pop()"), ("len", "len")]);
+        assert!(items.iter().all(|item| item.source == CompletionSource::Lsp));
+
+        // A CompletionList has them under items
+        assert_eq!(from_lsp(&json!({"isIncomplete": false, "items": [{"label": "len"}]})).len(), 1);
+        assert!(from_lsp(&json!(null)).is_empty());
+    }
+}
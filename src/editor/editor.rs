@@ -8,16 +8,36 @@
 //            |_|    
 // Made by vertex
 
+use crate::editor::action::describe_keys;
+use crate::editor::action::Action;
+use crate::editor::action::KeyPress;
+use crate::editor::vim::VimKey;
+use crate::editor::completion::Completion;
+use crate::editor::completion::COMPLETION_HEIGHT;
+use crate::editor::highlight::Decoration;
 use crate::editor::highlight::Token;
 use crate::editor::history::LineDeleteMode;
 use crate::editor::history::UndoRedo;
+use crate::editor::hover::SignatureHelp;
+use crate::editor::hover::HOVER_HEIGHT;
 use crate::editor::prelude::*;
 use crate::editor::utils::pad_center;
 use crate::editor::utils::pad_center_str;
+use crate::editor::utils::display_path;
+use crate::editor::utils::fit_width;
+use crate::editor::utils::fuzzy_matches;
+use crate::editor::utils::split_ansi;
+use crate::editor::lsp::LspEvent;
+use crate::editor::refactor::CodeAction;
+use crate::editor::tags::Tag;
+use crate::editor::tags::Tags;
+use crate::editor::tasks::RunningTask;
+use crate::editor::workspace::SymbolIndex;
 use crate::editor::*;
 use crate::editor::prelude::Editor;
 use clipboard::ClipboardContext;
 use clipboard::ClipboardProvider;
+use crossterm::event::poll;
 use crossterm::event::read;
 use crossterm::event::Event::Resize;
 use crossterm::event::{
@@ -29,6 +49,7 @@ use crossterm::style::Color;
 use std::error::Error;
 use std::io::stdout;
 use std::path::Path;
+use std::time::Duration;
 
 use crossterm::ExecutableCommand;
 
@@ -76,14 +97,31 @@ pub fn line_ending() -> String {
     "\r\n".to_owned()
 }
 
+// How often to check on language servers while waiting for input
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Columns taken by diagnostic markers, only shown while a document has diagnostics
+const GUTTER_WIDTH: usize = 2;
+
 pub fn char_width(chr: char, file_config: &FileConfig) -> Option<usize> {
-    if chr != '\t' {
-        chr.width()
-    } else {
+    if chr == '\t' {
         Some(file_config.tab_str.len())
+    } else if file_config.show_whitespace && is_invisible(chr) {
+        Some(1) // Drawn with a glyph
+    } else {
+        chr.width()
     }
 }
 
+// Characters that are easy to paste in by accident and impossible to spot, including the bidi controls
+// that can make code read differently to how it compiles
+pub fn is_invisible(chr: char) -> bool {
+    matches!(
+        chr,
+        '\u{00AD}' | '\u{200B}'..='\u{200D}' | '\u{202A}'..='\u{202E}' | '\u{2060}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}'
+    )
+}
+
 /*
     +=======================================================+
     |  ___  ___      _         _____    _ _ _               |
@@ -108,6 +146,13 @@ impl Editor {
 
         let mut is_conhost = false;
 
+        // Plugins that failed to load matter more than whatever the loaded ones have to say
+        let load_errors = std::mem::take(&mut self.status_msg);
+        self.run_plugins(|plugin, editor| plugin.on_load(editor));
+        if !load_errors.is_empty() {
+            self.status_msg = load_errors;
+        }
+
         // If any files are included open them
         if args.len() > 0 {
             self.open_doc = Some(0);
@@ -146,6 +191,10 @@ impl Editor {
             self.main_screen()?;
         }
 
+        // Index the workspace in the background so it's ready by the time it's needed
+        let root = std::env::current_dir().and_then(std::fs::canonicalize).unwrap_or_default();
+        self.symbol_index = Some(SymbolIndex::build(root, self.config.languages.clone()));
+
         // Editor loop
         self.redraw = true;
         let mut _last_letter: Option<std::time::SystemTime> = None;
@@ -153,6 +202,8 @@ impl Editor {
         // Diagnostic data
 
         'editor: loop {
+            self.notify_plugins();
+
             if let Some((w, h)) = self.resize {
                 self.resize(w, h);
                 self.resize = None;
@@ -167,9 +218,46 @@ impl Editor {
             let (width, height) = (self.width(), self.height());
 
             if let Some(doc_index) = self.open_doc {
+                macro_rules! format_document {
+                    () => {{
+                        let config = self
+                            .config
+                            .language(&self.docs[doc_index].as_text_doc().extension())
+                            .clone();
+                        format::format_document(
+                            self.docs[doc_index].as_mut_text_doc(),
+                            &config,
+                            &mut self.language_servers,
+                            height - 3,
+                        )
+                    }};
+                }
+
                 macro_rules! save_file {
                     () => {{
+                        // A plugin can change the document before it's written, or refuse to have it written at all
+                        let mut veto = None;
+                        self.run_plugins(|plugin, editor| {
+                            if veto.is_none() {
+                                veto = plugin.before_save(editor, doc_index).err();
+                            }
+                        });
+                        if let Some(e) = veto {
+                            self.status_msg = format!("Not saved. {}", e);
+                            continue 'editor;
+                        }
+
                         if self.docs[doc_index].is_text_doc() {
+                            let format_on_save = self
+                                .config
+                                .language(&self.docs[doc_index].as_text_doc().extension())
+                                .format_on_save;
+                            let format_error = if format_on_save {
+                                format_document!().err()
+                            } else {
+                                None
+                            };
+
                             let config = if self
                                 .config
                                 .languages
@@ -184,12 +272,31 @@ impl Editor {
                             self.docs[doc_index].as_mut_text_doc().dirty = 0;
                             self.status_msg =
                                 format!("Saved file as {} in ", self.docs[doc_index].filename());
+
+                            if let Err(e) = self
+                                .language_servers
+                                .did_save(self.docs[doc_index].as_text_doc(), config)
+                            {
+                                self.status_msg = e;
+                            }
+
+                            if let Some(index) = &mut self.symbol_index {
+                                let doc = self.docs[doc_index].as_text_doc();
+                                index.update(&doc.filename, &doc.contents(), config, &self.config.languages);
+                            }
+
+                            if let Some(e) = format_error {
+                                self.status_msg = format!("Saved without formatting. {}", e);
+                            }
                         } else if self.docs[doc_index].is_binary_doc() {
                             self.docs[doc_index].as_bin_doc().save()?;
                             self.docs[doc_index].as_bin_doc().dirty = 0;
                             self.status_msg =
                                 format!("Saved file as {} in ", self.docs[doc_index].filename());
                         }
+
+                        self.run_plugins(|plugin, editor| plugin.after_save(editor, doc_index));
+                        continue 'editor;
                     }};
                 }
 
@@ -215,7 +322,7 @@ impl Editor {
                                     };
                                     self.open_doc = Some(self.docs.len());
                                     let mut doc = TextDocument::new(filename.clone());
-                                    doc.tokenize(0, height, config);
+                                    doc.tokenize(0, height, config, &self.config.languages);
                                     self.add_doc(doc);
                                 }
                             } else {
@@ -254,7 +361,7 @@ impl Editor {
                                                 &self.config.languages[&"*".to_owned()]
                                             };
                                         self.open_doc = Some(self.docs.len());
-                                        doc.tokenize(0, height, config);
+                                        doc.tokenize(0, height, config, &self.config.languages);
                                         self.add_doc(doc);
 
                                         continue 'editor;
@@ -273,7 +380,7 @@ impl Editor {
                     () => {
                         if self.docs[doc_index].is_text_doc() {
                             if self.docs[doc_index].as_mut_text_doc().dirty == 0 {
-                                self.docs.remove(doc_index);
+                                self.remove_doc(doc_index);
 
                                 if self.docs.len() == 0 {
                                     self.open_doc = None;
@@ -296,7 +403,7 @@ impl Editor {
                                     )
                                     .is_ok()
                                 {
-                                    self.docs.remove(doc_index);
+                                    self.remove_doc(doc_index);
 
                                     if self.docs.len() == 0 {
                                         self.open_doc = None;
@@ -313,7 +420,7 @@ impl Editor {
                             }
                         } else if self.docs[doc_index].is_binary_doc() {
                             if self.docs[doc_index].as_bin_doc().dirty == 0 {
-                                self.docs.remove(doc_index);
+                                self.remove_doc(doc_index);
 
                                 if self.docs.len() == 0 {
                                     self.open_doc = None;
@@ -336,7 +443,7 @@ impl Editor {
                                     )
                                     .is_ok()
                                 {
-                                    self.docs.remove(doc_index);
+                                    self.remove_doc(doc_index);
 
                                     if self.docs.len() == 0 {
                                         self.open_doc = None;
@@ -370,10 +477,33 @@ impl Editor {
 
                     let lines = self.docs[doc_index].as_mut_text_doc().rows.len();
 
+                    let (plugin_tokens, decorations) = self.plugin_highlighting(
+                        doc_index,
+                        self.docs[doc_index].as_text_doc().line_start,
+                        height - 3,
+                    );
+
+                    let has_signs = decorations
+                        .iter()
+                        .any(|decoration| matches!(decoration, Decoration::GutterSign { .. }));
+                    let gutter = if self.docs[doc_index].as_text_doc().diagnostics.is_empty() && !has_signs {
+                        0
+                    } else {
+                        GUTTER_WIDTH
+                    };
+                    let width = width - gutter; // Space left for the text itself
+
                     if self.docs[doc_index].as_mut_text_doc().rows.len() == 0 {
                         self.docs[doc_index].as_mut_text_doc().rows.push(Row::empty());
                     }
 
+                    if let Err(e) = self
+                        .language_servers
+                        .sync(self.docs[doc_index].as_text_doc(), &config)
+                    {
+                        self.status_msg = e;
+                    }
+
                     if !self.undergoing_selection {
                         self.docs[doc_index].as_mut_text_doc().selection = None;
                     } else {
@@ -397,10 +527,23 @@ impl Editor {
 
                         let line_start = self.docs[doc_index].as_mut_text_doc().line_start;
 
+                        let matching_brackets = {
+                            let doc = self.docs[doc_index].as_mut_text_doc();
+                            let (cursor_row, cursor_col) = (doc.cursor_row, doc.cursor_col);
+                            doc.matching_bracket(cursor_row, cursor_col, &config, &self.config.languages)
+                                .map(|partner| [(cursor_row, cursor_col), partner])
+                        };
+
+                        let doc = self.docs[doc_index].as_mut_text_doc();
                         Token::tokenize(
-                            &mut self.docs[doc_index].as_mut_text_doc().rows,
+                            &mut doc.rows,
                             HighlightingInfo {
                                 selection: selection,
+                                matching_brackets,
+                                languages: Some(&self.config.languages),
+                                diagnostics: Some(&doc.diagnostics),
+                                plugin_tokens: plugin_tokens.as_deref(),
+                                decorations: Some(&decorations),
                             },
                             line_start,
                             height - 3,
@@ -416,7 +559,90 @@ impl Editor {
                             } else if processing_row + line_start - 1
                                 < self.docs[doc_index].as_mut_text_doc().rows.len()
                             {
-                                if self.docs[doc_index].as_mut_text_doc().rows
+                                if gutter != 0 || !decorations.is_empty() {
+                                    let row_index = processing_row - 1 + line_start;
+                                    let doc = self.docs[doc_index].as_text_doc();
+                                    let line_width = doc.rows[row_index].line_width(&config);
+
+                                    let mut visual_lines = split_ansi(
+                                        &doc.rows[row_index].display_buf(&config, &self.config.theme),
+                                        width,
+                                    );
+                                    // Same number of lines position_cursor expects
+                                    let expected_lines =
+                                        if line_width > width { line_width / width + 1 } else { 1 };
+                                    while visual_lines.len() < expected_lines {
+                                        visual_lines.push((String::new(), 0));
+                                    }
+
+                                    let theme_colors = format!(
+                                        "{}{}",
+                                        crossterm::style::SetBackgroundColor(Color::from(
+                                            self.config.theme.background_color
+                                        )),
+                                        crossterm::style::SetForegroundColor(Color::from(
+                                            self.config.theme.foreground_color
+                                        ))
+                                    );
+                                    // Diagnostics take the gutter over plugin signs
+                                    let sign = doc
+                                        .row_severity(row_index)
+                                        .map(|severity| (severity.marker(), severity.color(&config)))
+                                        .or_else(|| {
+                                            decorations.iter().find_map(|decoration| match decoration {
+                                                Decoration::GutterSign { row, sign, color } if *row == row_index => {
+                                                    Some((*sign, *color))
+                                                }
+                                                _ => None,
+                                            })
+                                        });
+                                    let marker = match sign {
+                                        _ if gutter == 0 => String::new(),
+                                        Some((sign, color)) => format!(
+                                            "{}{}{}{}",
+                                            crossterm::style::SetForegroundColor(Color::from(color)),
+                                            sign,
+                                            theme_colors,
+                                            " ".repeat(GUTTER_WIDTH - 1)
+                                        ),
+                                        None => " ".repeat(GUTTER_WIDTH),
+                                    };
+
+                                    // Virtual text goes after the last visual line, cut short to fit
+                                    let mut virtual_text = String::new();
+                                    for decoration in &decorations {
+                                        if let Decoration::VirtualText { row, text, color } = decoration {
+                                            if *row == row_index {
+                                                virtual_text.push_str(&format!(
+                                                    "{} {}",
+                                                    crossterm::style::SetForegroundColor(Color::from(*color)),
+                                                    text
+                                                ));
+                                            }
+                                        }
+                                    }
+
+                                    let last = visual_lines.len() - 1;
+                                    for (i, (line, line_width)) in visual_lines.iter().enumerate() {
+                                        let (virtual_text, virtual_width) = if i == last && !virtual_text.is_empty() && *line_width < width {
+                                            split_ansi(&virtual_text, width.saturating_sub(*line_width))
+                                                .swap_remove(0)
+                                        } else {
+                                            (String::new(), 0)
+                                        };
+
+                                        println!(
+                                            "{}{}{}{}{}{}",
+                                            theme_colors,
+                                            if i == 0 { &marker[..] } else { &"  "[..gutter] },
+                                            line,
+                                            virtual_text,
+                                            theme_colors,
+                                            " ".repeat(width.saturating_sub(*line_width + virtual_width))
+                                        );
+                                    }
+                                    drawing_row += visual_lines.len() - 1;
+                                } else if self.docs[doc_index].as_mut_text_doc().rows
                                     [processing_row - 1 + line_start]
                                     .line_width(&config)
                                     > width
@@ -463,7 +689,7 @@ impl Editor {
                                     );
                                 }
                             } else {
-                                println!("~{}", " ".repeat(width - 1));
+                                println!("~{}", " ".repeat(width + gutter - 1));
                             }
                             drawing_row += 1;
                             processing_row += 1;
@@ -471,8 +697,8 @@ impl Editor {
 
                         if self.clear {
                             self.clear = false;
-                            println!("~{}", " ".repeat(width - 1));
-                            println!("~{}", " ".repeat(width - 1));
+                            println!("~{}", " ".repeat(width + gutter - 1));
+                            println!("~{}", " ".repeat(width + gutter - 1));
                         }
 
                         std::io::stdout().flush()?;
@@ -482,6 +708,18 @@ impl Editor {
 
                     if !self.mouse_event {
                         self.draw_tabs();
+
+                        if self.status_msg.is_empty() {
+                            let doc = self.docs[doc_index].as_text_doc();
+                            if let Some(diagnostic) = doc.diagnostic_at(doc.cursor_row, doc.cursor_col) {
+                                self.status_msg = format!(
+                                    "{}: {}",
+                                    diagnostic.severity.name(),
+                                    diagnostic.message.lines().next().unwrap_or_default()
+                                );
+                            }
+                        }
+
                         if is_debug() {
                             let row = self.docs[doc_index].as_mut_text_doc().cursor_row;
                             let status;
@@ -503,6 +741,38 @@ impl Editor {
                             }
                             self.write_status_bar(Some(status));
                         }
+
+                        if let Some(help) = &self.signature_help {
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let (x, y) = Editor::cursor_position(
+                                doc.cursor_row,
+                                help.open_col,
+                                &doc.rows,
+                                width,
+                                doc.line_start,
+                                &config,
+                                gutter,
+                            );
+                            self.draw_signature_help(help, x, y);
+                        }
+
+                        if let Some(completion) = &self.completion {
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let (x, y) = Editor::cursor_position(
+                                doc.cursor_row,
+                                doc.cursor_col,
+                                &doc.rows,
+                                width,
+                                doc.line_start,
+                                &config,
+                                gutter,
+                            );
+                            self.draw_completion(
+                                completion,
+                                x.saturating_sub(doc.cursor_col - completion.start_col),
+                                y,
+                            );
+                        }
                     }
 
                     if !self.status_msg.is_empty() {
@@ -535,6 +805,104 @@ impl Editor {
                                             std::env::set_current_dir(dir)?;
                                         }
                                     }
+                                } else if let Some(name) = command.strip_prefix("tag ") {
+                                    goto_tag!(name.trim().to_owned());
+                                } else if command.trim() == "format" {
+                                    format_current!();
+                                } else if command.trim() == "run" {
+                                    pick_task!();
+                                } else if let Some(name) = command.strip_prefix("run ") {
+                                    run_task!(name.trim().to_owned());
+                                } else if command.trim() == "stop" {
+                                    if let Some(task) = self.task.take() {
+                                        self.status_msg = format!("Stopped {}", task.name);
+                                    }
+                                } else if command.trim() == "quickfix" {
+                                    show_quickfix!();
+                                } else if command.trim() == "diagnostics" {
+                                    show_diagnostics!();
+                                } else if command.trim() == "outline" {
+                                    show_outline!();
+                                } else if command.trim() == "symbols" {
+                                    workspace_symbols!();
+                                } else if command.trim() == "whitespace" {
+                                    let extension = self.docs[doc_index].as_text_doc().extension();
+                                    let key = if self.config.languages.contains_key(&extension) {
+                                        extension.clone()
+                                    } else {
+                                        "*".to_owned()
+                                    };
+
+                                    match self.config.languages.get_mut(&key) {
+                                        Some(language) => language.show_whitespace = !language.show_whitespace,
+                                        None => {
+                                            self.status_msg = format!(
+                                                "No language config for .{} or * to show whitespace with",
+                                                extension
+                                            )
+                                        }
+                                    }
+                                } else if self
+                                    .actions
+                                    .contains_key(command.split_whitespace().next().unwrap_or_default())
+                                {
+                                    let mut words = command.split_whitespace().map(|word| word.to_owned());
+                                    let name = words.next().unwrap_or_default();
+                                    self.run_action(&name, &words.collect::<Vec<_>>());
+                                    continue 'editor;
+                                } else if self.scripts.as_ref().is_some_and(|scripts| {
+                                    scripts.has_command(command.split_whitespace().next().unwrap_or_default())
+                                }) {
+                                    if let Err(e) = self.run_script_command(&command) {
+                                        self.status_msg = e;
+                                    }
+                                    continue 'editor;
+                                } else if command.split_whitespace().next() == Some("export") {
+                                    // export <html|ansi> [filename] [-n]
+                                    let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+                                    let line_numbers = args.contains(&"-n");
+                                    let args: Vec<&str> =
+                                        args.into_iter().filter(|a| *a != "-n").collect();
+
+                                    let doc = self.docs[doc_index].as_mut_text_doc();
+                                    let (contents, extension) = match args.get(0) {
+                                        Some(&"html") => (
+                                            Some(export::to_html(
+                                                doc,
+                                                &config,
+                                                &self.config.languages,
+                                                &self.config.theme,
+                                                line_numbers,
+                                            )),
+                                            "html",
+                                        ),
+                                        Some(&"ansi") => (
+                                            Some(export::to_ansi(
+                                                doc,
+                                                &config,
+                                                &self.config.languages,
+                                                &self.config.theme,
+                                                line_numbers,
+                                            )),
+                                            "ansi",
+                                        ),
+                                        _ => (None, ""),
+                                    };
+
+                                    if let Some(contents) = contents {
+                                        let filename = args
+                                            .get(1)
+                                            .map(|f| f.to_string())
+                                            .unwrap_or(format!("{}.{}", doc.filename, extension));
+
+                                        self.status_msg = match std::fs::write(&filename, contents) {
+                                            Ok(_) => format!("Exported to {}", filename),
+                                            Err(e) => format!("Couldn't export to {}: {}", filename, e),
+                                        };
+                                    } else {
+                                        self.status_msg =
+                                            "Usage: export <html|ansi> [filename] [-n]".to_owned();
+                                    }
                                 }
                             }
                         };
@@ -547,6 +915,8 @@ impl Editor {
                                 if history_index < doc.history.len() {
                                     let action = doc.history[history_index].clone();
                                     let (x, y) = action.apply(UndoRedo::Undo, doc);
+                                    doc.changes.push((action, UndoRedo::Undo));
+                                    doc.changed();
 
                                     if history_index == 0 {
                                         doc.history_index = None;
@@ -567,6 +937,8 @@ impl Editor {
                                 if history_index + 1 < doc.history.len() {
                                     let action = doc.history[history_index + 1].clone();
                                     let (x, y) = action.apply(UndoRedo::Redo, doc);
+                                    doc.changes.push((action, UndoRedo::Redo));
+                                    doc.changed();
                                     *doc.history_index.as_mut().unwrap() += 1;
                                     doc.cursor_col = x;
                                     doc.cursor_row = y;
@@ -576,6 +948,8 @@ impl Editor {
 
                                 let action = doc.history[0].clone();
                                 let (x, y) = action.apply(UndoRedo::Redo, doc);
+                                doc.changes.push((action, UndoRedo::Redo));
+                                doc.changed();
                                 doc.history_index = Some(0);
                                 doc.cursor_col = x;
                                 doc.cursor_row = y;
@@ -583,6 +957,564 @@ impl Editor {
                         }};
                     }
 
+                    macro_rules! jump_to_bracket {
+                        () => {{
+                            let doc = self.docs[doc_index].as_mut_text_doc();
+                            if let Some((row, col)) =
+                                doc.matching_bracket(doc.cursor_row, doc.cursor_col, &config, &self.config.languages)
+                            {
+                                doc.jump_to(row, col, height - 3);
+                            }
+                        }};
+                    }
+
+                    macro_rules! start_completion {
+                        () => {{
+                            let languages = &self.config;
+                            let mut candidates = completion::buffer_words(
+                                self.docs.iter_mut().filter_map(|doc| match doc {
+                                    Document::TextDocument(doc) => {
+                                        let config = languages.language(&doc.extension());
+                                        Some((doc, config))
+                                    }
+                                    _ => None,
+                                }),
+                                &languages.languages,
+                            );
+                            candidates.extend(completion::snippets(&config));
+
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let params = lsp::text_document_position(doc, doc.cursor_row, doc.cursor_col);
+                            match self.language_servers.request(doc, &config, "textDocument/completion", params) {
+                                Ok(Some(response)) => candidates.extend(completion::from_lsp(&response)),
+                                Ok(None) => {}
+                                Err(e) => self.status_msg = e,
+                            }
+
+                            let completion = Completion::new(doc_index, doc, candidates);
+                            if completion.matches.is_empty() {
+                                self.status_msg = "No completions".to_owned();
+                            } else {
+                                self.completion = Some(completion);
+                            }
+                        }};
+                    }
+
+                    macro_rules! format_current {
+                        () => {{
+                            match format_document!() {
+                                Ok(true) => {}
+                                Ok(false) => self.status_msg = "Already formatted".to_owned(),
+                                Err(e) => self.status_msg = e,
+                            }
+                        }};
+                    }
+
+                    macro_rules! show_hover {
+                        () => {{
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let params = lsp::text_document_position(doc, doc.cursor_row, doc.cursor_col);
+
+                            match self.language_servers.request(doc, &config, "textDocument/hover", params) {
+                                Ok(response) => {
+                                    let lines = response.map(|r| hover::hover_text(&r)).unwrap_or_default();
+
+                                    if lines.is_empty() {
+                                        self.status_msg = "No hover information".to_owned();
+                                    } else {
+                                        let (x, y) = Editor::cursor_position(
+                                            doc.cursor_row,
+                                            doc.cursor_col,
+                                            &doc.rows,
+                                            width,
+                                            doc.line_start,
+                                            &config,
+                                            gutter,
+                                        );
+                                        self.show_hover(&lines, x, y);
+                                    }
+                                }
+                                Err(e) => self.status_msg = e,
+                            }
+                        }};
+                    }
+
+                    macro_rules! signature_help {
+                        () => {{
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let params = lsp::text_document_position(doc, doc.cursor_row, doc.cursor_col);
+
+                            match self.language_servers.request(doc, &config, "textDocument/signatureHelp", params) {
+                                Ok(Some(response)) => {
+                                    self.signature_help = SignatureHelp::from_lsp(&response, doc_index, doc)
+                                }
+                                Ok(None) => {}
+                                Err(e) => self.status_msg = e,
+                            }
+                        }};
+                    }
+
+                    macro_rules! rename_symbol {
+                        () => {{
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let (row, col) = (doc.cursor_row, doc.cursor_col);
+                            let name = completion::word_at(&doc.rows[row], col).to_owned();
+
+                            if config.language_server.is_none() {
+                                self.status_msg = "Renaming needs a language server".to_owned();
+                            } else if name.is_empty() {
+                                self.status_msg = "No identifier under the cursor".to_owned();
+                            } else if let Ok(new_name) = self.read_new_filename(Some(name.clone())) {
+                                let new_name = new_name.trim().to_owned();
+
+                                if !new_name.is_empty() && new_name != name {
+                                    let doc = self.docs[doc_index].as_text_doc();
+                                    let mut params = lsp::text_document_position(doc, row, col);
+                                    params["newName"] = serde_json::Value::String(new_name.clone());
+
+                                    self.status_msg =
+                                        match self.language_servers.request(doc, &config, "textDocument/rename", params) {
+                                            Ok(Some(edit)) if !edit.is_null() => match self.apply_workspace_edit(&edit) {
+                                                Ok(files) => format!("Renamed {} to {} in {} files", name, new_name, files),
+                                                Err(e) => e,
+                                            },
+                                            Ok(_) => format!("The language server couldn't rename {}", name),
+                                            Err(e) => e,
+                                        };
+                                }
+                            }
+                            continue 'editor;
+                        }};
+                    }
+
+                    macro_rules! code_actions {
+                        () => {{
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let (start, end) = match doc.selection {
+                                Some(mut selection) => {
+                                    selection.normalize();
+                                    ((selection.start_row, selection.start_col), (selection.end_row, selection.end_col))
+                                }
+                                None => ((doc.cursor_row, doc.cursor_col), (doc.cursor_row, doc.cursor_col)),
+                            };
+
+                            // The server needs to know what's wrong here to offer fixes for it
+                            let diagnostics: Vec<serde_json::Value> = doc
+                                .diagnostics
+                                .iter()
+                                .filter(|d| d.start_row <= end.0 && d.end_row >= start.0)
+                                .map(|d| lsp::from_diagnostic(d, doc))
+                                .collect();
+
+                            let params = serde_json::json!({
+                                "textDocument": { "uri": lsp::path_to_uri(&doc.filename) },
+                                "range": {
+                                    "start": lsp::position(doc, start.0, start.1),
+                                    "end": lsp::position(doc, end.0, end.1)
+                                },
+                                "context": { "diagnostics": diagnostics }
+                            });
+
+                            let actions = match self.language_servers.request(doc, &config, "textDocument/codeAction", params) {
+                                Ok(Some(response)) => refactor::code_actions(&response),
+                                Ok(None) => Vec::new(),
+                                Err(e) => {
+                                    self.status_msg = e;
+                                    continue 'editor;
+                                }
+                            };
+
+                            let titles: Vec<String> = actions.iter().map(|action| action.title.clone()).collect();
+                            if titles.is_empty() {
+                                self.status_msg = "No code actions here".to_owned();
+                            } else if let Some(selected) = self.show_list_prompt("Code actions".to_owned(), &titles) {
+                                let mut action = actions[selected].clone();
+
+                                if action.needs_resolving() {
+                                    let doc = self.docs[doc_index].as_text_doc();
+                                    if let Ok(Some(resolved)) =
+                                        self.language_servers.request(doc, &config, "codeAction/resolve", action.raw.clone())
+                                    {
+                                        action = CodeAction::from_lsp(&resolved).unwrap_or(action);
+                                    }
+                                }
+
+                                if let Some(edit) = &action.edit {
+                                    if let Err(e) = self.apply_workspace_edit(edit) {
+                                        self.status_msg = e;
+                                    }
+                                }
+
+                                // Any edits the command makes come back as workspace/applyEdit requests
+                                if let Some(command) = &action.command {
+                                    let doc = self.docs[doc_index].as_text_doc();
+                                    let params = serde_json::json!({
+                                        "command": command["command"],
+                                        "arguments": command["arguments"]
+                                    });
+                                    if let Err(e) =
+                                        self.language_servers.request(doc, &config, "workspace/executeCommand", params)
+                                    {
+                                        self.status_msg = e;
+                                    }
+                                }
+                            }
+                            continue 'editor;
+                        }};
+                    }
+
+                    macro_rules! show_diagnostics {
+                        () => {{
+                            let mut items = Vec::new();
+                            let mut locations = Vec::new();
+
+                            for (i, doc) in self.docs.iter().enumerate() {
+                                if let Document::TextDocument(doc) = doc {
+                                    for diagnostic in &doc.diagnostics {
+                                        items.push(format!(
+                                            "{}:{}:{} {}: {}",
+                                            doc.filename,
+                                            diagnostic.start_row + 1,
+                                            diagnostic.start_col + 1,
+                                            diagnostic.severity.name(),
+                                            diagnostic.message.lines().next().unwrap_or_default()
+                                        ));
+                                        locations.push((i, diagnostic.start_row, diagnostic.start_col));
+                                    }
+                                }
+                            }
+
+                            if items.is_empty() {
+                                self.status_msg = "No diagnostics".to_owned();
+                            } else if let Some(selected) =
+                                self.show_list_prompt("Diagnostics".to_owned(), &items)
+                            {
+                                let (i, row, col) = locations[selected];
+                                self.open_doc = Some(i);
+                                self.docs[i].as_mut_text_doc().jump_to(row, col, height - 3);
+                                continue 'editor;
+                            }
+                        }};
+                    }
+
+                    // Lists the definitions in the document, from the language server if it has them
+                    macro_rules! show_outline {
+                        () => {{
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let params = serde_json::json!({
+                                "textDocument": { "uri": lsp::path_to_uri(&doc.filename) }
+                            });
+                            let mut symbols =
+                                match self.language_servers.request(doc, &config, "textDocument/documentSymbol", params) {
+                                    Ok(Some(response)) => outline::symbols_from_lsp(&response, doc),
+                                    _ => Vec::new(),
+                                };
+
+                            if symbols.is_empty() {
+                                let doc = self.docs[doc_index].as_mut_text_doc();
+                                symbols = outline::symbols_from_tokens(&mut doc.rows, &config, &self.config.languages);
+                            }
+
+                            let items: Vec<String> = symbols.iter().map(|symbol| symbol.display_name()).collect();
+                            let title = format!("Symbols in {}", self.docs[doc_index].as_text_doc().filename);
+
+                            if items.is_empty() {
+                                self.status_msg = "No symbols found".to_owned();
+                            } else if let Some(selected) = self.show_fuzzy_prompt(title, &items) {
+                                let doc = self.docs[doc_index].as_mut_text_doc();
+                                self.jump_stack.push((doc.filename.clone(), doc.cursor_row, doc.cursor_col));
+                                doc.jump_to(symbols[selected].row, symbols[selected].col, height - 3);
+                            }
+                            continue 'editor;
+                        }};
+                    }
+
+                    // Lists the definitions in every file under the current directory
+                    macro_rules! workspace_symbols {
+                        () => {{
+                            // The index belongs to the directory it was built in, so start over after a cd
+                            let root = std::env::current_dir().and_then(std::fs::canonicalize).unwrap_or_default();
+                            if !self.symbol_index.as_ref().is_some_and(|index| index.root == root) {
+                                self.symbol_index = Some(SymbolIndex::build(root, self.config.languages.clone()));
+                            }
+
+                            let index = self.symbol_index.as_mut().unwrap();
+                            index.poll();
+
+                            let indexing = index.is_indexing();
+                            let symbols: Vec<_> = index
+                                .symbols()
+                                .into_iter()
+                                .map(|(path, symbol)| (display_path(path), symbol.clone()))
+                                .collect();
+                            let items: Vec<String> = symbols
+                                .iter()
+                                .map(|(file, symbol)| format!("{}  {}:{}", symbol.display_name(), file, symbol.row + 1))
+                                .collect();
+
+                            let title = if indexing {
+                                "Symbols in workspace (still indexing)"
+                            } else {
+                                "Symbols in workspace"
+                            };
+
+                            if items.is_empty() {
+                                self.status_msg = if indexing {
+                                    "Still indexing the workspace".to_owned()
+                                } else {
+                                    "No symbols found in the workspace".to_owned()
+                                };
+                            } else if let Some(selected) = self.show_fuzzy_prompt(title.to_owned(), &items) {
+                                let (file, symbol) = &symbols[selected];
+                                let (row, col) = (symbol.row, symbol.col);
+                                jump_to_file!(file.clone(), |_: &TextDocument| (row, col));
+                            }
+                            continue 'editor;
+                        }};
+                    }
+
+                    // Opens the file in its own tab and remembers where we came from
+                    macro_rules! jump_to_file {
+                        ($filename:expr, $locate:expr) => {{
+                            match self.open_path(&$filename) {
+                                Ok(i) => {
+                                    let doc = self.docs[doc_index].as_text_doc();
+                                    self.jump_stack
+                                        .push((doc.filename.clone(), doc.cursor_row, doc.cursor_col));
+
+                                    self.open_doc = Some(i);
+                                    let doc = self.docs[i].as_mut_text_doc();
+                                    let (row, col) = $locate(&*doc);
+                                    doc.jump_to(row, col, height - 3);
+                                    continue 'editor;
+                                }
+                                Err(e) => {
+                                    self.status_msg = e;
+                                    continue 'editor;
+                                }
+                            }
+                        }};
+                    }
+
+                    macro_rules! goto_tag {
+                        ($name:expr) => {{
+                            let name: String = $name;
+                            match Tags::refresh(&mut self.tags) {
+                                Ok(tags) => {
+                                    let definitions: Vec<Tag> =
+                                        tags.definitions(&name).into_iter().cloned().collect();
+
+                                    let selected = match definitions.len() {
+                                        0 => {
+                                            self.status_msg = format!("No definition found for {}", name);
+                                            None
+                                        }
+                                        1 => Some(0),
+                                        _ => {
+                                            let items: Vec<String> =
+                                                definitions.iter().map(Tag::display_name).collect();
+                                            self.show_list_prompt(format!("Definitions of {}", name), &items)
+                                        }
+                                    };
+
+                                    if let Some(selected) = selected {
+                                        let tag = &definitions[selected];
+                                        jump_to_file!(display_path(&tag.file), |doc| tag.locate(doc));
+                                    }
+                                }
+                                Err(e) => self.status_msg = e,
+                            }
+                        }};
+                    }
+
+                    macro_rules! goto_definition {
+                        () => {{
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let name = completion::word_at(&doc.rows[doc.cursor_row], doc.cursor_col).to_owned();
+
+                            if name.is_empty() {
+                                self.status_msg = "No identifier under the cursor".to_owned();
+                            } else {
+                                goto_tag!(name);
+                            }
+                        }};
+                    }
+
+                    macro_rules! find_references {
+                        () => {{
+                            let doc = self.docs[doc_index].as_text_doc();
+                            let name = completion::word_at(&doc.rows[doc.cursor_row], doc.cursor_col).to_owned();
+
+                            if name.is_empty() {
+                                self.status_msg = "No identifier under the cursor".to_owned();
+                            } else {
+                                match Tags::refresh(&mut self.tags) {
+                                    Ok(tags) => {
+                                        let references = tags.references(&name);
+                                        let items: Vec<String> =
+                                            references.iter().map(|r| r.display_name()).collect();
+
+                                        if items.is_empty() {
+                                            self.status_msg = format!("No references to {} found", name);
+                                        } else if let Some(selected) =
+                                            self.show_list_prompt(format!("References to {}", name), &items)
+                                        {
+                                            let reference = &references[selected];
+                                            jump_to_file!(reference.file, |_| (reference.row, reference.col));
+                                        }
+                                    }
+                                    Err(e) => self.status_msg = e,
+                                }
+                            }
+                        }};
+                    }
+
+                    macro_rules! jump_back {
+                        () => {{
+                            match self.jump_stack.pop() {
+                                Some((filename, row, col)) => match self.open_path(&filename) {
+                                    Ok(i) => {
+                                        self.open_doc = Some(i);
+                                        self.docs[i].as_mut_text_doc().jump_to(row, col, height - 3);
+                                        continue 'editor;
+                                    }
+                                    Err(e) => {
+                                        self.status_msg = e;
+                                        continue 'editor;
+                                    }
+                                },
+                                None => self.status_msg = "Nowhere to jump back to".to_owned(),
+                            }
+                        }};
+                    }
+
+                    // Runs a task from the config, or failing that the command line it was given, showing its output in a tab
+                    macro_rules! run_task {
+                        ($name:expr) => {{
+                            let name: String = $name;
+                            let task = match self.config.tasks.get(&name) {
+                                Some(task) => Some(task.clone()),
+                                None => tasks::task_from_command_line(&name),
+                            };
+
+                            if let Some(task) = task {
+                                self.task = None; // Only one at a time, dropping it stops it
+                                self.quickfix.clear();
+                                self.quickfix_index = None;
+
+                                match RunningTask::start(name, &task) {
+                                    Ok(task) => {
+                                        let doc_name = task.doc_name();
+                                        self.status_msg = format!("Running {}", task.name);
+                                        self.task = Some(task);
+
+                                        let existing = self.docs.iter().position(|doc| match doc {
+                                            Document::TextDocument(doc) => doc.read_only && doc.filename == doc_name,
+                                            _ => false,
+                                        });
+
+                                        let i = match existing {
+                                            Some(i) => {
+                                                let doc = self.docs[i].as_mut_text_doc();
+                                                doc.rows = vec![Row::empty()];
+                                                doc.changed();
+                                                doc.jump_to(0, 0, height - 3);
+                                                i
+                                            }
+                                            None => {
+                                                let mut doc = TextDocument::new(doc_name);
+                                                doc.read_only = true;
+                                                doc.rows.push(Row::empty());
+                                                self.add_doc(doc);
+                                                self.docs.len() - 1
+                                            }
+                                        };
+
+                                        self.open_doc = Some(i);
+                                    }
+                                    Err(e) => self.status_msg = format!("Couldn't run {}: {}", task.command, e),
+                                }
+                            }
+                            continue 'editor;
+                        }};
+                    }
+
+                    macro_rules! pick_task {
+                        () => {{
+                            let mut names: Vec<String> = self.config.tasks.keys().cloned().collect();
+                            names.sort();
+
+                            let selected = match names.len() {
+                                0 => {
+                                    self.status_msg =
+                                        "No tasks in the config, use run <command> instead".to_owned();
+                                    None
+                                }
+                                1 => Some(0),
+                                _ => self.show_list_prompt("Run task".to_owned(), &names),
+                            };
+
+                            if let Some(selected) = selected {
+                                run_task!(names[selected].clone());
+                            }
+                        }};
+                    }
+
+                    macro_rules! goto_quickfix {
+                        ($index:expr) => {{
+                            let index: usize = $index;
+                            self.quickfix_index = Some(index);
+
+                            let entry = &self.quickfix[index];
+                            let (file, row, col) = (display_path(Path::new(&entry.file)), entry.row, entry.col);
+                            let message = format!(
+                                "[{}/{}] {}: {}",
+                                index + 1,
+                                self.quickfix.len(),
+                                entry.severity.name(),
+                                entry.message
+                            );
+
+                            match self.open_path(&file) {
+                                Ok(i) => {
+                                    self.open_doc = Some(i);
+                                    self.docs[i].as_mut_text_doc().jump_to(row, col, height - 3);
+                                    self.status_msg = message;
+                                }
+                                Err(e) => self.status_msg = e,
+                            }
+                            continue 'editor;
+                        }};
+                    }
+
+                    macro_rules! goto_error {
+                        ($forward:expr) => {{
+                            let len = self.quickfix.len();
+                            if len == 0 {
+                                self.status_msg = "No errors".to_owned();
+                            } else {
+                                goto_quickfix!(match self.quickfix_index {
+                                    Some(i) if $forward => (i + 1) % len,
+                                    Some(i) => (i + len - 1) % len,
+                                    None if $forward => 0,
+                                    None => len - 1,
+                                });
+                            }
+                        }};
+                    }
+
+                    macro_rules! show_quickfix {
+                        () => {{
+                            let items: Vec<String> = self.quickfix.iter().map(|entry| entry.display_name()).collect();
+
+                            if items.is_empty() {
+                                self.status_msg = "No errors".to_owned();
+                            } else if let Some(selected) = self.show_list_prompt("Errors".to_owned(), &items) {
+                                goto_quickfix!(selected);
+                            }
+                        }};
+                    }
+
                     macro_rules! copy_selection {
                         () => {{
                             let doc = self.docs[doc_index].as_mut_text_doc();
@@ -682,39 +1614,149 @@ impl Editor {
                             width,
                             doc.line_start,
                             &config,
+                            gutter,
                         );
                     } else {
                         self.mouse_event = false;
                     }
 
-                    let event = read().unwrap();
+                    let event = match self.read_event().unwrap() {
+                        Some(event) => event,
+                        None => {
+                            self.undergoing_selection = true; // Keep the selection across the redraw
+                            continue 'editor;
+                        }
+                    };
                     let mut process_event = true;
 
-                    if let crossterm::event::Event::Key(k) = event {
-                        for (name, keybound_event) in &self.config.keybinds {
-                            if keybound_event.equals(&k) {
-                                match &name[..] {
-                                    "copy" => copy_selection!(),
-                                    "paste" => paste_clip!(),
-                                    "redo" => redo_last!(),
-                                    "undo" => undo_last!(),
-                                    "start_command" => process_command!(),
-                                    "close_file" => close_file!(),
-                                    "open_file" => open_file!(),
-                                    "save_file" => save_file!(),
-                                    "new_file" => new_file!(),
-                                    _ => {}
+                    // An open completion popup gets first go at navigation keys
+                    if let (Some(completion), Key(k)) = (self.completion.as_mut(), event) {
+                        match k.code {
+                            KeyCode::Up => completion.select_previous(),
+                            KeyCode::Down => completion.select_next(),
+                            KeyCode::Tab | KeyCode::Enter => {
+                                completion.accept(self.docs[doc_index].as_mut_text_doc(), &config);
+                                self.completion = None;
+                            }
+                            KeyCode::Esc => self.completion = None,
+                            _ => {}
+                        }
+
+                        process_event = !matches!(
+                            k.code,
+                            KeyCode::Up | KeyCode::Down | KeyCode::Tab | KeyCode::Enter | KeyCode::Esc
+                        );
+                    }
+
+                    if let (true, Some(_), Key(k)) = (process_event, &self.signature_help, event) {
+                        if k.code == KeyCode::Esc {
+                            self.signature_help = None;
+                            process_event = false;
+                        }
+                    }
+
+                    // Output documents can be looked at but not changed
+                    let read_only = self.docs[doc_index].as_text_doc().read_only;
+
+                    // Modal editing gets the keys before the keybinds, and can hand some of them on as actions
+                    let mut vim_resolved = None;
+                    if let (true, true, Key(k)) = (process_event, self.config.vim, event) {
+                        match self.vim_key(doc_index, &k) {
+                            VimKey::Handled => process_event = false,
+                            VimKey::Action(found) => vim_resolved = Some(KeyPress::Action(found.name().to_owned())),
+                            VimKey::Resolved(resolved) => vim_resolved = Some(resolved),
+                            VimKey::PassThrough => {}
+                        }
+                    }
+
+                    // Keys go through the keybinds, unbound characters are typed
+                    let mut action = None;
+                    if let (true, Key(k)) = (process_event, event) {
+                        let resolved = match vim_resolved {
+                            Some(resolved) => resolved,
+                            None => self.resolve_key(&k, false),
+                        };
+                        let previous_action = self.last_action.take();
+
+                        match resolved {
+                            KeyPress::Action(name) => {
+                                self.last_action = Action::from_name(&name);
+
+                                match self.last_action {
+                                    Some(found) if read_only && found.edits() => {
+                                        self.status_msg = "Document is read only".to_owned()
+                                    }
+                                    Some(Action::Copy) => copy_selection!(),
+                                    Some(Action::Paste) => paste_clip!(),
+                                    Some(Action::Redo) => redo_last!(),
+                                    Some(Action::Undo) => undo_last!(),
+                                    Some(Action::StartCommand) => process_command!(),
+                                    Some(Action::CloseFile) => close_file!(),
+                                    Some(Action::OpenFile) => open_file!(),
+                                    Some(Action::SaveFile) => save_file!(),
+                                    Some(Action::NewFile) => new_file!(),
+                                    Some(Action::JumpToBracket) => jump_to_bracket!(),
+                                    Some(Action::ShowDiagnostics) => show_diagnostics!(),
+                                    Some(Action::Outline) => show_outline!(),
+                                    Some(Action::WorkspaceSymbols) => workspace_symbols!(),
+                                    Some(Action::Complete) => start_completion!(),
+                                    Some(Action::Hover) => show_hover!(),
+                                    Some(Action::Format) => format_current!(),
+                                    Some(Action::GotoDefinition) => goto_definition!(),
+                                    Some(Action::Rename) => rename_symbol!(),
+                                    Some(Action::CodeActions) => code_actions!(),
+                                    Some(Action::RunTask) => pick_task!(),
+                                    Some(Action::NextError) => goto_error!(true),
+                                    Some(Action::PreviousError) => goto_error!(false),
+                                    Some(Action::FindReferences) => find_references!(),
+                                    Some(Action::JumpBack) => jump_back!(),
+                                    Some(Action::SetMark) => self.set_mark(doc_index),
+                                    Some(Action::KillLine) => self.kill_line(doc_index, previous_action),
+                                    Some(Action::KillRegion) => self.kill_region(doc_index, previous_action, true),
+                                    Some(Action::KillRingSave) => self.kill_region(doc_index, previous_action, false),
+                                    Some(Action::Yank) => self.yank(doc_index),
+                                    Some(Action::YankPop) => self.yank_pop(doc_index, previous_action),
+                                    Some(found) => action = Some(found),
+                                    None if self.actions.contains_key(&name) => {
+                                        self.run_action(&name, &[]);
+                                        continue 'editor;
+                                    }
+                                    None => {}
+                                }
+                                process_event = action.is_some();
+
+                                // Editing ends the region
+                                if action.is_some_and(Action::edits) {
+                                    self.kill_ring.mark = None;
                                 }
+                            }
+                            KeyPress::Chord => {
+                                self.last_action = previous_action;
+                                process_event = false;
+                            }
+                            KeyPress::Unbound
+                                if read_only
+                                    && matches!(k.code, KeyCode::Char(_))
+                                    && !k.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+                            {
+                                self.status_msg = "Document is read only".to_owned();
                                 process_event = false;
                             }
+                            // Typing ends the region without typing over it
+                            KeyPress::Unbound if self.kill_ring.mark.take().is_some() => {
+                                self.docs[doc_index].as_mut_text_doc().selection = None;
+                            }
+                            KeyPress::Unbound => {}
                         }
                     }
+                    let by_word = action.is_some_and(Action::by_word);
+                    let selecting = action.is_some_and(Action::selects);
 
                     if process_event {
                         match event {
                             Key(k) => {
-                                match k.code {
-                                    KeyCode::Char(c) => {
+                                match (action, k.code) {
+                                    (None, KeyCode::Char(c)) => {
                                         let doc = self.docs[doc_index].as_mut_text_doc();
                                         if doc.selection.is_some() {
                                             doc.selection.as_mut().unwrap().normalize();
@@ -858,13 +1900,13 @@ impl Editor {
                                             doc.to_auto_close = true;
                                         }
                                     }
-                                    KeyCode::Esc => {
+                                    (Some(Action::Menu), _) => {
                                         if self.show_global_prompt().is_err() {
                                             break;
                                         } 
                                     },
-                                    KeyCode::F(1) => break,
-                                    KeyCode::Backspace => {
+                                    (Some(Action::Quit), _) => break,
+                                    (Some(Action::Backspace), _) => {
                                         let doc = self.docs[doc_index].as_mut_text_doc();
                                         if doc.selection.is_some() {
                                             doc.selection.as_mut().unwrap().normalize();
@@ -972,7 +2014,7 @@ impl Editor {
                                         doc.dirty += 1;
                                         if doc.rows[doc.cursor_row].len() != 0 {
                                             if doc.cursor_col == doc.rows[doc.cursor_row].len() {
-                                                let c = doc.rows[doc.cursor_row].buf.pop().unwrap();
+                                                let c = doc.rows[doc.cursor_row].remove_at(doc.cursor_col - 1);
 
                                                 doc.add_diff(EditDiff::DeleteChar(
                                                     doc.cursor_col,
@@ -1006,9 +2048,7 @@ impl Editor {
                                                                 line.clone(),
                                                                 LineDeleteMode::Joined,
                                                             ));
-                                                            doc.rows[doc.cursor_row]
-                                                                .buf
-                                                                .push_str(&line);
+                                                            doc.rows[doc.cursor_row].push_str(&line);
                                                         }
                                                     }
                                                 } else {
@@ -1043,7 +2083,7 @@ impl Editor {
                                             }
                                         }
                                     }
-                                    KeyCode::Delete => {
+                                    (Some(Action::Delete), _) => {
                                         let doc = self.docs[doc_index].as_mut_text_doc();
                                         if doc.selection.is_some() {
                                             doc.selection.as_mut().unwrap().normalize();
@@ -1154,9 +2194,7 @@ impl Editor {
                                                 if doc.cursor_row + 1 < doc.rows.len() {
                                                     let next_line =
                                                         doc.rows[doc.cursor_row + 1].buf.clone();
-                                                    doc.rows[doc.cursor_row]
-                                                        .buf
-                                                        .push_str(&next_line);
+                                                    doc.rows[doc.cursor_row].push_str(&next_line);
 
                                                     doc.rows.remove(doc.cursor_row + 1);
                                                 }
@@ -1186,32 +2224,37 @@ impl Editor {
                                             }
                                         }
                                     }
-                                    KeyCode::Tab => {
-                                        if k.modifiers.contains(KeyModifiers::SHIFT) {
-                                            if self.open_doc.unwrap() + 1 < num_docs {
-                                                self.open_doc = Some(self.open_doc.unwrap() + 1);
-                                            } else {
-                                                self.open_doc = Some(0);
-                                            }
+                                    (Some(Action::NextDoc), _) => {
+                                        if self.open_doc.unwrap() + 1 < num_docs {
+                                            self.open_doc = Some(self.open_doc.unwrap() + 1);
+                                        } else {
+                                            self.open_doc = Some(0);
                                         }
-                                        else {
-                                            let doc = self.docs[doc_index].as_mut_text_doc();
-                                            doc.dirty += 1;
-                                            let mut diff_vec = Vec::new();
-                                            for c in unescape(&config.tab_str).unwrap().chars() {
-                                                doc.rows[doc.cursor_row].insert_char(doc.cursor_col, c);
-                                                diff_vec.push(EditDiff::InsertChar(
-                                                    doc.cursor_col,
-                                                    doc.cursor_row,
-                                                    c,
-                                                ));
+                                    }
+                                    (Some(Action::PreviousDoc), _) => {
+                                        if self.open_doc.unwrap() != 0 {
+                                            self.open_doc = Some(self.open_doc.unwrap() - 1);
+                                        } else {
+                                            self.open_doc = Some(num_docs - 1);
+                                        }
+                                    }
+                                    (Some(Action::Indent), _) => {
+                                        let doc = self.docs[doc_index].as_mut_text_doc();
+                                        doc.dirty += 1;
+                                        let mut diff_vec = Vec::new();
+                                        for c in unescape(&config.tab_str).unwrap().chars() {
+                                            doc.rows[doc.cursor_row].insert_char(doc.cursor_col, c);
+                                            diff_vec.push(EditDiff::InsertChar(
+                                                doc.cursor_col,
+                                                doc.cursor_row,
+                                                c,
+                                            ));
 
-                                                doc.cursor_col += 1;
-                                            }
-                                            doc.add_diff(EditDiff::Compound(diff_vec));
+                                            doc.cursor_col += 1;
                                         }
+                                        doc.add_diff(EditDiff::Compound(diff_vec));
                                     }
-                                    KeyCode::Enter => {
+                                    (Some(Action::NewLine), _) => {
                                         let doc = self.docs[doc_index].as_mut_text_doc();
                                         doc.dirty += 1;
                                         if doc.rows[doc.cursor_row].len() == 0 {
@@ -1260,7 +2303,7 @@ impl Editor {
                                             doc.cursor_col = 0;
                                         }
                                     }
-                                    KeyCode::Up => {
+                                    (Some(Action::MoveUp), _) | (Some(Action::SelectUp), _) => {
                                         let doc = self.docs[doc_index].as_mut_text_doc();
 
                                         let cursor_row = doc.cursor_row;
@@ -1279,7 +2322,7 @@ impl Editor {
                                             }
                                         }
 
-                                        if k.modifiers.contains(KeyModifiers::SHIFT) {
+                                        if selecting {
                                             if doc.selection.is_none() {
                                                 doc.selection = Some(Selection::new(
                                                     cursor_row,
@@ -1297,7 +2340,7 @@ impl Editor {
                                             self.undergoing_selection = true;
                                         }
                                     }
-                                    KeyCode::Down => {
+                                    (Some(Action::MoveDown), _) | (Some(Action::SelectDown), _) => {
                                         let doc = self.docs[doc_index].as_mut_text_doc();
 
                                         let cursor_row = doc.cursor_row;
@@ -1318,7 +2361,7 @@ impl Editor {
                                             }
                                         }
 
-                                        if k.modifiers.contains(KeyModifiers::SHIFT) {
+                                        if selecting {
                                             if doc.selection.is_none() {
                                                 doc.selection = Some(Selection::new(
                                                     cursor_row,
@@ -1335,13 +2378,16 @@ impl Editor {
                                             self.undergoing_selection = true;
                                         }
                                     }
-                                    KeyCode::Left => {
+                                    (Some(Action::MoveLeft), _)
+                                    | (Some(Action::WordLeft), _)
+                                    | (Some(Action::SelectLeft), _)
+                                    | (Some(Action::SelectWordLeft), _) => {
                                         let doc = self.docs[doc_index].as_mut_text_doc();
 
                                         let cursor_row = doc.cursor_row;
                                         let cursor_col = doc.cursor_col;
 
-                                        if k.modifiers.contains(KeyModifiers::CONTROL) {
+                                        if by_word {
                                             if doc.rows[doc.cursor_row].tokens.len() != 0
                                                 && doc.cursor_col != 0
                                             {
@@ -1356,23 +2402,6 @@ impl Editor {
                                                     }
                                                 }
                                             }
-
-                                            if k.modifiers.contains(KeyModifiers::SHIFT) {
-                                                if doc.selection.is_none() {
-                                                    doc.selection = Some(Selection::new(
-                                                        cursor_row,
-                                                        cursor_col,
-                                                        doc.cursor_row,
-                                                        doc.cursor_col,
-                                                    ));
-                                                } else {
-                                                    let selection = doc.selection.as_mut().unwrap();
-    
-                                                    selection.end_row = doc.cursor_row;
-                                                    selection.end_col = doc.cursor_col;
-                                                }
-                                                self.undergoing_selection = true;
-                                            }
                                         } else {
                                             if doc.cursor_col != 0 {
                                                 doc.cursor_col -= 1;
@@ -1384,7 +2413,7 @@ impl Editor {
                                             }
                                         }
 
-                                        if k.modifiers.contains(KeyModifiers::SHIFT) {
+                                        if selecting {
                                             if doc.selection.is_none() {
                                                 doc.selection = Some(Selection::new(
                                                     cursor_row,
@@ -1401,13 +2430,16 @@ impl Editor {
                                             self.undergoing_selection = true;
                                         }
                                     }
-                                    KeyCode::Right => {
+                                    (Some(Action::MoveRight), _)
+                                    | (Some(Action::WordRight), _)
+                                    | (Some(Action::SelectRight), _)
+                                    | (Some(Action::SelectWordRight), _) => {
                                         let doc = self.docs[doc_index].as_mut_text_doc();
 
                                         let cursor_row = doc.cursor_row;
                                         let cursor_col = doc.cursor_col;
 
-                                        if k.modifiers.contains(KeyModifiers::CONTROL) {
+                                        if by_word {
                                             let mut found_next_token = false;
 
                                             if doc.rows[doc.cursor_row].tokens.len() != 0 {
@@ -1416,29 +2448,12 @@ impl Editor {
                                                         doc.cursor_col = token.start();
                                                         found_next_token = true;
                                                         break;
-                                                    }
-                                                }
-                                            }
-
-                                            if !found_next_token {
-                                                doc.cursor_col = doc.rows[doc.cursor_row].len();
-                                            }
-
-                                            if k.modifiers.contains(KeyModifiers::SHIFT) {
-                                                if doc.selection.is_none() {
-                                                    doc.selection = Some(Selection::new(
-                                                        cursor_row,
-                                                        cursor_col,
-                                                        doc.cursor_row,
-                                                        doc.cursor_col,
-                                                    ));
-                                                } else {
-                                                    let selection = doc.selection.as_mut().unwrap();
-    
-                                                    selection.end_row = doc.cursor_row;
-                                                    selection.end_col = doc.cursor_col;
+                                                    }
                                                 }
-                                                self.undergoing_selection = true;
+                                            }
+
+                                            if !found_next_token {
+                                                doc.cursor_col = doc.rows[doc.cursor_row].len();
                                             }
                                         } else {
                                             doc.cursor_col += 1;
@@ -1452,7 +2467,7 @@ impl Editor {
                                             }
                                         }
 
-                                        if k.modifiers.contains(KeyModifiers::SHIFT) {
+                                        if selecting {
                                             if doc.selection.is_none() {
                                                 doc.selection = Some(Selection::new(
                                                     cursor_row,
@@ -1469,10 +2484,10 @@ impl Editor {
                                             self.undergoing_selection = true;
                                         }
                                     }
-                                    KeyCode::Home => {
+                                    (Some(Action::LineStart), _) => {
                                         self.docs[doc_index].as_mut_text_doc().cursor_col = 0;
                                     }
-                                    KeyCode::End => {
+                                    (Some(Action::LineEnd), _) => {
                                         let row = self.docs[doc_index].as_mut_text_doc().cursor_row;
                                         self.docs[doc_index].as_mut_text_doc().cursor_col =
                                             self.docs[doc_index].as_mut_text_doc().rows[row].len();
@@ -1498,8 +2513,9 @@ impl Editor {
                                                 doc.cursor_col = doc.rows[doc.cursor_row].buf.len();
                                             }
                                         }
-                                        if e.column < doc.rows[doc.cursor_row].buf.len() as u16 {
-                                            doc.cursor_col = e.column as usize;
+                                        let column = e.column.saturating_sub(gutter as u16);
+                                        if column < doc.rows[doc.cursor_row].buf.len() as u16 {
+                                            doc.cursor_col = column as usize;
                                             while !doc.rows[doc.cursor_row]
                                                 .buf
                                                 .is_char_boundary(doc.cursor_col)
@@ -1516,6 +2532,7 @@ impl Editor {
                                             width,
                                             doc.line_start,
                                             &config,
+                                            gutter,
                                         );
                                     } else {
                                         for (i, doc_index) in
@@ -1531,13 +2548,13 @@ impl Editor {
                                                         } else {
                                                             self.open_doc = Some(1);
                                                         }
-                                                        self.docs.remove(i);
+                                                        self.remove_doc(i);
                                                         self.redraw = true;
                                                         self.mouse_event = false;
                                                         continue 'editor;
                                                     } else if self.docs.len() > 0 {
                                                         self.open_doc = None;
-                                                        self.docs.remove(i);
+                                                        self.remove_doc(i);
                                                         self.redraw = true;
                                                         self.mouse_event = false;
                                                         continue 'editor;
@@ -1562,6 +2579,31 @@ impl Editor {
                         }
                     }
 
+                    if let Some(completion) = self.completion.as_mut() {
+                        if completion.doc != doc_index
+                            || !completion.update(self.docs[doc_index].as_text_doc())
+                        {
+                            self.completion = None;
+                        }
+                    }
+
+                    if let Some(help) = self.signature_help.as_mut() {
+                        if help.doc != doc_index || !help.update(self.docs[doc_index].as_text_doc()) {
+                            self.signature_help = None;
+                        }
+                    }
+
+                    self.show_region(doc_index);
+
+                    // Typing a call's '(' asks for its signature, as does a ',' inside one we don't know about yet
+                    if let (true, Key(k)) = (process_event, event) {
+                        if k.code == KeyCode::Char('(')
+                            || (k.code == KeyCode::Char(',') && self.signature_help.is_none())
+                        {
+                            signature_help!();
+                        }
+                    }
+
                     let doc = self.docs[doc_index].as_mut_text_doc();
                     let actual_rows = height as i32 - 3;
                     let diff = doc.visual_rows_to(width, doc.cursor_row, &config) as i32
@@ -1773,21 +2815,35 @@ impl Editor {
                         std::io::stdout().flush()?;
                     }
 
-                    let event = read().unwrap();
+                    let event = match self.read_event().unwrap() {
+                        Some(event) => event,
+                        None => {
+                            self.undergoing_selection = true; // Keep the selection across the redraw
+                            continue 'editor;
+                        }
+                    };
                     let mut process_event = true;
 
+                    let mut action = None;
                     if let crossterm::event::Event::Key(k) = event {
-                        for (name, keybound_event) in &self.config.keybinds {
-                            if keybound_event.equals(&k) {
-                                match &name[..] {
-                                    "close_file" => close_file!(),
-                                    "open_file" => open_file!(),
-                                    "save_file" => save_file!(),
-                                    "new_file" => new_file!(),
-                                    _ => {}
+                        match self.resolve_key(&k, true) {
+                            KeyPress::Action(name) => {
+                                match Action::from_name(&name) {
+                                    Some(Action::CloseFile) => close_file!(),
+                                    Some(Action::OpenFile) => open_file!(),
+                                    Some(Action::SaveFile) => save_file!(),
+                                    Some(Action::NewFile) => new_file!(),
+                                    Some(found) => action = Some(found),
+                                    None if self.actions.contains_key(&name) => {
+                                        self.run_action(&name, &[]);
+                                        continue 'editor;
+                                    }
+                                    None => {}
                                 }
-                                process_event = false;
+                                process_event = action.is_some();
                             }
+                            KeyPress::Chord => process_event = false,
+                            KeyPress::Unbound => {}
                         }
                     }
 
@@ -1795,21 +2851,28 @@ impl Editor {
                         continue;
                     }
 
+                    // There's nothing to select here, so the word selection keys switch documents instead
                     match event {
-                        Key(k) => match k.code {
-                            KeyCode::Esc => break,
-                            KeyCode::Right => {
-                                if k.modifiers.contains(KeyModifiers::CONTROL)
-                                    && k.modifiers.contains(KeyModifiers::SHIFT)
-                                {
-                                    if self.open_doc.unwrap() + 1 < self.docs.len() {
-                                        self.open_doc = Some(self.open_doc.unwrap() + 1);
-                                    } else {
-                                        self.open_doc = Some(0);
-                                    }
-                                    continue;
+                        Key(k) => match (action, k.code) {
+                            (Some(Action::Menu), _) | (Some(Action::Quit), _) => break,
+                            (Some(Action::NextDoc), _) | (Some(Action::SelectWordRight), _) => {
+                                if self.open_doc.unwrap() + 1 < self.docs.len() {
+                                    self.open_doc = Some(self.open_doc.unwrap() + 1);
+                                } else {
+                                    self.open_doc = Some(0);
                                 }
-
+                                continue;
+                            }
+                            (Some(Action::PreviousDoc), _) | (Some(Action::SelectWordLeft), _) => {
+                                if self.open_doc.unwrap() != 0 {
+                                    self.open_doc = Some(self.open_doc.unwrap() - 1);
+                                } else if self.open_doc.unwrap() == 0 && self.docs.len() == 1 {
+                                } else {
+                                    self.open_doc = Some(self.docs.len());
+                                }
+                                continue;
+                            }
+                            (Some(Action::MoveRight), _) => {
                                 if self.docs[doc_index].as_bin_doc().high_nibble {
                                     self.docs[doc_index].as_bin_doc().high_nibble = false;
                                 } else {
@@ -1821,19 +2884,7 @@ impl Editor {
                                     }
                                 }
                             }
-                            KeyCode::Left => {
-                                if k.modifiers.contains(KeyModifiers::CONTROL)
-                                    && k.modifiers.contains(KeyModifiers::SHIFT)
-                                {
-                                    if self.open_doc.unwrap() != 0 {
-                                        self.open_doc = Some(self.open_doc.unwrap() - 1);
-                                    } else if self.open_doc.unwrap() == 0 && self.docs.len() == 1 {
-                                    } else {
-                                        self.open_doc = Some(self.docs.len());
-                                    }
-                                    continue;
-                                }
-
+                            (Some(Action::MoveLeft), _) => {
                                 if !self.docs[doc_index].as_bin_doc().high_nibble {
                                     self.docs[doc_index].as_bin_doc().high_nibble = true;
                                 } else {
@@ -1843,7 +2894,7 @@ impl Editor {
                                     }
                                 }
                             }
-                            KeyCode::Up => {
+                            (Some(Action::MoveUp), _) => {
                                 if self.docs[doc_index].as_bin_doc().cursor
                                     >= BinaryDocument::bytes_per_row()
                                 {
@@ -1851,7 +2902,7 @@ impl Editor {
                                         BinaryDocument::bytes_per_row();
                                 }
                             }
-                            KeyCode::Down => {
+                            (Some(Action::MoveDown), _) => {
                                 if self.docs[doc_index].as_bin_doc().cursor
                                     + BinaryDocument::bytes_per_row()
                                     < self.docs[doc_index].as_bin_doc().data.len()
@@ -1860,7 +2911,7 @@ impl Editor {
                                         BinaryDocument::bytes_per_row();
                                 }
                             }
-                            KeyCode::Char(c) => {
+                            (None, KeyCode::Char(c)) => {
                                 let allowed_chars = "1234567890abcdef";
 
                                 let high_nibble = self.docs[doc_index].as_bin_doc().high_nibble;
@@ -1892,17 +2943,21 @@ impl Editor {
                                             self.docs[doc_index].as_bin_doc().cursor += 1;
                                         }
                                     }
-                                } else if c == 'i' {
-                                    self.docs[doc_index].as_bin_doc().data.insert(cursor, 0);
-                                } else if c == 'I' {
-                                    if cursor + 1 < self.docs[doc_index].as_bin_doc().data.len() {
-                                        self.docs[doc_index]
-                                            .as_bin_doc()
-                                            .data
-                                            .insert(cursor + 1, 0);
-                                    } else {
-                                        self.docs[doc_index].as_bin_doc().data.push(0);
-                                    }
+                                }
+                            }
+                            (Some(Action::InsertByte), _) => {
+                                let cursor = self.docs[doc_index].as_bin_doc().cursor;
+                                self.docs[doc_index].as_bin_doc().data.insert(cursor, 0);
+                            }
+                            (Some(Action::AppendByte), _) => {
+                                let cursor = self.docs[doc_index].as_bin_doc().cursor;
+                                if cursor + 1 < self.docs[doc_index].as_bin_doc().data.len() {
+                                    self.docs[doc_index]
+                                        .as_bin_doc()
+                                        .data
+                                        .insert(cursor + 1, 0);
+                                } else {
+                                    self.docs[doc_index].as_bin_doc().data.push(0);
                                 }
                             }
                             _ => {}
@@ -1957,9 +3012,19 @@ impl Editor {
             }
 
             let event = read()?;
+            self.run_plugins(|plugin, editor| plugin.on_event(editor, &event));
 
             if let Key(k) = event {
-                if k.modifiers.contains(KeyModifiers::CONTROL) && k.code == KeyCode::Char('n') {
+                let action = match self.resolve_key(&k, false) {
+                    KeyPress::Action(name) => Action::from_name(&name),
+                    KeyPress::Chord => {
+                        redraw = true;
+                        None
+                    }
+                    KeyPress::Unbound => None,
+                };
+
+                if action == Some(Action::NewFile) {
                     if let Ok(filename) = self.read_new_filename(None) {
                         if !Path::new(&filename).exists() {
                             self.open_doc = Some(self.docs.len());
@@ -1974,9 +3039,7 @@ impl Editor {
                     } else {
                         redraw = true;
                     }
-                } else if k.modifiers.contains(KeyModifiers::CONTROL)
-                    && k.code == KeyCode::Char('o')
-                {
+                } else if action == Some(Action::OpenFile) {
                     if let Ok(filename) = self.read_new_filename(None) {
                         if filename.starts_with("bin:") {
                             let mut chars = filename.chars();
@@ -2000,7 +3063,7 @@ impl Editor {
                                             &self.config.languages[&"*".to_owned()]
                                         };
                                     self.open_doc = Some(self.docs.len());
-                                    doc.tokenize(0, self.height(), config);
+                                    doc.tokenize(0, self.height(), config, &self.config.languages);
 
                                     self.add_doc(doc);
                                     return Ok(());
@@ -2014,9 +3077,7 @@ impl Editor {
                     } else {
                         redraw = true;
                     }
-                } else if k.modifiers.contains(KeyModifiers::CONTROL)
-                    && k.code == KeyCode::Char('g')
-                {
+                } else if action == Some(Action::StartCommand) {
                     if let Ok(mut command) = self.read_new_filename(None) {
                         if command.starts_with("cd") {
                             command.drain(..2);
@@ -2035,7 +3096,7 @@ impl Editor {
                             }
                         }
                     }
-                } else if k.code == KeyCode::Esc {
+                } else if action == Some(Action::Menu) || action == Some(Action::Quit) {
                     std::io::stdout()
                         .execute(crossterm::event::DisableMouseCapture)?
                         .execute(crossterm::terminal::Clear(
@@ -2057,6 +3118,162 @@ impl Editor {
         }
     }
 
+    // Index of the text document for filename, loading it into a new tab if it isn't open yet
+    pub fn open_path(&mut self, filename: &str) -> Result<usize, String> {
+        let path = std::fs::canonicalize(filename).ok();
+
+        for (i, doc) in self.docs.iter().enumerate() {
+            if let Document::TextDocument(doc) = doc {
+                if doc.filename == filename
+                    || (path.is_some() && std::fs::canonicalize(&doc.filename).ok() == path)
+                {
+                    return Ok(i);
+                }
+            }
+        }
+
+        match TextDocument::load(filename.to_owned()) {
+            Ok(mut doc) => {
+                doc.tokenize(0, self.height(), self.config.language(&doc.extension()), &self.config.languages);
+                self.add_doc(doc);
+                Ok(self.docs.len() - 1)
+            }
+            Err(filename) => Err(format!("File {} not found!", filename)),
+        }
+    }
+
+    // Makes the changes in an lsp WorkspaceEdit, opening files that aren't open yet. Each file gets one undo step.
+    // Returns how many files were changed
+    pub fn apply_workspace_edit(&mut self, edit: &serde_json::Value) -> Result<usize, String> {
+        let mut changed = 0;
+
+        for (path, edits) in refactor::workspace_edit_files(edit) {
+            let i = self.open_path(&display_path(Path::new(&path)))?;
+            if refactor::apply_edits(self.docs[i].as_mut_text_doc(), &edits) {
+                changed += 1;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    // Makes the edit a server asked for with workspace/applyEdit, then tells it whether that worked
+    pub fn answer_apply_edit(&mut self, edit: &serde_json::Value, reply: lsp::Reply) {
+        match self.apply_workspace_edit(edit) {
+            Ok(_) => reply.send(serde_json::json!({ "applied": true })),
+            Err(e) => {
+                reply.send(serde_json::json!({ "applied": false, "failureReason": e }));
+                self.status_msg = e;
+            }
+        }
+    }
+
+    // Waits for the next terminal event, handling whatever the language servers send in the meantime.
+    // Returns None if something changed that needs the screen redrawn first
+    pub fn read_event(&mut self) -> crossterm::Result<Option<crossterm::event::Event>> {
+        // Keys being repeated by vim's .
+        if let Some(key) = self.vim.replay.pop_front() {
+            return Ok(Some(Key(key)));
+        }
+
+        loop {
+            if poll(EVENT_POLL_INTERVAL)? {
+                let event = read()?;
+
+                // Plugins see every event first, if one switches documents the event no longer applies
+                let open = (self.open_doc, self.docs.len());
+                self.run_plugins(|plugin, editor| plugin.on_event(editor, &event));
+                if (self.open_doc, self.docs.len()) != open {
+                    return Ok(None);
+                }
+
+                return Ok(Some(event));
+            }
+
+            // Let go of a chord that was never finished, which also clears it from the status bar
+            if self.chord_timed_out() {
+                self.pending_keys.clear();
+                return Ok(None);
+            }
+
+            let mut redraw = false;
+
+            for event in self.language_servers.poll() {
+                match event {
+                    LspEvent::Message(message) => {
+                        print!("{}", crossterm::cursor::SavePosition);
+                        self.write_status_bar(Some(message.clone()));
+                        print!("{}", crossterm::cursor::RestorePosition);
+                        std::io::stdout().flush()?;
+
+                        self.status_msg = message;
+                    }
+                    LspEvent::ApplyEdit(edit, reply) => {
+                        self.answer_apply_edit(&edit, reply);
+                        redraw = true;
+                    }
+                    LspEvent::Diagnostics(uri, diagnostics) => {
+                        for (i, doc) in self.docs.iter_mut().enumerate() {
+                            if let Document::TextDocument(doc) = doc {
+                                if lsp::path_to_uri(&doc.filename) == uri {
+                                    doc.diagnostics = lsp::to_diagnostics(&diagnostics, doc);
+                                    redraw |= self.open_doc == Some(i);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            let visible_rows = self.height() - 3;
+            if let Some(task) = self.task.as_mut() {
+                let (lines, status) = task.poll();
+                let doc_name = task.doc_name();
+
+                if !lines.is_empty() {
+                    for (i, doc) in self.docs.iter_mut().enumerate() {
+                        if let Document::TextDocument(doc) = doc {
+                            if doc.read_only && doc.filename == doc_name {
+                                // Keep following the output if the cursor was at the end of it
+                                let follow = doc.cursor_row + 1 >= doc.rows.len();
+                                if doc.rows.len() == 1 && doc.rows[0].buf.is_empty() {
+                                    doc.rows.clear();
+                                }
+
+                                doc.rows.extend(lines.iter().map(|line| Row::from_string(line.clone())));
+                                doc.changed();
+                                if follow {
+                                    doc.jump_to(doc.rows.len() - 1, 0, visible_rows);
+                                }
+                                redraw |= self.open_doc == Some(i);
+                            }
+                        }
+                    }
+                }
+
+                if let Some(status) = status {
+                    self.quickfix = task.quickfix();
+                    self.quickfix_index = None;
+
+                    let errors = self.quickfix.iter().filter(|entry| entry.severity == Severity::Error).count();
+                    self.status_msg = format!(
+                        "{} {} with {} errors and {} warnings",
+                        task.name,
+                        if status.success() { "finished" } else { "failed" },
+                        errors,
+                        self.quickfix.len() - errors
+                    );
+                    self.task = None;
+                    redraw = true;
+                }
+            }
+
+            if redraw {
+                return Ok(None);
+            }
+        }
+    }
+
     pub fn write_status_bar(&self, mut extra_info: Option<String>) {
         if extra_info.is_none() {
             extra_info =
@@ -2075,29 +3292,39 @@ impl Editor {
             index = self.open_doc.unwrap() + 1;
         }
 
-        let status_str = format!("[{}] - Doc {} of {}", filename, index, self.docs.len());
+        let mut status_str = format!("[{}] - Doc {} of {}", filename, index, self.docs.len());
+        if self.config.vim {
+            status_str.push_str(&format!(" | {} {}", self.vim.mode.name(), self.vim.pending));
+            status_str.truncate(status_str.trim_end().len());
+        }
+        if !self.pending_keys.is_empty() {
+            status_str.push_str(&format!(" | {} ...", describe_keys(&self.pending_keys)));
+        }
+        for segment in self.status_segments() {
+            // Plugins don't get to crowd out the status message
+            if status_str.len() + segment.len() + 3 > width / 2 {
+                break;
+            }
+            status_str.push_str(" | ");
+            status_str.push_str(&segment);
+        }
 
         let dir = std::env::current_dir().unwrap_or_default();
         let mut dir = format!(
             "{} [/{}]",
             extra_info.unwrap_or("".to_owned()),
-            dir.iter()
-                .last()
-                .unwrap()
-                .to_os_string()
-                .into_string()
-                .unwrap()
+            dir.iter().next_back().unwrap_or_default().to_string_lossy()
         );
 
-        if width < status_str.len() + dir.len() + 8 {
-            dir.drain(
-                ..dir
-                    .char_indices()
-                    .nth(status_str.len() + dir.len() + 8 - width)
-                    .unwrap()
-                    .0,
-            );
-            dir.insert_str(0, "...");
+        let overflow = (status_str.len() + dir.len() + 8).saturating_sub(width);
+        if overflow > 0 {
+            match dir.char_indices().nth(overflow) {
+                Some((cut, _)) => {
+                    dir.drain(..cut);
+                    dir.insert_str(0, "...");
+                }
+                None => dir.clear(), // Not even the end of it fits
+            }
         }
 
         print!(
@@ -2106,7 +3333,7 @@ impl Editor {
             crossterm::style::SetBackgroundColor(Color::from(self.config.theme.foreground_color)),
             crossterm::style::SetForegroundColor(Color::from(self.config.theme.background_color)),
             status_str,
-            " ".repeat(width as usize - status_str.len() - dir.len()),
+            " ".repeat(width.saturating_sub(status_str.len() + dir.len())),
             dir,
             crossterm::style::Attribute::Reset
         );
@@ -2460,6 +3687,283 @@ impl Editor {
         }
     }
 
+    // A scrollable box of text next to the cursor at (x, y), any key other than scrolling closes it
+    pub fn show_hover(&self, text: &[String], x: usize, y: usize) {
+        print!("{}", crossterm::cursor::Hide);
+
+        let max_len = std::cmp::max(self.width() * 7 / 10, 20);
+        let len = std::cmp::min(text.iter().map(|line| line.width()).max().unwrap_or(0) + 4, max_len);
+        let lines = hover::wrap(text, len - 4);
+
+        let visible = std::cmp::min(lines.len(), std::cmp::min(HOVER_HEIGHT, self.height() - 4));
+        let x = std::cmp::min(x, self.width() - len);
+        let y = if y + visible + 3 < self.height() { y + 1 } else { y.saturating_sub(visible + 2) };
+
+        let mut scroll = 0;
+
+        loop {
+            print!(
+                "{}{}{}+{}+",
+                crossterm::style::SetBackgroundColor(Color::from(self.config.theme.foreground_color)),
+                crossterm::style::SetForegroundColor(Color::from(self.config.theme.background_color)),
+                crossterm::cursor::MoveTo(x as u16, y as u16),
+                "=".repeat(len - 2)
+            );
+
+            for i in 0..visible {
+                print!(
+                    "{}| {} |",
+                    crossterm::cursor::MoveTo(x as u16, (y + 1 + i) as u16),
+                    fit_width(&lines[scroll + i], len - 4)
+                );
+            }
+
+            // Show where we are when there's more than fits
+            let position = if lines.len() > visible {
+                format!(" {}-{}/{} ", scroll + 1, scroll + visible, lines.len())
+            } else {
+                String::new()
+            };
+            print!(
+                "{}+{}{}+",
+                crossterm::cursor::MoveTo(x as u16, (y + 1 + visible) as u16),
+                "=".repeat((len - 2).saturating_sub(position.len())),
+                position
+            );
+            std::io::stdout().flush().unwrap();
+
+            match read().unwrap() {
+                Key(e) => match e.code {
+                    KeyCode::Up => scroll = scroll.saturating_sub(1),
+                    KeyCode::Down => scroll = std::cmp::min(scroll + 1, lines.len() - visible),
+                    KeyCode::PageUp => scroll = scroll.saturating_sub(visible),
+                    KeyCode::PageDown => scroll = std::cmp::min(scroll + visible, lines.len() - visible),
+                    _ => break,
+                },
+                Resize(_, _) => break,
+                _ => {}
+            }
+        }
+
+        print!("{}", crossterm::cursor::Show);
+    }
+
+    // The signature of the call being typed, above the '(' at (x, y) with the current parameter highlighted
+    fn draw_signature_help(&self, help: &SignatureHelp, x: usize, y: usize) {
+        let len = std::cmp::min(help.label.width() + 4, self.width());
+        let x = std::cmp::min(x, self.width() - len);
+        let y = if y >= 4 { y - 3 } else { y + 1 };
+
+        let label: Vec<char> = fit_width(&help.label, len - 4).chars().collect();
+        let active = help.active_parameter().unwrap_or(0..0);
+        let start = std::cmp::min(active.start, label.len());
+        let end = std::cmp::min(active.end, label.len());
+
+        print!(
+            "{}{}{}+{}+",
+            crossterm::style::SetBackgroundColor(Color::from(self.config.theme.foreground_color)),
+            crossterm::style::SetForegroundColor(Color::from(self.config.theme.background_color)),
+            crossterm::cursor::MoveTo(x as u16, y as u16),
+            "=".repeat(len - 2)
+        );
+        print!(
+            "{}| {}{}{}{}{}{}{} |",
+            crossterm::cursor::MoveTo(x as u16, y as u16 + 1),
+            label[..start].iter().collect::<String>(),
+            crossterm::style::Attribute::Underlined,
+            crossterm::style::Attribute::Bold,
+            label[start..end].iter().collect::<String>(),
+            crossterm::style::Attribute::NoUnderline,
+            crossterm::style::Attribute::NormalIntensity,
+            label[end..].iter().collect::<String>()
+        );
+        print!(
+            "{}+{}+{}",
+            crossterm::cursor::MoveTo(x as u16, y as u16 + 2),
+            "=".repeat(len - 2),
+            crossterm::style::Attribute::Reset
+        );
+
+        std::io::stdout().flush().unwrap();
+    }
+
+    // The completion popup, below the word being completed or above it if there's no room
+    fn draw_completion(&self, completion: &Completion, x: usize, y: usize) {
+        let visible = std::cmp::min(completion.matches.len(), COMPLETION_HEIGHT);
+        let first = completion.selected.saturating_sub(visible - 1);
+
+        let label_width = completion
+            .matches
+            .iter()
+            .map(|i| completion.candidates[*i].label.width())
+            .max()
+            .unwrap_or(0)
+            .clamp(10, 40);
+        let len = std::cmp::min(label_width + 8, self.width());
+        let x = std::cmp::min(x, self.width() - len);
+        let y = if y + visible + 2 < self.height() { y + 1 } else { y.saturating_sub(visible) };
+
+        for i in 0..visible {
+            let item = &completion.candidates[completion.matches[first + i]];
+            let line = format!(
+                " {} {: >4} ",
+                fit_width(&item.label, len - 7),
+                item.source.name()
+            );
+
+            print!(
+                "{}{}{}{}{}{}",
+                crossterm::cursor::MoveTo(x as u16, (y + i) as u16),
+                crossterm::style::SetBackgroundColor(Color::from(self.config.theme.foreground_color)),
+                crossterm::style::SetForegroundColor(Color::from(self.config.theme.background_color)),
+                if first + i == completion.selected {
+                    format!("{}", crossterm::style::Attribute::Reverse)
+                } else {
+                    String::new()
+                },
+                line,
+                crossterm::style::Attribute::Reset
+            );
+        }
+
+        std::io::stdout().flush().unwrap();
+    }
+
+    // A scrollable list to pick an item from, returns the index of the chosen one
+    pub fn show_list_prompt(&self, title: String, items: &[String]) -> Option<usize> {
+        self.list_prompt(title, items, false)
+    }
+
+    // A list prompt with a query line above the items, typing narrows them down by fuzzy matching
+    pub fn show_fuzzy_prompt(&self, title: String, items: &[String]) -> Option<usize> {
+        self.list_prompt(title, items, true)
+    }
+
+    fn list_prompt(&self, title: String, items: &[String], filter: bool) -> Option<usize> {
+        if items.is_empty() {
+            return None;
+        }
+
+        print!("{}", crossterm::cursor::Hide);
+
+        let len = std::cmp::max(self.width() * 7 / 10, 10);
+        let header = if filter { 4 } else { 3 }; // Rows above the items
+        let visible = std::cmp::min(items.len(), std::cmp::max(self.height() * 7 / 10, 5) - header - 1);
+        let x = (self.width() - len) / 2;
+        let y = self.height().saturating_sub(visible + header + 1) / 2;
+
+        let mut query = String::new();
+        let mut matches: Vec<usize> = (0..items.len()).collect(); // Indices into items, best match first
+        let mut selected = 0;
+        let mut scroll = 0;
+
+        loop {
+            if selected < scroll {
+                scroll = selected;
+            } else if selected >= scroll + visible {
+                scroll = selected + 1 - visible;
+            }
+
+            print!(
+                "{}{}{}+{}+",
+                crossterm::style::SetBackgroundColor(Color::from(self.config.theme.foreground_color)),
+                crossterm::style::SetForegroundColor(Color::from(self.config.theme.background_color)),
+                crossterm::cursor::MoveTo(x as u16, y as u16),
+                "=".repeat(len - 2)
+            );
+            print!(
+                "{}|{}{}{}|",
+                crossterm::cursor::MoveTo(x as u16, y as u16 + 1),
+                crossterm::style::Attribute::Bold,
+                pad_center(fit_width(&title, len - 2).trim_end().to_owned(), len - 2),
+                crossterm::style::Attribute::NormalIntensity
+            );
+            if filter {
+                // Show the end of a query too long to fit
+                let shown: String = query.chars().skip(query.chars().count().saturating_sub(len - 6)).collect();
+                print!(
+                    "{}| > {}|",
+                    crossterm::cursor::MoveTo(x as u16, y as u16 + 2),
+                    fit_width(&format!("{}_", shown), len - 5)
+                );
+            }
+            print!(
+                "{}+{}+",
+                crossterm::cursor::MoveTo(x as u16, (y + header - 1) as u16),
+                "-".repeat(len - 2)
+            );
+
+            for i in 0..visible {
+                let item = match matches.get(scroll + i) {
+                    Some(index) => fit_width(&items[*index], len - 4),
+                    None => " ".repeat(len - 4),
+                };
+
+                if scroll + i == selected {
+                    print!(
+                        "{}|{}{}> {}{}{}|",
+                        crossterm::cursor::MoveTo(x as u16, (y + header + i) as u16),
+                        crossterm::style::Attribute::Underlined,
+                        crossterm::style::Attribute::Bold,
+                        item,
+                        crossterm::style::Attribute::NoUnderline,
+                        crossterm::style::Attribute::NormalIntensity
+                    );
+                } else {
+                    print!(
+                        "{}|  {}|",
+                        crossterm::cursor::MoveTo(x as u16, (y + header + i) as u16),
+                        item
+                    );
+                }
+            }
+
+            print!(
+                "{}+{}+",
+                crossterm::cursor::MoveTo(x as u16, (y + header + visible) as u16),
+                "=".repeat(len - 2)
+            );
+            std::io::stdout().flush().unwrap();
+
+            if let Key(e) = read().unwrap() {
+                let mut query_changed = false;
+
+                match e.code {
+                    KeyCode::Esc => {
+                        print!("{}", crossterm::cursor::Show);
+                        return None;
+                    }
+                    KeyCode::Enter => {
+                        if let Some(index) = matches.get(selected) {
+                            print!("{}", crossterm::cursor::Show);
+                            return Some(*index);
+                        }
+                    }
+                    KeyCode::Up if !matches.is_empty() => {
+                        selected = if selected == 0 { matches.len() - 1 } else { selected - 1 };
+                    }
+                    KeyCode::Down if !matches.is_empty() => selected = (selected + 1) % matches.len(),
+                    KeyCode::PageUp => selected = selected.saturating_sub(visible),
+                    KeyCode::PageDown => selected = std::cmp::min(selected + visible, matches.len().saturating_sub(1)),
+                    KeyCode::Char(c) if filter && !e.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => {
+                        query.push(c);
+                        query_changed = true;
+                    }
+                    KeyCode::Backspace if filter => {
+                        query_changed = query.pop().is_some();
+                    }
+                    _ => {}
+                }
+
+                if query_changed {
+                    matches = fuzzy_matches(&query, items);
+                    selected = 0;
+                    scroll = 0;
+                }
+            }
+        }
+    }
+
     pub fn show_start_splash(&self) -> Result<(), Box<dyn Error>> {
         print!("{}", crossterm::cursor::MoveTo(0, 0));
         print!(
@@ -2820,3 +4324,141 @@ impl Editor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invisible_characters_take_a_column_when_shown() {
+        let config = FileConfig {
+            show_whitespace: true,
+            ..FileConfig::default()
+        };
+
+        // Zero width space, soft hyphen, right to left override, first strong isolate and pop directional isolate
+        let row = Row::from_string("a\u{200B}b\u{00AD}c\u{202E}d\u{2068}e\u{2069}".to_owned());
+        assert!(row.buf.chars().filter(|c| !c.is_ascii()).all(is_invisible));
+        assert_eq!(row.line_width(&config), 10);
+    }
+
+    // A row as shown with whitespace visible, the colours dropped and trailing whitespace marked with []
+    fn shown_with_whitespace(line: &str) -> String {
+        let config = FileConfig {
+            show_whitespace: true,
+            ..FileConfig::default()
+        };
+        let trailing = crossterm::style::SetBackgroundColor(Color::from((150, 30, 30))).to_string();
+
+        let mut rows = vec![Row::from_string(line.to_owned())];
+        Token::tokenize(
+            &mut rows,
+            HighlightingInfo {
+                selection: None,
+                matching_brackets: None,
+                languages: None,
+                diagnostics: None,
+                plugin_tokens: None,
+                decorations: None,
+            },
+            0,
+            1,
+            &config,
+        );
+
+        let shown = rows[0].display_buf(&config, &Theme::default());
+        let mut res = String::new();
+        let mut in_trailing = false;
+        let mut chars = shown.chars();
+        while let Some(c) = chars.next() {
+            if c != '\x1B' {
+                res.push(c);
+                continue;
+            }
+
+            let mut escape = String::from(c);
+            for c in &mut chars {
+                escape.push(c);
+                if c != '[' && ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+
+            if escape == trailing {
+                in_trailing = true;
+                res.push('[');
+            } else if escape == "\x1B[0m" && in_trailing {
+                in_trailing = false;
+                res.push(']');
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn whitespace_and_invisible_characters_are_drawn_as_glyphs() {
+        assert_eq!(shown_with_whitespace("\tlet x = 1;"), "→   let·x·=·1;");
+        assert_eq!(shown_with_whitespace("x  \t"), "x[·][·][→   ]");
+        assert_eq!(shown_with_whitespace("a\u{00A0}b"), "a␣b");
+        assert_eq!(shown_with_whitespace("a\u{202E}b\u{00AD}c\u{2066}d"), "a¤b¤c¤d");
+    }
+
+    #[test]
+    fn apply_edit_is_answered_after_it_is_made() {
+        let dir = std::env::temp_dir().join(format!("kelp_apply_edit_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.rs");
+        std::fs::write(&path, "let x = 1;\n").unwrap();
+
+        let mut editor = Editor::default();
+        editor.resize(80, 24);
+        let mut doc = TextDocument::new(path.to_string_lossy().into_owned());
+        doc.rows = vec![Row::from_string("let x = 1;".to_owned())];
+        editor.add_doc(doc);
+
+        let (outgoing, replies) = std::sync::mpsc::channel();
+        let reply = |id| lsp::Reply {
+            id: serde_json::json!(id),
+            outgoing: outgoing.clone(),
+        };
+        let rename = |uri: String| {
+            serde_json::json!({
+                "changes": {
+                    uri: [{
+                        "range": { "start": { "line": 0, "character": 4 }, "end": { "line": 0, "character": 5 } },
+                        "newText": "count"
+                    }]
+                }
+            })
+        };
+
+        editor.answer_apply_edit(&rename(lsp::path_to_uri(&path.to_string_lossy())), reply(1));
+        assert_eq!(editor.docs[0].as_text_doc().rows[0].buf, "let count = 1;");
+        let answer = replies.try_recv().unwrap();
+        assert_eq!(answer["id"], 1);
+        assert_eq!(answer["result"], serde_json::json!({ "applied": true }));
+
+        let missing = dir.join("missing.rs");
+        editor.answer_apply_edit(&rename(lsp::path_to_uri(&missing.to_string_lossy())), reply(2));
+        let answer = replies.try_recv().unwrap();
+        assert_eq!(answer["id"], 2);
+        assert_eq!(answer["result"]["applied"], false);
+        assert!(answer["result"]["failureReason"].as_str().unwrap().contains("missing.rs"));
+        assert_eq!(answer["result"]["failureReason"].as_str(), Some(editor.status_msg.as_str()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn status_bar_in_a_narrow_terminal() {
+        let mut editor = Editor::default();
+        editor.add_doc(TextDocument::new("a_rather_long_file_name.rs".to_owned()));
+        editor.open_doc = Some(0);
+
+        for width in 0..60 {
+            editor.resize(width, 24);
+            editor.write_status_bar(None);
+            editor.write_status_bar(Some("A long message that goes on and on ".repeat(3)));
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use crate::editor::highlight::Token;
+use crate::editor::prelude::*;
+use std::collections::HashMap;
+
+// A styled run of text, the style being the same escape string the terminal gets from Token::get_style
+struct Span {
+    style: String,
+    text: String,
+}
+
+// Tokenizes and slices the rows to export, just the selected text if there is a selection
+fn styled_lines(
+    doc: &mut TextDocument,
+    config: &FileConfig,
+    languages: &HashMap<String, FileConfig>,
+) -> (usize, Vec<Vec<Span>>) {
+    let mut selection = doc.selection;
+    if let Some(selection) = selection.as_mut() {
+        selection.normalize();
+    }
+
+    let (first_row, last_row) = match selection {
+        Some(selection) => (selection.start_row, selection.end_row),
+        None => (0, doc.rows.len().saturating_sub(1)),
+    };
+
+    Token::tokenize(
+        &mut doc.rows,
+        HighlightingInfo {
+            selection: None,
+            matching_brackets: None,
+            languages: Some(languages),
+            diagnostics: None,
+            plugin_tokens: None,
+            decorations: None,
+        },
+        0,
+        last_row + 1,
+        config,
+    );
+
+    let mut lines = Vec::new();
+
+    for row_index in first_row..=last_row {
+        let row = match doc.rows.get(row_index) {
+            Some(row) => row,
+            None => break,
+        };
+
+        let (start, end) = match selection {
+            Some(selection) => (
+                if row_index == selection.start_row {
+                    row.char_to_byte(selection.start_col)
+                } else {
+                    0
+                },
+                if row_index == selection.end_row {
+                    row.char_to_byte(selection.end_col)
+                } else {
+                    row.buf.len()
+                },
+            ),
+            None => (0, row.buf.len()),
+        };
+
+        let mut line = Vec::new();
+
+        if row.tokens.is_empty() && end > start {
+            line.push(Span {
+                style: String::new(),
+                text: row.buf[start..end].replace('\t', &config.tab_str),
+            });
+        }
+
+        for token in &row.tokens {
+            let token_start = std::cmp::max(token.start(), start);
+            let token_end = std::cmp::min(token.end(), end);
+
+            if token_start < token_end {
+                line.push(Span {
+                    style: token.get_style(config),
+                    text: row.buf[token_start..token_end].replace('\t', &config.tab_str),
+                });
+            }
+        }
+
+        lines.push(line);
+    }
+
+    (first_row, lines)
+}
+
+pub fn to_ansi(
+    doc: &mut TextDocument,
+    config: &FileConfig,
+    languages: &HashMap<String, FileConfig>,
+    theme: &Theme,
+    line_numbers: bool,
+) -> String {
+    let (first_row, lines) = styled_lines(doc, config, languages);
+    let number_width = (first_row + lines.len()).to_string().len();
+
+    let base = format!(
+        "{}{}",
+        crossterm::style::SetForegroundColor(crossterm::style::Color::from(theme.foreground_color)),
+        crossterm::style::SetBackgroundColor(crossterm::style::Color::from(theme.background_color))
+    );
+
+    let mut res = String::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        if line_numbers {
+            res.push_str(&format!(
+                "{}{}{: >width$} {}",
+                base,
+                crossterm::style::Attribute::Dim,
+                first_row + i + 1,
+                crossterm::style::Attribute::Reset,
+                width = number_width
+            ));
+        }
+
+        for span in line {
+            res.push_str(&format!("{}{}{}\x1B[0m", base, span.style, span.text));
+        }
+
+        res.push('\n');
+    }
+
+    res
+}
+
+pub fn to_html(
+    doc: &mut TextDocument,
+    config: &FileConfig,
+    languages: &HashMap<String, FileConfig>,
+    theme: &Theme,
+    line_numbers: bool,
+) -> String {
+    let (first_row, lines) = styled_lines(doc, config, languages);
+    let number_width = (first_row + lines.len()).to_string().len();
+
+    let mut body = String::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        if line_numbers {
+            body.push_str(&format!(
+                "<span style=\"opacity:0.5;user-select:none;\">{: >width$} </span>",
+                first_row + i + 1,
+                width = number_width
+            ));
+        }
+
+        for span in line {
+            let css = ansi_to_css(&span.style);
+            if css.is_empty() {
+                body.push_str(&escape_html(&span.text));
+            } else {
+                body.push_str(&format!(
+                    "<span style=\"{}\">{}</span>",
+                    css,
+                    escape_html(&span.text)
+                ));
+            }
+        }
+
+        body.push('\n');
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body style=\"margin:0;\">\n<pre style=\"margin:0;padding:1em;font-family:monospace;color:{};background-color:{};\">{}</pre>\n</body>\n</html>\n",
+        escape_html(&doc.filename),
+        css_color(theme.foreground_color),
+        css_color(theme.background_color),
+        body
+    )
+}
+
+fn css_color((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            _ => res.push(c),
+        }
+    }
+
+    res
+}
+
+// Turns the SGR escapes crossterm emits for our styles back into css
+fn ansi_to_css(style: &str) -> String {
+    let mut css = String::new();
+
+    for sequence in style.split("\x1B[").skip(1) {
+        let params = match sequence.find('m') {
+            Some(end) => &sequence[..end],
+            None => continue,
+        };
+
+        let params: Vec<u32> = params.split(';').filter_map(|p| p.parse().ok()).collect();
+
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                38 | 48 if i + 4 < params.len() && params[i + 1] == 2 => {
+                    css.push_str(&format!(
+                        "{}:{};",
+                        if params[i] == 38 {
+                            "color"
+                        } else {
+                            "background-color"
+                        },
+                        css_color((params[i + 2] as u8, params[i + 3] as u8, params[i + 4] as u8))
+                    ));
+                    i += 4;
+                }
+                38 | 48 if i + 2 < params.len() && params[i + 1] == 5 => i += 2,
+                1 => css.push_str("font-weight:bold;"),
+                2 => css.push_str("opacity:0.7;"),
+                3 => css.push_str("font-style:italic;"),
+                4 => css.push_str("text-decoration:underline;"),
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    css
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> FileConfig {
+        let mut config = FileConfig {
+            keywords: vec!["let".to_owned()],
+            ..FileConfig::default()
+        };
+        config.syntax_colors.insert("keyword".to_owned(), (255, 0, 0));
+        config.syntax_colors.insert("string".to_owned(), (0, 128, 0));
+        config
+    }
+
+    fn doc(lines: &[&str]) -> TextDocument {
+        let mut doc = TextDocument::new("main.rs".to_owned());
+        doc.rows = lines.iter().map(|line| Row::from_string(line.to_string())).collect();
+        doc
+    }
+
+    // The text of the exported <pre>, tags stripped and entities left escaped
+    fn text(html: &str) -> String {
+        let start = html.find("<pre").unwrap();
+        let body = &html[start + html[start..].find('>').unwrap() + 1..html.find("</pre>").unwrap()];
+
+        let mut res = String::new();
+        let mut in_tag = false;
+        for c in body.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' if in_tag => in_tag = false,
+                _ if !in_tag => res.push(c),
+                _ => {}
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn html_escapes_and_styles_spans() {
+        let mut doc = doc(&["let s = \"<a & b>\";"]);
+        let html = to_html(&mut doc, &config(), &HashMap::new(), &Theme::default(), false);
+
+        assert!(html.contains("<span style=\"color:#ff0000;\">let</span>"));
+        assert!(html.contains("<span style=\"color:#008000;\">&quot;&lt;a &amp; b&gt;&quot;</span>"));
+        assert!(html.contains("color:#ffffff;background-color:#000000;"));
+        assert_eq!(text(&html), "let s = &quot;&lt;a &amp; b&gt;&quot;;\n");
+    }
+
+    #[test]
+    fn sgr_escapes_to_css() {
+        assert_eq!(ansi_to_css(""), "");
+        assert_eq!(
+            ansi_to_css("\x1B[38;2;1;2;255m\x1B[48;2;16;32;48m"),
+            "color:#0102ff;background-color:#102030;"
+        );
+        assert_eq!(ansi_to_css("\x1B[1m\x1B[3m\x1B[4m"), "font-weight:bold;font-style:italic;text-decoration:underline;");
+        // 256 colour palette entries have no css colour, the params after them are still read
+        assert_eq!(ansi_to_css("\x1B[38;5;12;1m"), "font-weight:bold;");
+    }
+
+    #[test]
+    fn line_numbers_are_padded_to_the_last_one() {
+        let lines: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+
+        let html = to_html(&mut doc(&lines), &config(), &HashMap::new(), &Theme::default(), true);
+        let numbered = text(&html);
+        assert!(numbered.starts_with(" 1 line 1\n 2 line 2\n"));
+        assert!(numbered.ends_with("10 line 10\n"));
+
+        let ansi = to_ansi(&mut doc(&lines), &config(), &HashMap::new(), &Theme::default(), true);
+        assert!(ansi.contains(&format!("{} 1 {}", crossterm::style::Attribute::Dim, crossterm::style::Attribute::Reset)));
+        assert_eq!(ansi.lines().count(), 10);
+
+        let html = to_html(&mut doc(&lines), &config(), &HashMap::new(), &Theme::default(), false);
+        assert!(!text(&html).contains(" 1 "));
+    }
+
+    #[test]
+    fn exports_only_the_selection() {
+        let mut doc = doc(&["let a = 1;", "let b = 2;", "let c = 3;", "let d = 4;"]);
+        doc.selection = Some(Selection::new(2, 4, 1, 4));
+
+        let html = to_html(&mut doc, &config(), &HashMap::new(), &Theme::default(), true);
+        assert_eq!(text(&html), "2 b = 2;\n3 let \n");
+    }
+}
@@ -0,0 +1,370 @@
+use crate::editor::history::EditDiff;
+use crate::editor::lsp;
+use crate::editor::lsp::LanguageServers;
+use crate::editor::prelude::*;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// Formats a document with its language's formatter, or its language server if it has none.
+// Returns whether anything changed
+pub fn format_document(
+    doc: &mut TextDocument,
+    config: &FileConfig,
+    language_servers: &mut LanguageServers,
+    visible_rows: usize,
+) -> Result<bool, String> {
+    let formatted = match &config.formatter {
+        Some(formatter) if !formatter.command.is_empty() => run_formatter(formatter, doc)?,
+        _ if config.language_server.is_some() => {
+            let params = lsp::formatting_params(doc, config);
+            match language_servers.request(doc, config, "textDocument/formatting", params)? {
+                Some(Value::Array(edits)) => apply_text_edits(doc, &edits),
+                Some(_) => return Ok(false),
+                None => return Err("Language server didn't answer the format request".to_owned()),
+            }
+        }
+        _ => return Err(format!("No formatter set up for .{} files", doc.extension())),
+    };
+
+    if apply(doc, &formatted) {
+        doc.jump_to(doc.cursor_row, doc.cursor_col, visible_rows);
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+// Runs the formatter with the document on stdin and returns what it printed
+pub fn run_formatter(formatter: &Formatter, doc: &TextDocument) -> Result<String, String> {
+    let args: Vec<String> = formatter
+        .args
+        .iter()
+        .map(|arg| arg.replace("{file}", &doc.filename))
+        .collect();
+
+    let mut process = Command::new(&formatter.command)
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Couldn't run formatter {}: {}", formatter.command, e))?;
+
+    // Written from another thread so a formatter that prints before reading everything can't deadlock us
+    let mut stdin = process.stdin.take().unwrap();
+    let mut text = doc.contents();
+    text.push('\n');
+    let writer = std::thread::spawn(move || stdin.write_all(text.as_bytes()));
+
+    // Its output is read on other threads too, so it can't fill a pipe and stall while we wait for it to exit
+    let read_all = |mut pipe: Box<dyn Read + Send>| {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            buf
+        })
+    };
+    let stdout = read_all(Box::new(process.stdout.take().unwrap()));
+    let stderr = read_all(Box::new(process.stderr.take().unwrap()));
+
+    let deadline = Instant::now() + Duration::from_millis(formatter.timeout);
+    let status = loop {
+        match process.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            Ok(None) => {
+                // The threads are left to finish once the pipes close, which a child of its own could hold open
+                let _ = process.kill();
+                let _ = process.wait();
+                return Err(format!(
+                    "Formatter {} didn't finish within {} ms",
+                    formatter.command, formatter.timeout
+                ));
+            }
+            Err(e) => return Err(format!("Formatter {} failed: {}", formatter.command, e)),
+        }
+    };
+    let _ = writer.join();
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(format!(
+            "Formatter {} failed: {}",
+            formatter.command,
+            stderr.lines().find(|line| !line.trim().is_empty()).unwrap_or("no output")
+        ));
+    }
+
+    String::from_utf8(stdout).map_err(|_| format!("Formatter {} printed invalid utf 8", formatter.command))
+}
+
+// The document's text with a textDocument/formatting response applied
+pub fn apply_text_edits(doc: &TextDocument, edits: &[Value]) -> String {
+    let text = doc.contents();
+
+    // Byte offset in text of each row's start
+    let mut line_starts = Vec::with_capacity(doc.rows.len());
+    let mut offset = 0;
+    for row in &doc.rows {
+        line_starts.push(offset);
+        offset += row.buf.len() + 1;
+    }
+
+    let to_offset = |position: &Value| match lsp::to_position(position, doc) {
+        Some((row, col)) if row < doc.rows.len() => line_starts[row] + doc.rows[row].char_to_byte(col),
+        _ => text.len(),
+    };
+
+    let mut edits: Vec<(usize, usize, usize, &str)> = edits
+        .iter()
+        .enumerate()
+        .filter_map(|(i, edit)| {
+            let start = to_offset(&edit["range"]["start"]);
+            let end = to_offset(&edit["range"]["end"]);
+            Some((start, i, std::cmp::max(start, end), edit["newText"].as_str()?))
+        })
+        .collect();
+
+    // Applied back to front so earlier offsets stay valid, edits at the same spot keep their order
+    edits.sort_by_key(|(start, i, _, _)| std::cmp::Reverse((*start, *i)));
+
+    let mut text = text;
+    for (start, _, end, new_text) in edits {
+        text.replace_range(start..end, new_text);
+    }
+    text
+}
+
+// Replaces the changed lines with the formatted text as one undo step, keeping the cursor on the same code.
+// Returns false if there was nothing to change
+pub fn apply(doc: &mut TextDocument, formatted: &str) -> bool {
+    let old: Vec<String> = doc.rows.iter().map(|row| row.buf.clone()).collect();
+    let mut new: Vec<&str> = formatted.lines().collect();
+    if new.is_empty() {
+        new.push("");
+    }
+
+    if old.len() == new.len() && old.iter().zip(&new).all(|(a, b)| a == b) {
+        return false;
+    }
+
+    let mut prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let mut suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take(std::cmp::min(old.len(), new.len()) - prefix)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    // Both sides need at least one line to replace
+    if old.len() - prefix - suffix == 0 || new.len() - prefix - suffix == 0 {
+        if prefix > 0 {
+            prefix -= 1;
+        } else {
+            suffix -= 1;
+        }
+    }
+
+    let old_end = old.len() - suffix; // Exclusive
+    let new_end = new.len() - suffix;
+
+    let (row, col) = (doc.cursor_row, doc.cursor_col);
+    let cursor = if row < prefix {
+        (row, col)
+    } else if row >= old_end {
+        (row + new.len() - old.len(), col)
+    } else {
+        // Formatters mostly move whitespace around, so land before the same non whitespace char as before
+        let seen: usize = old[prefix..row]
+            .iter()
+            .map(|line| non_whitespace(line))
+            .sum::<usize>()
+            + non_whitespace(&old[row].chars().take(col).collect::<String>());
+
+        position_of(&new[prefix..new_end], seen)
+            .map(|(r, c)| (prefix + r, c))
+            .unwrap_or((new_end - 1, new[new_end - 1].chars().count()))
+    };
+
+    let (diffs, _) = doc.replace_range(
+        (prefix, 0),
+        (old_end - 1, old[old_end - 1].chars().count()),
+        &new[prefix..new_end].join("\n"),
+    );
+
+    doc.selection = None;
+    doc.cursor_row = cursor.0;
+    doc.cursor_col = cursor.1;
+    doc.dirty += 1;
+    doc.add_diff(EditDiff::Compound(diffs));
+
+    true
+}
+
+fn non_whitespace(line: &str) -> usize {
+    line.chars().filter(|c| !c.is_whitespace()).count()
+}
+
+// Where the non whitespace char after the first n of them is
+fn position_of(lines: &[&str], n: usize) -> Option<(usize, usize)> {
+    let mut seen = 0;
+
+    for (row, line) in lines.iter().enumerate() {
+        for (col, c) in line.chars().enumerate() {
+            if !c.is_whitespace() {
+                if seen == n {
+                    return Some((row, col));
+                }
+                seen += 1;
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::history::UndoRedo;
+    use serde_json::json;
+
+    fn doc(lines: &[&str], cursor: (usize, usize)) -> TextDocument {
+        let mut doc = TextDocument::new("main.rs".to_owned());
+        doc.rows = lines.iter().map(|line| Row::from_string(line.to_string())).collect();
+        doc.cursor_row = cursor.0;
+        doc.cursor_col = cursor.1;
+        doc
+    }
+
+    fn text(doc: &TextDocument) -> Vec<&str> {
+        doc.rows.iter().map(|row| row.buf.as_str()).collect()
+    }
+
+    // Checks the whole change went in as a single step that undoes back to lines
+    fn undoes_to(doc: &mut TextDocument, lines: &[&str]) {
+        assert_eq!(doc.history.len(), 1);
+        let diff = doc.history[0].clone();
+        diff.apply(UndoRedo::Undo, doc);
+        assert_eq!(text(doc), lines);
+    }
+
+    fn edit(start: (usize, usize), end: (usize, usize), new_text: &str) -> Value {
+        json!({
+            "range": {
+                "start": {"line": start.0, "character": start.1},
+                "end": {"line": end.0, "character": end.1},
+            },
+            "newText": new_text,
+        })
+    }
+
+    fn formatter(command: &str, args: &[&str], timeout: u64) -> Formatter {
+        Formatter {
+            command: command.to_owned(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout,
+        }
+    }
+
+    #[test]
+    fn unchanged_text_is_left_alone() {
+        let mut doc = doc(&["a", "b"], (1, 0));
+        assert!(!apply(&mut doc, "a\nb\n"));
+        assert!(doc.history.is_empty());
+    }
+
+    #[test]
+    fn inserting_lines() {
+        let lines = ["use a;", "fn f() {}", "fn g() {}"];
+        let mut doc = doc(&lines, (2, 3));
+
+        assert!(apply(&mut doc, "use a;\n\nfn f() {}\n\nfn g() {}\n"));
+        assert_eq!(text(&doc), ["use a;", "", "fn f() {}", "", "fn g() {}"]);
+        assert_eq!((doc.cursor_row, doc.cursor_col), (4, 3));
+        undoes_to(&mut doc, &lines);
+    }
+
+    #[test]
+    fn deleting_lines() {
+        let lines = ["use a;", "", "", "fn f() {}"];
+        let mut doc = doc(&lines, (3, 5));
+
+        assert!(apply(&mut doc, "use a;\n\nfn f() {}\n"));
+        assert_eq!(text(&doc), ["use a;", "", "fn f() {}"]);
+        assert_eq!((doc.cursor_row, doc.cursor_col), (2, 5));
+        undoes_to(&mut doc, &lines);
+    }
+
+    #[test]
+    fn rewriting_the_whole_file() {
+        let lines = ["x", "y"];
+        let mut doc = doc(&lines, (0, 0));
+
+        assert!(apply(&mut doc, "p\nq\nr\n"));
+        assert_eq!(text(&doc), ["p", "q", "r"]);
+        undoes_to(&mut doc, &lines);
+    }
+
+    #[test]
+    fn cursor_stays_on_the_same_code_when_reindented() {
+        let lines = ["fn f() {", "let a = 1;", "if a {", "g( a );", "}", "}"];
+        // On the "a" inside g( a )
+        let mut doc = doc(&lines, (3, 3));
+
+        assert!(apply(&mut doc, "fn f() {\n    let a = 1;\n    if a {\n        g(a);\n    }\n}\n"));
+        assert_eq!(text(&doc)[3], "        g(a);");
+        assert_eq!((doc.cursor_row, doc.cursor_col), (3, 10));
+        undoes_to(&mut doc, &lines);
+    }
+
+    #[test]
+    fn empty_result() {
+        let lines = ["a", "b"];
+        let mut doc = doc(&lines, (1, 1));
+
+        assert!(apply(&mut doc, ""));
+        assert_eq!(text(&doc), [""]);
+        assert_eq!((doc.cursor_row, doc.cursor_col), (0, 0));
+        undoes_to(&mut doc, &lines);
+    }
+
+    #[test]
+    fn text_edits_at_the_same_offset_keep_their_order() {
+        let doc = doc(&["fn f() {", "x", "}"], (0, 0));
+        let edits = [
+            edit((1, 0), (1, 0), "    "),
+            edit((1, 0), (1, 0), "let "),
+            edit((1, 1), (1, 1), " = 1;"),
+            edit((0, 3), (0, 4), "g"),
+        ];
+
+        assert_eq!(apply_text_edits(&doc, &edits), "fn g() {\n    let x = 1;\n}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn formatter_reads_stdin() {
+        let doc = doc(&["a", "b"], (0, 0));
+        assert_eq!(run_formatter(&formatter("cat", &[], 5000), &doc), Ok("a\nb\n".to_owned()));
+
+        let failing = formatter("sh", &["-c", "echo; echo bad syntax >&2; exit 1"], 5000);
+        assert_eq!(run_formatter(&failing, &doc), Err("Formatter sh failed: bad syntax".to_owned()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn formatter_is_killed_after_its_timeout() {
+        let doc = doc(&["a"], (0, 0));
+        let started = Instant::now();
+
+        let result = run_formatter(&formatter("sleep", &["10"], 100), &doc);
+        assert_eq!(result, Err("Formatter sleep didn't finish within 100 ms".to_owned()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
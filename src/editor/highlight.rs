@@ -2,6 +2,7 @@ use crate::editor::editor::debug_file;
 use crate::editor::editor::is_debug;
 use crate::editor::prelude::*;
 use core::ops::Range;
+use std::collections::HashMap;
 use crossterm::style::Color;
 
 #[allow(dead_code)]
@@ -15,14 +16,61 @@ pub enum Token {
     FnCall(Range<usize>),
     Macro(Range<usize>),
     Number(Range<usize>),
+    Char(Range<usize>),
+    Lifetime(Range<usize>),
+    DocComment(Range<usize>),
+    TypeName(Range<usize>),
+    Operator(Range<usize>),
+    Punctuation(Range<usize>),
+    Bracket(Range<usize>, usize), // Nesting depth for rainbow brackets
+    MatchingBracket(Range<usize>),
+    Diagnostic(Range<usize>, Severity),
     Selection(Range<usize>),
 
     CustomStyle(Range<usize>, String),
 }
 
+// Drawn by plugins over whatever the highlighter came up with
+#[derive(Debug, Clone)]
+pub enum Decoration {
+    Style {
+        row: usize,
+        range: Range<usize>, // In bytes, like token ranges
+        style: String,       // Ansi escapes, as in Token::CustomStyle
+    },
+    GutterSign {
+        row: usize,
+        sign: char,
+        color: (u8, u8, u8),
+    },
+    VirtualText {
+        row: usize,
+        text: String, // Shown after the end of the row if there's room
+        color: (u8, u8, u8),
+    },
+}
+
+#[allow(clippy::enum_variant_names)]
 enum TokenizerAction {
     ParseString(char),
-    ParseComment,
+    ParseRawString(String),
+    ParseComment(usize, bool), // Nesting depth and whether it's a doc comment
+}
+
+#[derive(Default)]
+struct TokenizerState {
+    parser: Option<TokenizerAction>,
+    embedded_parser: Option<TokenizerAction>,
+    bracket_depth: usize,
+    embedded: Option<(usize, String)>, // Region index in the host config and the language inside it
+}
+
+const OPERATOR_CHARS: &str = "+-*/%=<>!&|^~?:.@";
+const PUNCTUATION_CHARS: &str = "()[]{},;#";
+
+// Plugins can hand back anything, so ranges are checked before they get used to slice the row
+fn is_valid_range(src: &str, range: &Range<usize>) -> bool {
+    range.start < range.end && src.is_char_boundary(range.start) && src.is_char_boundary(range.end)
 }
 
 impl Token {
@@ -48,44 +96,469 @@ impl Token {
             Some(s)
         };
 
-        let mut parser = None;
+        let matching_brackets = info.matching_brackets;
+
+        let languages = info.languages;
+
+        let diagnostics = info.diagnostics;
+
+        let plugin_tokens = info.plugin_tokens;
+
+        let decorations = info.decorations;
+
+        // Bracket depth and embedded regions carry over from the row above, which is only right if that row was
+        // tokenized after the one above it and so on, with the embedded languages highlighted the same way. The rows
+        // above from that weren't are caught up first
+        let from = std::cmp::min(from, rows.len());
+        let with_languages = languages.is_some();
+        let mut first = 0;
+        while first < from && Token::carries_over(rows, first, with_languages) {
+            first += 1;
+        }
+
+        let (bracket_depth, embedded) = Token::state_after(rows, first);
+        let mut state = TokenizerState {
+            bracket_depth,
+            embedded,
+            ..TokenizerState::default()
+        };
+
+        for row in &mut rows[first..from] {
+            row.tokenized_after = Some((state.bracket_depth, state.embedded.clone(), with_languages));
+            if !config.syntax_highlighting_disabled {
+                Token::tokenize_line(&row.buf, &mut Vec::new(), &mut state, config, languages);
+            }
+            row.bracket_depth = state.bracket_depth;
+            row.embedded = state.embedded.clone();
+        }
 
         for (row_index, row) in rows.iter_mut().enumerate().skip(from).take(num_lines) {
             let mut res = Vec::new();
+            row.tokenized_after = Some((state.bracket_depth, state.embedded.clone(), with_languages));
 
             let src = &row.buf;
 
-            let char_indices: Vec<(usize, char)> = src.char_indices().collect();
+            if let Some(selection) = &selection {
+                if row_index >= selection.start_row && row_index <= selection.end_row {
+                    if selection.start_row != selection.end_row {
+                        if row_index == selection.start_row {
+                            res.push(Token::Selection(row.char_to_byte(selection.start_col)..src.len()));
+                        } else if row_index == selection.end_row {
+                            res.push(Token::Selection(0..row.char_to_byte(selection.end_col)));
+                        } else {
+                            res.push(Token::Selection(0..src.len()));
+                        }
+                    } else {
+                        res.push(Token::Selection(
+                            row.char_to_byte(selection.start_col)..row.char_to_byte(selection.end_col),
+                        ));
+                    }
+                }
+            }
 
-            let mut odd_token = String::new();
+            if let Some(matching_brackets) = &matching_brackets {
+                for (bracket_row, bracket_col) in matching_brackets {
+                    if *bracket_row == row_index && *bracket_col < row.len() {
+                        res.push(Token::MatchingBracket(
+                            row.char_to_byte(*bracket_col)..row.char_to_byte(*bracket_col + 1),
+                        ));
+                    }
+                }
+            }
 
-            let mut i = 0;
-            macro_rules! parse_string {
-                ($c: expr) => {
-                    if !odd_token.is_empty() {
-                        if odd_token.ends_with(&config.line_comment_start) {
-                            if odd_token.len() > config.line_comment_start.len() {
-                                res.push(Token::Plain(
-                                    from_char!(i) - odd_token.len()
-                                        ..from_char!(i) - config.line_comment_start.len(),
-                                ));
+            if let Some(diagnostics) = diagnostics {
+                for diagnostic in diagnostics {
+                    if row_index < diagnostic.start_row || row_index > diagnostic.end_row {
+                        continue;
+                    }
+
+                    let start = if row_index == diagnostic.start_row {
+                        row.char_to_byte(diagnostic.start_col)
+                    } else {
+                        0
+                    };
+                    let mut end = if row_index == diagnostic.end_row {
+                        row.char_to_byte(diagnostic.end_col)
+                    } else {
+                        src.len()
+                    };
+
+                    // Empty ranges still underline the character they point at
+                    if end <= start {
+                        end = row.char_to_byte(row.byte_to_char(start) + 1);
+                    }
+
+                    if start < end {
+                        res.push(Token::Diagnostic(start..end, diagnostic.severity));
+                    }
+                }
+            }
+
+            if let Some(decorations) = decorations {
+                for decoration in decorations {
+                    if let Decoration::Style { row: style_row, range, style } = decoration {
+                        if *style_row == row_index && is_valid_range(src, range) {
+                            res.push(Token::CustomStyle(range.clone(), style.clone()));
+                        }
+                    }
+                }
+            }
+
+            // A plugin highlighter takes over from the built in one, anything it leaves out is plain
+            if let Some(tokens) = plugin_tokens.and_then(|tokens| tokens.get(row_index - from)) {
+                res.push(Token::Plain(0..src.len()));
+                res.extend(tokens.iter().filter(|token| is_valid_range(src, token.get_range())).cloned());
+                Token::normalize(&mut res, src.len(), config, src.to_string());
+                row.tokens = res;
+                row.bracket_depth = state.bracket_depth;
+                row.embedded = state.embedded.clone();
+                continue;
+            }
+
+            if config.syntax_highlighting_disabled {
+                res.push(Token::Plain(0..src.len()));
+                Token::normalize(&mut res, src.len(), config, src.to_string());
+                row.tokens = res;
+                row.bracket_depth = state.bracket_depth;
+                row.embedded = state.embedded.clone();
+                continue;
+            }
+
+            Token::tokenize_line(src, &mut res, &mut state, config, languages);
+
+            Token::normalize(&mut res, src.len(), config, src.to_string());
+
+            row.tokens = res;
+            row.bracket_depth = state.bracket_depth;
+            row.embedded = state.embedded.clone();
+        }
+    }
+
+    // Whether the row was tokenized after what the row above it ended with now, and with languages or not
+    fn carries_over(rows: &[Row], index: usize, with_languages: bool) -> bool {
+        let (depth, embedded) = match index.checked_sub(1) {
+            Some(above) => (rows[above].bracket_depth, rows[above].embedded.as_ref()),
+            None => (0, None),
+        };
+        rows[index]
+            .tokenized_after
+            .as_ref()
+            .is_some_and(|(after_depth, after_embedded, after_languages)| {
+                *after_depth == depth && after_embedded.as_ref() == embedded && *after_languages == with_languages
+            })
+    }
+
+    // What the row before index ended with
+    fn state_after(rows: &[Row], index: usize) -> (usize, Option<(usize, String)>) {
+        match index.checked_sub(1).and_then(|above| rows.get(above)) {
+            Some(above) => (above.bracket_depth, above.embedded.clone()),
+            None => (0, None),
+        }
+    }
+
+    // Splits a row into host and embedded language segments and tokenizes each with its own config
+    fn tokenize_line(
+        src: &str,
+        res: &mut Vec<Token>,
+        state: &mut TokenizerState,
+        config: &FileConfig,
+        languages: Option<&HashMap<String, FileConfig>>,
+    ) {
+        let mut offset = 0;
+        let mut search_from = 0;
+
+        loop {
+            if let Some((region_index, language)) = state.embedded.clone() {
+                let region = match config.embedded.get(region_index) {
+                    Some(region) => region,
+                    None => {
+                        state.embedded = None;
+                        continue;
+                    }
+                };
+
+                let end = src[offset..].find(&region.end).map(|end| end + offset);
+                let segment_end = end.unwrap_or(src.len());
+
+                let inner = languages.and_then(|l| FileConfig::find_language(l, &language));
+
+                if segment_end > offset {
+                    match inner {
+                        Some(inner) => {
+                            for token in Token::tokenize_segment(
+                                &src[offset..segment_end],
+                                &mut state.embedded_parser,
+                                &mut state.bracket_depth,
+                                inner,
+                            ) {
+                                res.push(token.offset_by(offset));
                             }
-                            res.push(Token::Comment(
-                                from_char!(i) - config.line_comment_start.len()..src.len(),
+                        }
+                        None => res.push(Token::Plain(offset..segment_end)),
+                    }
+                }
+
+                match end {
+                    Some(end) => {
+                        state.embedded = None;
+                        state.embedded_parser = None;
+
+                        res.push(Token::Keyword(end..end + region.end.len()));
+                        offset = end + region.end.len();
+                        search_from = offset;
+                    }
+                    None => break,
+                }
+            } else {
+                let next = config
+                    .embedded
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, region)| !region.start.is_empty())
+                    .filter_map(|(index, region)| {
+                        src[search_from..]
+                            .find(&region.start)
+                            .map(|start| (start + search_from, index))
+                    })
+                    .min();
+
+                // Markers are highlighted on their own so the host can't mistake ``` for a string
+                let (host_end, marker_end, switch_to) = match next {
+                    Some((start, index)) => {
+                        let region = &config.embedded[index];
+                        let mut content_start = start + region.start.len();
+                        let mut language = region.language.clone();
+
+                        if region.language_from_start {
+                            // ```rust - the rest of the line names the language
+                            let info: String = src[content_start..]
+                                .trim_start()
+                                .chars()
+                                .take_while(|c| c.is_alphanumeric() || *c == '_' || *c == '+' || *c == '-')
+                                .collect();
+                            if !info.is_empty() {
+                                language = info;
+                            }
+                            content_start = src.len();
+                        } else if !region.start_close.is_empty() {
+                            // <script type="..."> - the tag only ends at start_close
+                            content_start = match src[content_start..].find(&region.start_close) {
+                                Some(close) => content_start + close + region.start_close.len(),
+                                None => src.len(),
+                            };
+                        }
+
+                        (start, start + region.start.len(), Some((content_start, index, language)))
+                    }
+                    None => (src.len(), src.len(), None),
+                };
+
+                if host_end > offset {
+                    for token in Token::tokenize_segment(
+                        &src[offset..host_end],
+                        &mut state.parser,
+                        &mut state.bracket_depth,
+                        config,
+                    ) {
+                        res.push(token.offset_by(offset));
+                    }
+                }
+
+                let (content_start, index, language) = match switch_to {
+                    Some(switch_to) => switch_to,
+                    None => break,
+                };
+
+                res.push(Token::Keyword(host_end..marker_end));
+
+                // Whatever is left of the opening tag, like the language name or attributes
+                if content_start > marker_end {
+                    for token in Token::tokenize_segment(
+                        &src[marker_end..content_start],
+                        &mut state.parser,
+                        &mut state.bracket_depth,
+                        config,
+                    ) {
+                        res.push(token.offset_by(marker_end));
+                    }
+                }
+
+                state.embedded = Some((index, language));
+                offset = content_start;
+                search_from = content_start;
+            }
+        }
+    }
+
+    fn tokenize_segment(
+        src: &str,
+        parser_state: &mut Option<TokenizerAction>,
+        depth_state: &mut usize,
+        config: &FileConfig,
+    ) -> Vec<Token> {
+        let mut res = Vec::new();
+        let mut parser = parser_state.take();
+        let mut bracket_depth = *depth_state;
+
+        let char_indices: Vec<(usize, char)> = src.char_indices().collect();
+
+        let mut odd_token = String::new();
+
+        let mut i = 0;
+        macro_rules! parse_string {
+            ($c: expr) => {
+                if !odd_token.is_empty() {
+                    if odd_token.ends_with(&config.line_comment_start) {
+                        if odd_token.len() > config.line_comment_start.len() {
+                            res.push(Token::Plain(
+                                from_char!(i) - odd_token.len()
+                                    ..from_char!(i) - config.line_comment_start.len(),
                             ));
-                            odd_token.clear();
+                        }
+                        res.push(Token::Comment(
+                            from_char!(i) - config.line_comment_start.len()..src.len(),
+                        ));
+                        odd_token.clear();
+                        break;
+                    } else {
+                        res.push(Token::Plain(from_char!(i) - odd_token.len()..from_char!(i)));
+                    }
+
+                    odd_token.clear();
+                }
+
+                let mut len = 1;
+                let mut is_escaped = false;
+                i += 1;
+                while i < char_indices.len() && (char_indices[i].1 != $c || is_escaped) {
+                    if i < char_indices.len() && char_indices[i].1 == '\\' {
+                        if i + 2 <= char_indices.len() {
+                            res.push(Token::CustomStyle(
+                                from_char!(i)..from_char!(i + 2),
+                                format!(
+                                    "{}{}",
+                                    Token::String(0..0).get_style(config),
+                                    crossterm::style::Attribute::Dim
+                                ),
+                            ));
+                        }
+                        is_escaped = true;
+                    } else {
+                        is_escaped = false;
+                    }
+
+                    len += 1;
+                    i += 1;
+                }
+
+                if i < char_indices.len() {
+                    len += 1;
+                    i += 1;
+                } else {
+                    parser = Some(TokenizerAction::ParseString($c));
+                }
+
+                res.push(Token::String(from_char!(i - len)..from_char!(i)));
+
+                i -= 1;
+            };
+        }
+
+        macro_rules! string_match {
+            ($str: expr) => {{
+                let mut offset = 0;
+                let mut res = true;
+                for c in $str.chars() {
+                    if i + offset < char_indices.len() {
+                        if c != char_indices[i + offset].1 {
+                            res = false;
                             break;
-                        } else {
-                            res.push(Token::Plain(from_char!(i) - odd_token.len()..from_char!(i)));
                         }
+                    } else {
+                        res = false;
+                        break;
+                    }
+                    offset += 1;
+                }
+                res
+            }};
+        }
 
-                        odd_token.clear();
+        macro_rules! scan_block_comment {
+            ($depth: expr) => {{
+                let mut depth = $depth;
+                while i < char_indices.len() && depth > 0 {
+                    if config.nested_comments && string_match!(config.multi_line_comment.0) {
+                        depth += 1;
+                        i += config.multi_line_comment.0.chars().count();
+                    } else if string_match!(config.multi_line_comment.1) {
+                        depth -= 1;
+                        i += config.multi_line_comment.1.chars().count();
+                    } else {
+                        i += 1;
                     }
+                }
+                depth
+            }};
+        }
 
-                    let mut len = 1;
-                    let mut is_escaped = false;
+        macro_rules! scan_raw_string {
+            ($closing: expr) => {{
+                while i < char_indices.len() && !string_match!($closing) {
                     i += 1;
-                    while i < char_indices.len() && (char_indices[i].1 != $c || is_escaped) {
+                }
+                if i < char_indices.len() {
+                    i += $closing.chars().count();
+                    true
+                } else {
+                    false
+                }
+            }};
+        }
+
+        macro_rules! comment_token {
+            ($range: expr, $is_doc: expr) => {
+                if $is_doc {
+                    Token::DocComment($range)
+                } else {
+                    Token::Comment($range)
+                }
+            };
+        }
+
+        macro_rules! handle_odd_token {
+            () => {
+                if !odd_token.is_empty() {
+                    let li = if i >= char_indices.len() {
+                        src.len()
+                    } else {
+                        char_indices[i].0
+                    };
+
+                    res.push(Token::Plain(li - odd_token.len()..li));
+                    odd_token.clear();
+                }
+            };
+        }
+
+        macro_rules! from_char {
+            ($i: expr) => {
+                if $i < char_indices.len() {
+                    char_indices[$i].0
+                } else if $i == char_indices.len() {
+                    src.len()
+                } else {
+                    panic!("{} out of bounds...", $i)
+                }
+            };
+        }
+
+        if let Some(p) = &parser {
+            match p {
+                TokenizerAction::ParseString(c) => {
+                    let mut len = 0;
+                    let mut is_escaped = false;
+                    while i < char_indices.len() && (char_indices[i].1 != *c || is_escaped) {
                         if i < char_indices.len() && char_indices[i].1 == '\\' {
                             if i + 2 <= char_indices.len() {
                                 res.push(Token::CustomStyle(
@@ -102,271 +575,319 @@ impl Token {
                             is_escaped = false;
                         }
 
-                        len += 1;
                         i += 1;
+                        len += 1;
                     }
-
-                    if i < char_indices.len() {
+                    if i != char_indices.len() {
                         len += 1;
                         i += 1;
+                        res.push(Token::String(from_char!(i - len)..from_char!(i)));
+                        parser = None;
                     } else {
-                        parser = Some(TokenizerAction::ParseString($c));
-                    }
-
-                    res.push(Token::String(from_char!(i - len)..from_char!(i)));
-
-                    i -= 1;
-                };
-            }
-
-            macro_rules! string_match {
-                ($str: expr) => {{
-                    let mut offset = 0;
-                    let mut res = true;
-                    for c in $str.chars() {
-                        if i + offset < char_indices.len() {
-                            if c != char_indices[i + offset].1 {
-                                res = false;
-                                break;
-                            }
-                        } else {
-                            res = false;
-                            break;
-                        }
-                        offset += 1;
+                        res.push(Token::String(0..src.len()));
                     }
-                    res
-                }};
-            }
-
-            macro_rules! handle_odd_token {
-                () => {
-                    if !odd_token.is_empty() {
-                        let li = if i >= char_indices.len() {
-                            src.len()
-                        } else {
-                            char_indices[i].0
-                        };
-
-                        res.push(Token::Plain(li - odd_token.len()..li));
-                        odd_token.clear();
-                    }
-                };
-            }
-
-            macro_rules! is_inside_sel {
-                () => {{
-                    if let Some(selection) = &selection {
-                        row_index >= selection.start_row
-                            && row_index <= selection.end_row
-                            && if selection.start_row != selection.end_row {
-                                if row_index == selection.start_row {
-                                    i >= selection.start_col
-                                } else if row_index == selection.end_row {
-                                    i < selection.end_col
-                                } else {
-                                    true
-                                }
-                            } else {
-                                i >= selection.start_col && i < selection.end_col
-                            }
+                }
+                TokenizerAction::ParseRawString(closing) => {
+                    let closing = closing.clone();
+                    if scan_raw_string!(closing) {
+                        res.push(Token::String(0..from_char!(i)));
+                        parser = None;
                     } else {
-                        false
+                        res.push(Token::String(0..src.len()));
                     }
-                }};
-            }
-
-            macro_rules! from_char {
-                ($i: expr) => {
-                    if $i < char_indices.len() {
-                        char_indices[$i].0
-                    } else if $i == char_indices.len() {
-                        src.len()
+                }
+                TokenizerAction::ParseComment(depth, is_doc) => {
+                    let is_doc = *is_doc;
+                    let depth = scan_block_comment!(*depth);
+                    if depth == 0 {
+                        res.push(comment_token!(0..from_char!(i), is_doc));
+                        parser = None;
                     } else {
-                        panic!("{} out of bounds...", $i)
+                        res.push(comment_token!(0..src.len(), is_doc));
+                        parser = Some(TokenizerAction::ParseComment(depth, is_doc));
                     }
-                };
+                }
             }
+        }
 
-            if let Some(selection) = &selection {
-                if row_index >= selection.start_row && row_index <= selection.end_row {
-                    if selection.start_row != selection.end_row {
-                        if row_index == selection.start_row {
-                            res.push(Token::Selection(from_char!(selection.start_col)..src.len()));
-                        } else if row_index == selection.end_row {
-                            res.push(Token::Selection(0..from_char!(selection.end_col)));
-                        } else {
-                            res.push(Token::Selection(0..src.len()));
-                        }
+        while i < char_indices.len() {
+            // Raw strings look like identifiers until the quote so they have to be caught first
+            let raw_string_start = if config.raw_strings {
+                let mut j = i;
+                if j < char_indices.len() && char_indices[j].1 == 'b' {
+                    j += 1;
+                }
+                if j < char_indices.len() && char_indices[j].1 == 'r' {
+                    j += 1;
+                    let mut hashes = 0;
+                    while j < char_indices.len() && char_indices[j].1 == '#' {
+                        hashes += 1;
+                        j += 1;
+                    }
+                    if j < char_indices.len() && char_indices[j].1 == '"' {
+                        Some((j + 1, format!("\"{}", "#".repeat(hashes))))
                     } else {
-                        res.push(Token::Selection(
-                            from_char!(selection.start_col)..from_char!(selection.end_col),
-                        ));
+                        None
                     }
+                } else {
+                    None
                 }
-            }
+            } else {
+                None
+            };
 
-            if config.syntax_highlighting_disabled {
-                res.push(Token::Plain(0..src.len()));
-                Token::normalize(&mut res, src.len(), config, src.to_string());
-                row.tokens = res;
-                continue;
-            }
+            if let Some((body_start, closing)) = raw_string_start {
+                handle_odd_token!();
 
-            if let Some(p) = &parser {
-                match p {
-                    TokenizerAction::ParseString(c) => {
-                        let mut len = 0;
-                        let mut is_escaped = false;
-                        while i < char_indices.len() && (char_indices[i].1 != *c || is_escaped) {
-                            if i < char_indices.len() && char_indices[i].1 == '\\' {
-                                if i + 2 <= char_indices.len() {
-                                    res.push(Token::CustomStyle(
-                                        from_char!(i)..from_char!(i + 2),
-                                        format!(
-                                            "{}{}",
-                                            Token::String(0..0).get_style(config),
-                                            crossterm::style::Attribute::Dim
-                                        ),
-                                    ));
-                                }
-                                is_escaped = true;
-                            } else {
-                                is_escaped = false;
-                            }
+                let start = i;
+                i = body_start;
+                if !scan_raw_string!(closing) {
+                    parser = Some(TokenizerAction::ParseRawString(closing));
+                }
 
-                            i += 1;
-                            len += 1;
-                        }
-                        if i != char_indices.len() {
-                            len += 1;
-                            i += 1;
-                            res.push(Token::String(from_char!(i - len)..from_char!(i)));
-                            parser = None;
-                        } else {
-                            res.push(Token::String(0..src.len()));
-                        }
+                res.push(Token::String(from_char!(start)..from_char!(i)));
+
+                if i != char_indices.len() {
+                    i -= 1;
+                }
+            } else if char_indices[i].1.is_alphabetic() || char_indices[i].1 == '_' {
+                handle_odd_token!();
+
+                let mut len = 0;
+                while i < char_indices.len()
+                    && (char_indices[i].1.is_alphanumeric() || char_indices[i].1 == '_')
+                {
+                    len += 1;
+                    i += 1;
+                }
+
+                if len != 0 {
+                    let word = &src[from_char!(i - len)..from_char!(i)];
+
+                    if i < char_indices.len() && char_indices[i].1 == '(' {
+                        res.push(Token::FnCall(from_char!(i - len)..from_char!(i)));
+                    } else if i < char_indices.len() && char_indices[i].1 == '!' {
+                        res.push(Token::Macro(from_char!(i - len)..from_char!(i)));
+                    } else if config.keywords.contains(&word.to_owned()) {
+                        res.push(Token::Keyword(from_char!(i - len)..from_char!(i)));
+                    } else if word.starts_with(char::is_uppercase)
+                        && word.chars().any(char::is_lowercase)
+                    {
+                        // Capitalized words are types, SCREAMING_CASE is left alone as those are usually constants
+                        res.push(Token::TypeName(from_char!(i - len)..from_char!(i)));
+                    } else {
+                        res.push(Token::Identifier(from_char!(i - len)..from_char!(i)));
                     }
-                    TokenizerAction::ParseComment => {
-                        let mut len = 0;
-                        while i < char_indices.len() && !string_match!(config.multi_line_comment.1)
-                        {
-                            i += 1;
-                            len += 1;
-                        }
-                        if i < char_indices.len() {
-                            len += config.multi_line_comment.1.chars().count();
-                            i += config.multi_line_comment.1.chars().count();
 
-                            res.push(Token::Comment(from_char!(i - len)..from_char!(i)));
-                            parser = None;
-                        } else {
-                            res.push(Token::Comment(0..src.len()));
-                        }
+                    if i != char_indices.len() {
+                        i -= 1;
                     }
                 }
-            }
 
-            while i < char_indices.len() {
-                if char_indices[i].1.is_alphabetic() || char_indices[i].1 == '_' {
-                    handle_odd_token!();
+                // else if i != 0 {
+                //     panic!("{} at {} {:?} {}",char_indices[i].1, i, res, status);
+                // }
+            } else if char_indices[i].1.is_numeric() {
+                handle_odd_token!();
 
-                    let mut len = 0;
+                let start = i;
+
+                let radix = if char_indices[i].1 == '0' && i + 1 < char_indices.len() {
+                    match char_indices[i + 1].1 {
+                        'x' | 'X' => 16,
+                        'o' | 'O' => 8,
+                        'b' | 'B' => 2,
+                        _ => 10,
+                    }
+                } else {
+                    10
+                };
+
+                if radix != 10 {
+                    i += 2;
                     while i < char_indices.len()
-                        && (char_indices[i].1.is_alphanumeric() || char_indices[i].1 == '_')
+                        && (char_indices[i].1.is_digit(radix) || char_indices[i].1 == '_')
+                    {
+                        i += 1;
+                    }
+                } else {
+                    while i < char_indices.len()
+                        && (char_indices[i].1.is_numeric() || char_indices[i].1 == '_')
                     {
-                        len += 1;
                         i += 1;
                     }
 
-                    if len != 0 {
-                        if i < char_indices.len() && char_indices[i].1 == '(' {
-                            res.push(Token::FnCall(from_char!(i - len)..from_char!(i)));
-                        } else if i < char_indices.len() && char_indices[i].1 == '!' {
-                            res.push(Token::Macro(from_char!(i - len)..from_char!(i)));
-                        } else if config
-                            .keywords
-                            .contains(&src[from_char!(i - len)..from_char!(i)].to_owned())
+                    // Fractional part, 1..2 is a range and not a float so the dot needs a digit after it
+                    if i + 1 < char_indices.len()
+                        && char_indices[i].1 == '.'
+                        && char_indices[i + 1].1.is_ascii_digit()
+                    {
+                        i += 1;
+                        while i < char_indices.len()
+                            && (char_indices[i].1.is_ascii_digit() || char_indices[i].1 == '_')
                         {
-                            res.push(Token::Keyword(from_char!(i - len)..from_char!(i)));
-                        } else {
-                            res.push(Token::Identifier(from_char!(i - len)..from_char!(i)));
+                            i += 1;
                         }
+                    }
 
-                        if i != char_indices.len() && !is_inside_sel!() {
-                            i -= 1;
+                    // Exponent
+                    if i < char_indices.len() && (char_indices[i].1 == 'e' || char_indices[i].1 == 'E') {
+                        let mut j = i + 1;
+                        if j < char_indices.len() && (char_indices[j].1 == '+' || char_indices[j].1 == '-') {
+                            j += 1;
+                        }
+                        if j < char_indices.len() && char_indices[j].1.is_ascii_digit() {
+                            i = j;
+                            while i < char_indices.len()
+                                && (char_indices[i].1.is_ascii_digit() || char_indices[i].1 == '_')
+                            {
+                                i += 1;
+                            }
                         }
                     }
+                }
 
-                    // else if i != 0 {
-                    //     panic!("{} at {} {:?} {}",char_indices[i].1, i, res, status);
-                    // }
-                } else if char_indices[i].1.is_numeric() {
-                    handle_odd_token!();
+                // Type suffixes like u8, f32 or usize
+                while i < char_indices.len()
+                    && (char_indices[i].1.is_alphanumeric() || char_indices[i].1 == '_')
+                {
+                    i += 1;
+                }
 
-                    let mut len = 0;
-                    while i < char_indices.len() && char_indices[i].1.is_numeric() {
-                        len += 1;
-                        i += 1;
-                    }
+                res.push(Token::Number(from_char!(start)..from_char!(i)));
 
-                    res.push(Token::Number(from_char!(i - len)..from_char!(i)));
+                if i != char_indices.len() {
+                    i -= 1;
+                }
+            } else if string_match!(config.line_comment_start) {
+                handle_odd_token!();
 
-                    if i != char_indices.len() {
-                        i -= 1;
-                    }
-                } else if string_match!(config.line_comment_start) {
-                    handle_odd_token!();
+                let is_doc = config.doc_comments.iter().any(|d| string_match!(d));
+                res.push(comment_token!(from_char!(i)..src.len(), is_doc));
+                i = src.len();
+            } else if string_match!(config.multi_line_comment.0) {
+                handle_odd_token!();
 
-                    res.push(Token::Comment(from_char!(i)..src.len()));
-                    i = src.len();
-                } else if string_match!(config.multi_line_comment.0) {
-                    handle_odd_token!();
-                    let mut len = config.multi_line_comment.0.len();
-                    i += config.multi_line_comment.0.len();
-                    while i < char_indices.len() && !string_match!(config.multi_line_comment.1) {
-                        i += 1;
-                        len += 1;
-                    }
+                let start = i;
+                // An empty /**/ isn't a doc comment even though it starts like one
+                let empty = format!("{}{}", config.multi_line_comment.0, config.multi_line_comment.1);
+                let is_doc = config.doc_comments.iter().any(|d| string_match!(d)) && !string_match!(empty);
 
-                    if i < char_indices.len() {
-                        let line_ender_len = config.multi_line_comment.1.chars().count();
-                        i += line_ender_len;
-                        len += line_ender_len;
+                i += config.multi_line_comment.0.chars().count();
+                let depth = scan_block_comment!(1);
 
-                        res.push(Token::Comment(from_char!(i - len)..from_char!(i)));
+                res.push(comment_token!(from_char!(start)..from_char!(i), is_doc));
 
-                        if i < char_indices.len() {
-                            i -= 1;
+                if depth != 0 {
+                    parser = Some(TokenizerAction::ParseComment(depth, is_doc));
+                } else if i < char_indices.len() {
+                    i -= 1;
+                }
+            } else if char_indices[i].1 == '"' {
+                handle_odd_token!();
+
+                parse_string!('"');
+            } else if char_indices[i].1 == '\'' {
+                handle_odd_token!();
+
+                // 'a' and '\n' are chars, 'a without a closing quote is a lifetime
+                let mut char_end = None;
+                if i + 2 < char_indices.len()
+                    && char_indices[i + 1].1 != '\\'
+                    && char_indices[i + 2].1 == '\''
+                {
+                    char_end = Some(i + 3);
+                } else if i + 1 < char_indices.len() && char_indices[i + 1].1 == '\\' {
+                    // Longest escape is \u{10FFFF}
+                    let mut j = i + 3;
+                    while j < char_indices.len() && j < i + 12 {
+                        if char_indices[j].1 == '\'' {
+                            char_end = Some(j + 1);
+                            break;
                         }
-                    } else {
-                        res.push(Token::Comment(from_char!(i - len)..from_char!(i)));
-                        parser = Some(TokenizerAction::ParseComment);
+                        j += 1;
                     }
-                } else if char_indices[i].1 == '"' {
-                    handle_odd_token!();
+                }
 
-                    parse_string!('"');
-                } else if char_indices[i].1 == '\'' {
-                    handle_odd_token!();
+                if let Some(char_end) = char_end {
+                    res.push(Token::Char(from_char!(i)..from_char!(char_end)));
+                    i = char_end - 1;
+                } else if config.lifetimes
+                    && i + 1 < char_indices.len()
+                    && (char_indices[i + 1].1.is_alphabetic() || char_indices[i + 1].1 == '_')
+                {
+                    let start = i;
+                    i += 1;
+                    while i < char_indices.len()
+                        && (char_indices[i].1.is_alphanumeric() || char_indices[i].1 == '_')
+                    {
+                        i += 1;
+                    }
 
-                    parse_string!('\'');
-                } else if char_indices[i].1 == '`' {
-                    handle_odd_token!();
+                    res.push(Token::Lifetime(from_char!(start)..from_char!(i)));
 
-                    parse_string!('`');
+                    if i != char_indices.len() {
+                        i -= 1;
+                    }
                 } else {
-                    odd_token.push(char_indices[i].1);
+                    parse_string!('\'');
                 }
+            } else if char_indices[i].1 == '`' {
+                handle_odd_token!();
+
+                parse_string!('`');
+            } else if OPERATOR_CHARS.contains(char_indices[i].1) {
+                handle_odd_token!();
 
+                // Operators are grouped so that ->, == and friends read as one token
+                let start = i;
                 i += 1;
+                while i < char_indices.len()
+                    && OPERATOR_CHARS.contains(char_indices[i].1)
+                    && !string_match!(config.line_comment_start)
+                    && !string_match!(config.multi_line_comment.0)
+                {
+                    i += 1;
+                }
+
+                res.push(Token::Operator(from_char!(start)..from_char!(i)));
+
+                if i != char_indices.len() {
+                    i -= 1;
+                }
+            } else if PUNCTUATION_CHARS.contains(char_indices[i].1) {
+                handle_odd_token!();
+
+                let range = from_char!(i)..from_char!(i + 1);
+                match char_indices[i].1 {
+                    '(' | '[' | '{' => {
+                        res.push(Token::Bracket(range, bracket_depth));
+                        bracket_depth += 1;
+                    }
+                    ')' | ']' | '}' => {
+                        bracket_depth = bracket_depth.saturating_sub(1);
+                        res.push(Token::Bracket(range, bracket_depth));
+                    }
+                    _ => res.push(Token::Punctuation(range)),
+                }
+            } else {
+                odd_token.push(char_indices[i].1);
             }
-            handle_odd_token!();
 
-            Token::normalize(&mut res, src.len(), config, src.to_string());
-            
-            row.tokens = res;
+            i += 1;
         }
+        handle_odd_token!();
+
+        *parser_state = parser;
+        *depth_state = bracket_depth;
+
+        res
+    }
+
+    fn offset_by(mut self, offset: usize) -> Token {
+        let range = self.get_range_mut();
+        *range = range.start + offset..range.end + offset;
+        self
     }
 
     pub fn normalize(tokens: &mut Vec<Token>, len: usize, _config: &FileConfig, src: String) {
@@ -455,6 +976,15 @@ impl Token {
             Token::FnCall(_) => 3,
             Token::Macro(_) => 3,
             Token::Number(_) => 1,
+            Token::Char(_) => 5,
+            Token::Lifetime(_) => 2,
+            Token::DocComment(_) => 6,
+            Token::TypeName(_) => 2,
+            Token::Operator(_) => 1,
+            Token::Punctuation(_) => 1,
+            Token::Bracket(_, _) => 1,
+            Token::MatchingBracket(_) => 9,
+            Token::Diagnostic(_, _) => 8,
             Token::Selection(_) => 10,
             Token::CustomStyle(_, _) => 10,
         }
@@ -470,6 +1000,15 @@ impl Token {
             Token::FnCall(r) => r.start,
             Token::Macro(r) => r.start,
             Token::Number(r) => r.start,
+            Token::Char(r) => r.start,
+            Token::Lifetime(r) => r.start,
+            Token::DocComment(r) => r.start,
+            Token::TypeName(r) => r.start,
+            Token::Operator(r) => r.start,
+            Token::Punctuation(r) => r.start,
+            Token::Bracket(r, _) => r.start,
+            Token::MatchingBracket(r) => r.start,
+            Token::Diagnostic(r, _) => r.start,
             Token::Selection(r) => r.start,
             Token::CustomStyle(r, _) => r.start,
         }
@@ -485,6 +1024,15 @@ impl Token {
             Token::FnCall(r) => r.end,
             Token::Macro(r) => r.end,
             Token::Number(r) => r.end,
+            Token::Char(r) => r.end,
+            Token::Lifetime(r) => r.end,
+            Token::DocComment(r) => r.end,
+            Token::TypeName(r) => r.end,
+            Token::Operator(r) => r.end,
+            Token::Punctuation(r) => r.end,
+            Token::Bracket(r, _) => r.end,
+            Token::MatchingBracket(r) => r.end,
+            Token::Diagnostic(r, _) => r.end,
             Token::Selection(r) => r.end,
             Token::CustomStyle(r, _) => r.end,
         }
@@ -500,6 +1048,15 @@ impl Token {
             Token::FnCall(r) => r,
             Token::Macro(r) => r,
             Token::Number(r) => r,
+            Token::Char(r) => r,
+            Token::Lifetime(r) => r,
+            Token::DocComment(r) => r,
+            Token::TypeName(r) => r,
+            Token::Operator(r) => r,
+            Token::Punctuation(r) => r,
+            Token::Bracket(r, _) => r,
+            Token::MatchingBracket(r) => r,
+            Token::Diagnostic(r, _) => r,
             Token::Selection(r) => r,
             Token::CustomStyle(r, _) => r,
         }
@@ -539,6 +1096,24 @@ impl Token {
             .get(&"selection".to_owned())
             .unwrap_or(&(0, 0, 255));
 
+        // Newer categories fall back to their closest older color so existing configs look the same
+        let char_ = config.syntax_colors.get("char").unwrap_or(string);
+        let lifetime = config
+            .syntax_colors
+            .get("lifetime")
+            .unwrap_or(keyword);
+        let doc_comment = config
+            .syntax_colors
+            .get("doc_comment")
+            .unwrap_or(comment);
+        let type_name = config.syntax_colors.get("type").unwrap_or(ident);
+        let operator = config.syntax_colors.get("operator");
+        let punctuation = config.syntax_colors.get("punctuation");
+        let matching_bracket = config
+            .syntax_colors
+            .get("matching_bracket")
+            .unwrap_or(&(80, 80, 80));
+
         match self {
             Token::Identifier(_) => {
                 format!(
@@ -583,6 +1158,70 @@ impl Token {
                     crossterm::style::SetForegroundColor(Color::from(*number))
                 )
             }
+            Token::Char(_) => {
+                format!(
+                    "{}",
+                    crossterm::style::SetForegroundColor(Color::from(*char_))
+                )
+            }
+            Token::Lifetime(_) => {
+                format!(
+                    "{}",
+                    crossterm::style::SetForegroundColor(Color::from(*lifetime))
+                )
+            }
+            Token::DocComment(_) => {
+                format!(
+                    "{}",
+                    crossterm::style::SetForegroundColor(Color::from(*doc_comment))
+                )
+            }
+            Token::TypeName(_) => {
+                format!(
+                    "{}",
+                    crossterm::style::SetForegroundColor(Color::from(*type_name))
+                )
+            }
+            Token::Operator(_) => match operator {
+                Some(operator) => format!(
+                    "{}",
+                    crossterm::style::SetForegroundColor(Color::from(*operator))
+                ),
+                None => String::new(),
+            },
+            Token::Punctuation(_) => match punctuation {
+                Some(punctuation) => format!(
+                    "{}",
+                    crossterm::style::SetForegroundColor(Color::from(*punctuation))
+                ),
+                None => String::new(),
+            },
+            Token::Bracket(_, depth) => {
+                if config.bracket_colors.is_empty() {
+                    Token::Punctuation(0..0).get_style(config)
+                } else {
+                    format!(
+                        "{}",
+                        crossterm::style::SetForegroundColor(Color::from(
+                            config.bracket_colors[depth % config.bracket_colors.len()]
+                        ))
+                    )
+                }
+            }
+            Token::MatchingBracket(_) => {
+                format!(
+                    "{}{}",
+                    crossterm::style::SetBackgroundColor(Color::from(*matching_bracket)),
+                    crossterm::style::Attribute::Bold
+                )
+            }
+            Token::Diagnostic(_, severity) => {
+                format!(
+                    "{}{}",
+                    crossterm::style::SetForegroundColor(Color::from(severity.color(config))),
+                    crossterm::style::Attribute::Underlined
+                )
+            }
             Token::Selection(_) => {
                 format!(
                     "{}",
@@ -603,8 +1242,228 @@ impl Token {
             Token::FnCall(r) => r,
             Token::Macro(r) => r,
             Token::Number(r) => r,
+            Token::Char(r) => r,
+            Token::Lifetime(r) => r,
+            Token::DocComment(r) => r,
+            Token::TypeName(r) => r,
+            Token::Operator(r) => r,
+            Token::Punctuation(r) => r,
+            Token::Bracket(r, _) => r,
+            Token::MatchingBracket(r) => r,
+            Token::Diagnostic(r, _) => r,
             Token::Selection(r) => r,
             Token::CustomStyle(r, _) => r,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every token on the line but whitespace, as Kind:text
+    fn tokens(src: &str, config: &FileConfig) -> Vec<String> {
+        let mut parser = None;
+        let mut depth = 0;
+        Token::tokenize_segment(src, &mut parser, &mut depth, config)
+            .iter()
+            .filter(|token| !matches!(token, Token::Plain(_) | Token::CustomStyle(_, _)))
+            .map(|token| {
+                let kind = format!("{:?}", token);
+                let kind = &kind[..kind.find('(').unwrap()];
+                format!("{}:{}", kind, &src[token.get_range().clone()])
+            })
+            .collect()
+    }
+
+    fn rust() -> FileConfig {
+        FileConfig {
+            nested_comments: true,
+            doc_comments: vec!["///".to_owned(), "//!".to_owned(), "/**".to_owned(), "/*!".to_owned()],
+            raw_strings: true,
+            lifetimes: true,
+            ..FileConfig::default()
+        }
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(
+            tokens("0x1F 0o17 0b1010 1_000 3.14 2.5E-3 1e10", &rust()),
+            ["Number:0x1F", "Number:0o17", "Number:0b1010", "Number:1_000", "Number:3.14", "Number:2.5E-3", "Number:1e10"]
+        );
+        assert_eq!(tokens("10u8 1.0f32 0xFFusize", &rust()), ["Number:10u8", "Number:1.0f32", "Number:0xFFusize"]);
+    }
+
+    #[test]
+    fn ranges_are_not_floats() {
+        assert_eq!(tokens("1..2", &rust()), ["Number:1", "Operator:..", "Number:2"]);
+    }
+
+    #[test]
+    fn chars_and_lifetimes() {
+        assert_eq!(
+            tokens(r"'a' '\n' '\u{1F600}' 'a 'static", &rust()),
+            ["Char:'a'", r"Char:'\n'", r"Char:'\u{1F600}'", "Lifetime:'a", "Lifetime:'static"]
+        );
+
+        // Without lifetimes a lone quote starts a string
+        assert_eq!(tokens("'a b'", &FileConfig::default()), ["String:'a b'"]);
+    }
+
+    #[test]
+    fn raw_strings() {
+        assert_eq!(
+            tokens(r###"r#"a "quoted" b"# br"x" rx"###, &rust()),
+            [r###"String:r#"a "quoted" b"#"###, r#"String:br"x""#, "Identifier:rx"]
+        );
+    }
+
+    #[test]
+    fn nested_comments() {
+        assert_eq!(tokens("/* a /* b */ c */ d", &rust()), ["Comment:/* a /* b */ c */", "Identifier:d"]);
+
+        let flat = FileConfig::default();
+        assert_eq!(tokens("/* a /* b */ c */", &flat), ["Comment:/* a /* b */", "Identifier:c", "Operator:*/"]);
+    }
+
+    #[test]
+    fn doc_comments() {
+        assert_eq!(tokens("/// doc", &rust()), ["DocComment:/// doc"]);
+        assert_eq!(tokens("/** doc */ x", &rust()), ["DocComment:/** doc */", "Identifier:x"]);
+        assert_eq!(tokens("/**/ x", &rust()), ["Comment:/**/", "Identifier:x"]);
+        assert_eq!(tokens("// not doc", &rust()), ["Comment:// not doc"]);
+
+        // Only languages that ask for them get doc comments
+        assert_eq!(tokens("/// doc", &FileConfig::default()), ["Comment:/// doc"]);
+    }
+
+    fn rows(lines: &[&str]) -> Vec<Row> {
+        lines.iter().map(|line| Row::from_string(line.to_string())).collect()
+    }
+
+    fn tokenize(
+        rows: &mut Vec<Row>,
+        from: usize,
+        count: usize,
+        config: &FileConfig,
+        languages: Option<&HashMap<String, FileConfig>>,
+    ) {
+        let info = HighlightingInfo {
+            selection: None,
+            matching_brackets: None,
+            languages,
+            diagnostics: None,
+            plugin_tokens: None,
+            decorations: None,
+        };
+        Token::tokenize(rows, info, from, count, config);
+    }
+
+    fn bracket_depths(row: &Row) -> Vec<usize> {
+        row.tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Bracket(_, depth) => Some(*depth),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bracket_depth_after_a_jump() {
+        let config = FileConfig::default();
+        let mut rows = rows(&["fn f() {", "    if x {", "        y", "    }", "}"]);
+
+        // Nothing above row 2 has been tokenized yet
+        tokenize(&mut rows, 2, 3, &config, None);
+        assert_eq!(bracket_depths(&rows[3]), [1]);
+        assert_eq!(bracket_depths(&rows[4]), [0]);
+
+        // Editing a row above the view carries down to it
+        rows[1].remove_at(9);
+        tokenize(&mut rows, 3, 2, &config, None);
+        assert_eq!(bracket_depths(&rows[3]), [0]);
+    }
+
+    // Markdown with ```rust fences, and the languages that fill them in
+    fn markdown() -> (FileConfig, HashMap<String, FileConfig>) {
+        let markdown = FileConfig {
+            line_comment_start: "<!--".to_owned(),
+            keywords: vec!["fn".to_owned()],
+            embedded: vec![EmbeddedRegion {
+                start: "```".to_owned(),
+                end: "```".to_owned(),
+                start_close: String::new(),
+                language: String::new(),
+                language_from_start: true,
+            }],
+            ..FileConfig::default()
+        };
+
+        let mut languages = HashMap::new();
+        languages.insert(
+            "rs".to_owned(),
+            FileConfig {
+                keywords: vec!["let".to_owned()],
+                aliases: vec!["rust".to_owned()],
+                ..FileConfig::default()
+            },
+        );
+        (markdown, languages)
+    }
+
+    #[test]
+    fn tokenizing_without_languages_does_not_leave_its_depths_behind() {
+        let (markdown, languages) = markdown();
+        let lines = ["```rust", "fn f() {", "```", "(x)"];
+
+        let mut fresh = rows(&lines);
+        tokenize(&mut fresh, 0, 4, &markdown, Some(&languages));
+        assert_eq!(bracket_depths(&fresh[3]), [1, 1]);
+
+        // Without the languages the brackets in the fence aren't counted, which mustn't carry into the next
+        // tokenize that has them
+        let mut rows = rows(&lines);
+        tokenize(&mut rows, 0, 4, &markdown, None);
+        assert_eq!(bracket_depths(&rows[3]), [0, 0]);
+        tokenize(&mut rows, 3, 1, &markdown, Some(&languages));
+        assert_eq!(bracket_depths(&rows[3]), [1, 1]);
+    }
+
+    #[test]
+    fn matching_bracket_in_an_embedded_region() {
+        let (markdown, languages) = markdown();
+        let mut doc = TextDocument::new("notes.md".to_owned());
+        doc.rows = rows(&["```rust", "fn f() {", "}", "```"]);
+
+        assert_eq!(doc.matching_bracket(1, 7, &markdown, &languages), Some((2, 0)));
+        assert_eq!(doc.matching_bracket(2, 0, &markdown, &languages), Some((1, 7)));
+    }
+
+    // Kind:text of each token on a row, leaving out the plain text between them
+    fn kinds(row: &Row) -> Vec<String> {
+        row.tokens
+            .iter()
+            .filter(|token| !matches!(token, Token::Plain(_) | Token::CustomStyle(_, _)))
+            .map(|token| {
+                let kind = format!("{:?}", token);
+                let kind = &kind[..kind.find('(').unwrap()];
+                format!("{}:{}", kind, &row.buf[token.get_range().clone()])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn embedded_region_after_a_jump() {
+        let (markdown, languages) = markdown();
+        let mut rows = rows(&["fn a", "```rust", "let a = 1;", "let b = 2;", "```", "fn c <!-- let"]);
+
+        // Jump straight to the middle of the fence, the region has to be picked up from the rows above
+        tokenize(&mut rows, 3, 3, &markdown, Some(&languages));
+
+        assert_eq!(kinds(&rows[3]), ["Keyword:let", "Identifier:b", "Operator:=", "Number:2", "Punctuation:;"]);
+        assert_eq!(kinds(&rows[5]), ["Keyword:fn", "Identifier:c", "Comment:<!-- let"]);
+    }
+
+}
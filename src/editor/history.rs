@@ -1,5 +1,6 @@
 use crate::editor::prelude::*;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum UndoRedo {
     Undo,
     Redo,
@@ -19,6 +20,7 @@ pub enum EditDiff {
     DeleteLine(Y, String, LineDeleteMode),
 
     SplitLine(X, Y),
+    JoinLine(X, Y), // Row y + 1 appended to row y, which was x chars long
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -91,6 +93,7 @@ impl EditDiff {
                     if y != 0 && mode == LineDeleteMode::Joined {
                         let l = rows[y - 1].buf.len();
                         rows[y - 1].buf.truncate(l - s.len());
+                        rows[y - 1].tokenized_after = None;
                     }
 
                     rows.insert(y, Row::from_string(s.clone()));
@@ -135,6 +138,22 @@ impl EditDiff {
                     (0, y + 1)
                 }
             },
+
+            JoinLine(x, y) => match which {
+                Undo => {
+                    let (left, right) = rows[y].split_at(x);
+                    rows[y] = Row::from_string(left);
+                    rows.insert(y + 1, Row::from_string(right));
+
+                    (x, y)
+                }
+                Redo => {
+                    rows[y] = Row::from_string(format!("{}{}", rows[y].buf, rows[y + 1].buf));
+                    rows.remove(y + 1);
+
+                    (x, y)
+                }
+            },
         }
     }
 }
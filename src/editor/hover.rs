@@ -0,0 +1,195 @@
+use crate::editor::prelude::*;
+use serde_json::Value;
+use std::ops::Range;
+use unicode_width::UnicodeWidthChar;
+use unicode_width::UnicodeWidthStr;
+
+// Most lines of a hover box shown at once
+pub const HOVER_HEIGHT: usize = 12;
+
+// An open signature help box for the call whose '(' is at (row, open_col)
+pub struct SignatureHelp {
+    pub doc: usize,
+    pub row: usize,
+    pub open_col: usize,
+
+    pub label: String,
+    pub parameters: Vec<Range<usize>>, // Char ranges of each parameter in label
+    pub active: usize,
+}
+
+// Reads a textDocument/hover response into plain text lines, dropping markdown code fences
+pub fn hover_text(response: &Value) -> Vec<String> {
+    let contents = &response["contents"];
+
+    let text = match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(marked_string)
+            .collect::<Vec<String>>()
+            .join("\n\n"),
+        _ => marked_string(contents).unwrap_or_default(),
+    };
+
+    let mut lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .map(|line| line.trim_end().to_owned())
+        .collect();
+
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    lines
+}
+
+// Wraps lines at word boundaries so none is wider than width, breaking words that don't fit on their own
+pub fn wrap(lines: &[String], width: usize) -> Vec<String> {
+    let mut wrapped = Vec::new();
+
+    for line in lines {
+        let line = line.replace('\t', "    ");
+        let indent = line.len() - line.trim_start().len();
+        let mut current = line[..indent].to_owned();
+
+        for word in line[indent..].split(' ') {
+            if !current.trim().is_empty() && current.width() + word.width() + 1 > width {
+                wrapped.push(current);
+                current = String::new();
+            }
+
+            if !current.trim().is_empty() {
+                current.push(' ');
+            }
+
+            for c in word.chars() {
+                if current.width() + c.width().unwrap_or(0) > width {
+                    wrapped.push(current);
+                    current = String::new();
+                }
+                current.push(c);
+            }
+        }
+
+        wrapped.push(current);
+    }
+
+    wrapped
+}
+
+// A MarkedString or MarkupContent
+fn marked_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        _ => value["value"].as_str().map(|text| text.to_owned()),
+    }
+}
+
+impl SignatureHelp {
+    // Reads a textDocument/signatureHelp response, None if the server had nothing to show
+    pub fn from_lsp(response: &Value, doc_index: usize, doc: &TextDocument) -> Option<Self> {
+        let signatures = response["signatures"].as_array()?;
+        let active_signature = response["activeSignature"].as_u64().unwrap_or(0) as usize;
+        let signature = signatures.get(active_signature).or_else(|| signatures.first())?;
+
+        let label = signature["label"].as_str()?.to_owned();
+        let utf16: Vec<u16> = label.encode_utf16().collect();
+
+        let parameters = signature["parameters"]
+            .as_array()
+            .map(|parameters| {
+                parameters
+                    .iter()
+                    .filter_map(|parameter| match &parameter["label"] {
+                        // Either a substring of the label or utf 16 offsets into it
+                        Value::String(name) => label.find(&name[..]).map(|start| {
+                            let start = label[..start].chars().count();
+                            start..start + name.chars().count()
+                        }),
+                        Value::Array(offsets) => {
+                            let start = offsets.first()?.as_u64()? as usize;
+                            let end = offsets.get(1)?.as_u64()? as usize;
+                            let to_char = |offset: usize| {
+                                String::from_utf16_lossy(&utf16[..std::cmp::min(offset, utf16.len())])
+                                    .chars()
+                                    .count()
+                            };
+                            Some(to_char(start)..to_char(end))
+                        }
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let open_col = open_paren(doc)?;
+
+        let mut help = SignatureHelp {
+            doc: doc_index,
+            row: doc.cursor_row,
+            open_col,
+            label,
+            parameters,
+            active: 0,
+        };
+
+        if !help.update(doc) {
+            return None;
+        }
+
+        Some(help)
+    }
+
+    // Works out the active parameter from the commas typed so far, false once the cursor has left the call
+    pub fn update(&mut self, doc: &TextDocument) -> bool {
+        if doc.cursor_row != self.row || doc.cursor_col <= self.open_col {
+            return false;
+        }
+
+        let row = &doc.rows[self.row];
+        if self.open_col >= row.len() || row.char_at(self.open_col) != '(' {
+            return false;
+        }
+
+        let mut depth = 0;
+        let mut commas = 0;
+
+        for c in row.substring(self.open_col + 1, doc.cursor_col).chars() {
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' if depth == 0 => return false,
+                ')' | ']' | '}' => depth -= 1,
+                ',' if depth == 0 => commas += 1,
+                _ => {}
+            }
+        }
+
+        self.active = commas;
+        true
+    }
+
+    pub fn active_parameter(&self) -> Option<Range<usize>> {
+        self.parameters.get(self.active).cloned()
+    }
+}
+
+// The unclosed '(' before the cursor on its row
+pub fn open_paren(doc: &TextDocument) -> Option<usize> {
+    let row = &doc.rows[doc.cursor_row];
+    let before: Vec<char> = row.substring(0, std::cmp::min(doc.cursor_col, row.len())).chars().collect();
+
+    let mut depth = 0;
+    for (i, c) in before.iter().enumerate().rev() {
+        match c {
+            ')' | ']' | '}' => depth += 1,
+            '(' if depth == 0 => return Some(i),
+            '(' | '[' | '{' if depth > 0 => depth -= 1,
+            '[' | '{' => return None,
+            _ => {}
+        }
+    }
+
+    None
+}
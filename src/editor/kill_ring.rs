@@ -0,0 +1,274 @@
+// Emacs style killing and yanking, kept apart from the system clipboard that copy and paste use.
+//
+// set_mark starts a region that runs to wherever the cursor goes, shown as the selection.
+// kill_line and kill_region take text out and put it on the ring, kills straight after each other
+// going into the same entry. yank puts back the newest entry and yank_pop, right after a yank,
+// swaps what was put in for the entry before it.
+//
+// "keybind_preset": "emacs" in config.json binds these the Emacs way, see action.rs.
+use crate::editor::action::Action;
+use crate::editor::history::EditDiff;
+use crate::editor::prelude::*;
+use std::collections::VecDeque;
+
+const KILL_RING_SIZE: usize = 60;
+
+type Pos = (usize, usize);
+
+#[derive(Default)]
+pub struct KillRing {
+    entries: VecDeque<String>, // Newest first
+    yanked: Option<Yanked>,
+    pub mark: Option<(usize, Pos)>, // Document and position
+}
+
+// Where the last yank went, for yank_pop to replace
+struct Yanked {
+    doc: usize,
+    start: Pos,
+    end: Pos,
+    entry: usize,
+}
+
+impl KillRing {
+    fn kill(&mut self, text: String, append: bool) {
+        match self.entries.front_mut() {
+            Some(newest) if append => newest.push_str(&text),
+            _ => {
+                self.entries.push_front(text);
+                self.entries.truncate(KILL_RING_SIZE);
+            }
+        }
+    }
+}
+
+fn follows_kill(previous: Option<Action>) -> bool {
+    matches!(previous, Some(Action::KillLine) | Some(Action::KillRegion))
+}
+
+impl Editor {
+    // Setting it again where it already is turns it off
+    pub fn set_mark(&mut self, doc_index: usize) {
+        let doc = self.docs[doc_index].as_text_doc();
+        let cursor = (doc.cursor_row, doc.cursor_col);
+
+        if self.kill_ring.mark == Some((doc_index, cursor)) {
+            self.kill_ring.mark = None;
+            self.status_msg = "Mark deactivated".to_owned();
+        } else {
+            self.kill_ring.mark = Some((doc_index, cursor));
+            self.status_msg = "Mark set".to_owned();
+        }
+    }
+
+    // Shows the region as the selection, forgetting the mark if edits left it past the end of the document
+    pub fn show_region(&mut self, doc_index: usize) {
+        let (doc, (row, col)) = match self.kill_ring.mark {
+            Some(mark) if mark.0 == doc_index => mark,
+            _ => return,
+        };
+
+        let text_doc = self.docs[doc].as_mut_text_doc();
+        if text_doc.rows.get(row).is_none_or(|line| col > line.len()) {
+            self.kill_ring.mark = None;
+            return;
+        }
+
+        text_doc.selection = Some(Selection::new(row, col, text_doc.cursor_row, text_doc.cursor_col));
+        self.undergoing_selection = true;
+    }
+
+    // From the cursor to the end of the line, or the line break too if that's all that's left
+    pub fn kill_line(&mut self, doc_index: usize, previous: Option<Action>) {
+        let doc = self.docs[doc_index].as_mut_text_doc();
+        let start = (doc.cursor_row, doc.cursor_col);
+        let line_end = (start.0, doc.rows[start.0].len());
+
+        let end = if doc.text_between(start, line_end).trim().is_empty() && start.0 + 1 < doc.rows.len() {
+            (start.0 + 1, 0)
+        } else {
+            line_end
+        };
+
+        if start == end {
+            self.status_msg = "End of document".to_owned();
+            return;
+        }
+
+        let text = doc.text_between(start, end);
+        let (diffs, _) = doc.replace_range(start, end, "");
+        doc.add_diff(EditDiff::Compound(diffs));
+        doc.dirty += 1;
+
+        self.kill_ring.kill(text, follows_kill(previous));
+    }
+
+    // Between the mark and the cursor, or the selection if there's no mark. kill_ring_save leaves the text in place
+    pub fn kill_region(&mut self, doc_index: usize, previous: Option<Action>, delete: bool) {
+        let doc = self.docs[doc_index].as_mut_text_doc();
+        let cursor = (doc.cursor_row, doc.cursor_col);
+
+        // A mark in another document is left for when that one is back
+        let mark = match self.kill_ring.mark {
+            Some((mark_doc, mark)) if mark_doc == doc_index => {
+                self.kill_ring.mark = None;
+                Some(mark)
+            }
+            _ => None,
+        };
+
+        let (start, end) = match (mark, doc.selection) {
+            (Some(mark), _) => (mark.min(cursor), mark.max(cursor)),
+            (_, Some(mut selection)) => {
+                selection.normalize();
+                ((selection.start_row, selection.start_col), (selection.end_row, selection.end_col))
+            }
+            _ => {
+                self.status_msg = "The mark is not set".to_owned();
+                return;
+            }
+        };
+        doc.selection = None;
+
+        let text = doc.text_between(start, end);
+        if delete && start != end {
+            let (diffs, _) = doc.replace_range(start, end, "");
+            doc.add_diff(EditDiff::Compound(diffs));
+            doc.dirty += 1;
+
+            doc.cursor_row = start.0;
+            doc.cursor_col = start.1;
+        }
+
+        self.kill_ring.kill(text, delete && follows_kill(previous));
+    }
+
+    pub fn yank(&mut self, doc_index: usize) {
+        self.yank_entry(doc_index, 0, None);
+    }
+
+    pub fn yank_pop(&mut self, doc_index: usize, previous: Option<Action>) {
+        match self.kill_ring.yanked.take() {
+            Some(yanked) if yanked.doc == doc_index && matches!(previous, Some(Action::Yank) | Some(Action::YankPop)) => {
+                let entry = (yanked.entry + 1) % self.kill_ring.entries.len();
+                self.yank_entry(doc_index, entry, Some((yanked.start, yanked.end)));
+            }
+            _ => self.status_msg = "Previous command was not a yank".to_owned(),
+        }
+    }
+
+    // Puts an entry in at the cursor, or over the text of the last yank
+    fn yank_entry(&mut self, doc_index: usize, entry: usize, replacing: Option<(Pos, Pos)>) {
+        let text = match self.kill_ring.entries.get(entry) {
+            Some(text) => text.clone(),
+            None => {
+                self.status_msg = "Kill ring is empty".to_owned();
+                return;
+            }
+        };
+
+        let doc = self.docs[doc_index].as_mut_text_doc();
+        let (start, end) = replacing.unwrap_or(((doc.cursor_row, doc.cursor_col), (doc.cursor_row, doc.cursor_col)));
+
+        let (diffs, end) = doc.replace_range(start, end, &text);
+        doc.add_diff(EditDiff::Compound(diffs));
+        doc.dirty += 1;
+
+        doc.cursor_row = end.0;
+        doc.cursor_col = end.1;
+
+        self.kill_ring.yanked = Some(Yanked {
+            doc: doc_index,
+            start,
+            end,
+            entry,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor_with(docs: &[&str]) -> Editor {
+        let mut editor = Editor::default();
+        for (i, text) in docs.iter().enumerate() {
+            let mut doc = TextDocument::new(format!("{}.txt", i));
+            doc.rows = text.split('\n').map(|line| Row::from_string(line.to_owned())).collect();
+            editor.add_doc(doc);
+        }
+        editor
+    }
+
+    fn text(editor: &Editor, doc: usize) -> String {
+        editor.docs[doc].as_text_doc().contents()
+    }
+
+    fn move_to(editor: &mut Editor, doc: usize, row: usize, col: usize) {
+        let doc = editor.docs[doc].as_mut_text_doc();
+        doc.cursor_row = row;
+        doc.cursor_col = col;
+    }
+
+    #[test]
+    fn kills_in_a_row_go_together() {
+        let mut editor = editor_with(&["one\ntwo\nthree"]);
+
+        // The text, then the line break it leaves behind
+        editor.kill_line(0, None);
+        for _ in 0..3 {
+            editor.kill_line(0, Some(Action::KillLine));
+        }
+        assert_eq!(text(&editor, 0), "three");
+
+        editor.yank(0);
+        assert_eq!(text(&editor, 0), "one\ntwo\nthree");
+        assert_eq!(editor.kill_ring.entries.len(), 1);
+    }
+
+    #[test]
+    fn yank_pop_cycles_through_older_kills() {
+        let mut editor = editor_with(&["a b c"]);
+
+        editor.set_mark(0);
+        move_to(&mut editor, 0, 0, 2);
+        editor.kill_region(0, None, true);
+        editor.set_mark(0);
+        move_to(&mut editor, 0, 0, 2);
+        editor.kill_region(0, None, true);
+        assert_eq!(text(&editor, 0), "c");
+
+        editor.yank(0);
+        assert_eq!(text(&editor, 0), "b c");
+        editor.yank_pop(0, Some(Action::Yank));
+        assert_eq!(text(&editor, 0), "a c");
+        editor.yank_pop(0, Some(Action::YankPop));
+        assert_eq!(text(&editor, 0), "b c");
+
+        editor.yank_pop(0, Some(Action::MoveLeft));
+        assert_eq!(editor.status_msg, "Previous command was not a yank");
+    }
+
+    #[test]
+    fn a_mark_in_another_document_is_kept() {
+        let mut editor = editor_with(&["first", "second"]);
+
+        move_to(&mut editor, 0, 0, 2);
+        editor.set_mark(0);
+
+        editor.docs[1].as_mut_text_doc().selection = Some(Selection::new(0, 0, 0, 3));
+        editor.kill_region(1, None, true);
+        assert_eq!(text(&editor, 1), "ond");
+        assert_eq!(editor.kill_ring.mark, Some((0, (0, 2))));
+
+        // Without a selection there it's nothing to kill, and still doesn't lose the mark
+        editor.kill_region(1, None, true);
+        assert_eq!(editor.status_msg, "The mark is not set");
+        assert_eq!(editor.kill_ring.mark, Some((0, (0, 2))));
+
+        move_to(&mut editor, 0, 0, 5);
+        editor.kill_region(0, None, true);
+        assert_eq!(text(&editor, 0), "fi");
+        assert_eq!(editor.kill_ring.mark, None);
+    }
+}
@@ -0,0 +1,720 @@
+use crate::editor::kelp_version;
+use crate::editor::prelude::*;
+use serde_json::json;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+// How long to wait for a server to answer shutdown before killing it
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How long a user triggered request like completion can hold up the editor
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(1500);
+
+// A language server running as a child process.
+// Reading and writing happen on their own threads so a slow server never blocks the editor
+pub struct LspClient {
+    process: Child,
+
+    outgoing: Sender<Value>,
+    incoming: Receiver<Value>,
+
+    next_id: i64,
+    initialize_id: i64,
+    initialized: bool,
+    queued: Vec<Value>, // Messages held back until the server has answered initialize
+
+    documents: HashMap<String, (i64, usize)>, // uri -> (version the server has, TextDocument::version it was sent at)
+
+    events: Vec<LspEvent>, // Handled while waiting on a response, given out on the next poll
+}
+
+// What the editor needs to act on after polling the servers
+pub enum LspEvent {
+    Message(String),
+    Diagnostics(String, Vec<Value>), // Document uri and the raw lsp diagnostics for it
+    ApplyEdit(Value, Reply),         // A WorkspaceEdit the server wants made, usually after running a command
+}
+
+// Answers a request from a server once the editor has acted on it
+pub struct Reply {
+    pub id: Value,
+    pub outgoing: Sender<Value>,
+}
+
+// All the running servers, one per distinct command line
+#[derive(Default)]
+pub struct LanguageServers {
+    clients: HashMap<String, LspClient>,
+    failed: Vec<String>, // Commands that couldn't be started, so we don't retry on every key press
+}
+
+impl LspClient {
+    pub fn start(server: &LanguageServer) -> std::io::Result<Self> {
+        let mut process = Command::new(&server.command)
+            .args(&server.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let mut stdin = process.stdin.take().unwrap();
+        let stdout = process.stdout.take().unwrap();
+
+        let (outgoing, outgoing_rx) = channel::<Value>();
+        let (incoming_tx, incoming) = channel();
+
+        std::thread::spawn(move || {
+            for message in outgoing_rx {
+                let body = message.to_string();
+                if write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).is_err()
+                    || stdin.flush().is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Some(message) = read_message(&mut reader) {
+                if incoming_tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut client = LspClient {
+            process,
+            outgoing,
+            incoming,
+            next_id: 0,
+            initialize_id: 0,
+            initialized: false,
+            queued: Vec::new(),
+            documents: HashMap::new(),
+            events: Vec::new(),
+        };
+
+        let root = std::env::current_dir()
+            .map(|dir| path_to_uri(&dir.to_string_lossy()))
+            .unwrap_or_default();
+
+        client.initialize_id = client.next_id;
+        client.next_id += 1;
+        let _ = client.outgoing.send(json!({
+            "jsonrpc": "2.0",
+            "id": client.initialize_id,
+            "method": "initialize",
+            "params": {
+                "processId": std::process::id(),
+                "clientInfo": { "name": "kelp", "version": kelp_version() },
+                "rootUri": root,
+                "capabilities": {
+                    "textDocument": {
+                        "synchronization": { "didSave": true },
+                        "publishDiagnostics": { "relatedInformation": false },
+                        "completion": {
+                            "completionItem": { "snippetSupport": false }
+                        },
+                        "hover": { "contentFormat": ["plaintext", "markdown"] },
+                        "formatting": {},
+                        "rename": { "prepareSupport": false },
+                        "documentSymbol": { "hierarchicalDocumentSymbolSupport": true },
+                        "codeAction": {
+                            "codeActionLiteralSupport": {
+                                "codeActionKind": {
+                                    "valueSet": ["quickfix", "refactor", "refactor.extract", "refactor.inline", "refactor.rewrite", "source"]
+                                }
+                            },
+                            "resolveSupport": { "properties": ["edit"] }
+                        },
+                        "signatureHelp": {
+                            "signatureInformation": {
+                                "parameterInformation": { "labelOffsetSupport": true }
+                            }
+                        }
+                    }
+                }
+            }
+        }));
+
+        Ok(client)
+    }
+
+    fn send(&mut self, message: Value) {
+        if self.initialized {
+            let _ = self.outgoing.send(message);
+        } else {
+            self.queued.push(message);
+        }
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) {
+        self.send(message(None, method, params));
+    }
+
+    pub fn request(&mut self, method: &str, params: Value) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(message(Some(id), method, params));
+        id
+    }
+
+    // Sends didOpen the first time a document is seen and didChange whenever it has changed since.
+    // Called on every pass of the editor loop, so the text is only gathered up when there's something to send
+    pub fn sync(&mut self, doc: &TextDocument, language_id: &str) {
+        let uri = path_to_uri(&doc.filename);
+
+        match self.documents.get_mut(&uri) {
+            None => {
+                self.notify(
+                    "textDocument/didOpen",
+                    json!({
+                        "textDocument": {
+                            "uri": uri,
+                            "languageId": language_id,
+                            "version": 1,
+                            "text": doc.contents()
+                        }
+                    }),
+                );
+                self.documents.insert(uri, (1, doc.version));
+            }
+            Some((version, sent_at)) => {
+                if *sent_at != doc.version {
+                    *version += 1;
+                    *sent_at = doc.version;
+                    let version = *version;
+
+                    self.notify(
+                        "textDocument/didChange",
+                        json!({
+                            "textDocument": { "uri": uri, "version": version },
+                            "contentChanges": [{ "text": doc.contents() }]
+                        }),
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn did_save(&mut self, doc: &TextDocument, language_id: &str) {
+        self.sync(doc, language_id);
+
+        self.notify(
+            "textDocument/didSave",
+            json!({
+                "textDocument": { "uri": path_to_uri(&doc.filename) },
+                "text": doc.contents()
+            }),
+        );
+    }
+
+    // Handles everything the server has sent so far, returns what the editor needs to know about
+    pub fn poll(&mut self) -> Vec<LspEvent> {
+        let mut events = std::mem::take(&mut self.events);
+
+        while let Ok(message) = self.incoming.try_recv() {
+            if let Some(event) = self.handle(message) {
+                events.push(event);
+            }
+        }
+
+        events
+    }
+
+    fn handle(&mut self, message: Value) -> Option<LspEvent> {
+        let id = message.get("id").cloned();
+        let method = message.get("method").and_then(Value::as_str);
+
+        match (id, method) {
+            // Requests from the server, answer the ones we understand enough to not leave it hanging
+            (Some(id), Some(method)) => {
+                let result = match method {
+                    "workspace/configuration" => {
+                        let items = message["params"]["items"].as_array().map_or(0, Vec::len);
+                        Value::Array(vec![Value::Null; items])
+                    }
+                    "window/workDoneProgress/create"
+                    | "client/registerCapability"
+                    | "client/unregisterCapability" => Value::Null,
+                    "workspace/applyEdit" => {
+                        // Answered by the editor once it has tried making the edit
+                        let reply = Reply {
+                            id,
+                            outgoing: self.outgoing.clone(),
+                        };
+                        return Some(LspEvent::ApplyEdit(message["params"]["edit"].clone(), reply));
+                    }
+                    _ => {
+                        let _ = self.outgoing.send(json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "error": { "code": -32601, "message": "Method not found" }
+                        }));
+                        return None;
+                    }
+                };
+
+                let _ = self
+                    .outgoing
+                    .send(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+                None
+            }
+            (Some(id), None) => {
+                if id.as_i64() == Some(self.initialize_id) && !self.initialized {
+                    if let Some(error) = message.get("error") {
+                        return Some(LspEvent::Message(format!(
+                            "Language server failed to initialize: {}",
+                            error["message"].as_str().unwrap_or("unknown error")
+                        )));
+                    }
+
+                    self.initialized = true;
+
+                    let _ = self
+                        .outgoing
+                        .send(json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }));
+                    for message in self.queued.drain(..) {
+                        let _ = self.outgoing.send(message);
+                    }
+                }
+                None
+            }
+            (None, Some("window/showMessage")) => message["params"]["message"]
+                .as_str()
+                .map(|message| LspEvent::Message(message.to_owned())),
+            (None, Some("textDocument/publishDiagnostics")) => {
+                let params = &message["params"];
+                Some(LspEvent::Diagnostics(
+                    params["uri"].as_str()?.to_owned(),
+                    params["diagnostics"].as_array().cloned().unwrap_or_default(),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    // Blocks until the response to a request arrives, handling anything else the server sends meanwhile
+    pub fn wait_for(&mut self, id: i64, timeout: Duration) -> Option<Value> {
+        let deadline = Instant::now() + timeout;
+
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let message = self.incoming.recv_timeout(remaining).ok()?;
+
+            if message.get("method").is_none() && message.get("id").and_then(Value::as_i64) == Some(id) {
+                return message.get("result").cloned();
+            }
+
+            if let Some(event) = self.handle(message) {
+                self.events.push(event);
+            }
+        }
+
+        None
+    }
+
+    pub fn shutdown(&mut self) {
+        if self.initialized {
+            let id = self.request("shutdown", Value::Null);
+            self.wait_for(id, SHUTDOWN_TIMEOUT);
+
+            self.notify("exit", Value::Null);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        if let Ok(None) = self.process.try_wait() {
+            let _ = self.process.kill();
+        }
+        let _ = self.process.wait();
+    }
+}
+
+impl Drop for LspClient {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl LanguageServers {
+    // The running server for a file type, starting it if this is the first time it's needed
+    pub fn client(&mut self, config: &FileConfig) -> Result<Option<&mut LspClient>, String> {
+        let server = match &config.language_server {
+            Some(server) if !server.command.is_empty() => server,
+            _ => return Ok(None),
+        };
+
+        let key = server.key();
+        if self.failed.contains(&key) {
+            return Ok(None);
+        }
+
+        if !self.clients.contains_key(&key) {
+            match LspClient::start(server) {
+                Ok(client) => {
+                    self.clients.insert(key.clone(), client);
+                }
+                Err(e) => {
+                    self.failed.push(key);
+                    return Err(format!(
+                        "Couldn't start language server {}: {}",
+                        server.command, e
+                    ));
+                }
+            }
+        }
+
+        Ok(self.clients.get_mut(&key))
+    }
+
+    pub fn sync(&mut self, doc: &TextDocument, config: &FileConfig) -> Result<(), String> {
+        let language_id = language_id(doc, config);
+        if let Some(client) = self.client(config)? {
+            client.sync(doc, &language_id);
+        }
+        Ok(())
+    }
+
+    pub fn did_save(&mut self, doc: &TextDocument, config: &FileConfig) -> Result<(), String> {
+        let language_id = language_id(doc, config);
+        if let Some(client) = self.client(config)? {
+            client.did_save(doc, &language_id);
+        }
+        Ok(())
+    }
+
+    // Sends a request about a document and waits for the answer, None if there's no server or it didn't answer in time
+    pub fn request(
+        &mut self,
+        doc: &TextDocument,
+        config: &FileConfig,
+        method: &str,
+        params: Value,
+    ) -> Result<Option<Value>, String> {
+        let language_id = language_id(doc, config);
+        match self.client(config)? {
+            Some(client) => {
+                client.sync(doc, &language_id);
+                let id = client.request(method, params);
+                Ok(client.wait_for(id, REQUEST_TIMEOUT))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn poll(&mut self) -> Vec<LspEvent> {
+        self.clients.values_mut().flat_map(LspClient::poll).collect()
+    }
+}
+
+impl Reply {
+    pub fn send(self, result: Value) {
+        let _ = self
+            .outgoing
+            .send(json!({ "jsonrpc": "2.0", "id": self.id, "result": result }));
+    }
+}
+
+impl LanguageServer {
+    fn key(&self) -> String {
+        format!("{} {}", self.command, self.args.join(" "))
+    }
+}
+
+fn language_id(doc: &TextDocument, config: &FileConfig) -> String {
+    match &config.language_server {
+        Some(server) if !server.language_id.is_empty() => server.language_id.clone(),
+        _ => doc.extension(),
+    }
+}
+
+// The params most requests about a spot in a document take
+pub fn text_document_position(doc: &TextDocument, row: usize, col: usize) -> Value {
+    json!({
+        "textDocument": { "uri": path_to_uri(&doc.filename) },
+        "position": position(doc, row, col)
+    })
+}
+
+pub fn position(doc: &TextDocument, row: usize, col: usize) -> Value {
+    json!({
+        "line": row,
+        "character": doc.rows.get(row).map_or(0, |line| line.char_to_utf16(col))
+    })
+}
+
+// Turns one of the editor's diagnostics back into what the server sent, for the context of code action requests
+pub fn from_diagnostic(diagnostic: &Diagnostic, doc: &TextDocument) -> Value {
+    json!({
+        "range": {
+            "start": position(doc, diagnostic.start_row, diagnostic.start_col),
+            "end": position(doc, diagnostic.end_row, diagnostic.end_col)
+        },
+        "severity": diagnostic.severity as u64 + 1,
+        "message": diagnostic.message
+    })
+}
+
+// Params for textDocument/formatting, indentation is taken from tab_str
+pub fn formatting_params(doc: &TextDocument, config: &FileConfig) -> Value {
+    json!({
+        "textDocument": { "uri": path_to_uri(&doc.filename) },
+        "options": {
+            "tabSize": if config.tab_str.contains('\t') { 4 } else { std::cmp::max(config.tab_str.len(), 1) },
+            "insertSpaces": !config.tab_str.contains('\t')
+        }
+    })
+}
+
+// Converts lsp diagnostics to the editor's own, positions are only valid for the text the server last saw
+pub fn to_diagnostics(diagnostics: &[Value], doc: &TextDocument) -> Vec<Diagnostic> {
+    diagnostics
+        .iter()
+        .filter_map(|diagnostic| {
+            let (start_row, start_col) = to_position(&diagnostic["range"]["start"], doc)?;
+            let (end_row, end_col) = to_position(&diagnostic["range"]["end"], doc)?;
+
+            Some(Diagnostic {
+                start_row,
+                start_col,
+                end_row,
+                end_col,
+                severity: Severity::from_lsp(diagnostic["severity"].as_u64().unwrap_or(1)),
+                message: diagnostic["message"].as_str().unwrap_or_default().to_owned(),
+            })
+        })
+        .collect()
+}
+
+// An lsp position as (row, char index)
+pub fn to_position(position: &Value, doc: &TextDocument) -> Option<(usize, usize)> {
+    let row = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+
+    match doc.rows.get(row) {
+        Some(line) => Some((row, line.utf16_to_char(character))),
+        None => Some((row, 0)),
+    }
+}
+
+// Null params are left out entirely, some servers reject "params": null
+fn message(id: Option<i64>, method: &str, params: Value) -> Value {
+    let mut message = json!({ "jsonrpc": "2.0", "method": method });
+    if let Some(id) = id {
+        message["id"] = json!(id);
+    }
+    if !params.is_null() {
+        message["params"] = params;
+    }
+    message
+}
+
+// Reads one Content-Length framed message, None once the stream is closed
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    loop {
+        let mut length = None;
+
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).ok()? == 0 {
+                return None;
+            }
+
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+
+        if let Some(length) = length {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).ok()?;
+
+            // Skip anything that isn't valid json rather than dropping the connection
+            if let Ok(message) = serde_json::from_slice(&body) {
+                return Some(message);
+            }
+        }
+    }
+}
+
+pub fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut chars = path.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex: Vec<u8> = chars.by_ref().take(2).collect();
+            match std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                Some(byte) => bytes.push(byte),
+                None => {
+                    bytes.push(b'%');
+                    bytes.extend(hex);
+                }
+            }
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    let path = String::from_utf8_lossy(&bytes).into_owned();
+
+    // file:///C:/... on windows
+    if path.len() > 2 && path.as_bytes()[2] == b':' {
+        path[1..].to_owned()
+    } else {
+        path
+    }
+}
+
+pub fn path_to_uri(path: &str) -> String {
+    let path = std::fs::canonicalize(path)
+        .or_else(|_| std::env::current_dir().map(|dir| dir.join(path)))
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|_| path.to_owned());
+
+    // Windows paths come back as \\?\C:\... from canonicalize
+    let path = path.trim_start_matches(r"\\?\").replace('\\', "/");
+
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::history::EditDiff;
+
+    // examples/lsp_stub.rs, which cargo test builds along with the tests unless it's only running some of them
+    fn stub() -> LanguageServer {
+        let mut path = std::env::current_exe().unwrap();
+        path.pop();
+        if path.ends_with("deps") {
+            path.pop();
+        }
+        path.push("examples");
+        path.push(format!("lsp_stub{}", std::env::consts::EXE_SUFFIX));
+
+        let built = std::fs::metadata(&path).and_then(|built| built.modified()).ok();
+        let source = std::fs::metadata(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/lsp_stub.rs"))
+            .and_then(|source| source.modified())
+            .ok();
+        assert!(
+            built.is_some() && built >= source,
+            "{} is missing or out of date, build it with cargo build --example lsp_stub",
+            path.display()
+        );
+
+        LanguageServer {
+            command: path.to_string_lossy().into_owned(),
+            args: Vec::new(),
+            language_id: String::new(),
+        }
+    }
+
+    fn wait_for_diagnostics(servers: &mut LanguageServers, doc: &TextDocument) -> Vec<Diagnostic> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            for event in servers.poll() {
+                if let LspEvent::Diagnostics(uri, diagnostics) = event {
+                    if uri == path_to_uri(&doc.filename) {
+                        return to_diagnostics(&diagnostics, doc);
+                    }
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("lsp_stub never sent diagnostics");
+    }
+
+    #[test]
+    fn talks_to_the_stub_server() {
+        let log = std::env::temp_dir().join(format!("kelp_lsp_stub_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        std::env::set_var("LSP_STUB_LOG", &log);
+
+        let config = FileConfig {
+            language_server: Some(stub()),
+            ..FileConfig::default()
+        };
+        let mut doc = TextDocument::new("lsp_stub_test.rs".to_owned());
+        doc.rows = vec![Row::from_string("fn main() {} // TODO".to_owned())];
+
+        // didOpen waits for the answer to initialize, which poll picks up
+        let mut servers = LanguageServers::default();
+        servers.sync(&doc, &config).unwrap();
+
+        let diagnostics = wait_for_diagnostics(&mut servers, &doc);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].start_row, diagnostics[0].start_col), (0, 16));
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+
+        // Nothing is sent for a document that hasn't changed
+        servers.sync(&doc, &config).unwrap();
+
+        let (diffs, _) = doc.replace_range((0, 0), (0, 0), "// TODO first\n");
+        doc.add_diff(EditDiff::Compound(diffs));
+        servers.sync(&doc, &config).unwrap();
+
+        let diagnostics = wait_for_diagnostics(&mut servers, &doc);
+        let rows: Vec<usize> = diagnostics.iter().map(|diagnostic| diagnostic.start_row).collect();
+        assert_eq!(rows, [0, 1]);
+
+        let completion = servers
+            .request(&doc, &config, "textDocument/completion", text_document_position(&doc, 1, 3))
+            .unwrap()
+            .unwrap();
+        let labels: Vec<&str> = completion["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|item| item["label"].as_str())
+            .collect();
+        assert_eq!(labels, ["TODO", "first", "fn", "main"]);
+
+        // Shuts the stub down, after which everything it got is in the log
+        drop(servers);
+        let log = std::fs::read_to_string(&log).unwrap();
+        let methods: Vec<String> = log
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter_map(|message| message["method"].as_str().map(str::to_owned))
+            .collect();
+        assert_eq!(
+            methods,
+            [
+                "initialize",
+                "initialized",
+                "textDocument/didOpen",
+                "textDocument/didChange",
+                "textDocument/completion",
+                "shutdown",
+                "exit"
+            ]
+        );
+    }
+}
@@ -4,18 +4,33 @@ TODO:
 2. Collapse whitespace into a single Plain enum variant. - Done
 3. Add per file type config - Done
 4. Configurable syntax highlighting - In progress
-5. Rebindable keys - TODO
+5. Rebindable keys - Done
 6. Unicode support still patchy - Fixed for the time being
 7. Move code out of mod.rs very cringe - Done
 8. Plugins prolly lua based... cause dynamic cdylib loading very sketch
 */
 
+pub mod action;
+mod completion;
 mod editor;
-mod highlight;
-mod history;
-mod plugin;
+mod export;
+mod format;
+pub mod highlight;
+pub mod history;
+mod hover;
+mod kill_ring;
+mod lsp;
+mod outline;
+pub mod plugin;
 pub mod prelude;
+mod refactor;
+mod script;
+mod tags;
+mod tasks;
 mod utils;
+mod vim;
+mod wasm;
+mod workspace;
 
 use crate::editor::history::EditDiff;
 use unescape::unescape;
@@ -0,0 +1,320 @@
+use crate::editor::highlight::Token;
+use crate::editor::lsp;
+use crate::editor::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Keywords that start a definition when followed by a name, in any language that has them as keywords
+const DEFINITION_KEYWORDS: &[&str] = &[
+    "fn",
+    "struct",
+    "enum",
+    "union",
+    "trait",
+    "impl",
+    "mod",
+    "type",
+    "macro",
+    "class",
+    "interface",
+    "namespace",
+    "module",
+    "def",
+    "func",
+    "function",
+];
+
+// A definition in a document
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: String,
+    pub container: Option<String>, // The symbol this one is defined inside of
+    pub row: usize,
+    pub col: usize,
+}
+
+impl Symbol {
+    pub fn display_name(&self) -> String {
+        match &self.container {
+            Some(container) => format!("{} {}  ({})", self.kind, self.name, container),
+            None => format!("{} {}", self.kind, self.name),
+        }
+    }
+}
+
+// Finds definitions by looking for a definition keyword followed by a name in freshly tokenized rows.
+// Symbols are nested in whatever symbol above them is indented less
+pub fn symbols_from_tokens(
+    rows: &mut Vec<Row>,
+    config: &FileConfig,
+    languages: &HashMap<String, FileConfig>,
+) -> Vec<Symbol> {
+    let len = rows.len();
+    Token::tokenize(
+        rows,
+        HighlightingInfo {
+            selection: None,
+            matching_brackets: None,
+            languages: Some(languages),
+            diagnostics: None,
+            plugin_tokens: None,
+            decorations: None,
+        },
+        0,
+        len,
+        config,
+    );
+
+    let mut symbols = Vec::new();
+    let mut parents: Vec<(usize, String)> = Vec::new(); // Indentation and name of the enclosing symbols
+
+    for (row_index, row) in rows.iter().enumerate() {
+        let indent = row.buf.len() - row.buf.trim_start().len();
+
+        let mut tokens = row.tokens.iter().filter(|token| !matches!(token, Token::Plain(_)));
+        while let Some(token) = tokens.next() {
+            let keyword = match token {
+                Token::Keyword(range) if DEFINITION_KEYWORDS.contains(&&row.buf[range.clone()]) => range,
+                _ => continue,
+            };
+
+            let name = if &row.buf[keyword.clone()] == "impl" {
+                impl_name(&row.buf[keyword.end..]).map(|name| (name, keyword.end))
+            } else {
+                match tokens.next() {
+                    Some(Token::Identifier(range)) | Some(Token::TypeName(range)) | Some(Token::FnCall(range)) => {
+                        Some((row.buf[range.clone()].to_owned(), range.start))
+                    }
+                    _ => None,
+                }
+            };
+
+            if let Some((name, start)) = name {
+                while parents.last().is_some_and(|(parent_indent, _)| *parent_indent >= indent) {
+                    parents.pop();
+                }
+
+                symbols.push(Symbol {
+                    name: name.clone(),
+                    kind: row.buf[keyword.clone()].to_owned(),
+                    container: parents.last().map(|(_, parent)| parent.clone()),
+                    row: row_index,
+                    col: row.byte_to_char(start),
+                });
+                parents.push((indent, name));
+            }
+            break;
+        }
+    }
+
+    symbols
+}
+
+// "impl<T> Display for Row<T> where ..." is named by everything up to the body
+fn impl_name(rest: &str) -> Option<String> {
+    let mut rest = rest.trim_start();
+
+    // Skip the generic parameters right after the keyword
+    if rest.starts_with('<') {
+        let mut depth = 0;
+        let end = rest.char_indices().find_map(|(i, c)| {
+            match c {
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                _ => {}
+            }
+            Some(i + 1).filter(|_| depth == 0)
+        })?;
+        rest = &rest[end..];
+    }
+
+    let end = [rest.find('{'), rest.find(" where")].iter().flatten().min().copied();
+    let name = rest[..end.unwrap_or(rest.len())].trim();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+// Reads a textDocument/documentSymbol response, which is either a tree of DocumentSymbols or a flat list of
+// SymbolInformation
+pub fn symbols_from_lsp(response: &Value, doc: &TextDocument) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+
+    if let Some(items) = response.as_array() {
+        for item in items {
+            if item["location"].is_object() {
+                if let Some(symbol) = symbol_information(item, doc) {
+                    symbols.push(symbol);
+                }
+            } else {
+                document_symbol(item, None, doc, &mut symbols);
+            }
+        }
+    }
+
+    symbols.sort_by_key(|symbol| (symbol.row, symbol.col));
+    symbols
+}
+
+fn document_symbol(item: &Value, container: Option<&str>, doc: &TextDocument, symbols: &mut Vec<Symbol>) {
+    let name = match item["name"].as_str() {
+        Some(name) => name,
+        None => return,
+    };
+
+    let range = if item["selectionRange"].is_object() {
+        &item["selectionRange"]
+    } else {
+        &item["range"]
+    };
+
+    if let Some((row, col)) = lsp::to_position(&range["start"], doc) {
+        symbols.push(Symbol {
+            name: name.to_owned(),
+            kind: kind_name(&item["kind"]).to_owned(),
+            container: container.map(|container| container.to_owned()),
+            row,
+            col,
+        });
+    }
+
+    if let Some(children) = item["children"].as_array() {
+        for child in children {
+            document_symbol(child, Some(name), doc, symbols);
+        }
+    }
+}
+
+fn symbol_information(item: &Value, doc: &TextDocument) -> Option<Symbol> {
+    let (row, col) = lsp::to_position(&item["location"]["range"]["start"], doc)?;
+
+    Some(Symbol {
+        name: item["name"].as_str()?.to_owned(),
+        kind: kind_name(&item["kind"]).to_owned(),
+        container: item["containerName"]
+            .as_str()
+            .filter(|container| !container.is_empty())
+            .map(|container| container.to_owned()),
+        row,
+        col,
+    })
+}
+
+// The lsp SymbolKind numbers
+pub fn kind_name(kind: &Value) -> &'static str {
+    match kind.as_u64().unwrap_or(0) {
+        1 => "file",
+        2 => "module",
+        3 => "namespace",
+        4 => "package",
+        5 => "class",
+        6 => "method",
+        7 => "property",
+        8 => "field",
+        9 => "constructor",
+        10 => "enum",
+        11 => "interface",
+        12 => "function",
+        13 => "variable",
+        14 => "constant",
+        15 => "string",
+        16 => "number",
+        17 => "boolean",
+        18 => "array",
+        19 => "object",
+        20 => "key",
+        21 => "null",
+        22 => "variant",
+        23 => "struct",
+        24 => "event",
+        25 => "operator",
+        26 => "type parameter",
+        _ => "symbol",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::utils::fuzzy_matches;
+
+    fn rust() -> FileConfig {
+        let keywords = ["fn", "struct", "enum", "impl", "mod", "trait", "for", "let", "pub", "where"];
+        FileConfig {
+            keywords: keywords.iter().map(|keyword| keyword.to_string()).collect(),
+            ..FileConfig::default()
+        }
+    }
+
+    fn symbols(lines: &[&str]) -> Vec<Symbol> {
+        let mut rows: Vec<Row> = lines.iter().map(|line| Row::from_string(line.to_string())).collect();
+        symbols_from_tokens(&mut rows, &rust(), &HashMap::new())
+    }
+
+    const BUFFER: &[&str] = &[
+        "// fn commented_out() {}",
+        "pub struct Buffer {",
+        "    rows: Vec<String>,",
+        "}",
+        "",
+        "impl<T: Clone> Display for Wrapper<T> where T: Debug {",
+        "    fn fmt(&self) {}",
+        "}",
+        "",
+        "impl Buffer {",
+        "    pub fn new() -> Self {",
+        "        let fn_name = \"fn not_this\";",
+        "    }",
+        "",
+        "    fn new_row(&mut self) {}",
+        "}",
+        "",
+        "mod tests {",
+        "    fn renew() {}",
+        "}",
+    ];
+
+    #[test]
+    fn definitions_with_their_containers() {
+        let found: Vec<(String, usize, usize)> = symbols(BUFFER)
+            .iter()
+            .map(|symbol| (symbol.display_name(), symbol.row, symbol.col))
+            .collect();
+
+        assert_eq!(
+            found,
+            [
+                ("struct Buffer".to_owned(), 1, 11),
+                ("impl Display for Wrapper<T>".to_owned(), 5, 4),
+                ("fn fmt  (Display for Wrapper<T>)".to_owned(), 6, 7),
+                ("impl Buffer".to_owned(), 9, 4),
+                ("fn new  (Buffer)".to_owned(), 10, 11),
+                ("fn new_row  (Buffer)".to_owned(), 14, 7),
+                ("mod tests".to_owned(), 17, 4),
+                ("fn renew  (tests)".to_owned(), 18, 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn picker_puts_the_best_match_first() {
+        let symbols = symbols(BUFFER);
+        let items: Vec<String> = symbols.iter().map(|symbol| symbol.display_name()).collect();
+        let names = |query| -> Vec<&str> {
+            fuzzy_matches(query, &items).into_iter().map(|i| symbols[i].name.as_str()).collect()
+        };
+
+        // Everything in buffer order until something is typed
+        assert_eq!(names("").len(), symbols.len());
+        assert_eq!(names("")[0], "Buffer");
+
+        assert_eq!(names("fn new"), ["new", "new_row", "renew"]);
+        assert_eq!(names("newrow"), ["new_row"]);
+        assert_eq!(names("imbuf"), ["Buffer"]);
+        assert!(names("xyz").is_empty());
+    }
+}
@@ -1,23 +1,646 @@
-use crate::editor::prelude::Editor;
+use crate::editor::history::{EditDiff, UndoRedo};
+use crate::editor::highlight::{Decoration, Token};
+use crate::editor::prelude::{Editor, KelpKeyEvent, TextDocument};
 use crossterm::event::Event;
+use libloading::Library;
 use std::any::Any;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
 
+// Bumped whenever PluginDeclaration changes shape, the rest of the ABI is covered by SOURCE_HASH
+pub const PLUGIN_ABI_VERSION: u32 = 6;
+
+// Rust has no stable ABI, so a plugin has to be built by the same compiler against the same kelp. Any change to
+// kelp's sources changes the hash (see build.rs), so a new field in Editor or EditorConfig can't go unnoticed
+pub const RUSTC_VERSION: &str = concat!(env!("KELP_RUSTC_VERSION"), "\0");
+pub const KELP_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+pub const SOURCE_HASH: &str = concat!(env!("KELP_SOURCE_HASH"), "\0");
+
+// Documents are passed as their index in editor.docs, which stays valid until the hook returns
 pub trait Plugin: Any + Send + Sync {
     fn on_load(&self, _editor: &mut Editor) {}
     fn on_event(&self, _editor: &mut Editor, _event: &Event) {}
+
+    // Runs before anything is written, so changes made here (see TextDocument::replace_contents) get saved.
+    // An error stops the save and is shown in the status bar
+    fn before_save(&self, _editor: &mut Editor, _doc: usize) -> Result<(), String> {
+        Ok(())
+    }
+    fn after_save(&self, _editor: &mut Editor, _doc: usize) {}
+
+    fn on_open(&self, _editor: &mut Editor, _doc: usize) {}
+    fn on_close(&self, _editor: &mut Editor, _doc: usize) {} // Before it's removed
+
+    // Undo when the diff was reversed by an undo, Redo when it was applied as written
+    fn on_change(&self, _editor: &mut Editor, _doc: usize, _diff: &EditDiff, _direction: UndoRedo) {}
+    fn on_cursor_moved(&self, _editor: &mut Editor, _doc: usize, _row: usize, _col: usize) {}
+
+    // None is the start screen
+    fn on_tab_switched(&self, _editor: &mut Editor, _from: Option<usize>, _to: Option<usize>) {}
+
+    // Asked for once at startup. Actions run through run_action
+    fn actions(&self) -> Vec<PluginAction> {
+        Vec::new()
+    }
+    fn run_action(&self, _editor: &mut Editor, _name: &str, _args: &[String]) {}
+
+    // Shown after the document count in the status bar, asked for on every redraw so keep it cheap
+    fn status_segments(&self, _editor: &Editor) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Highlights rows from..from + count instead of kelp, one list of tokens per row. The whole document is there
+    // so state can carry over from the rows above. None leaves it to kelp or the next plugin
+    fn tokenize(&self, _doc: &TextDocument, _from: usize, _count: usize) -> Option<Vec<Vec<Token>>> {
+        None
+    }
+
+    // Drawn over the highlighting of rows from..from + count, every plugin's are shown
+    fn decorations(&self, _doc: &TextDocument, _from: usize, _count: usize) -> Vec<Decoration> {
+        Vec::new()
+    }
+}
+
+// A named action that can be bound in the keybinds config or typed into the command prompt.
+// Names of kelp's own actions, like copy, always mean kelp's own action
+pub struct PluginAction {
+    pub name: String,
+    pub key: Option<KelpKeyEvent>, // Used unless config.json binds the action to something already
 }
 
+// Exported by every plugin as _plugin_declaration, only plain C types so it can be checked before trusting anything else
+#[repr(C)]
+pub struct PluginDeclaration {
+    pub abi_version: u32,
+    pub rustc_version: *const c_char,
+    pub kelp_version: *const c_char,
+    pub source_hash: *const c_char,
+}
+
+// The pointers only ever point at string literals
+unsafe impl Sync for PluginDeclaration {}
+
 #[macro_export]
 macro_rules! declare_plugin {
     ($plugin_type:ty, $constructor:path) => {
         #[no_mangle]
-        pub extern "C" fn _plugin_create() -> *mut $crate::Plugin {
+        pub static _plugin_declaration: $crate::editor::plugin::PluginDeclaration =
+            $crate::editor::plugin::PluginDeclaration {
+                abi_version: $crate::editor::plugin::PLUGIN_ABI_VERSION,
+                rustc_version: $crate::editor::plugin::RUSTC_VERSION.as_ptr() as *const std::os::raw::c_char,
+                kelp_version: $crate::editor::plugin::KELP_VERSION.as_ptr() as *const std::os::raw::c_char,
+                source_hash: $crate::editor::plugin::SOURCE_HASH.as_ptr() as *const std::os::raw::c_char,
+            };
+
+        #[no_mangle]
+        #[allow(improper_ctypes_definitions)] // Fine as kelp only loads plugins built by the same compiler
+        pub extern "C" fn _plugin_create() -> *mut dyn $crate::editor::plugin::Plugin {
             // make sure the constructor is the correct type.
             let constructor: fn() -> $plugin_type = $constructor;
 
             let object = constructor();
-            let boxed: Box<$crate::Plugin> = Box::new(object);
+            let boxed: Box<dyn $crate::editor::plugin::Plugin> = Box::new(object);
             Box::into_raw(boxed)
         }
     };
 }
+
+// A plugin along with the library its code lives in, which has to outlive it
+pub struct LoadedPlugin {
+    pub name: String,
+    pub plugin: Box<dyn Plugin>,
+
+    _library: Option<Library>, // Dropped last as fields drop in order, None for WebAssembly plugins
+}
+
+impl LoadedPlugin {
+    // A plugin whose code kelp runs itself rather than loading from a library
+    pub fn sandboxed(name: String, plugin: Box<dyn Plugin>) -> Self {
+        LoadedPlugin {
+            name,
+            plugin,
+            _library: None,
+        }
+    }
+}
+
+// Loads every plugin library in dir, along with an error for each file that couldn't be loaded
+pub fn load_plugins(dir: &Path) -> (Vec<LoadedPlugin>, Vec<String>) {
+    let mut plugins = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut paths: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == std::env::consts::DLL_EXTENSION))
+            .collect(),
+        Err(_) => return (plugins, errors), // No plugins directory means no plugins
+    };
+    paths.sort();
+
+    for path in paths {
+        match load(&path) {
+            Ok(plugin) => plugins.push(plugin),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    (plugins, errors)
+}
+
+fn load(path: &Path) -> Result<LoadedPlugin, String> {
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let fail = |e: String| format!("Couldn't load plugin {}: {}", name, e);
+
+    unsafe {
+        let library = Library::new(path).map_err(|e| fail(e.to_string()))?;
+
+        let declaration = library
+            .get::<*const PluginDeclaration>(b"_plugin_declaration\0")
+            .map_err(|_| fail("not a kelp plugin".to_owned()))?;
+        check_declaration(&**declaration).map_err(fail)?;
+
+        #[allow(improper_ctypes_definitions)]
+        type Create = unsafe extern "C" fn() -> *mut dyn Plugin;
+        let create = library
+            .get::<Create>(b"_plugin_create\0")
+            .map_err(|_| fail("missing _plugin_create".to_owned()))?;
+        let plugin = Box::from_raw(create());
+
+        Ok(LoadedPlugin {
+            name,
+            plugin,
+            _library: Some(library),
+        })
+    }
+}
+
+// The ABI version goes first as the fields after it are only known to be there once it matches
+unsafe fn check_declaration(declaration: &PluginDeclaration) -> Result<(), String> {
+    if declaration.abi_version != PLUGIN_ABI_VERSION {
+        return Err(format!(
+            "built for plugin ABI {} but kelp uses {}",
+            declaration.abi_version, PLUGIN_ABI_VERSION
+        ));
+    }
+
+    let kelp_version = CStr::from_ptr(declaration.kelp_version).to_string_lossy();
+    if kelp_version != KELP_VERSION.trim_end_matches('\0') {
+        return Err(format!(
+            "built against kelp {} but this is kelp {}",
+            kelp_version,
+            KELP_VERSION.trim_end_matches('\0')
+        ));
+    }
+
+    let rustc_version = CStr::from_ptr(declaration.rustc_version).to_string_lossy();
+    if rustc_version != RUSTC_VERSION.trim_end_matches('\0') {
+        return Err(format!(
+            "built with {} but kelp was built with {}",
+            rustc_version,
+            RUSTC_VERSION.trim_end_matches('\0')
+        ));
+    }
+
+    let source_hash = CStr::from_ptr(declaration.source_hash).to_string_lossy();
+    if source_hash != SOURCE_HASH.trim_end_matches('\0') {
+        return Err("built against a different build of kelp, rebuild it against this one".to_owned());
+    }
+
+    Ok(())
+}
+
+impl Editor {
+    // Each plugin gets the editor to itself while it runs, so they are taken out for the duration
+    pub fn run_plugins(&mut self, mut hook: impl FnMut(&dyn Plugin, &mut Editor)) {
+        let plugins = std::mem::take(&mut self.plugins);
+        for loaded in &plugins {
+            hook(&*loaded.plugin, self);
+        }
+
+        // Keep any plugins loaded while these ran
+        let added = std::mem::replace(&mut self.plugins, plugins);
+        self.plugins.extend(added);
+    }
+
+    // Fills the action registry, first plugin to claim a name gets it
+    pub fn register_plugin_actions(&mut self) {
+        for (index, loaded) in self.plugins.iter().enumerate() {
+            for action in loaded.plugin.actions() {
+                if self.actions.contains_key(&action.name) {
+                    continue;
+                }
+
+                if let Some(key) = action.key {
+                    self.config.bind(&action.name, key);
+                }
+                self.actions.insert(action.name, index);
+            }
+        }
+    }
+
+    // Runs a registered action, false if no plugin has one by that name
+    pub fn run_action(&mut self, name: &str, args: &[String]) -> bool {
+        let owner = match self.actions.get(name) {
+            Some(owner) => *owner,
+            None => return false,
+        };
+
+        let mut index = 0;
+        self.run_plugins(|plugin, editor| {
+            if index == owner {
+                plugin.run_action(editor, name, args);
+            }
+            index += 1;
+        });
+        true
+    }
+
+    pub fn status_segments(&self) -> Vec<String> {
+        self.plugins
+            .iter()
+            .flat_map(|loaded| loaded.plugin.status_segments(self))
+            .collect()
+    }
+
+    // Tokens from the first plugin that highlights the document, along with every plugin's decorations
+    pub fn plugin_highlighting(&self, doc: usize, from: usize, count: usize) -> (Option<Vec<Vec<Token>>>, Vec<Decoration>) {
+        let doc = self.docs[doc].as_text_doc();
+
+        let tokens = self
+            .plugins
+            .iter()
+            .find_map(|loaded| loaded.plugin.tokenize(doc, from, count));
+        let decorations = self
+            .plugins
+            .iter()
+            .flat_map(|loaded| loaded.plugin.decorations(doc, from, count))
+            .collect();
+
+        (tokens, decorations)
+    }
+
+    // Tells plugins about the edits, cursor moves and tab switches since the last time this ran
+    pub fn notify_plugins(&mut self) {
+        // A hook can close documents, so the length is checked every time round
+        let mut doc_index = 0;
+        while doc_index < self.docs.len() {
+            if self.docs[doc_index].is_text_doc() {
+                let changes = std::mem::take(&mut self.docs[doc_index].as_mut_text_doc().changes);
+                for (diff, direction) in &changes {
+                    if doc_index < self.docs.len() {
+                        self.run_plugins(|plugin, editor| plugin.on_change(editor, doc_index, diff, *direction));
+                    }
+                }
+            }
+            doc_index += 1;
+        }
+
+        if self.open_doc != self.plugin_open_doc {
+            let (from, to) = (self.plugin_open_doc, self.open_doc);
+            self.plugin_open_doc = to;
+            self.run_plugins(|plugin, editor| plugin.on_tab_switched(editor, from, to));
+        }
+
+        let doc_index = match self.open_doc {
+            Some(doc_index) if doc_index < self.docs.len() && self.docs[doc_index].is_text_doc() => doc_index,
+            _ => return,
+        };
+
+        let doc = self.docs[doc_index].as_text_doc();
+        let cursor = Some((doc.cursor_row, doc.cursor_col));
+        if cursor != self.plugin_cursor {
+            self.plugin_cursor = cursor;
+            let (row, col) = (doc.cursor_row, doc.cursor_col);
+            self.run_plugins(|plugin, editor| plugin.on_cursor_moved(editor, doc_index, row, col));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::action::KeyPress;
+    use crate::editor::prelude::{Document, FileConfig, HighlightingInfo, KelpKeyModifiers, Row};
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use std::sync::{Arc, Mutex};
+
+    // examples/hello_plugin.rs, which cargo test builds along with the tests unless it's only running some of them
+    fn hello_plugin() -> Vec<u8> {
+        let mut path = std::env::current_exe().unwrap();
+        path.pop();
+        if path.ends_with("deps") {
+            path.pop();
+        }
+        path.push("examples");
+        path.push(format!(
+            "{}hello_plugin.{}",
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_EXTENSION
+        ));
+
+        std::fs::read(&path)
+            .unwrap_or_else(|_| panic!("{} is missing, build it with cargo build --example hello_plugin", path.display()))
+    }
+
+    // Each test gets its own copy as a library can't be loaded again from the same path once it's been changed
+    fn load_copy(name: &str, library: &[u8]) -> Result<LoadedPlugin, String> {
+        let dir = std::env::temp_dir().join(format!("kelp-plugin-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(format!("{}.{}", name, std::env::consts::DLL_EXTENSION));
+        std::fs::write(&path, library).unwrap();
+        let loaded = load(&path);
+        let _ = std::fs::remove_file(&path);
+        loaded
+    }
+
+    fn replace(haystack: &mut [u8], needle: &[u8], with: &[u8]) {
+        let at = haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .expect("the declaration is in the library");
+        haystack[at..at + with.len()].copy_from_slice(with);
+    }
+
+    #[test]
+    fn loads_a_matching_plugin() {
+        let loaded = load_copy("matching", &hello_plugin()).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(loaded.name, "matching");
+        assert_eq!(loaded.plugin.actions()[0].name, "hello");
+    }
+
+    #[test]
+    fn refuses_a_plugin_built_against_other_sources() {
+        let mut library = hello_plugin();
+        replace(&mut library, SOURCE_HASH.as_bytes(), b"0000000000000000\0");
+
+        let error = load_copy("mismatched", &library).err().expect("the plugin is refused");
+        assert!(error.contains("different build of kelp"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_other_declarations() {
+        let declaration = |abi_version, kelp_version: &str, rustc_version: &str| PluginDeclaration {
+            abi_version,
+            kelp_version: kelp_version.as_ptr() as *const c_char,
+            rustc_version: rustc_version.as_ptr() as *const c_char,
+            source_hash: SOURCE_HASH.as_ptr() as *const c_char,
+        };
+
+        unsafe {
+            assert!(check_declaration(&declaration(PLUGIN_ABI_VERSION, KELP_VERSION, RUSTC_VERSION)).is_ok());
+            assert!(check_declaration(&declaration(PLUGIN_ABI_VERSION - 1, KELP_VERSION, RUSTC_VERSION)).is_err());
+            assert!(check_declaration(&declaration(PLUGIN_ABI_VERSION, "0.0.0\0", RUSTC_VERSION)).is_err());
+            assert!(check_declaration(&declaration(PLUGIN_ABI_VERSION, KELP_VERSION, "rustc 1.0.0\0")).is_err());
+        }
+    }
+
+    // An in-process plugin that writes down every hook it gets
+    #[derive(Clone)]
+    struct Recorder {
+        name: &'static str,
+        hooks: Arc<Mutex<Vec<String>>>,
+        highlights: bool, // Takes over highlighting .plug files
+    }
+
+    impl Recorder {
+        fn new(name: &'static str, highlights: bool) -> Self {
+            Recorder {
+                name,
+                hooks: Arc::new(Mutex::new(Vec::new())),
+                highlights,
+            }
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.hooks.lock().unwrap())
+        }
+
+        fn record(&self, hook: String) {
+            self.hooks.lock().unwrap().push(hook);
+        }
+    }
+
+    impl Plugin for Recorder {
+        fn on_open(&self, _editor: &mut Editor, doc: usize) {
+            self.record(format!("open {}", doc));
+        }
+
+        fn on_close(&self, editor: &mut Editor, doc: usize) {
+            // Still there while the hook runs
+            self.record(format!("close {} {}", doc, editor.docs[doc].as_text_doc().filename));
+        }
+
+        fn on_change(&self, _editor: &mut Editor, doc: usize, _diff: &EditDiff, direction: UndoRedo) {
+            self.record(format!("change {} {:?}", doc, direction));
+        }
+
+        fn on_cursor_moved(&self, _editor: &mut Editor, doc: usize, row: usize, col: usize) {
+            self.record(format!("cursor {} {}:{}", doc, row, col));
+        }
+
+        fn on_tab_switched(&self, _editor: &mut Editor, from: Option<usize>, to: Option<usize>) {
+            self.record(format!("switch {:?} {:?}", from, to));
+        }
+
+        fn actions(&self) -> Vec<PluginAction> {
+            let ctrl = |c| Some(KelpKeyEvent::new(KeyCode::Char(c), KelpKeyModifiers::Control));
+            vec![
+                PluginAction {
+                    name: "greet".to_owned(),
+                    key: if self.name == "first" { ctrl('j') } else { ctrl('u') },
+                },
+                PluginAction {
+                    name: format!("{}_paste", self.name),
+                    key: ctrl('v'),
+                },
+            ]
+        }
+
+        fn run_action(&self, editor: &mut Editor, name: &str, args: &[String]) {
+            editor.status_msg = format!("{} ran {} {}", self.name, name, args.join(" "));
+        }
+
+        fn status_segments(&self, editor: &Editor) -> Vec<String> {
+            vec![format!("{}: {} docs", self.name, editor.docs.len())]
+        }
+
+        fn tokenize(&self, doc: &TextDocument, from: usize, count: usize) -> Option<Vec<Vec<Token>>> {
+            if !self.highlights || !doc.filename.ends_with(".plug") {
+                return None;
+            }
+
+            let rows = &doc.rows[from..std::cmp::min(from + count, doc.rows.len())];
+            Some(rows.iter().map(|row| vec![Token::Keyword(0..row.buf.len())]).collect())
+        }
+
+        fn decorations(&self, _doc: &TextDocument, from: usize, _count: usize) -> Vec<Decoration> {
+            vec![
+                Decoration::Style {
+                    row: from,
+                    range: if self.name == "first" { 0..1 } else { 2..3 },
+                    style: format!("<{}>", self.name),
+                },
+                Decoration::GutterSign {
+                    row: from,
+                    sign: '+',
+                    color: (0, 255, 0),
+                },
+            ]
+        }
+    }
+
+    fn editor(plugins: &[&Recorder]) -> Editor {
+        let mut editor = Editor::default();
+        editor.resize(80, 24);
+        editor.config.bind_defaults();
+        editor.plugins = plugins
+            .iter()
+            .map(|plugin| LoadedPlugin::sandboxed(plugin.name.to_owned(), Box::new((*plugin).clone())))
+            .collect();
+        editor
+    }
+
+    fn doc(filename: &str, lines: &[&str]) -> TextDocument {
+        let mut doc = TextDocument::new(filename.to_owned());
+        doc.rows = lines.iter().map(|line| Row::from_string(line.to_string())).collect();
+        doc
+    }
+
+    #[test]
+    fn hooks_fire_for_open_edit_switch_and_close() {
+        let plugin = Recorder::new("first", false);
+        let mut editor = editor(&[&plugin]);
+
+        editor.add_doc(doc("a.rs", &["let a = 1;"]));
+        editor.add_doc(doc("b.rs", &["let b = 2;"]));
+        assert_eq!(plugin.take(), ["open 0", "open 1"]);
+
+        editor.open_doc = Some(0);
+        editor.notify_plugins();
+        assert_eq!(plugin.take(), ["switch None Some(0)", "cursor 0 0:0"]);
+
+        // Nothing new to tell
+        editor.notify_plugins();
+        assert!(plugin.take().is_empty());
+
+        let doc = editor.docs[0].as_mut_text_doc();
+        let (diffs, _) = doc.replace_range((0, 4), (0, 5), "x");
+        doc.add_diff(EditDiff::Compound(diffs));
+        doc.cursor_col = 5;
+        let diff = doc.history[0].clone();
+        diff.apply(UndoRedo::Undo, doc);
+        doc.changes.push((diff, UndoRedo::Undo));
+        editor.notify_plugins();
+        assert_eq!(plugin.take(), ["change 0 Redo", "change 0 Undo", "cursor 0 0:5"]);
+
+        editor.open_doc = Some(1);
+        editor.notify_plugins();
+        assert_eq!(plugin.take(), ["switch Some(0) Some(1)", "cursor 1 0:0"]);
+
+        editor.remove_doc(0);
+        assert_eq!(plugin.take(), ["close 0 a.rs"]);
+        assert_eq!(editor.docs.len(), 1);
+    }
+
+    #[test]
+    fn actions_are_registered_with_their_keys() {
+        let (first, second) = (Recorder::new("first", false), Recorder::new("second", false));
+        let mut editor = editor(&[&first, &second]);
+        editor.register_plugin_actions();
+
+        let press = |editor: &mut Editor, c| {
+            match editor.resolve_key(&KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL), false) {
+                KeyPress::Action(name) => Some(name),
+                _ => None,
+            }
+        };
+
+        // The first plugin to offer a name gets it, along with its key
+        assert_eq!(editor.actions["greet"], 0);
+        assert_eq!(press(&mut editor, 'j').as_deref(), Some("greet"));
+        assert_eq!(press(&mut editor, 'u'), None);
+
+        // Keys kelp already uses stay with kelp, the action can still be run by name
+        assert_eq!(press(&mut editor, 'v').as_deref(), Some("paste"));
+        assert_eq!(editor.actions["second_paste"], 1);
+
+        assert!(editor.run_action("greet", &["you".to_owned()]));
+        assert_eq!(editor.status_msg, "first ran greet you");
+        assert!(editor.run_action("second_paste", &[]));
+        assert_eq!(editor.status_msg, "second ran second_paste ");
+        assert!(!editor.run_action("missing", &[]));
+    }
+
+    #[test]
+    fn status_segments_from_every_plugin() {
+        let (first, second) = (Recorder::new("first", false), Recorder::new("second", false));
+        let mut editor = editor(&[&first, &second]);
+        editor.add_doc(doc("a.rs", &[""]));
+
+        assert_eq!(editor.status_segments(), ["first: 1 docs", "second: 1 docs"]);
+    }
+
+    #[test]
+    fn highlighting_and_decorations_show_up() {
+        let (first, second) = (Recorder::new("first", false), Recorder::new("second", true));
+        let mut editor = editor(&[&first, &second]);
+        editor.add_doc(doc("a.plug", &["one", "two", "three"]));
+        editor.add_doc(doc("b.rs", &["one"]));
+
+        // Only the second plugin highlights, both decorate
+        let (tokens, decorations) = editor.plugin_highlighting(0, 1, 5);
+        assert_eq!(tokens.as_ref().map(Vec::len), Some(2));
+        let styles: Vec<&str> = decorations
+            .iter()
+            .filter_map(|decoration| match decoration {
+                Decoration::Style { style, .. } => Some(style.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(styles, ["<first>", "<second>"]);
+        assert_eq!(decorations.len(), 4);
+
+        let (tokens, _) = editor.plugin_highlighting(1, 0, 5);
+        assert!(tokens.is_none());
+
+        // And they make it into the rows as the editor tokenizes them
+        let (tokens, decorations) = editor.plugin_highlighting(0, 1, 2);
+        let doc = match &mut editor.docs[0] {
+            Document::TextDocument(doc) => doc,
+            _ => unreachable!(),
+        };
+        Token::tokenize(
+            &mut doc.rows,
+            HighlightingInfo {
+                selection: None,
+                matching_brackets: None,
+                languages: None,
+                diagnostics: None,
+                plugin_tokens: tokens.as_deref(),
+                decorations: Some(&decorations),
+            },
+            1,
+            2,
+            &FileConfig::default(),
+        );
+
+        let row = &doc.rows[1];
+        let tokens: Vec<String> = row
+            .tokens
+            .iter()
+            .map(|token| match token {
+                Token::CustomStyle(range, style) => format!("{}:{}", style, &row.buf[range.clone()]),
+                token => format!("{:?}", token),
+            })
+            .collect();
+        assert_eq!(tokens, ["<first>:t", "Keyword(1..2)", "<second>:o"]);
+        assert!(doc.rows[2].tokens.iter().all(|token| !matches!(token, Token::CustomStyle(..))));
+    }
+}
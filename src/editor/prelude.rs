@@ -1,8 +1,27 @@
+use crate::editor::action::Action;
+use crate::editor::completion::Completion;
 use crate::editor::editor::char_width;
+use crate::editor::editor::is_invisible;
 use crate::editor::editor::line_ending;
+use crate::editor::highlight::Decoration;
 use crate::editor::highlight::Token;
 use crate::editor::history::EditDiff;
+use crate::editor::history::LineDeleteMode;
+use crate::editor::history::UndoRedo;
+use crate::editor::hover::SignatureHelp;
+use crate::editor::kill_ring::KillRing;
+use crate::editor::lsp::LanguageServers;
+use crate::editor::plugin;
+use crate::editor::wasm;
+use crate::editor::plugin::LoadedPlugin;
+use crate::editor::script::Scripts;
+use crate::editor::tags::Tags;
+use crate::editor::tasks::QuickfixEntry;
+use crate::editor::tasks::RunningTask;
+use crate::editor::vim::Vim;
+use crate::editor::workspace::SymbolIndex;
 use crossterm::event::KeyCode;
+use crossterm::event::KeyEvent;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
@@ -10,12 +29,17 @@ use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Read;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 use unescape::unescape;
+use unicode_width::UnicodeWidthChar;
 use unicode_width::UnicodeWidthStr;
 
 
 use std::io::Write;
 
+// Native plugins are handed this as is, so adding a field (like scripts) changes the plugin ABI. Plugins built
+// before the change are turned away by the source hash check in plugin.rs rather than by a version bump
 #[derive(Default)]
 pub struct Editor {
     pub docs: Vec<Document>,
@@ -36,6 +60,38 @@ pub struct Editor {
     pub status_msg: String,
 
     pub docs_mouse_cache: Vec<(usize, usize)>,
+
+    pub language_servers: LanguageServers,
+
+    pub completion: Option<Completion>,
+    pub signature_help: Option<SignatureHelp>,
+
+    pub tags: Option<Tags>,
+    pub jump_stack: Vec<(String, usize, usize)>, // Where go to definition jumped from, as (filename, row, col)
+
+    pub task: Option<RunningTask>,
+    pub quickfix: Vec<QuickfixEntry>, // Locations from the output of the last task
+    pub quickfix_index: Option<usize>,
+
+    pub symbol_index: Option<SymbolIndex>, // Definitions in every file under the current directory
+
+    pub plugins: Vec<LoadedPlugin>,
+    pub scripts: Option<Scripts>,
+
+    // What plugins were last told about, to notice tab switches and cursor moves
+    pub plugin_open_doc: Option<usize>,
+    pub plugin_cursor: Option<(usize, usize)>,
+
+    pub actions: HashMap<String, usize>, // Plugin actions by name, to the index of the plugin in plugins
+
+    // The start of a chord, waiting for the rest
+    pub pending_keys: Vec<KeyEvent>,
+    pub pending_since: Option<Instant>,
+
+    pub vim: Vim,
+
+    pub kill_ring: KillRing,
+    pub last_action: Option<Action>, // For kills to tell if they follow another one, and yank_pop a yank
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -46,8 +102,13 @@ pub struct Selection {
     pub end_col: usize,
 }
 
-pub struct HighlightingInfo {
+pub struct HighlightingInfo<'a> {
     pub selection: Option<Selection>,
+    pub matching_brackets: Option<[(usize, usize); 2]>,
+    pub languages: Option<&'a HashMap<String, FileConfig>>, // Needed to highlight embedded regions
+    pub diagnostics: Option<&'a [Diagnostic]>,
+    pub plugin_tokens: Option<&'a [Vec<Token>]>, // From a plugin highlighter, one entry per row starting at the first tokenized one
+    pub decorations: Option<&'a [Decoration]>,
 }
 
 pub enum Document {
@@ -55,6 +116,9 @@ pub enum Document {
     BinaryDocument(BinaryDocument),
 }
 
+// How far matching_bracket looks for a partner before giving up
+const BRACKET_SCAN_LIMIT: usize = 1000;
+
 #[derive(Default)]
 pub struct TextDocument {
     pub filename: String,
@@ -73,6 +137,37 @@ pub struct TextDocument {
     pub history_index: Option<usize>,
 
     pub to_auto_close: bool,
+
+    pub diagnostics: Vec<Diagnostic>,
+
+    pub read_only: bool, // Output of tasks and the like, never edited or saved
+
+    pub changes: Vec<(EditDiff, UndoRedo)>, // Edits plugins haven't been told about yet, Undo if the diff was reversed
+    pub version: usize, // New after every change to the text, unlike dirty which goes back to 0 on save
+}
+
+// Shared by every document so one that is closed and opened again can't be mistaken for the old one
+static NEXT_VERSION: AtomicUsize = AtomicUsize::new(1);
+
+// Ordered most severe first
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+// A problem reported in a document, columns are char indices like the cursor
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub start_row: usize,
+    pub start_col: usize,
+    pub end_row: usize,
+    pub end_col: usize,
+
+    pub severity: Severity,
+    pub message: String,
 }
 
 #[derive(Default)]
@@ -99,6 +194,15 @@ pub struct FileConfig {
     pub line_comment_start: String,
     #[serde(default)]
     pub multi_line_comment: (String, String),
+    #[serde(default)]
+    pub nested_comments: bool,
+    #[serde(default)]
+    pub doc_comments: Vec<String>,
+
+    #[serde(default)]
+    pub raw_strings: bool,
+    #[serde(default)]
+    pub lifetimes: bool,
 
     #[serde(default)]
     pub keywords: Vec<String>,
@@ -111,6 +215,89 @@ pub struct FileConfig {
 
     #[serde(default)]
     pub auto_close: HashMap<char, char>,
+
+    #[serde(default)]
+    pub bracket_colors: Vec<(u8, u8, u8)>,
+
+    #[serde(default)]
+    pub show_whitespace: bool,
+
+    #[serde(default)]
+    pub language_server: Option<LanguageServer>,
+
+    #[serde(default)]
+    pub snippets: HashMap<String, String>, // Trigger word to body, $0 marks where the cursor ends up
+
+    #[serde(default)]
+    pub formatter: Option<Formatter>, // Falls back to the language server if there is one
+    #[serde(default)]
+    pub format_on_save: bool,
+
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub embedded: Vec<EmbeddedRegion>,
+}
+
+// A region of a file written in another language, like a ```rust fence in markdown or <script> in html
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddedRegion {
+    pub start: String,
+    pub end: String,
+
+    #[serde(default)]
+    pub start_close: String, // If set the start marker runs until this, eg. the > closing <script ...>
+
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    pub language_from_start: bool, // Read the language from the text after the start marker
+}
+
+// The command to launch a language server for a file type, spoken to over stdio
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LanguageServer {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    #[serde(default)]
+    pub language_id: String, // Defaults to the file extension
+}
+
+// A command that reads a file on stdin and prints it formatted, {file} in args is replaced with the filename
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Formatter {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    #[serde(default = "default_formatter_timeout")]
+    pub timeout: u64, // Milliseconds to wait for it before killing it, the editor is held up until then
+}
+
+fn default_formatter_timeout() -> u64 {
+    5000
+}
+
+// A command run from the editor whose output is scanned for file:line:col locations
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Task {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    #[serde(default)]
+    pub matcher: Matcher,
+}
+
+// How to find errors in a task's output
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Matcher {
+    Rustc,
+    Gcc,
+    #[default]
+    Generic,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -123,6 +310,28 @@ pub struct EditorConfig {
 
     #[serde(default)]
     pub keybinds: HashMap<String, KelpKeyEvent>,
+
+    #[serde(default)]
+    pub tasks: HashMap<String, Task>,
+
+    #[serde(default)]
+    pub plugin_capabilities: HashMap<String, Vec<String>>, // What each WebAssembly plugin is allowed to do, by name
+
+    #[serde(default)]
+    pub leader: Option<KelpKey>, // Starts every keybind with "leader": true
+
+    #[serde(default = "default_chord_timeout")]
+    pub chord_timeout: u64, // Milliseconds to wait for the next key of a chord before giving up on it
+
+    #[serde(default)]
+    pub vim: bool, // Modal editing, see vim.rs
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keybind_preset: Option<String>, // Put into keybinds the next time kelp starts, see action.rs
+}
+
+fn default_chord_timeout() -> u64 {
+    1000
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -131,16 +340,23 @@ pub struct Theme {
     pub foreground_color: (u8, u8, u8),
 }
 
+// What the row above ended with when a row was tokenized (bracket depth and embedded region), and whether
+// embedded languages were highlighted
+pub type TokenizedAfter = (usize, Option<(usize, String)>, bool);
+
 #[allow(dead_code)]
 pub struct Row {
     pub buf: String,
 
     pub tokens: Vec<Token>,
+    pub bracket_depth: usize, // Nesting depth at the end of the row, used to continue rainbow brackets
+    pub embedded: Option<(usize, String)>, // Embedded region still open at the end of the row
+    pub tokenized_after: Option<TokenizedAfter>, // None once it's edited
 
     pub indices: Option<Vec<usize>>, // Allocate this only if there are utf 8 chars in the row. Shamelessly stolen from kiro-editor by rhysd
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum KelpKeyModifiers {
     Alt,
     Control,
@@ -150,13 +366,28 @@ pub enum KelpKeyModifiers {
     ShiftAndControl,
     AltAndShift,
 
+    AltAndShiftAndControl,
+
     NoModifier,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KelpKey {
+    pub key: KeyCode,
+    pub modifiers: KelpKeyModifiers,
+}
+
+// A key, or a chord of them like Ctrl+K Ctrl+C when then has the rest
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct KelpKeyEvent {
     pub key: KeyCode,
     pub modifiers: KelpKeyModifiers,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub leader: bool, // Pressed after the leader key in the config
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub then: Vec<KelpKey>,
 }
 //==========================================================================================
 
@@ -168,6 +399,18 @@ impl Default for FileConfig {
         syntax_colors.insert("keyword".to_owned(), (0, 148, 255));
         syntax_colors.insert("comment".to_owned(), (0, 127, 14));
         syntax_colors.insert("string".to_owned(), (255, 240, 24));
+        syntax_colors.insert("char".to_owned(), (255, 200, 24));
+        syntax_colors.insert("number".to_owned(), (181, 206, 168));
+        syntax_colors.insert("doc_comment".to_owned(), (80, 160, 90));
+        syntax_colors.insert("type".to_owned(), (78, 201, 176));
+        syntax_colors.insert("lifetime".to_owned(), (86, 156, 214));
+        syntax_colors.insert("whitespace".to_owned(), (70, 70, 70));
+        syntax_colors.insert("trailing_whitespace".to_owned(), (150, 30, 30));
+        syntax_colors.insert("invisible".to_owned(), (255, 80, 80));
+        syntax_colors.insert("error".to_owned(), (240, 70, 70));
+        syntax_colors.insert("warning".to_owned(), (230, 180, 60));
+        syntax_colors.insert("info".to_owned(), (80, 160, 240));
+        syntax_colors.insert("hint".to_owned(), (150, 150, 150));
 
         Self {
             tab_str: String::from("    "),
@@ -177,11 +420,93 @@ impl Default for FileConfig {
             keywords: vec![],
             syntax_highlighting_disabled: false,
             multi_line_comment: ("/*".to_owned(), "*/".to_owned()),
+            nested_comments: false,
+            doc_comments: vec![],
+            raw_strings: false,
+            lifetimes: false,
             auto_close: HashMap::new(),
+            bracket_colors: vec![(255, 215, 0), (218, 112, 214), (23, 159, 255)],
+            show_whitespace: false,
+            language_server: None,
+            snippets: HashMap::new(),
+            formatter: None,
+            format_on_save: false,
+            aliases: vec![],
+            embedded: vec![],
         }
     }
 }
 
+impl FileConfig {
+    // Looks a language up by its extension first and then by its aliases, so ```rust finds the rs config
+    pub fn find_language<'a>(
+        languages: &'a HashMap<String, FileConfig>,
+        name: &str,
+    ) -> Option<&'a FileConfig> {
+        languages.get(name).or_else(|| {
+            languages
+                .values()
+                .find(|config| config.aliases.iter().any(|alias| alias == name))
+        })
+    }
+}
+
+impl EditorConfig {
+    // The config for a file extension, falling back to the "*" config
+    pub fn language(&self, extension: &str) -> &FileConfig {
+        self.languages
+            .get(extension)
+            .unwrap_or_else(|| &self.languages["*"])
+    }
+}
+
+impl Severity {
+    pub fn from_lsp(severity: u64) -> Self {
+        match severity {
+            1 => Severity::Error,
+            2 => Severity::Warning,
+            3 => Severity::Information,
+            _ => Severity::Hint,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Information => "info",
+            Severity::Hint => "hint",
+        }
+    }
+
+    // Shown in the gutter next to the row
+    pub fn marker(&self) -> char {
+        match self {
+            Severity::Error => 'E',
+            Severity::Warning => 'W',
+            Severity::Information => 'I',
+            Severity::Hint => 'H',
+        }
+    }
+
+    pub fn color(&self, config: &FileConfig) -> (u8, u8, u8) {
+        let default = match self {
+            Severity::Error => (240, 70, 70),
+            Severity::Warning => (230, 180, 60),
+            Severity::Information => (80, 160, 240),
+            Severity::Hint => (150, 150, 150),
+        };
+
+        *config.syntax_colors.get(self.name()).unwrap_or(&default)
+    }
+}
+
+impl Diagnostic {
+    pub fn contains(&self, row: usize, col: usize) -> bool {
+        (row, col) >= (self.start_row, self.start_col) && (row, col) <= (self.end_row, self.end_col)
+    }
+}
+
 impl Default for Theme {
     fn default() -> Self {
         Self {
@@ -219,7 +544,10 @@ impl Row {
         Self {
             buf: String::new(),
             indices: None,
-            tokens: Vec::new()
+            tokens: Vec::new(),
+            bracket_depth: 0,
+            embedded: None,
+            tokenized_after: None,
         }
     }
 
@@ -235,7 +563,10 @@ impl Row {
         Self {
             buf: line,
             indices,
-            tokens: Vec::new()
+            tokens: Vec::new(),
+            bracket_depth: 0,
+            embedded: None,
+            tokenized_after: None,
         }
     }
 
@@ -258,7 +589,17 @@ impl Row {
         if self.indices.is_none() {
             self.buf.len() + (file_config.tab_str.len() - 1) * self.buf.matches('\t').count()
         } else {
-            self.buf.width() + file_config.tab_str.len() * self.buf.matches('\t').count()
+            let invisible = if file_config.show_whitespace {
+                // Drawn a column wide whether or not they take up any space themselves
+                self.buf
+                    .chars()
+                    .filter(|c| is_invisible(*c) && UnicodeWidthChar::width(*c) == Some(0))
+                    .count()
+            } else {
+                0
+            };
+
+            self.buf.width() + file_config.tab_str.len() * self.buf.matches('\t').count() + invisible
         }
     }
 
@@ -278,13 +619,17 @@ impl Row {
             let mut res = String::new();
 
             for token in &self.tokens {
-                let tmp = format!(
-                    "{}{}{}{}\x1B[0m",
-                    f_color,
-                    b_color,
-                    token.get_style(file_config),
-                    &self.buf[token.get_range().start..token.get_range().end]
-                );
+                let tmp = if file_config.show_whitespace {
+                    self.display_whitespace(token, file_config, theme)
+                } else {
+                    format!(
+                        "{}{}{}{}\x1B[0m",
+                        f_color,
+                        b_color,
+                        token.get_style(file_config),
+                        &self.buf[token.get_range().start..token.get_range().end]
+                    )
+                };
                 res.push_str(&tmp);
             }
 
@@ -292,6 +637,58 @@ impl Row {
         }
     }
 
+    // Same as a token's usual output but with whitespace and invisible characters drawn as glyphs
+    fn display_whitespace(&self, token: &Token, file_config: &FileConfig, theme: &Theme) -> String {
+        let style = format!(
+            "{}{}{}",
+            crossterm::style::SetForegroundColor(crossterm::style::Color::from(
+                theme.foreground_color,
+            )),
+            crossterm::style::SetBackgroundColor(crossterm::style::Color::from(
+                theme.background_color,
+            )),
+            token.get_style(file_config)
+        );
+
+        let color = |name: &str, default: (u8, u8, u8)| {
+            crossterm::style::Color::from(*file_config.syntax_colors.get(name).unwrap_or(&default))
+        };
+        let whitespace = crossterm::style::SetForegroundColor(color("whitespace", (70, 70, 70)));
+        let invisible = crossterm::style::SetForegroundColor(color("invisible", (255, 80, 80)));
+        let trailing =
+            crossterm::style::SetBackgroundColor(color("trailing_whitespace", (150, 30, 30)));
+
+        let trailing_start = self.buf.trim_end().len();
+
+        let mut res = style.clone();
+
+        for (i, c) in self.buf[token.get_range().start..token.get_range().end].char_indices() {
+            let is_trailing = token.get_range().start + i >= trailing_start;
+
+            let glyph = match c {
+                ' ' => "·".to_owned(),
+                '\t' => format!("→{}", " ".repeat(file_config.tab_str.len().saturating_sub(1))),
+                '\u{A0}' => "␣".to_owned(),
+                c if is_invisible(c) => "¤".to_owned(),
+                c => {
+                    res.push(c);
+                    continue;
+                }
+            };
+
+            res.push_str(&format!(
+                "{}{}{}\x1B[0m{}",
+                if c == ' ' || c == '\t' { whitespace } else { invisible },
+                if is_trailing { trailing.to_string() } else { String::new() },
+                glyph,
+                style
+            ));
+        }
+
+        res.push_str("\x1B[0m");
+        res
+    }
+
     #[inline]
     pub fn display_buf_upto(&self, file_config: &FileConfig, theme: &Theme, width: usize) -> String {
         let w = std::cmp::min(width, self.len());
@@ -339,6 +736,7 @@ impl Row {
 
     #[inline]
     pub fn insert_char(&mut self, idx: usize, chr: char) {
+        self.tokenized_after = None;
         if chr.is_ascii() {
             if self.indices.is_none() {
                 if !self.buf.is_char_boundary(idx) {
@@ -370,6 +768,7 @@ impl Row {
 
     #[inline]
     pub fn remove_at(&mut self, idx: usize) -> char {
+        self.tokenized_after = None;
         let mut loc_idx = if self.indices.is_none() {
             idx
         } else {
@@ -385,6 +784,15 @@ impl Row {
         c
     }
 
+    // For joining lines
+    pub fn push_str(&mut self, text: &str) {
+        self.tokenized_after = None;
+        self.buf.push_str(text);
+        if self.indices.is_some() || !text.is_ascii() {
+            self.refresh_cache();
+        }
+    }
+
     #[inline]
     pub fn split_at(&mut self, idx: usize) -> (String, String) {
         if self.indices.is_some() {
@@ -404,6 +812,47 @@ impl Row {
         }
     }
 
+    #[inline]
+    pub fn byte_to_char(&self, byte: usize) -> usize {
+        if let Some(indices) = &self.indices {
+            match indices.binary_search(&byte) {
+                Ok(idx) => idx,
+                Err(idx) => idx,
+            }
+        } else {
+            byte
+        }
+    }
+
+    #[inline]
+    // Lsp positions count utf 16 code units
+    pub fn utf16_to_char(&self, col: usize) -> usize {
+        let mut units = 0;
+        for (i, c) in self.buf.chars().enumerate() {
+            if units >= col {
+                return i;
+            }
+            units += c.len_utf16();
+        }
+        self.len()
+    }
+
+    pub fn char_to_utf16(&self, idx: usize) -> usize {
+        self.buf.chars().take(idx).map(char::len_utf16).sum()
+    }
+
+    pub fn char_to_byte(&self, idx: usize) -> usize {
+        if let Some(indices) = &self.indices {
+            if idx < indices.len() {
+                indices[idx]
+            } else {
+                self.buf.len()
+            }
+        } else {
+            idx
+        }
+    }
+
     #[inline]
     fn refresh_cache(&mut self) {
         self.indices = Some(self.buf.char_indices().map(|index| index.0).collect());
@@ -415,6 +864,7 @@ impl TextDocument {
         TextDocument {
             filename,
             rows: Vec::new(),
+            version: NEXT_VERSION.fetch_add(1, Ordering::Relaxed),
             ..TextDocument::default()
         }
     }
@@ -447,10 +897,16 @@ impl TextDocument {
         Ok(TextDocument {
             rows,
             filename,
+            version: NEXT_VERSION.fetch_add(1, Ordering::Relaxed),
             ..TextDocument::default()
         })
     }
 
+    // For changes that don't go through add_diff, like undo
+    pub fn changed(&mut self) {
+        self.version = NEXT_VERSION.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn save(&self, config: &FileConfig) -> Result<(), Box<dyn Error>> {
         let mut file = File::create(&self.filename)?;
 
@@ -475,7 +931,15 @@ impl TextDocument {
         Ok(())
     }
 
+    // Replaces the whole text as one undo step, keeping the cursor on the same code. False if nothing changed
+    pub fn replace_contents(&mut self, text: &str) -> bool {
+        crate::editor::format::apply(self, text)
+    }
+
     pub fn add_diff(&mut self, diff: EditDiff) {
+        self.changes.push((diff.clone(), UndoRedo::Redo));
+        self.changed();
+
         if let Some(history_index) = self.history_index {
             if history_index + 1 < self.history.len() {
                 self.history.truncate(history_index + 2);
@@ -507,6 +971,14 @@ impl TextDocument {
         }
     }
 
+    pub fn contents(&self) -> String {
+        self.rows
+            .iter()
+            .map(|row| &row.buf[..])
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
     #[inline]
     pub fn extension(&self) -> String {
         Path::new(&self.filename)
@@ -537,16 +1009,218 @@ impl TextDocument {
         rows
     }
 
-    pub fn tokenize(&mut self, start: usize, end: usize, config: &FileConfig) {
+    pub fn tokenize(&mut self, start: usize, end: usize, config: &FileConfig, languages: &HashMap<String, FileConfig>) {
         Token::tokenize(
             &mut self.rows,
             HighlightingInfo {
                 selection: self.selection,
+                matching_brackets: None,
+                languages: Some(languages),
+                diagnostics: Some(&self.diagnostics),
+                plugin_tokens: None,
+                decorations: None,
+            },
+            start,
+            end - start,
+            config,
+        );
+    }
+
+    // Replaces the text between two (row, col) positions, returning the diffs that did it and where the new text ends.
+    // The caller decides how the diffs go into the history
+    pub fn replace_range(
+        &mut self,
+        start: (usize, usize),
+        end: (usize, usize),
+        text: &str,
+    ) -> (Vec<EditDiff>, (usize, usize)) {
+        let mut diffs = Vec::new();
+        let (row, col) = start;
+
+        if end.0 > row {
+            for _ in col..self.rows[row].len() {
+                let c = self.rows[row].remove_at(col);
+                diffs.push(EditDiff::DeleteChar(col + 1, row, c, false));
+            }
+
+            for _ in row + 1..end.0 {
+                let line = self.rows.remove(row + 1);
+                diffs.push(EditDiff::DeleteLine(row + 1, line.buf, LineDeleteMode::WholeLine));
+            }
+
+            for _ in 0..std::cmp::min(end.1, self.rows[row + 1].len()) {
+                let c = self.rows[row + 1].remove_at(0);
+                diffs.push(EditDiff::DeleteChar(1, row + 1, c, false));
+            }
+
+            self.rows[row] = Row::from_string(format!("{}{}", self.rows[row].buf, self.rows[row + 1].buf));
+            self.rows.remove(row + 1);
+            diffs.push(EditDiff::JoinLine(col, row));
+        } else {
+            for _ in col..std::cmp::min(end.1, self.rows[row].len()) {
+                let c = self.rows[row].remove_at(col);
+                diffs.push(EditDiff::DeleteChar(col + 1, row, c, false));
+            }
+        }
+
+        let (mut row, mut col) = start;
+        for c in text.chars() {
+            if c == '\n' {
+                let split = self.rows[row].char_to_byte(col);
+                let right = self.rows[row].buf.split_off(split);
+                self.rows[row] = Row::from_string(std::mem::take(&mut self.rows[row].buf));
+                self.rows.insert(row + 1, Row::from_string(right));
+                diffs.push(EditDiff::SplitLine(col, row));
+
+                row += 1;
+                col = 0;
+            } else if c != '\r' {
+                self.rows[row].insert_char(col, c);
+                diffs.push(EditDiff::InsertChar(col, row, c));
+                col += 1;
+            }
+        }
+
+        (diffs, (row, col))
+    }
+
+    pub fn text_between(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let slice = |row: usize, from: usize, to: usize| {
+            let row = &self.rows[row];
+            row.buf[row.char_to_byte(from)..row.char_to_byte(to)].to_owned()
+        };
+
+        if start.0 == end.0 {
+            return slice(start.0, start.1, end.1);
+        }
+
+        let mut text = slice(start.0, start.1, self.rows[start.0].len());
+        for row in start.0 + 1..end.0 {
+            text.push('\n');
+            text.push_str(&self.rows[row].buf);
+        }
+        text.push('\n');
+        text.push_str(&slice(end.0, 0, end.1));
+        text
+    }
+
+    // Moves the cursor, scrolling so the target row is in the middle of the screen if it's out of view
+    pub fn jump_to(&mut self, row: usize, col: usize, visible_rows: usize) {
+        self.cursor_row = std::cmp::min(row, self.rows.len().saturating_sub(1));
+        self.cursor_col = std::cmp::min(col, self.rows.get(self.cursor_row).map_or(0, Row::len));
+
+        if self.cursor_row < self.line_start || self.cursor_row >= self.line_start + visible_rows {
+            self.line_start = self.cursor_row.saturating_sub(visible_rows / 2);
+        }
+    }
+
+    // The most severe diagnostic under the cursor position
+    pub fn diagnostic_at(&self, row: usize, col: usize) -> Option<&Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.contains(row, col))
+            .min_by_key(|diagnostic| diagnostic.severity)
+    }
+
+    pub fn row_severity(&self, row: usize) -> Option<Severity> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.start_row <= row && row <= diagnostic.end_row)
+            .map(|diagnostic| diagnostic.severity)
+            .min()
+    }
+
+    // Finds the partner of the bracket at (row, col) skipping over brackets inside strings and comments
+    pub fn matching_bracket(
+        &mut self,
+        row: usize,
+        col: usize,
+        config: &FileConfig,
+        languages: &HashMap<String, FileConfig>,
+    ) -> Option<(usize, usize)> {
+        if row >= self.rows.len() || col >= self.rows[row].len() {
+            return None;
+        }
+
+        let (open, close, forward) = match self.rows[row].char_at(col) {
+            '(' => ('(', ')', true),
+            '[' => ('[', ']', true),
+            '{' => ('{', '}', true),
+            ')' => ('(', ')', false),
+            ']' => ('[', ']', false),
+            '}' => ('{', '}', false),
+            _ => return None,
+        };
+
+        let (start, end) = if forward {
+            (row, std::cmp::min(self.rows.len(), row + BRACKET_SCAN_LIMIT))
+        } else {
+            (row.saturating_sub(BRACKET_SCAN_LIMIT), row + 1)
+        };
+
+        Token::tokenize(
+            &mut self.rows,
+            HighlightingInfo {
+                selection: None,
+                matching_brackets: None,
+                languages: Some(languages),
+                diagnostics: None,
+                plugin_tokens: None,
+                decorations: None,
             },
             start,
             end - start,
             config,
         );
+
+        let cursor_byte = self.rows[row].char_to_byte(col);
+        let is_code = self.rows[row]
+            .tokens
+            .iter()
+            .any(|t| matches!(t, Token::Bracket(r, _) if r.start == cursor_byte));
+        if !is_code {
+            return None;
+        }
+
+        let mut depth = 0;
+        let scan: Vec<usize> = if forward {
+            (row..end).collect()
+        } else {
+            (start..end).rev().collect()
+        };
+
+        for r in scan {
+            let row_ref = &self.rows[r];
+            let mut brackets: Vec<usize> = row_ref
+                .tokens
+                .iter()
+                .filter_map(|t| match t {
+                    Token::Bracket(range, _) => Some(range.start),
+                    _ => None,
+                })
+                .filter(|b| {
+                    r != row || if forward { *b >= cursor_byte } else { *b <= cursor_byte }
+                })
+                .collect();
+
+            if !forward {
+                brackets.reverse();
+            }
+
+            for b in &brackets {
+                let c = row_ref.buf[*b..].chars().next().unwrap_or(' ');
+                if (c == open && forward) || (c == close && !forward) {
+                    depth += 1;
+                } else if c == open || c == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((r, row_ref.byte_to_char(*b)));
+                    }
+                }
+            }
+        }
+
+        None
     }
 }
 
@@ -679,88 +1353,32 @@ impl Document {
 
 impl Editor {
     pub fn new() -> Self {
-        if let Ok(mut plugin_path) = std::env::current_exe() {
+        let plugin_path = std::env::current_exe().ok().map(|mut plugin_path| {
             plugin_path.pop();
             plugin_path.push("plugins/");
+            plugin_path
+        });
 
-            // TODO: Plugin loading
-        }
+        let (mut plugins, plugin_errors) = match &plugin_path {
+            Some(plugin_path) => plugin::load_plugins(plugin_path),
+            None => (Vec::new(), Vec::new()),
+        };
 
         let default_theme = Theme {
             background_color: (0, 0, 0),
             foreground_color: (255, 255, 255),
         };
 
-        let mut default_keybinds = HashMap::new();
-
-        default_keybinds.insert(
-            "copy".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('c'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-        default_keybinds.insert(
-            "paste".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('v'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-        default_keybinds.insert(
-            "redo".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('y'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-        default_keybinds.insert(
-            "undo".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('z'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-        default_keybinds.insert(
-            "start_command".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('g'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-        default_keybinds.insert(
-            "close_file".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('w'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-        default_keybinds.insert(
-            "open_file".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('o'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-        default_keybinds.insert(
-            "new_file".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('n'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-        default_keybinds.insert(
-            "save_file".to_owned(),
-            KelpKeyEvent {
-                key: KeyCode::Char('s'),
-                modifiers: KelpKeyModifiers::Control,
-            },
-        );
-
         let mut config = EditorConfig {
             languages: HashMap::new(),
             theme: default_theme,
-            keybinds: default_keybinds,
+            keybinds: HashMap::new(),
+            tasks: HashMap::new(),
+            plugin_capabilities: HashMap::new(),
+            leader: None,
+            chord_timeout: default_chord_timeout(),
+            vim: false,
+            keybind_preset: None,
         };
 
         config
@@ -772,6 +1390,8 @@ impl Editor {
 
         std::fs::create_dir_all(path.clone()).expect("Couldn't create config directory");
 
+        let (scripts, script_errors) = Scripts::load(&path.join("scripts"));
+
         path.push("config.json");
         let config_file = File::open(path.clone());
 
@@ -795,14 +1415,38 @@ impl Editor {
             }
         }
 
-        Self {
+        // A preset is put into the keybinds once, after which they're what gets saved and can be changed
+        let preset_error = config.keybind_preset.take().and_then(|preset| config.apply_preset(&preset).err());
+
+        // Config files from before an action existed still get its key
+        config.bind_defaults();
+
+        // These need the config to know what each one is allowed
+        let (wasm_plugins, wasm_errors) = match &plugin_path {
+            Some(plugin_path) => wasm::load_plugins(plugin_path, &config.plugin_capabilities),
+            None => (Vec::new(), Vec::new()),
+        };
+        plugins.extend(wasm_plugins);
+
+        let mut editor = Self {
             docs: Vec::new(),
             open_doc: None,
             width: crossterm::terminal::size().unwrap_or((100, 100)).0 as usize,
             height: crossterm::terminal::size().unwrap_or((100, 100)).1 as usize,
             config,
+            status_msg: plugin_errors
+                .into_iter()
+                .chain(wasm_errors)
+                .chain(script_errors)
+                .chain(preset_error)
+                .collect::<Vec<_>>()
+                .join("; "),
+            plugins,
+            scripts: Some(scripts),
             ..Editor::default()
-        }
+        };
+        editor.register_plugin_actions();
+        editor
     }
 
     pub fn resize(&mut self, width: usize, height: usize) {
@@ -813,11 +1457,23 @@ impl Editor {
     pub fn add_doc(&mut self, doc: TextDocument) {
         self.docs.push(Document::TextDocument(doc));
         self.refresh_mouse_cache();
+
+        let index = self.docs.len() - 1;
+        self.run_plugins(|plugin, editor| plugin.on_open(editor, index));
     }
 
     pub fn add_bin_doc(&mut self, doc: BinaryDocument) {
         self.docs.push(Document::BinaryDocument(doc));
         self.refresh_mouse_cache();
+
+        let index = self.docs.len() - 1;
+        self.run_plugins(|plugin, editor| plugin.on_open(editor, index));
+    }
+
+    // Plugins get to see the document one last time before it goes
+    pub fn remove_doc(&mut self, index: usize) {
+        self.run_plugins(|plugin, editor| plugin.on_close(editor, index));
+        self.docs.remove(index);
     }
 
     pub fn refresh_mouse_cache(&mut self) {
@@ -857,12 +1513,37 @@ impl Editor {
     }
 }
 
-impl KelpKeyEvent {
+impl KelpKey {
     pub fn equals(&self, event: &crossterm::event::KeyEvent) -> bool {
         self.key == event.code && self.modifiers.to_crossterm() == event.modifiers
     }
 }
 
+impl KelpKeyEvent {
+    pub fn new(key: KeyCode, modifiers: KelpKeyModifiers) -> Self {
+        KelpKeyEvent {
+            key,
+            modifiers,
+            leader: false,
+            then: Vec::new(),
+        }
+    }
+
+    // Every key to press in order, None if it needs a leader and there isn't one
+    pub fn sequence(&self, leader: Option<KelpKey>) -> Option<Vec<KelpKey>> {
+        let mut keys = Vec::new();
+        if self.leader {
+            keys.push(leader?);
+        }
+        keys.push(KelpKey {
+            key: self.key,
+            modifiers: self.modifiers,
+        });
+        keys.extend(self.then.iter().copied());
+        Some(keys)
+    }
+}
+
 impl KelpKeyModifiers {
     pub fn to_crossterm(&self) -> crossterm::event::KeyModifiers {
         match self {
@@ -880,6 +1561,11 @@ impl KelpKeyModifiers {
             KelpKeyModifiers::AltAndShift => {
                 crossterm::event::KeyModifiers::ALT | crossterm::event::KeyModifiers::SHIFT
             }
+            KelpKeyModifiers::AltAndShiftAndControl => {
+                crossterm::event::KeyModifiers::ALT
+                    | crossterm::event::KeyModifiers::SHIFT
+                    | crossterm::event::KeyModifiers::CONTROL
+            }
         }
     }
 }
@@ -895,12 +1581,41 @@ impl Editor {
         width: usize,
         first_row: usize,
         file_config: &FileConfig,
+        gutter: usize,
     ) {
+        let (x, y) = Editor::cursor_position(
+            cursor_row,
+            cursor_col,
+            rows,
+            width,
+            first_row,
+            file_config,
+            gutter,
+        );
+
+        print!(
+            "{}{}",
+            crossterm::cursor::Show,
+            crossterm::cursor::MoveTo(x as u16, y as u16)
+        );
+        std::io::stdout().flush().unwrap();
+    }
+
+    // Where the cursor is on screen
+    pub fn cursor_position(
+        cursor_row: usize,
+        cursor_col: usize,
+        rows: &[Row],
+        width: usize,
+        first_row: usize,
+        file_config: &FileConfig,
+        gutter: usize,
+    ) -> (usize, usize) {
         let mut y = 1 + cursor_col / width;
         let mut x = 0;
 
         if cursor_row < first_row {
-            return; // Should never happen...
+            return (x + gutter, y); // Should never happen...
         }
 
         for row in rows.iter().skip(first_row).take(cursor_row - first_row) {
@@ -928,12 +1643,7 @@ impl Editor {
             y -= 1;
         }
 
-        print!(
-            "{}{}",
-            crossterm::cursor::Show,
-            crossterm::cursor::MoveTo(x as u16, y as u16)
-        );
-        std::io::stdout().flush().unwrap();
+        (x + gutter, y)
     }
 
     #[inline]
@@ -946,3 +1656,68 @@ impl Editor {
         self.height
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(start: (usize, usize), end: (usize, usize), severity: Severity, message: &str) -> Diagnostic {
+        Diagnostic {
+            start_row: start.0,
+            start_col: start.1,
+            end_row: end.0,
+            end_col: end.1,
+            severity,
+            message: message.to_owned(),
+        }
+    }
+
+    fn doc() -> TextDocument {
+        let mut doc = TextDocument::new("main.rs".to_owned());
+        doc.diagnostics = vec![
+            diagnostic((1, 4), (1, 8), Severity::Hint, "hint"),
+            diagnostic((1, 6), (3, 2), Severity::Warning, "warning"),
+            diagnostic((1, 7), (1, 7), Severity::Error, "error"),
+            diagnostic((5, 0), (5, 3), Severity::Information, "information"),
+        ];
+        doc
+    }
+
+    #[test]
+    fn most_severe_diagnostic_at_a_position() {
+        let doc = doc();
+        let message = |row, col| doc.diagnostic_at(row, col).map(|diagnostic| diagnostic.message.as_str());
+
+        assert_eq!(message(1, 7), Some("error"));
+        assert_eq!(message(1, 6), Some("warning"));
+        assert_eq!(message(1, 8), Some("warning"));
+        assert_eq!(message(2, 0), Some("warning"));
+        assert_eq!(message(0, 10), None);
+    }
+
+    #[test]
+    fn diagnostic_range_edges() {
+        let doc = doc();
+        let message = |row, col| doc.diagnostic_at(row, col).map(|diagnostic| diagnostic.message.as_str());
+
+        // Both ends are inclusive, so the cursor just after the last character still shows it
+        assert_eq!(message(1, 4), Some("hint"));
+        assert_eq!(message(1, 3), None);
+        assert_eq!(message(5, 3), Some("information"));
+        assert_eq!(message(5, 4), None);
+        assert_eq!(message(3, 2), Some("warning"));
+        assert_eq!(message(3, 3), None);
+    }
+
+    #[test]
+    fn row_severity_is_the_most_severe_on_the_row() {
+        let doc = doc();
+
+        assert_eq!(doc.row_severity(0), None);
+        assert_eq!(doc.row_severity(1), Some(Severity::Error));
+        assert_eq!(doc.row_severity(2), Some(Severity::Warning));
+        assert_eq!(doc.row_severity(3), Some(Severity::Warning));
+        assert_eq!(doc.row_severity(4), None);
+        assert_eq!(doc.row_severity(5), Some(Severity::Information));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::editor::history::EditDiff;
+use crate::editor::lsp;
+use crate::editor::prelude::*;
+use serde_json::Value;
+
+// A code action offered by the language server, or a bare command which some servers send instead
+#[derive(Clone)]
+pub struct CodeAction {
+    pub title: String,
+    pub edit: Option<Value>,    // A WorkspaceEdit
+    pub command: Option<Value>, // Run on the server after the edit is applied
+    pub raw: Value,             // What the server sent, needed to resolve actions it left incomplete
+}
+
+impl CodeAction {
+    pub fn from_lsp(action: &Value) -> Option<Self> {
+        let title = action["title"].as_str()?.to_owned();
+
+        // A Command has the command name as a string where a CodeAction has a whole Command object
+        let (edit, command) = if action["command"].is_string() {
+            (None, Some(action.clone()))
+        } else {
+            (
+                Some(action["edit"].clone()).filter(|edit| !edit.is_null()),
+                Some(action["command"].clone()).filter(|command| !command.is_null()),
+            )
+        };
+
+        Some(CodeAction {
+            title,
+            edit,
+            command,
+            raw: action.clone(),
+        })
+    }
+
+    // Servers may leave out the edit until asked for it with codeAction/resolve
+    pub fn needs_resolving(&self) -> bool {
+        self.edit.is_none() && self.command.is_none()
+    }
+}
+
+pub fn code_actions(response: &Value) -> Vec<CodeAction> {
+    response
+        .as_array()
+        .map(|actions| actions.iter().filter_map(CodeAction::from_lsp).collect())
+        .unwrap_or_default()
+}
+
+// The text edits a WorkspaceEdit makes, grouped by the file they are in
+pub fn workspace_edit_files(edit: &Value) -> Vec<(String, Vec<Value>)> {
+    let mut files = Vec::new();
+
+    if let Some(changes) = edit["documentChanges"].as_array() {
+        // Creating, renaming and deleting files aren't supported, those entries have no edits
+        for change in changes {
+            if let (Some(uri), Some(edits)) = (change["textDocument"]["uri"].as_str(), change["edits"].as_array()) {
+                files.push((lsp::uri_to_path(uri), edits.clone()));
+            }
+        }
+    } else if let Some(changes) = edit["changes"].as_object() {
+        for (uri, edits) in changes {
+            if let Some(edits) = edits.as_array() {
+                files.push((lsp::uri_to_path(uri), edits.clone()));
+            }
+        }
+    }
+
+    files
+}
+
+// Applies lsp TextEdits to a document as one undo step, the cursor moves along with the text around it
+pub fn apply_edits(doc: &mut TextDocument, edits: &[Value]) -> bool {
+    if doc.rows.is_empty() {
+        doc.rows.push(Row::empty());
+    }
+
+    let clamp = |doc: &TextDocument, (row, col): (usize, usize)| {
+        if row < doc.rows.len() {
+            (row, std::cmp::min(col, doc.rows[row].len()))
+        } else {
+            let last = doc.rows.len() - 1;
+            (last, doc.rows[last].len())
+        }
+    };
+
+    let mut edits: Vec<_> = edits
+        .iter()
+        .enumerate()
+        .filter_map(|(i, edit)| {
+            let start = clamp(doc, lsp::to_position(&edit["range"]["start"], doc)?);
+            let end = clamp(doc, lsp::to_position(&edit["range"]["end"], doc)?);
+            Some((start, i, std::cmp::max(start, end), edit["newText"].as_str()?.to_owned()))
+        })
+        .collect();
+
+    if edits.is_empty() {
+        return false;
+    }
+
+    // Back to front so the positions of the edits still to come stay valid
+    edits.sort_by_key(|(start, i, _, _)| std::cmp::Reverse((*start, *i)));
+
+    let mut cursor = (doc.cursor_row, doc.cursor_col);
+    let mut diffs = Vec::new();
+
+    for (start, _, end, text) in edits {
+        let (edit_diffs, inserted_end) = doc.replace_range(start, end, &text);
+        diffs.extend(edit_diffs);
+
+        if end <= cursor {
+            cursor = if cursor.0 == end.0 {
+                (inserted_end.0, inserted_end.1 + cursor.1 - end.1)
+            } else {
+                (cursor.0 + inserted_end.0 - end.0, cursor.1)
+            };
+        } else if start < cursor {
+            cursor = start;
+        }
+    }
+
+    doc.selection = None;
+    doc.cursor_row = cursor.0;
+    doc.cursor_col = cursor.1;
+    doc.dirty += 1;
+    doc.add_diff(EditDiff::Compound(diffs));
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::history::UndoRedo;
+    use serde_json::json;
+
+    fn doc(lines: &[&str], cursor: (usize, usize)) -> TextDocument {
+        let mut doc = TextDocument::new("main.rs".to_owned());
+        doc.rows = lines.iter().map(|line| Row::from_string(line.to_string())).collect();
+        doc.cursor_row = cursor.0;
+        doc.cursor_col = cursor.1;
+        doc
+    }
+
+    fn text(doc: &TextDocument) -> Vec<&str> {
+        doc.rows.iter().map(|row| row.buf.as_str()).collect()
+    }
+
+    fn edit(start: (usize, usize), end: (usize, usize), new_text: &str) -> Value {
+        json!({
+            "range": {
+                "start": {"line": start.0, "character": start.1},
+                "end": {"line": end.0, "character": end.1},
+            },
+            "newText": new_text,
+        })
+    }
+
+    // Where the cursor ends up after the edits
+    fn cursor_after(lines: &[&str], cursor: (usize, usize), edits: &[Value]) -> (usize, usize) {
+        let mut doc = doc(lines, cursor);
+        assert!(apply_edits(&mut doc, edits));
+        (doc.cursor_row, doc.cursor_col)
+    }
+
+    #[test]
+    fn edits_are_one_undo_step() {
+        let lines = ["let x = 1;", "let y = x;"];
+        let mut doc = doc(&lines, (0, 0));
+        let edits = [edit((0, 4), (0, 5), "count"), edit((1, 8), (1, 9), "count")];
+
+        assert!(apply_edits(&mut doc, &edits));
+        assert_eq!(text(&doc), ["let count = 1;", "let y = count;"]);
+        assert_eq!(doc.history.len(), 1);
+
+        let diff = doc.history[0].clone();
+        diff.apply(UndoRedo::Undo, &mut doc);
+        assert_eq!(text(&doc), lines);
+
+        assert!(!apply_edits(&mut doc, &[json!({"newText": "no range"})]));
+    }
+
+    #[test]
+    fn cursor_moves_with_the_text_around_it() {
+        let lines = ["let x = 1;", "let y = x + x;"];
+        let rename = [edit((0, 4), (0, 5), "count"), edit((1, 8), (1, 9), "count"), edit((1, 12), (1, 13), "count")];
+
+        // Before every edit, after one on the same line, between two on a line and on the last character
+        assert_eq!(cursor_after(&lines, (0, 2), &rename), (0, 2));
+        assert_eq!(cursor_after(&lines, (0, 7), &rename), (0, 11));
+        assert_eq!(cursor_after(&lines, (1, 10), &rename), (1, 14));
+        assert_eq!(cursor_after(&lines, (1, 14), &rename), (1, 22));
+
+        // Inside a replaced range it goes to the start of it
+        assert_eq!(cursor_after(&lines, (1, 9), &[edit((1, 8), (1, 13), "z")]), (1, 8));
+
+        // Lines added or taken away above move it down or up
+        assert_eq!(cursor_after(&lines, (1, 4), &[edit((0, 0), (0, 0), "// a\n// b\n")]), (3, 4));
+        assert_eq!(cursor_after(&lines, (1, 4), &[edit((0, 3), (1, 3), "")]), (0, 4));
+        assert_eq!(cursor_after(&lines, (1, 6), &[edit((0, 10), (1, 0), " ")]), (0, 17));
+    }
+
+    #[test]
+    fn files_from_document_changes_or_changes() {
+        let edits = json!([edit((0, 0), (0, 0), "a")]);
+        let files = |edit: Value| -> Vec<(String, usize)> {
+            workspace_edit_files(&edit)
+                .into_iter()
+                .map(|(path, edits)| (path, edits.len()))
+                .collect()
+        };
+
+        let changes = json!({
+            "changes": {
+                "file:///src/main.rs": edits,
+                "file:///src/with%20space.rs": [],
+            }
+        });
+        let mut changed = files(changes);
+        changed.sort();
+        assert_eq!(changed, [("/src/main.rs".to_owned(), 1), ("/src/with space.rs".to_owned(), 0)]);
+
+        // Creating, renaming and deleting files are skipped, and changes are ignored when documentChanges is there
+        let document_changes = json!({
+            "documentChanges": [
+                {"kind": "create", "uri": "file:///src/new.rs"},
+                {"textDocument": {"uri": "file:///src/lib.rs", "version": 3}, "edits": edits},
+                {"kind": "rename", "oldUri": "file:///src/a.rs", "newUri": "file:///src/b.rs"},
+            ],
+            "changes": {"file:///src/main.rs": edits},
+        });
+        assert_eq!(files(document_changes), [("/src/lib.rs".to_owned(), 1)]);
+
+        assert!(files(json!({})).is_empty());
+    }
+}
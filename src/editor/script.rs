@@ -0,0 +1,481 @@
+// Rhai scripts loaded from the scripts/ folder in the kelp config directory.
+//
+// Each script runs once at startup, which is where it registers its commands:
+//     register_command("trim", "trim_lines");   // or register_command("trim", Fn("trim_lines"))
+//     fn trim_lines() {
+//         for i in 0..line_count() { let text = line(i); text.trim(); set_line(i, text); }
+//     }
+// Commands are run from the command prompt by name, anything typed after the name is in args().
+//
+// The functions a script can call:
+//     text(), set_text(text)                      The whole active document
+//     line_count(), line(i), set_line(i, text)    Single lines, 0 based
+//     insert_line(i, text), remove_line(i)
+//     insert(text)                                At the cursor, which ends up after it
+//     cursor_row(), cursor_col(), move_cursor(row, col)
+//     filename(), args()
+//     status(message), open(filename)
+//     register_command(name, function)
+// All the changes a command makes to the document are undone in one step.
+
+use crate::editor::prelude::*;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FnPtr, Scope, AST};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+// Stops a script that loops forever instead of hanging the editor
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+// What a script sees of the editor, copied in before it runs and read back out after
+#[derive(Default)]
+struct ScriptContext {
+    filename: String,
+    lines: Vec<String>,
+    cursor: (usize, usize),
+    args: Vec<String>,
+
+    text_changed: bool,
+    cursor_moved: bool,
+    status: Option<String>,
+    open: Vec<String>,
+    commands: Vec<(String, String)>, // Registered by the script being loaded, as (command, function)
+}
+
+// What a script asked the editor to do
+pub struct ScriptResult {
+    pub text: Option<String>,
+    pub cursor: Option<(usize, usize)>,
+    pub status: Option<String>,
+    pub open: Vec<String>,
+}
+
+pub struct Scripts {
+    engine: Engine,
+    scripts: Vec<(String, AST)>,
+    commands: HashMap<String, (usize, String)>, // Command name to the script and function that run it
+    context: Rc<RefCell<ScriptContext>>,
+}
+
+impl Scripts {
+    // Compiles and runs every .rhai file in dir, along with an error for each one that failed
+    pub fn load(dir: &Path) -> (Self, Vec<String>) {
+        let context = Rc::new(RefCell::new(ScriptContext::default()));
+        let mut scripts = Scripts {
+            engine: engine(&context),
+            scripts: Vec::new(),
+            commands: HashMap::new(),
+            context,
+        };
+        let mut errors = Vec::new();
+
+        let mut paths: Vec<_> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|extension| extension == "rhai"))
+                .collect(),
+            Err(_) => return (scripts, errors),
+        };
+        paths.sort();
+
+        for path in paths {
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            if let Err(e) = scripts.add(&path) {
+                errors.push(format!("Script {} failed: {}", name, e));
+            }
+        }
+
+        (scripts, errors)
+    }
+
+    fn add(&mut self, path: &Path) -> Result<(), String> {
+        let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let ast = self.engine.compile(&source).map_err(|e| e.to_string())?;
+
+        *self.context.borrow_mut() = ScriptContext::default();
+        self.engine.run_ast(&ast).map_err(|e| e.to_string())?;
+
+        let index = self.scripts.len();
+        for (command, function) in self.context.borrow_mut().commands.drain(..) {
+            self.commands.insert(command, (index, function));
+        }
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        self.scripts.push((name, ast));
+        Ok(())
+    }
+
+    pub fn has_command(&self, name: &str) -> bool {
+        self.commands.contains_key(name)
+    }
+
+    // Runs a command against the given document, the caller applies the result
+    pub fn run_command(
+        &mut self,
+        name: &str,
+        args: Vec<String>,
+        doc: Option<&TextDocument>,
+    ) -> Result<ScriptResult, String> {
+        let (index, function) = self
+            .commands
+            .get(name)
+            .ok_or_else(|| format!("No script command {}", name))?;
+        let (script_name, ast) = &self.scripts[*index];
+
+        *self.context.borrow_mut() = ScriptContext {
+            filename: doc.map(|doc| doc.filename.clone()).unwrap_or_default(),
+            lines: doc
+                .map(|doc| doc.rows.iter().map(|row| row.buf.clone()).collect())
+                .unwrap_or_default(),
+            cursor: doc.map(|doc| (doc.cursor_row, doc.cursor_col)).unwrap_or_default(),
+            args,
+            ..ScriptContext::default()
+        };
+
+        // The top level of the script already ran when it was loaded, so don't run it again
+        let options = CallFnOptions::new().eval_ast(false);
+        let _: Dynamic = self
+            .engine
+            .call_fn_with_options(options, &mut Scope::new(), ast, function, ())
+            .map_err(|e| format!("{} in {}: {}", name, script_name, e))?;
+
+        let mut context = self.context.borrow_mut();
+        Ok(ScriptResult {
+            text: Some(context.lines.join("\n")).filter(|_| context.text_changed),
+            cursor: Some(context.cursor).filter(|_| context.cursor_moved),
+            status: context.status.take(),
+            open: std::mem::take(&mut context.open),
+        })
+    }
+}
+
+fn engine(context: &Rc<RefCell<ScriptContext>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    let c = context.clone();
+    engine.register_fn("text", move || c.borrow().lines.join("\n"));
+
+    let c = context.clone();
+    engine.register_fn("set_text", move |text: &str| {
+        let mut context = c.borrow_mut();
+        context.lines = text.split('\n').map(|line| line.to_owned()).collect();
+        context.text_changed = true;
+        clamp_cursor(&mut context);
+    });
+
+    let c = context.clone();
+    engine.register_fn("line_count", move || c.borrow().lines.len() as i64);
+
+    let c = context.clone();
+    engine.register_fn("line", move |i: i64| -> Result<String, Box<EvalAltResult>> {
+        let context = c.borrow();
+        Ok(context.lines[line_index(i, context.lines.len())?].clone())
+    });
+
+    let c = context.clone();
+    engine.register_fn("set_line", move |i: i64, text: &str| -> Result<(), Box<EvalAltResult>> {
+        let mut context = c.borrow_mut();
+        let i = line_index(i, context.lines.len())?;
+        context.lines[i] = text.replace('\n', " ");
+        context.text_changed = true;
+        clamp_cursor(&mut context);
+        Ok(())
+    });
+
+    let c = context.clone();
+    engine.register_fn("insert_line", move |i: i64, text: &str| -> Result<(), Box<EvalAltResult>> {
+        let mut context = c.borrow_mut();
+        let i = line_index(i, context.lines.len() + 1)?;
+        context.lines.insert(i, text.replace('\n', " "));
+        context.text_changed = true;
+        Ok(())
+    });
+
+    let c = context.clone();
+    engine.register_fn("remove_line", move |i: i64| -> Result<(), Box<EvalAltResult>> {
+        let mut context = c.borrow_mut();
+        let i = line_index(i, context.lines.len())?;
+        context.lines.remove(i);
+        context.text_changed = true;
+        clamp_cursor(&mut context);
+        Ok(())
+    });
+
+    let c = context.clone();
+    engine.register_fn("insert", move |text: &str| {
+        let mut context = c.borrow_mut();
+        if context.lines.is_empty() {
+            context.lines.push(String::new());
+        }
+
+        let (row, col) = context.cursor;
+        let line = &context.lines[row];
+        let split = line.char_indices().nth(col).map(|(i, _)| i).unwrap_or(line.len());
+        let (before, after) = (line[..split].to_owned(), line[split..].to_owned());
+
+        let mut inserted: Vec<String> = text.split('\n').map(|line| line.to_owned()).collect();
+        let last = inserted.len() - 1;
+        let cursor = (row + last, inserted[last].chars().count() + if last == 0 { col } else { 0 });
+
+        inserted[0].insert_str(0, &before);
+        inserted[last].push_str(&after);
+        context.lines.splice(row..=row, inserted);
+
+        context.cursor = cursor;
+        context.text_changed = true;
+        context.cursor_moved = true;
+    });
+
+    let c = context.clone();
+    engine.register_fn("cursor_row", move || c.borrow().cursor.0 as i64);
+
+    let c = context.clone();
+    engine.register_fn("cursor_col", move || c.borrow().cursor.1 as i64);
+
+    let c = context.clone();
+    engine.register_fn("move_cursor", move |row: i64, col: i64| {
+        let mut context = c.borrow_mut();
+        context.cursor = (row.max(0) as usize, col.max(0) as usize);
+        context.cursor_moved = true;
+        clamp_cursor(&mut context);
+    });
+
+    let c = context.clone();
+    engine.register_fn("filename", move || c.borrow().filename.clone());
+
+    let c = context.clone();
+    engine.register_fn("args", move || -> Array {
+        c.borrow().args.iter().map(|arg| Dynamic::from(arg.clone())).collect()
+    });
+
+    let c = context.clone();
+    engine.register_fn("status", move |message: &str| c.borrow_mut().status = Some(message.to_owned()));
+
+    let c = context.clone();
+    engine.register_fn("open", move |filename: &str| c.borrow_mut().open.push(filename.to_owned()));
+
+    let c = context.clone();
+    engine.register_fn("register_command", move |name: &str, function: &str| {
+        c.borrow_mut().commands.push((name.to_owned(), function.to_owned()));
+    });
+
+    let c = context.clone();
+    engine.register_fn("register_command", move |name: &str, function: FnPtr| {
+        c.borrow_mut().commands.push((name.to_owned(), function.fn_name().to_owned()));
+    });
+
+    engine
+}
+
+fn line_index(i: i64, len: usize) -> Result<usize, Box<EvalAltResult>> {
+    if i >= 0 && (i as usize) < len {
+        Ok(i as usize)
+    } else {
+        Err(format!("Line {} is out of range, there are {} lines", i, len).into())
+    }
+}
+
+fn clamp_cursor(context: &mut ScriptContext) {
+    let last = context.lines.len().saturating_sub(1);
+    let row = std::cmp::min(context.cursor.0, last);
+    let len = context.lines.get(row).map(|line| line.chars().count()).unwrap_or(0);
+    context.cursor = (row, std::cmp::min(context.cursor.1, len));
+}
+
+impl Editor {
+    // Runs "name args..." if a script registered a command called name
+    pub fn run_script_command(&mut self, command_line: &str) -> Result<(), String> {
+        let mut words = command_line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args = words.map(|word| word.to_owned()).collect();
+
+        let doc_index = self.open_doc.filter(|i| self.docs[*i].is_text_doc());
+        let docs = &self.docs;
+        let doc = doc_index.map(|i| docs[i].as_text_doc());
+
+        let scripts = self.scripts.as_mut().ok_or_else(|| "No scripts loaded".to_owned())?;
+        let result = scripts.run_command(name, args, doc)?;
+
+        if let Some(i) = doc_index {
+            let visible_rows = self.height() - 3;
+            let doc = self.docs[i].as_mut_text_doc();
+
+            if let Some(text) = &result.text {
+                if doc.read_only {
+                    return Err("Document is read only".to_owned());
+                }
+                crate::editor::format::apply(doc, text);
+            }
+
+            if let Some((row, col)) = result.cursor {
+                doc.selection = None;
+                doc.jump_to(row, col, visible_rows);
+            }
+        } else if result.text.is_some() {
+            return Err(format!("{} needs a document open", name));
+        }
+
+        if let Some(status) = result.status {
+            self.status_msg = status;
+        }
+
+        for filename in result.open {
+            self.open_doc = Some(self.open_path(&filename)?);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::history::UndoRedo;
+
+    const SCRIPT: &str = r#"
+        register_command("put", "put");
+        register_command("shout", Fn("shout"));
+        register_command("set_past_end", "set_past_end");
+        register_command("append", "append");
+        register_command("insert_past_end", "insert_past_end");
+        register_command("remove_before_start", "remove_before_start");
+        register_command("remove_last", "remove_last");
+
+        fn put() {
+            let text = args()[0];
+            text.replace("|", "\n");
+            insert(text);
+        }
+        fn shout() {
+            for i in 0..line_count() {
+                let text = line(i);
+                text.make_upper();
+                set_line(i, text);
+            }
+            insert_line(0, "// shouted");
+            status("Shouted " + filename());
+        }
+        fn set_past_end() { set_line(line_count(), "x"); }
+        fn append() { insert_line(line_count(), "// end"); }
+        fn insert_past_end() { insert_line(line_count() + 1, "x"); }
+        fn remove_before_start() { remove_line(-1); }
+        fn remove_last() { remove_line(line_count() - 1); }
+    "#;
+
+    // An editor with the script loaded and a document with lines open, the cursor at cursor
+    fn editor_with_script(name: &str, lines: &[&str], cursor: (usize, usize)) -> Editor {
+        let dir = std::env::temp_dir().join(format!("kelp_scripts_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("edits.rhai"), SCRIPT).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a script").unwrap();
+
+        let (scripts, errors) = Scripts::load(&dir);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut editor = Editor::default();
+        editor.resize(80, 24);
+        editor.scripts = Some(scripts);
+
+        let mut doc = TextDocument::new("main.rs".to_owned());
+        doc.rows = lines.iter().map(|line| Row::from_string(line.to_string())).collect();
+        doc.cursor_row = cursor.0;
+        doc.cursor_col = cursor.1;
+        editor.add_doc(doc);
+        editor.open_doc = Some(0);
+        editor
+    }
+
+    fn doc(editor: &Editor) -> &TextDocument {
+        editor.docs[0].as_text_doc()
+    }
+
+    fn text(editor: &Editor) -> Vec<&str> {
+        doc(editor).rows.iter().map(|row| row.buf.as_str()).collect()
+    }
+
+    fn cursor(editor: &Editor) -> (usize, usize) {
+        (doc(editor).cursor_row, doc(editor).cursor_col)
+    }
+
+    #[test]
+    fn insert_leaves_the_cursor_after_the_text() {
+        let mut editor = editor_with_script("insert", &["let x = 1;"], (0, 4));
+        editor.run_script_command("put yz").unwrap();
+        assert_eq!(text(&editor), ["let yzx = 1;"]);
+        assert_eq!(cursor(&editor), (0, 6));
+
+        editor.run_script_command("put a|bc").unwrap();
+        assert_eq!(text(&editor), ["let yza", "bcx = 1;"]);
+        assert_eq!(cursor(&editor), (1, 2));
+
+        // Columns count chars, not bytes
+        let mut editor = editor_with_script("insert_utf8", &["é = ü;"], (0, 1));
+        editor.run_script_command("put ö").unwrap();
+        assert_eq!(text(&editor), ["éö = ü;"]);
+        assert_eq!(cursor(&editor), (0, 2));
+    }
+
+    #[test]
+    fn line_functions_check_their_bounds() {
+        let lines = ["a", "b"];
+        let mut editor = editor_with_script("bounds", &lines, (1, 1));
+
+        for command in ["set_past_end", "insert_past_end", "remove_before_start"] {
+            let error = editor.run_script_command(command).unwrap_err();
+            assert!(error.starts_with(&format!("{} in edits.rhai", command)), "{}", error);
+            assert!(error.contains("out of range"), "{}", error);
+        }
+        assert_eq!(text(&editor), lines);
+        assert!(doc(&editor).history.is_empty());
+
+        // Just past the last line is fine for inserting
+        editor.run_script_command("append").unwrap();
+        assert_eq!(text(&editor), ["a", "b", "// end"]);
+        editor.run_script_command("remove_last").unwrap();
+        editor.run_script_command("remove_last").unwrap();
+        assert_eq!(text(&editor), ["a"]);
+        assert_eq!(cursor(&editor), (0, 1));
+    }
+
+    #[test]
+    fn registered_commands() {
+        let mut editor = editor_with_script("commands", &["a"], (0, 0));
+        let scripts = editor.scripts.as_mut().unwrap();
+
+        // Registered by name or by function pointer
+        assert!(scripts.has_command("put"));
+        assert!(scripts.has_command("shout"));
+        assert!(!scripts.has_command("missing"));
+        assert_eq!(scripts.run_command("missing", Vec::new(), None).err().unwrap(), "No script command missing");
+
+        let result = scripts.run_command("shout", Vec::new(), None).unwrap();
+        assert_eq!(result.text, Some("// shouted".to_owned()));
+        assert_eq!(result.status, Some("Shouted ".to_owned()));
+        assert!(result.cursor.is_none());
+    }
+
+    #[test]
+    fn all_changes_are_one_undo_step() {
+        let lines = ["fn main() {", "    run();", "}"];
+        let mut editor = editor_with_script("undo", &lines, (1, 4));
+
+        editor.run_script_command("shout").unwrap();
+        assert_eq!(text(&editor), ["// shouted", "FN MAIN() {", "    RUN();", "}"]);
+        assert_eq!(editor.status_msg, "Shouted main.rs");
+
+        let doc = editor.docs[0].as_mut_text_doc();
+        assert_eq!(doc.history.len(), 1);
+        let diff = doc.history[0].clone();
+        diff.apply(UndoRedo::Undo, doc);
+        assert_eq!(text(&editor), lines);
+    }
+}
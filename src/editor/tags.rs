@@ -0,0 +1,331 @@
+use crate::editor::completion::is_word_char;
+use crate::editor::prelude::*;
+use crate::editor::utils::display_path;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+// Where a tag points inside its file
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagAddress {
+    Line(usize),     // 1 based, as ctags writes it
+    Pattern(String), // The search pattern with its delimiters and escapes removed
+}
+
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub file: PathBuf,
+    pub address: TagAddress,
+    pub line: Option<usize>, // From the line: extension field if ctags was run with it
+}
+
+// A loaded tags file, kept around until the file on disk changes
+pub struct Tags {
+    pub path: PathBuf,
+    modified: Option<SystemTime>,
+
+    pub tags: Vec<Tag>,
+}
+
+// A place in a file to jump to, and what the line there says
+pub struct Location {
+    pub file: String,
+    pub row: usize,
+    pub col: usize,
+    pub line: String,
+}
+
+// Looks for a tags file in the current directory and then each parent
+pub fn find_tags_file() -> Option<PathBuf> {
+    let mut dir = std::env::current_dir().ok()?;
+
+    loop {
+        let path = dir.join("tags");
+        if path.is_file() {
+            return Some(path);
+        }
+
+        if !dir.pop() {
+            return None;
+        }
+    }
+}
+
+impl Tags {
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let contents = std::fs::read(&path)
+            .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let contents = String::from_utf8_lossy(&contents);
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let tags = contents
+            .lines()
+            .filter(|line| !line.starts_with("!_TAG_"))
+            .filter_map(|line| parse_line(line, dir))
+            .collect();
+
+        Ok(Tags {
+            modified: modified(&path),
+            path,
+            tags,
+        })
+    }
+
+    // Loads the tags file for the current directory into cached, reusing it if it hasn't changed
+    pub fn refresh(cached: &mut Option<Tags>) -> Result<&Tags, String> {
+        let path = match find_tags_file() {
+            Some(path) => path,
+            None => {
+                *cached = None;
+                return Err("No tags file found".to_owned());
+            }
+        };
+
+        let stale = match cached {
+            Some(tags) => tags.path != path || tags.modified != modified(&path),
+            None => true,
+        };
+
+        if stale {
+            *cached = Some(Tags::load(path)?);
+        }
+
+        Ok(cached.as_ref().unwrap())
+    }
+
+    pub fn definitions(&self, name: &str) -> Vec<&Tag> {
+        self.tags.iter().filter(|tag| tag.name == name).collect()
+    }
+
+    // Every whole word use of name in the files the tags file knows about
+    pub fn references(&self, name: &str) -> Vec<Location> {
+        let mut files: Vec<&PathBuf> = self.tags.iter().map(|tag| &tag.file).collect();
+        files.sort();
+        files.dedup();
+
+        let mut references = Vec::new();
+
+        for file in files {
+            let contents = match std::fs::read(file) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            let contents = String::from_utf8_lossy(&contents);
+
+            for (row, line) in contents.lines().enumerate() {
+                for col in find_word(line, name) {
+                    references.push(Location {
+                        file: display_path(file),
+                        row,
+                        col,
+                        line: line.trim().to_owned(),
+                    });
+                }
+            }
+        }
+
+        references
+    }
+}
+
+impl Tag {
+    // Finds the row and column the tag refers to in its (already loaded) document
+    pub fn locate(&self, doc: &TextDocument) -> (usize, usize) {
+        let row = match &self.address {
+            TagAddress::Line(line) => Some(line.saturating_sub(1)),
+            TagAddress::Pattern(pattern) => {
+                let (anchored_start, pattern) = match pattern.strip_prefix('^') {
+                    Some(pattern) => (true, pattern),
+                    None => (false, &pattern[..]),
+                };
+                let (anchored_end, pattern) = match pattern.strip_suffix('$') {
+                    Some(pattern) => (true, pattern),
+                    None => (false, pattern),
+                };
+
+                let matches = |row: &Row| match (anchored_start, anchored_end) {
+                    (true, true) => row.buf == pattern,
+                    (true, false) => row.buf.starts_with(pattern),
+                    (false, true) => row.buf.ends_with(pattern),
+                    (false, false) => row.buf.contains(pattern),
+                };
+
+                // The same line can show up more than once, so prefer the one nearest the line hint
+                let hint = self.line.unwrap_or(1).saturating_sub(1);
+                doc.rows
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, row)| matches(row))
+                    .min_by_key(|(i, _)| (*i as isize - hint as isize).abs())
+                    .map(|(i, _)| i)
+                    .or_else(|| self.line.map(|line| line.saturating_sub(1)))
+            }
+        };
+
+        let row = std::cmp::min(row.unwrap_or(0), doc.rows.len().saturating_sub(1));
+        let col = doc
+            .rows
+            .get(row)
+            .and_then(|r| find_word(&r.buf, &self.name).next())
+            .unwrap_or(0);
+
+        (row, col)
+    }
+
+    pub fn display_name(&self) -> String {
+        format!("{} {}", self.name, display_path(&self.file))
+    }
+}
+
+// name<TAB>file<TAB>address;"<TAB>fields
+fn parse_line(line: &str, dir: &Path) -> Option<Tag> {
+    let mut parts = line.splitn(3, '\t');
+    let name = parts.next()?;
+    let file = parts.next()?;
+    let rest = parts.next()?;
+
+    let (address, fields) = parse_address(rest)?;
+
+    let line = fields
+        .split('\t')
+        .find_map(|field| field.strip_prefix("line:"))
+        .and_then(|line| line.parse().ok());
+
+    Some(Tag {
+        name: name.to_owned(),
+        file: dir.join(file),
+        address,
+        line,
+    })
+}
+
+fn parse_address(rest: &str) -> Option<(TagAddress, &str)> {
+    let mut chars = rest.char_indices();
+
+    let address = match chars.next()? {
+        (_, delimiter) if delimiter == '/' || delimiter == '?' => {
+            let mut pattern = String::new();
+            let mut end = None;
+
+            while let Some((i, c)) = chars.next() {
+                if c == '\\' {
+                    // ctags only escapes the delimiter and backslashes, anything else is kept as is
+                    match chars.next() {
+                        Some((_, c)) if c == delimiter || c == '\\' => pattern.push(c),
+                        Some((_, c)) => {
+                            pattern.push('\\');
+                            pattern.push(c);
+                        }
+                        None => break,
+                    }
+                } else if c == delimiter {
+                    end = Some(i + 1);
+                    break;
+                } else {
+                    pattern.push(c);
+                }
+            }
+
+            (TagAddress::Pattern(pattern), &rest[end?..])
+        }
+        _ => {
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            (TagAddress::Line(rest[..digits].parse().ok()?), &rest[digits..])
+        }
+    };
+
+    Some((address.0, address.1.strip_prefix(";\"").unwrap_or(address.1)))
+}
+
+// Char indices of each whole word occurrence of word in line
+fn find_word<'a>(line: &'a str, word: &'a str) -> impl Iterator<Item = usize> + 'a {
+    line.match_indices(word).filter_map(move |(i, _)| {
+        let before = line[..i].chars().next_back();
+        let after = line[i + word.len()..].chars().next();
+
+        if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+            None
+        } else {
+            Some(line[..i].chars().count())
+        }
+    })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Location {
+    pub fn display_name(&self) -> String {
+        format!("{}:{}:{} {}", self.file, self.row + 1, self.col + 1, self.line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> TagAddress {
+        TagAddress::Pattern(pattern.to_owned())
+    }
+
+    #[test]
+    fn pattern_addresses() {
+        assert_eq!(parse_address("/^fn main() {$/;\"\tf"), Some((pattern("^fn main() {$"), "\tf")));
+        assert_eq!(parse_address("?^}$?;\""), Some((pattern("^}$"), "")));
+    }
+
+    #[test]
+    fn escapes_in_patterns() {
+        assert_eq!(parse_address(r#"/^let path = "a\/b";$/;""#), Some((pattern(r#"^let path = "a/b";$"#), "")));
+        assert_eq!(parse_address(r#"/^let sep = '\\';$/;""#), Some((pattern(r"^let sep = '\';$"), "")));
+        // Only the delimiter and backslashes are escapes, anything else stays in the pattern
+        assert_eq!(parse_address(r#"/^a\.b$/;""#), Some((pattern(r"^a\.b$"), "")));
+        assert_eq!(parse_address(r#"?^where\?$?;""#), Some((pattern("^where?$"), "")));
+    }
+
+    #[test]
+    fn line_number_addresses() {
+        assert_eq!(parse_address("42;\"\tv"), Some((TagAddress::Line(42), "\tv")));
+        assert_eq!(parse_address("7"), Some((TagAddress::Line(7), "")));
+    }
+
+    #[test]
+    fn malformed_addresses() {
+        assert_eq!(parse_address(""), None);
+        assert_eq!(parse_address("/^fn main() {$"), None);
+        assert_eq!(parse_address("/ends in a backslash\\"), None);
+        assert_eq!(parse_address("main;\""), None);
+    }
+
+    #[test]
+    fn tag_lines() {
+        let tag = parse_line("main\tsrc/main.rs\t/^fn main() {$/;\"\tf\tline:12", Path::new("/project")).unwrap();
+        assert_eq!(tag.name, "main");
+        assert_eq!(tag.file, Path::new("/project/src/main.rs"));
+        assert_eq!(tag.address, pattern("^fn main() {$"));
+        assert_eq!(tag.line, Some(12));
+
+        let tag = parse_line("Editor\tsrc/editor.rs\t30;\"\ts", Path::new(".")).unwrap();
+        assert_eq!(tag.address, TagAddress::Line(30));
+        assert_eq!(tag.line, None);
+    }
+
+    #[test]
+    fn malformed_tag_lines() {
+        assert!(parse_line("", Path::new(".")).is_none());
+        assert!(parse_line("main", Path::new(".")).is_none());
+        assert!(parse_line("main\tsrc/main.rs", Path::new(".")).is_none());
+        assert!(parse_line("main\tsrc/main.rs\t/^fn main", Path::new(".")).is_none());
+    }
+
+    #[test]
+    fn whole_words() {
+        assert_eq!(find_word("foo foobar barfoo foo_x foo", "foo").collect::<Vec<_>>(), [0, 24]);
+        assert_eq!(find_word("(foo)+foo", "foo").collect::<Vec<_>>(), [1, 6]);
+        // Columns are in chars, not bytes
+        assert_eq!(find_word("é foo", "foo").collect::<Vec<_>>(), [2]);
+        assert_eq!(find_word("nothing here", "foo").count(), 0);
+    }
+}
@@ -0,0 +1,335 @@
+use crate::editor::prelude::*;
+use crate::editor::utils::display_path;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+// A task running in the background, its output is collected line by line as it comes in
+pub struct RunningTask {
+    pub name: String,
+    pub matcher: Matcher,
+    pub output: Vec<String>,
+
+    process: Child,
+    lines: Receiver<String>,
+    cwd: PathBuf,
+    status: Option<ExitStatus>,
+}
+
+// A place in a file the output of a task complained about
+pub struct QuickfixEntry {
+    pub file: String,
+    pub row: usize,
+    pub col: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl RunningTask {
+    pub fn start(name: String, task: &Task) -> std::io::Result<Self> {
+        let mut process = Command::new(&task.command)
+            .args(&task.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Compilers split their output between stdout and stderr, so read both into one channel
+        let (sender, lines) = channel();
+        let stdout_sender = sender.clone();
+        let stdout = process.stdout.take().unwrap();
+        let stderr = process.stderr.take().unwrap();
+        std::thread::spawn(move || read_lines(stdout, stdout_sender));
+        std::thread::spawn(move || read_lines(stderr, sender));
+
+        Ok(RunningTask {
+            name,
+            matcher: task.matcher,
+            output: Vec::new(),
+            process,
+            lines,
+            cwd: std::env::current_dir().unwrap_or_default(),
+            status: None,
+        })
+    }
+
+    // The lines printed since the last poll, and the exit status once the task is done and all its output is in
+    pub fn poll(&mut self) -> (Vec<String>, Option<ExitStatus>) {
+        let mut lines = Vec::new();
+        let mut closed = false;
+
+        loop {
+            match self.lines.try_recv() {
+                Ok(line) => lines.push(line),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+
+        self.output.extend(lines.iter().cloned());
+
+        if self.status.is_none() {
+            self.status = self.process.try_wait().ok().flatten();
+        }
+
+        (lines, if closed { self.status } else { None })
+    }
+
+    pub fn quickfix(&self) -> Vec<QuickfixEntry> {
+        parse_quickfix(&self.output, self.matcher, &self.cwd)
+    }
+
+    // The name shown for the task's output document
+    pub fn doc_name(&self) -> String {
+        format!("[{}]", self.name)
+    }
+}
+
+impl Drop for RunningTask {
+    fn drop(&mut self) {
+        if let Ok(None) = self.process.try_wait() {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+}
+
+impl QuickfixEntry {
+    pub fn display_name(&self) -> String {
+        format!(
+            "{}:{}:{} {}: {}",
+            display_path(Path::new(&self.file)),
+            self.row + 1,
+            self.col + 1,
+            self.severity.name(),
+            self.message
+        )
+    }
+}
+
+// A task given on the command line rather than from the config, the matcher is guessed from the program
+pub fn task_from_command_line(command_line: &str) -> Option<Task> {
+    let mut parts = command_line.split_whitespace().map(|part| part.to_owned());
+    let command = parts.next()?;
+
+    let matcher = match Path::new(&command).file_stem().and_then(|stem| stem.to_str()) {
+        Some("cargo") | Some("rustc") => Matcher::Rustc,
+        Some("gcc") | Some("g++") | Some("cc") | Some("c++") | Some("clang") | Some("clang++") | Some("make") => {
+            Matcher::Gcc
+        }
+        _ => Matcher::Generic,
+    };
+
+    Some(Task {
+        command,
+        args: parts.collect(),
+        matcher,
+    })
+}
+
+fn read_lines(stream: impl Read, sender: std::sync::mpsc::Sender<String>) {
+    for line in BufReader::new(stream).lines() {
+        match line {
+            Ok(line) => {
+                if sender.send(line.replace('\r', "")).is_err() {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+pub fn parse_quickfix(output: &[String], matcher: Matcher, cwd: &Path) -> Vec<QuickfixEntry> {
+    let mut entries = Vec::new();
+
+    // rustc puts the message on one line and the location on a later " --> file:line:col" one
+    let mut pending: Option<(Severity, String)> = None;
+
+    for line in output {
+        match matcher {
+            Matcher::Rustc => {
+                if let Some(header) = severity_prefix(line) {
+                    pending = Some(header);
+                } else if let Some(location) = line.trim_start().strip_prefix("--> ") {
+                    if let (Some((severity, message)), Some((file, row, col, _))) =
+                        (pending.take(), parse_location(location))
+                    {
+                        entries.push(QuickfixEntry {
+                            file: resolve(file, cwd),
+                            row,
+                            col,
+                            severity,
+                            message,
+                        });
+                    }
+                }
+            }
+            Matcher::Gcc | Matcher::Generic => {
+                if let Some((file, row, col, rest)) = parse_location(line) {
+                    let (severity, message) = match severity_prefix(rest.trim_start()) {
+                        Some(header) => header,
+                        None if matcher == Matcher::Gcc => continue, // Notes and "In function" lines
+                        None => (Severity::Error, rest.trim().to_owned()),
+                    };
+
+                    // Without a known format only trust locations that really are files
+                    if matcher == Matcher::Generic && !cwd.join(file).is_file() {
+                        continue;
+                    }
+
+                    entries.push(QuickfixEntry {
+                        file: resolve(file, cwd),
+                        row,
+                        col,
+                        severity,
+                        message,
+                    });
+                }
+            }
+        }
+    }
+
+    entries
+}
+
+// "error: ..." or "warning[W0]: ..." and the like
+fn severity_prefix(line: &str) -> Option<(Severity, String)> {
+    let (head, message) = line.split_once(": ")?;
+    let head = head.split('[').next()?;
+
+    let severity = match head {
+        "error" | "fatal error" => Severity::Error,
+        "warning" => Severity::Warning,
+        "note" | "info" => Severity::Information,
+        "help" => Severity::Hint,
+        _ => return None,
+    };
+
+    Some((severity, message.trim().to_owned()))
+}
+
+// file:line[:col] at the start of the text, along with whatever follows it
+fn parse_location(text: &str) -> Option<(&str, usize, usize, &str)> {
+    let mut parts = text.trim().splitn(4, ':');
+    let file = parts.next()?;
+    let row: usize = parts.next()?.trim().parse().ok()?;
+
+    if file.is_empty() || file.contains(' ') || row == 0 {
+        return None;
+    }
+
+    let rest = parts.next().unwrap_or("");
+    match rest.trim().parse::<usize>() {
+        Ok(col) => Some((file, row - 1, col.saturating_sub(1), parts.next().unwrap_or(""))),
+        Err(_) => {
+            let after = text.trim().splitn(3, ':').nth(2).unwrap_or("");
+            Some((file, row - 1, 0, after))
+        }
+    }
+}
+
+fn resolve(file: &str, cwd: &Path) -> String {
+    if Path::new(file).is_absolute() {
+        file.to_owned()
+    } else {
+        cwd.join(file).to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(output: &str, matcher: Matcher, cwd: &Path) -> Vec<(String, usize, usize, Severity, String)> {
+        let output: Vec<String> = output.lines().map(|line| line.to_owned()).collect();
+        parse_quickfix(&output, matcher, cwd)
+            .into_iter()
+            .map(|entry| (entry.file, entry.row, entry.col, entry.severity, entry.message))
+            .collect()
+    }
+
+    fn entry(file: &str, row: usize, col: usize, severity: Severity, message: &str) -> (String, usize, usize, Severity, String) {
+        (file.to_owned(), row, col, severity, message.to_owned())
+    }
+
+    #[test]
+    fn rustc_output() {
+        let output = r#"   Compiling kelp v0.1.0 (/home/user/kelp)
+warning: unused variable: `width`
+   --> src/editor/editor.rs:3261:13
+    |
+3261 |         let width = self.term_size.0;
+    |             ^^^^^ help: if this is intentional, prefix it with an underscore: `_width`
+    |
+    = note: `#[warn(unused_variables)]` on by default
+
+error[E0308]: mismatched types
+  --> src/main.rs:4:18
+   |
+4  |     let x: u32 = "four";
+   |            ---   ^^^^^^ expected `u32`, found `&str`
+   |            |
+   |            expected due to this
+
+error: aborting due to 1 previous error; 1 warning emitted
+
+For more information about this error, try `rustc --explain E0308`.
+error: could not compile `kelp` (bin "kelp") due to 1 previous error; 1 warning emitted"#;
+
+        assert_eq!(
+            parse(output, Matcher::Rustc, Path::new("/home/user/kelp")),
+            [
+                entry("/home/user/kelp/src/editor/editor.rs", 3260, 12, Severity::Warning, "unused variable: `width`"),
+                entry("/home/user/kelp/src/main.rs", 3, 17, Severity::Error, "mismatched types"),
+            ]
+        );
+    }
+
+    #[test]
+    fn gcc_output() {
+        let output = r#"main.c: In function 'main':
+main.c:5:12: error: 'y' undeclared (first use in this function); did you mean 'x'?
+    5 |     return y;
+      |            ^
+      |            x
+main.c:5:12: note: each undeclared identifier is reported only once for each function it appears in
+/usr/include/stdio.h:332:12: warning: format '%d' expects argument of type 'int' [-Wformat=]
+main.c:9: fatal error: missing.h: No such file or directory
+compilation terminated."#;
+
+        assert_eq!(
+            parse(output, Matcher::Gcc, Path::new("/src")),
+            [
+                entry("/src/main.c", 4, 11, Severity::Error, "'y' undeclared (first use in this function); did you mean 'x'?"),
+                entry(
+                    "/src/main.c",
+                    4,
+                    11,
+                    Severity::Information,
+                    "each undeclared identifier is reported only once for each function it appears in"
+                ),
+                entry("/usr/include/stdio.h", 331, 11, Severity::Warning, "format '%d' expects argument of type 'int' [-Wformat=]"),
+                entry("/src/main.c", 8, 0, Severity::Error, "missing.h: No such file or directory"),
+            ]
+        );
+    }
+
+    #[test]
+    fn generic_output_only_trusts_real_files() {
+        let cwd = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let output = "Cargo.toml:3: bad version\nnot/a/file.txt:1:1: oops\n12:30:01 started";
+
+        assert_eq!(
+            parse(output, Matcher::Generic, cwd),
+            [entry(&resolve("Cargo.toml", cwd), 2, 0, Severity::Error, "bad version")]
+        );
+    }
+}
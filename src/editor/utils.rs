@@ -1,3 +1,7 @@
+use std::path::Path;
+use unicode_width::UnicodeWidthChar;
+use unicode_width::UnicodeWidthStr;
+
 pub fn pad_center(src: String, len: usize) -> String {
     let mut res = String::with_capacity(len);
 
@@ -17,3 +21,130 @@ pub fn pad_center_str(src: &str, len: usize) -> String {
 
     res
 }
+
+// Cuts or pads src with spaces so it takes exactly width columns
+pub fn fit_width(src: &str, width: usize) -> String {
+    let mut res = String::with_capacity(width);
+
+    for c in src.chars() {
+        if res.width() + c.width().unwrap_or(0) > width {
+            break;
+        }
+        res.push(c);
+    }
+
+    let padding = width - res.width();
+    res.push_str(&" ".repeat(padding));
+    res
+}
+
+// Splits text containing ansi escapes into lines at most width columns wide, each with its width.
+// The style active at a split is carried over to the next line
+pub fn split_ansi(src: &str, width: usize) -> Vec<(String, usize)> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut line_width = 0;
+    let mut style = String::new();
+
+    let mut chars = src.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1B' {
+            let mut escape = String::from(c);
+            for c in &mut chars {
+                escape.push(c);
+                if c != '[' && ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+
+            if escape == "\x1B[0m" {
+                style.clear();
+            } else {
+                style.push_str(&escape);
+            }
+            line.push_str(&escape);
+            continue;
+        }
+
+        let char_width = c.width().unwrap_or(0);
+        if line_width + char_width > width && line_width > 0 {
+            line.push_str("\x1B[0m");
+            lines.push((line, line_width));
+
+            line = style.clone();
+            line_width = 0;
+        }
+
+        line.push(c);
+        line_width += char_width;
+    }
+
+    lines.push((line, line_width));
+    lines
+}
+
+// Scores how well query matches candidate as a case insensitive subsequence, None if it doesn't.
+// Matches at the start, right after a separator and runs of consecutive characters score higher
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let mut score = 0;
+    let mut query_chars = query.chars().flat_map(char::to_lowercase).peekable();
+    let mut last_match: Option<usize> = None;
+    let mut previous = None;
+
+    for (i, c) in candidate.chars().enumerate() {
+        let wanted = match query_chars.peek() {
+            Some(wanted) => *wanted,
+            None => break,
+        };
+
+        if c.to_lowercase().eq(std::iter::once(wanted)) {
+            score += 1;
+
+            if i == 0 {
+                score += 8;
+            } else if last_match == Some(i - 1) {
+                score += 5;
+            } else if matches!(previous, Some('_') | Some('-') | Some('.') | Some(' '))
+                || (c.is_uppercase() && previous.is_some_and(char::is_lowercase))
+            {
+                score += 3;
+            }
+
+            last_match = Some(i);
+            query_chars.next();
+        }
+
+        previous = Some(c);
+    }
+
+    if query_chars.peek().is_some() {
+        return None;
+    }
+
+    // Prefer shorter candidates when everything else is equal
+    Some(score * 100 - candidate.chars().count() as i64)
+}
+
+// Indices of the items that match query, best first. An empty query keeps the original order
+pub fn fuzzy_matches(query: &str, items: &[String]) -> Vec<usize> {
+    let mut scored: Vec<(i64, usize)> = items
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| fuzzy_score(query, item).map(|score| (score, i)))
+        .collect();
+
+    if !query.is_empty() {
+        scored.sort_by_key(|(score, i)| (std::cmp::Reverse(*score), *i));
+    }
+
+    scored.into_iter().map(|(_, i)| i).collect()
+}
+
+// Paths under the current directory are shown relative to it
+pub fn display_path(path: &Path) -> String {
+    let relative = std::env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok().map(|p| p.to_path_buf()));
+
+    relative.unwrap_or_else(|| path.to_path_buf()).to_string_lossy().into_owned()
+}
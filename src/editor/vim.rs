@@ -0,0 +1,1078 @@
+// Vim style modal editing, turned on with "vim": true in config.json.
+//
+// Normal and visual mode keys are handled here, a command being
+//     ["register][count]operator[count]motion    e.g. "a2d3w, or dd, yy, cc for whole lines
+// Insert mode is the usual editing, Esc goes back to normal mode. Keys with Ctrl or Alt, the F keys and the
+// like still go through the keybinds in every mode, though outside insert mode only to actions that don't type.
+//
+// Motions:   h j k l w b e 0 ^ $ gg G f t F T
+// Operators: d c y, which in visual mode act on the selection
+// Commands:  i a I A o O x X s S D C Y p P J u Ctrl+R . v V : and o in visual mode to swap ends
+//
+// Registers are a-z (A-Z appends), 0 for the last yank, + for the system clipboard and " which is the default.
+// Every change is made through TextDocument::replace_range so it undoes in one step.
+use crate::editor::action::{Action, KeyPress};
+use crate::editor::history::EditDiff;
+use crate::editor::prelude::*;
+use clipboard::{ClipboardContext, ClipboardProvider};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Mode {
+    #[default]
+    Normal,
+    Insert,
+    Visual,
+    VisualLine,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Visual => "VISUAL",
+            Mode::VisualLine => "VISUAL LINE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Register {
+    pub text: String,
+    pub linewise: bool, // Put on lines of its own
+}
+
+#[derive(Default)]
+pub struct Vim {
+    pub mode: Mode,
+    pub pending: String, // The command typed so far
+
+    keys: Vec<KeyEvent>,                // Pressed for the command typed so far, to repeat it
+    recording: Option<Vec<KeyEvent>>,   // A change that went into insert mode, finished by Esc
+    last_change: Vec<KeyEvent>,         // What . repeats
+    pub replay: VecDeque<KeyEvent>,     // Read before the terminal, see Editor::read_event
+
+    registers: HashMap<char, Register>,
+    visual_start: (usize, usize),
+}
+
+pub enum VimKey {
+    Handled,
+    PassThrough, // Left to the keybinds and the usual editing
+    Action(Action),
+    Resolved(KeyPress), // Already looked up in the keybinds
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordStart,
+    WordBack,
+    WordEnd,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    FirstLine,
+    LastLine,
+    Find(char, bool, bool), // Character, backwards, stop before it
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MotionKind {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+enum Target {
+    Motion(Motion),
+    Lines, // The operator twice, as in dd
+}
+
+enum Kind {
+    Move(Motion),
+    Operator(char, Target),
+    Command(char),
+}
+
+struct Command {
+    register: Option<char>,
+    count: Option<usize>,
+    kind: Kind,
+}
+
+type Pos = (usize, usize);
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+#[derive(PartialEq)]
+enum Class {
+    Space,
+    Word,
+    Punctuation,
+}
+
+// Ok(None) while the command isn't finished yet, Err if it never will be
+fn parse(command: &str, visual: bool) -> Result<Option<Command>, ()> {
+    let mut chars = command.chars().peekable();
+
+    let mut register = None;
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        match chars.next() {
+            Some(c) if c.is_ascii_alphanumeric() || c == '"' || c == '+' => register = Some(c),
+            Some(_) => return Err(()),
+            None => return Ok(None),
+        }
+    }
+
+    let count = parse_count(&mut chars);
+    let c = match chars.next() {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+
+    let kind = match c {
+        'd' | 'x' if visual => Kind::Operator('d', Target::Lines),
+        'c' | 's' if visual => Kind::Operator('c', Target::Lines),
+        'y' | 'Y' if visual => Kind::Operator('y', Target::Lines),
+        'd' | 'c' | 'y' => {
+            let motion_count = parse_count(&mut chars);
+            let target = match chars.next() {
+                Some(m) if m == c => Target::Lines,
+                Some(m) => match parse_motion(m, &mut chars)? {
+                    Some(motion) => Target::Motion(motion),
+                    None => return Ok(None),
+                },
+                None => return Ok(None),
+            };
+
+            let count = match (count, motion_count) {
+                (None, None) => None,
+                (a, b) => Some(a.unwrap_or(1) * b.unwrap_or(1)),
+            };
+            return Ok(Some(Command { register, count, kind: Kind::Operator(c, target) }));
+        }
+        'i' | 'a' | 'I' | 'A' | 'o' | 'O' | 'x' | 'X' | 's' | 'S' | 'D' | 'C' | 'Y' | 'p' | 'P' | 'J' | 'u'
+        | '.' | 'v' | 'V' | ':' => Kind::Command(c),
+        c => match parse_motion(c, &mut chars)? {
+            Some(motion) => Kind::Move(motion),
+            None => return Ok(None),
+        },
+    };
+
+    Ok(Some(Command { register, count, kind }))
+}
+
+fn parse_count(chars: &mut Chars) -> Option<usize> {
+    let mut count: Option<usize> = None;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        // A 0 on its own is the start of the line
+        if digit == 0 && count.is_none() {
+            break;
+        }
+        count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit as usize));
+        chars.next();
+    }
+    count
+}
+
+fn parse_motion(c: char, chars: &mut Chars) -> Result<Option<Motion>, ()> {
+    let motion = match c {
+        'h' => Motion::Left,
+        'l' => Motion::Right,
+        'k' => Motion::Up,
+        'j' => Motion::Down,
+        'w' => Motion::WordStart,
+        'b' => Motion::WordBack,
+        'e' => Motion::WordEnd,
+        '0' => Motion::LineStart,
+        '^' => Motion::FirstNonBlank,
+        '$' => Motion::LineEnd,
+        'G' => Motion::LastLine,
+        'g' => match chars.next() {
+            Some('g') => Motion::FirstLine,
+            Some(_) => return Err(()),
+            None => return Ok(None),
+        },
+        'f' | 't' | 'F' | 'T' => match chars.next() {
+            Some(target) => Motion::Find(target, c == 'F' || c == 'T', c == 't' || c == 'T'),
+            None => return Ok(None),
+        },
+        _ => return Err(()),
+    };
+    Ok(Some(motion))
+}
+
+// Line ends count as a space so words don't run across them
+fn class_at(doc: &TextDocument, (row, col): Pos) -> Class {
+    if col >= doc.rows[row].len() {
+        return Class::Space;
+    }
+
+    let c = doc.rows[row].char_at(col);
+    if c.is_whitespace() {
+        Class::Space
+    } else if c.is_alphanumeric() || c == '_' {
+        Class::Word
+    } else {
+        Class::Punctuation
+    }
+}
+
+fn forward(doc: &TextDocument, (row, col): Pos) -> Option<Pos> {
+    if col < doc.rows[row].len() {
+        Some((row, col + 1))
+    } else if row + 1 < doc.rows.len() {
+        Some((row + 1, 0))
+    } else {
+        None
+    }
+}
+
+fn backward(doc: &TextDocument, (row, col): Pos) -> Option<Pos> {
+    if col > 0 {
+        Some((row, col - 1))
+    } else if row > 0 {
+        Some((row - 1, doc.rows[row - 1].len()))
+    } else {
+        None
+    }
+}
+
+fn is_empty_line(doc: &TextDocument, (row, col): Pos) -> bool {
+    col == 0 && doc.rows[row].len() == 0
+}
+
+fn next_word_start(doc: &TextDocument, mut pos: Pos) -> Pos {
+    let mut previous = class_at(doc, pos);
+    while let Some(next) = forward(doc, pos) {
+        pos = next;
+
+        // An empty line counts as a word
+        let class = class_at(doc, pos);
+        if is_empty_line(doc, pos) || (class != Class::Space && class != previous) {
+            break;
+        }
+        previous = class;
+    }
+    pos
+}
+
+fn previous_word_start(doc: &TextDocument, pos: Pos) -> Pos {
+    let mut pos = match backward(doc, pos) {
+        Some(pos) => pos,
+        None => return pos,
+    };
+
+    while class_at(doc, pos) == Class::Space && !is_empty_line(doc, pos) {
+        pos = match backward(doc, pos) {
+            Some(previous) => previous,
+            None => return pos,
+        };
+    }
+
+    let class = class_at(doc, pos);
+    while let Some(previous) = backward(doc, pos) {
+        if class == Class::Space || class_at(doc, previous) != class {
+            break;
+        }
+        pos = previous;
+    }
+    pos
+}
+
+fn word_end(doc: &TextDocument, pos: Pos) -> Pos {
+    let mut pos = match forward(doc, pos) {
+        Some(pos) => pos,
+        None => return pos,
+    };
+
+    while class_at(doc, pos) == Class::Space {
+        pos = match forward(doc, pos) {
+            Some(next) => next,
+            None => return pos,
+        };
+    }
+
+    let class = class_at(doc, pos);
+    while let Some(next) = forward(doc, pos) {
+        if class_at(doc, next) != class {
+            break;
+        }
+        pos = next;
+    }
+    pos
+}
+
+fn first_non_blank(doc: &TextDocument, row: usize) -> usize {
+    doc.rows[row].buf.chars().take_while(|c| c.is_whitespace()).count()
+}
+
+// Where a motion goes from the cursor, None if it can't, like an f for a character that isn't there
+fn motion_target(doc: &TextDocument, motion: Motion, count: Option<usize>) -> Option<(Pos, MotionKind)> {
+    let (row, col) = (doc.cursor_row, doc.cursor_col);
+    let n = count.unwrap_or(1);
+    let last_row = doc.rows.len() - 1;
+
+    let target = match motion {
+        Motion::Left => ((row, col.saturating_sub(n)), MotionKind::Exclusive),
+        Motion::Right => ((row, std::cmp::min(col + n, doc.rows[row].len())), MotionKind::Exclusive),
+        Motion::Up => {
+            let row = row.saturating_sub(n);
+            ((row, std::cmp::min(col, doc.rows[row].len())), MotionKind::Linewise)
+        }
+        Motion::Down => {
+            let row = std::cmp::min(row + n, last_row);
+            ((row, std::cmp::min(col, doc.rows[row].len())), MotionKind::Linewise)
+        }
+        Motion::WordStart => ((0..n).fold((row, col), |pos, _| next_word_start(doc, pos)), MotionKind::Exclusive),
+        Motion::WordBack => ((0..n).fold((row, col), |pos, _| previous_word_start(doc, pos)), MotionKind::Exclusive),
+        Motion::WordEnd => ((0..n).fold((row, col), |pos, _| word_end(doc, pos)), MotionKind::Inclusive),
+        Motion::LineStart => ((row, 0), MotionKind::Exclusive),
+        Motion::FirstNonBlank => ((row, first_non_blank(doc, row)), MotionKind::Exclusive),
+        Motion::LineEnd => {
+            let row = std::cmp::min(row + n - 1, last_row);
+            ((row, doc.rows[row].len()), MotionKind::Exclusive)
+        }
+        Motion::FirstLine | Motion::LastLine => {
+            let row = match count {
+                Some(line) => std::cmp::min(line.saturating_sub(1), last_row),
+                None if motion == Motion::FirstLine => 0,
+                None => last_row,
+            };
+            ((row, first_non_blank(doc, row)), MotionKind::Linewise)
+        }
+        Motion::Find(c, backwards, before) => {
+            let chars: Vec<char> = doc.rows[row].buf.chars().collect();
+            let mut found = col;
+            for _ in 0..n {
+                found = if backwards {
+                    (0..found).rev().find(|&i| chars[i] == c)?
+                } else {
+                    (found + 1..chars.len()).find(|&i| chars[i] == c)?
+                };
+            }
+
+            match (backwards, before) {
+                (false, false) => ((row, found), MotionKind::Inclusive),
+                (false, true) => ((row, found - 1), MotionKind::Inclusive),
+                (true, false) => ((row, found), MotionKind::Exclusive),
+                (true, true) => ((row, found + 1), MotionKind::Exclusive),
+            }
+        }
+    };
+    Some(target)
+}
+
+fn lines_between(doc: &TextDocument, first: usize, last: usize) -> String {
+    doc.rows[first..=last].iter().map(|row| &row.buf[..]).collect::<Vec<_>>().join("\n")
+}
+
+// Takes the rows out completely, leaving an empty one behind if they were all there was
+fn delete_lines(doc: &mut TextDocument, first: usize, last: usize, diffs: &mut Vec<EditDiff>) {
+    let (start, end) = if last + 1 < doc.rows.len() {
+        ((first, 0), (last + 1, 0))
+    } else if first > 0 {
+        ((first - 1, doc.rows[first - 1].len()), (last, doc.rows[last].len()))
+    } else {
+        ((0, 0), (last, doc.rows[last].len()))
+    };
+    diffs.extend(doc.replace_range(start, end, "").0);
+
+    let row = std::cmp::min(first, doc.rows.len() - 1);
+    doc.cursor_row = row;
+    doc.cursor_col = first_non_blank(doc, row);
+}
+
+impl Editor {
+    pub fn vim_key(&mut self, doc_index: usize, key: &KeyEvent) -> VimKey {
+        if self.vim.mode == Mode::Insert {
+            if let Some(recording) = self.vim.recording.as_mut() {
+                recording.push(*key);
+            }
+
+            if key.code == KeyCode::Esc {
+                self.vim.mode = Mode::Normal;
+                if let Some(recording) = self.vim.recording.take() {
+                    self.vim.last_change = recording;
+                }
+
+                let doc = self.docs[doc_index].as_mut_text_doc();
+                doc.cursor_col = doc.cursor_col.saturating_sub(1);
+                return VimKey::Handled;
+            }
+            return VimKey::PassThrough;
+        }
+
+        // The rest of a chord goes to the keybinds, even plain characters
+        if !self.pending_keys.is_empty() && !self.chord_timed_out() {
+            return self.outside_insert(key);
+        }
+
+        let c = match key.code {
+            KeyCode::Char('r') if key.modifiers == KeyModifiers::CONTROL => {
+                self.vim.pending.clear();
+                self.vim.keys.clear();
+                return VimKey::Action(Action::Redo);
+            }
+            KeyCode::Esc => {
+                if self.vim.pending.is_empty() && self.vim.mode != Mode::Normal {
+                    self.vim.mode = Mode::Normal;
+                    self.docs[doc_index].as_mut_text_doc().selection = None;
+                }
+                self.vim.pending.clear();
+                self.vim.keys.clear();
+                return VimKey::Handled;
+            }
+            _ => match command_char(key) {
+                Some(c) => c,
+                None => return self.outside_insert(key),
+            },
+        };
+
+        self.vim.pending.push(c);
+        self.vim.keys.push(*key);
+
+        let visual = self.vim.mode != Mode::Normal;
+        let command = match parse(&self.vim.pending, visual) {
+            Ok(Some(command)) => command,
+            Ok(None) => return VimKey::Handled,
+            Err(()) => {
+                self.vim.pending.clear();
+                self.vim.keys.clear();
+                return VimKey::Handled;
+            }
+        };
+
+        self.vim.pending.clear();
+        let keys = std::mem::take(&mut self.vim.keys);
+
+        let result = self.run_vim_command(doc_index, command, keys);
+
+        // Normal mode sits on characters, not after the last one
+        let doc = self.docs[doc_index].as_mut_text_doc();
+        if self.vim.mode != Mode::Insert {
+            let len = doc.rows[doc.cursor_row].len();
+            doc.cursor_col = std::cmp::min(doc.cursor_col, len.saturating_sub(1));
+        }
+
+        if self.vim.mode == Mode::Visual || self.vim.mode == Mode::VisualLine {
+            let (start, end) = self.visual_range(doc_index);
+            self.docs[doc_index].as_mut_text_doc().selection = Some(Selection::new(start.0, start.1, end.0, end.1));
+            self.undergoing_selection = true;
+        }
+
+        result
+    }
+
+    // Keys vim doesn't use go to the keybinds, as long as they don't type anything
+    fn outside_insert(&mut self, key: &KeyEvent) -> VimKey {
+        match self.resolve_key(key, false) {
+            KeyPress::Action(name) if Action::from_name(&name).is_some_and(Action::types) => VimKey::Handled,
+            KeyPress::Unbound => VimKey::Handled,
+            resolved => VimKey::Resolved(resolved),
+        }
+    }
+
+    fn run_vim_command(&mut self, doc_index: usize, command: Command, keys: Vec<KeyEvent>) -> VimKey {
+        let visible_rows = self.height() - 3;
+        let count = command.count.unwrap_or(1);
+        let visual = self.vim.mode == Mode::Visual || self.vim.mode == Mode::VisualLine;
+
+        let edits = match &command.kind {
+            Kind::Move(_) => false,
+            Kind::Operator(op, _) => *op != 'y',
+            Kind::Command(c) => match c {
+                'Y' | 'u' | '.' | 'v' | 'V' | ':' => false,
+                'o' => !visual,
+                _ => true,
+            },
+        };
+        if edits && self.docs[doc_index].as_text_doc().read_only {
+            self.status_msg = "Document is read only".to_owned();
+            return VimKey::Handled;
+        }
+
+        // Changes remember how they were typed for ., the ones that go into insert mode finish on Esc
+        let mut insert = false;
+
+        match command.kind {
+            Kind::Move(motion) => {
+                let doc = self.docs[doc_index].as_mut_text_doc();
+                if let Some(((row, col), _)) = motion_target(doc, motion, command.count) {
+                    move_to(doc, row, col, visible_rows);
+                }
+            }
+            Kind::Operator(op, _) if visual => {
+                let (start, end) = self.visual_range(doc_index);
+                let linewise = self.vim.mode == Mode::VisualLine;
+                self.vim.mode = Mode::Normal;
+                self.docs[doc_index].as_mut_text_doc().selection = None;
+
+                if linewise {
+                    insert = self.operate_lines(doc_index, op, command.register, start.0, end.0);
+                } else {
+                    insert = self.operate(doc_index, op, command.register, start, end);
+                }
+            }
+            Kind::Operator(op, Target::Lines) => {
+                let doc = self.docs[doc_index].as_text_doc();
+                let first = doc.cursor_row;
+                let last = std::cmp::min(first + count - 1, doc.rows.len() - 1);
+                insert = self.operate_lines(doc_index, op, command.register, first, last);
+            }
+            Kind::Operator(op, Target::Motion(motion)) => {
+                let doc = self.docs[doc_index].as_text_doc();
+                let cursor = (doc.cursor_row, doc.cursor_col);
+
+                // cw on a word only changes the word, not the space after it
+                let motion = if op == 'c' && motion == Motion::WordStart && class_at(doc, cursor) != Class::Space {
+                    Motion::WordEnd
+                } else {
+                    motion
+                };
+
+                let (mut target, kind) = match motion_target(doc, motion, command.count) {
+                    Some(target) => target,
+                    None => return VimKey::Handled,
+                };
+
+                // The last word before a line break ends at the line end, so dw there leaves the next line alone
+                if motion == Motion::WordStart {
+                    let last_from = (1..count).fold(cursor, |pos, _| next_word_start(doc, pos));
+                    if target.0 > last_from.0 && doc.rows[last_from.0].len() > 0 {
+                        target = (last_from.0, doc.rows[last_from.0].len());
+                    }
+                }
+                let (start, mut end) = (std::cmp::min(cursor, target), std::cmp::max(cursor, target));
+
+                match kind {
+                    MotionKind::Linewise => {
+                        insert = self.operate_lines(doc_index, op, command.register, start.0, end.0);
+                    }
+                    _ => {
+                        if kind == MotionKind::Inclusive {
+                            end.1 = std::cmp::min(end.1 + 1, doc.rows[end.0].len());
+                        } else if end.1 == 0 && end.0 > start.0 {
+                            // An exclusive motion that ends at the start of a line stops at the end of the one before
+                            end = (end.0 - 1, doc.rows[end.0 - 1].len());
+                        }
+                        insert = self.operate(doc_index, op, command.register, start, end);
+                    }
+                }
+            }
+            Kind::Command(c) => match c {
+                // Anything else ends visual mode first
+                _ if visual && !matches!(c, 'o' | 'v' | 'V' | ':') => {
+                    self.vim.mode = Mode::Normal;
+                    self.docs[doc_index].as_mut_text_doc().selection = None;
+                    return self.run_vim_command(doc_index, command, keys);
+                }
+                'i' | 'a' | 'I' | 'A' => {
+                    let doc = self.docs[doc_index].as_mut_text_doc();
+                    let len = doc.rows[doc.cursor_row].len();
+                    doc.cursor_col = match c {
+                        'a' => std::cmp::min(doc.cursor_col + 1, len),
+                        'I' => first_non_blank(doc, doc.cursor_row),
+                        'A' => len,
+                        _ => doc.cursor_col,
+                    };
+                    insert = true;
+                }
+                'o' if visual => {
+                    let doc = self.docs[doc_index].as_mut_text_doc();
+                    let cursor = (doc.cursor_row, doc.cursor_col);
+                    doc.cursor_row = self.vim.visual_start.0;
+                    doc.cursor_col = self.vim.visual_start.1;
+                    self.vim.visual_start = cursor;
+                }
+                'o' | 'O' => {
+                    let doc = self.docs[doc_index].as_mut_text_doc();
+                    let row = doc.cursor_row;
+                    let (at, new_row) = if c == 'o' { ((row, doc.rows[row].len()), row + 1) } else { ((row, 0), row) };
+
+                    let (diffs, _) = doc.replace_range(at, at, "\n");
+                    doc.add_diff(EditDiff::Compound(diffs));
+                    doc.dirty += 1;
+                    move_to(doc, new_row, 0, visible_rows);
+                    insert = true;
+                }
+                'x' | 'X' | 's' => {
+                    let doc = self.docs[doc_index].as_text_doc();
+                    let (row, col) = (doc.cursor_row, doc.cursor_col);
+                    let len = doc.rows[row].len();
+                    let (start, end) = if c == 'X' {
+                        ((row, col.saturating_sub(count)), (row, col))
+                    } else {
+                        ((row, col), (row, std::cmp::min(col + count, len)))
+                    };
+
+                    insert = self.operate(doc_index, if c == 's' { 'c' } else { 'd' }, command.register, start, end);
+                }
+                'S' => {
+                    let row = self.docs[doc_index].as_text_doc().cursor_row;
+                    insert = self.operate_lines(doc_index, 'c', command.register, row, row);
+                }
+                'D' | 'C' => {
+                    let doc = self.docs[doc_index].as_text_doc();
+                    let start = (doc.cursor_row, doc.cursor_col);
+                    let end = (doc.cursor_row, doc.rows[doc.cursor_row].len());
+                    insert = self.operate(doc_index, if c == 'C' { 'c' } else { 'd' }, command.register, start, end);
+                }
+                'Y' => {
+                    let doc = self.docs[doc_index].as_text_doc();
+                    let first = doc.cursor_row;
+                    let last = std::cmp::min(first + count - 1, doc.rows.len() - 1);
+                    self.operate_lines(doc_index, 'y', command.register, first, last);
+                }
+                'p' | 'P' => self.put(doc_index, command.register, count, c == 'P'),
+                'J' => {
+                    let doc = self.docs[doc_index].as_mut_text_doc();
+                    let mut diffs = Vec::new();
+                    for _ in 0..std::cmp::max(count, 2) - 1 {
+                        let row = doc.cursor_row;
+                        if row + 1 >= doc.rows.len() {
+                            break;
+                        }
+
+                        let end = (row + 1, first_non_blank(doc, row + 1));
+                        let joint = if end.1 == doc.rows[row + 1].len() { "" } else { " " };
+                        let len = doc.rows[row].len();
+                        diffs.extend(doc.replace_range((row, len), end, joint).0);
+                        doc.cursor_col = len;
+                    }
+                    if !diffs.is_empty() {
+                        doc.add_diff(EditDiff::Compound(diffs));
+                        doc.dirty += 1;
+                    }
+                }
+                'v' | 'V' => {
+                    let mode = if c == 'v' { Mode::Visual } else { Mode::VisualLine };
+                    if self.vim.mode == mode {
+                        self.vim.mode = Mode::Normal;
+                        self.docs[doc_index].as_mut_text_doc().selection = None;
+                    } else {
+                        if !visual {
+                            let doc = self.docs[doc_index].as_text_doc();
+                            self.vim.visual_start = (doc.cursor_row, doc.cursor_col);
+                        }
+                        self.vim.mode = mode;
+                    }
+                }
+                'u' => return VimKey::Action(Action::Undo),
+                ':' => return VimKey::Action(Action::StartCommand),
+                // A count replaces the one the change was made with
+                '.' => {
+                    let last_change = match command.count {
+                        Some(count) => with_count(&self.vim.last_change, count),
+                        None => self.vim.last_change.clone(),
+                    };
+                    self.vim.replay.extend(last_change);
+                }
+                _ => {}
+            },
+        }
+
+        if insert {
+            self.vim.mode = Mode::Insert;
+            self.vim.recording = Some(keys);
+        } else if edits {
+            self.vim.last_change = keys;
+        }
+
+        VimKey::Handled
+    }
+
+    // The selection in visual mode, with the end just past the last character in it
+    fn visual_range(&self, doc_index: usize) -> (Pos, Pos) {
+        let doc = self.docs[doc_index].as_text_doc();
+        let cursor = (doc.cursor_row, doc.cursor_col);
+        let (start, end) = (std::cmp::min(cursor, self.vim.visual_start), std::cmp::max(cursor, self.vim.visual_start));
+
+        if self.vim.mode == Mode::VisualLine {
+            ((start.0, 0), (end.0, doc.rows[end.0].len()))
+        } else {
+            (start, (end.0, std::cmp::min(end.1 + 1, doc.rows[end.0].len())))
+        }
+    }
+
+    // Applies d, c or y to the text between start and end, true if it goes into insert mode
+    fn operate(&mut self, doc_index: usize, op: char, register: Option<char>, start: Pos, end: Pos) -> bool {
+        let doc = self.docs[doc_index].as_mut_text_doc();
+        let text = doc.text_between(start, end);
+
+        doc.cursor_row = start.0;
+        doc.cursor_col = start.1;
+
+        if op != 'y' && start != end {
+            let (diffs, _) = doc.replace_range(start, end, "");
+            doc.add_diff(EditDiff::Compound(diffs));
+            doc.dirty += 1;
+        }
+
+        // Deleting nothing, like x on an empty line, leaves the registers as they were
+        if start != end {
+            self.set_register(register, Register { text, linewise: false }, op == 'y');
+        }
+        op == 'c'
+    }
+
+    fn operate_lines(&mut self, doc_index: usize, op: char, register: Option<char>, first: usize, last: usize) -> bool {
+        let doc = self.docs[doc_index].as_mut_text_doc();
+        let text = lines_between(doc, first, last);
+
+        match op {
+            'd' => {
+                let mut diffs = Vec::new();
+                delete_lines(doc, first, last, &mut diffs);
+                doc.add_diff(EditDiff::Compound(diffs));
+                doc.dirty += 1;
+            }
+            'c' => {
+                // Keeps one empty line to type on
+                let (diffs, _) = doc.replace_range((first, 0), (last, doc.rows[last].len()), "");
+                doc.add_diff(EditDiff::Compound(diffs));
+                doc.dirty += 1;
+                doc.cursor_row = first;
+                doc.cursor_col = 0;
+            }
+            _ => doc.cursor_row = first,
+        }
+
+        self.set_register(register, Register { text, linewise: true }, op == 'y');
+        op == 'c'
+    }
+
+    fn put(&mut self, doc_index: usize, register: Option<char>, count: usize, before: bool) {
+        let register = match self.get_register(register) {
+            Some(register) => register,
+            None => return,
+        };
+
+        let doc = self.docs[doc_index].as_mut_text_doc();
+        let (row, col) = (doc.cursor_row, doc.cursor_col);
+        let text = vec![register.text; count];
+
+        if register.linewise {
+            let text = text.join("\n");
+            let (diffs, new_row) = if before {
+                (doc.replace_range((row, 0), (row, 0), &format!("{}\n", text)).0, row)
+            } else {
+                let end = (row, doc.rows[row].len());
+                (doc.replace_range(end, end, &format!("\n{}", text)).0, row + 1)
+            };
+
+            doc.add_diff(EditDiff::Compound(diffs));
+            doc.cursor_row = new_row;
+            doc.cursor_col = first_non_blank(doc, new_row);
+        } else {
+            let at = if before { (row, col) } else { (row, std::cmp::min(col + 1, doc.rows[row].len())) };
+            let (diffs, end) = doc.replace_range(at, at, &text.concat());
+
+            doc.add_diff(EditDiff::Compound(diffs));
+            doc.cursor_row = end.0;
+            doc.cursor_col = end.1.saturating_sub(1);
+        }
+        doc.dirty += 1;
+    }
+
+    fn set_register(&mut self, name: Option<char>, register: Register, yank: bool) {
+        match name {
+            Some('+') => {
+                let clipboard: Result<ClipboardContext, _> = ClipboardProvider::new();
+                if let Ok(mut clipboard) = clipboard {
+                    let _ = clipboard.set_contents(register.text.clone());
+                }
+            }
+            Some(name) if name.is_ascii_uppercase() => {
+                let existing = self.vim.registers.entry(name.to_ascii_lowercase()).or_default();
+                if existing.linewise || register.linewise {
+                    existing.text.push('\n');
+                }
+                existing.text.push_str(&register.text);
+                existing.linewise |= register.linewise;
+            }
+            Some(name) if name != '"' => {
+                self.vim.registers.insert(name, register.clone());
+            }
+            _ => {}
+        }
+
+        if yank {
+            self.vim.registers.insert('0', register.clone());
+        }
+        self.vim.registers.insert('"', register);
+    }
+
+    fn get_register(&self, name: Option<char>) -> Option<Register> {
+        match name {
+            Some('+') => {
+                let mut clipboard: ClipboardContext = ClipboardProvider::new().ok()?;
+                let text = clipboard.get_contents().ok()?;
+                let linewise = text.ends_with('\n');
+                Some(Register { text: text.trim_end_matches('\n').to_owned(), linewise })
+            }
+            Some(name) => self.vim.registers.get(&name.to_ascii_lowercase()).cloned(),
+            None => self.vim.registers.get(&'"').cloned(),
+        }
+    }
+}
+
+// The usual keys for moving around mean the same as their letters, None for keys that aren't commands
+fn command_char(key: &KeyEvent) -> Option<char> {
+    if key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+        return None;
+    }
+
+    let c = match key.code {
+        KeyCode::Char(c) => c,
+        KeyCode::Left | KeyCode::Backspace => 'h',
+        KeyCode::Right => 'l',
+        KeyCode::Up => 'k',
+        KeyCode::Down | KeyCode::Enter => 'j',
+        KeyCode::Home => '0',
+        KeyCode::End => '$',
+        KeyCode::Delete => 'x',
+        _ => return None,
+    };
+    Some(c)
+}
+
+// The keys of a change with count in place of the counts it was typed with, so 5. after "a2d3w runs "a5dw
+fn with_count(keys: &[KeyEvent], count: usize) -> Vec<KeyEvent> {
+    let chars: Vec<Option<char>> = keys.iter().map(command_char).collect();
+    let skip_count = |mut i: usize| {
+        // A 0 on its own is the start of the line, not a count
+        if chars.get(i).copied().flatten().is_some_and(|c| c.is_ascii_digit() && c != '0') {
+            while chars.get(i).copied().flatten().is_some_and(|c| c.is_ascii_digit()) {
+                i += 1;
+            }
+        }
+        i
+    };
+
+    let register = if chars.first() == Some(&Some('"')) { std::cmp::min(2, keys.len()) } else { 0 };
+    let command = skip_count(register);
+    let rest = match chars.get(command).copied().flatten() {
+        Some('d') | Some('c') | Some('y') => skip_count(command + 1),
+        _ => command + 1,
+    };
+
+    let mut repeated = keys[..register].to_vec();
+    repeated.extend(count.to_string().chars().map(|c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)));
+    repeated.extend(keys.get(command).copied());
+    repeated.extend(keys.get(rest..).into_iter().flatten().copied());
+    repeated
+}
+
+// Scrolls only if the cursor would go off screen by more than a line, which the editor loop handles itself
+fn move_to(doc: &mut TextDocument, row: usize, col: usize, visible_rows: usize) {
+    if row + 1 >= doc.line_start && row <= doc.line_start + visible_rows {
+        doc.cursor_row = row;
+        doc.cursor_col = col;
+    } else {
+        doc.jump_to(row, col, visible_rows);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor_with(text: &str) -> Editor {
+        let mut editor = Editor::default();
+        editor.resize(80, 24);
+
+        let mut doc = TextDocument::new("test.txt".to_owned());
+        doc.rows = text.split('\n').map(|line| Row::from_string(line.to_owned())).collect();
+        editor.add_doc(doc);
+        editor
+    }
+
+    fn key(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    // Runs the keys the way the editor loop would, with whatever . puts in the replay queue going first
+    fn press(editor: &mut Editor, keys: &str) {
+        let mut keys: VecDeque<KeyEvent> = keys.chars().map(key).collect();
+        while let Some(key) = keys.pop_front() {
+            editor.vim_key(0, &key);
+            for replayed in editor.vim.replay.drain(..).rev() {
+                keys.push_front(replayed);
+            }
+        }
+    }
+
+    fn text(editor: &Editor) -> String {
+        editor.docs[0].as_text_doc().contents()
+    }
+
+    fn cursor(editor: &Editor) -> Pos {
+        let doc = editor.docs[0].as_text_doc();
+        (doc.cursor_row, doc.cursor_col)
+    }
+
+    fn unnamed(editor: &Editor) -> String {
+        editor.vim.registers[&'"'].text.clone()
+    }
+
+    #[test]
+    fn word_motions() {
+        let mut editor = editor_with("foo.bar  baz\n\n  qux");
+
+        let mut positions = Vec::new();
+        for _ in 0..6 {
+            press(&mut editor, "w");
+            positions.push(cursor(&editor));
+        }
+        assert_eq!(positions, [(0, 3), (0, 4), (0, 9), (1, 0), (2, 2), (2, 4)]);
+
+        press(&mut editor, "b");
+        assert_eq!(cursor(&editor), (2, 2));
+        press(&mut editor, "2b");
+        assert_eq!(cursor(&editor), (0, 9));
+        press(&mut editor, "0e");
+        assert_eq!(cursor(&editor), (0, 2));
+    }
+
+    #[test]
+    fn find_motions() {
+        let mut editor = editor_with("a(b, c(d), e)");
+
+        press(&mut editor, "2f(");
+        assert_eq!(cursor(&editor), (0, 6));
+        press(&mut editor, "t)");
+        assert_eq!(cursor(&editor), (0, 7));
+        press(&mut editor, "F,");
+        assert_eq!(cursor(&editor), (0, 3));
+
+        // Not finding it stays put
+        press(&mut editor, "fz");
+        assert_eq!(cursor(&editor), (0, 3));
+    }
+
+    #[test]
+    fn dw_stops_at_the_end_of_the_line() {
+        let mut editor = editor_with("foo bar\n    baz");
+        press(&mut editor, "wdw");
+        assert_eq!(text(&editor), "foo \n    baz");
+        assert_eq!(unnamed(&editor), "bar");
+
+        // Even when the count takes it over more than one word
+        let mut editor = editor_with("foo bar\n    baz");
+        press(&mut editor, "d2w");
+        assert_eq!(text(&editor), "\n    baz");
+
+        // Words on the same line still take the space after them
+        let mut editor = editor_with("foo bar\n    baz");
+        press(&mut editor, "dw");
+        assert_eq!(text(&editor), "bar\n    baz");
+    }
+
+    #[test]
+    fn operators_and_motions() {
+        let mut editor = editor_with("one two three\nfour\nfive");
+
+        press(&mut editor, "de");
+        assert_eq!(text(&editor), " two three\nfour\nfive");
+        press(&mut editor, "wd$");
+        assert_eq!(text(&editor), " \nfour\nfive");
+        press(&mut editor, "jdd");
+        assert_eq!(text(&editor), " \nfive");
+        assert!(editor.vim.registers[&'"'].linewise);
+
+        press(&mut editor, "p");
+        assert_eq!(text(&editor), " \nfive\nfour");
+
+        press(&mut editor, "ggdj");
+        assert_eq!(text(&editor), "four");
+    }
+
+    #[test]
+    fn change_word_keeps_the_space() {
+        let mut editor = editor_with("foo bar");
+        press(&mut editor, "cw");
+        assert_eq!(text(&editor), " bar");
+        assert_eq!(editor.vim.mode, Mode::Insert);
+    }
+
+    #[test]
+    fn registers() {
+        let mut editor = editor_with("foo bar baz");
+
+        press(&mut editor, "\"ayw");
+        press(&mut editor, "w\"Ayw");
+        assert_eq!(editor.vim.registers[&'a'].text, "foo bar ");
+        assert_eq!(editor.vim.registers[&'0'].text, "bar ");
+
+        press(&mut editor, "dw");
+        assert_eq!(unnamed(&editor), "bar ");
+        assert_eq!(editor.vim.registers[&'0'].text, "bar ");
+    }
+
+    #[test]
+    fn x_on_an_empty_line_keeps_the_register() {
+        let mut editor = editor_with("abc\n");
+        press(&mut editor, "x");
+        assert_eq!(unnamed(&editor), "a");
+
+        press(&mut editor, "jx");
+        assert_eq!(text(&editor), "bc\n");
+        assert_eq!(unnamed(&editor), "a");
+    }
+
+    #[test]
+    fn dot_repeats_with_a_count() {
+        let mut editor = editor_with("a b c d e f g h");
+
+        press(&mut editor, "dw.");
+        assert_eq!(text(&editor), "c d e f g h");
+
+        // The count replaces the one the change had
+        press(&mut editor, "3.");
+        assert_eq!(text(&editor), "f g h");
+
+        let mut editor = editor_with("a b c d e f g h");
+        press(&mut editor, "d2w2.");
+        assert_eq!(text(&editor), "e f g h");
+
+        // And is what the next . uses
+        press(&mut editor, ".");
+        assert_eq!(text(&editor), "g h");
+    }
+
+    #[test]
+    fn counts_in_repeated_keys() {
+        let keys = |text: &str| -> Vec<KeyEvent> { text.chars().map(key).collect() };
+
+        assert_eq!(with_count(&keys("dw"), 3), keys("3dw"));
+        assert_eq!(with_count(&keys("2d3w"), 5), keys("5dw"));
+        assert_eq!(with_count(&keys("\"a2dd"), 4), keys("\"a4dd"));
+        assert_eq!(with_count(&keys("d0"), 2), keys("2d0"));
+        assert_eq!(with_count(&keys("3x"), 12), keys("12x"));
+    }
+
+    #[test]
+    fn editing_keys_are_swallowed_outside_insert_mode() {
+        let mut editor = editor_with("foo");
+        editor.config.bind_defaults();
+
+        let tab = KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE);
+        assert!(matches!(editor.vim_key(0, &tab), VimKey::Handled));
+        let paste = KeyEvent::new(KeyCode::Char('v'), KeyModifiers::CONTROL);
+        assert!(matches!(editor.vim_key(0, &paste), VimKey::Handled));
+
+        let save = KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL);
+        assert!(matches!(editor.vim_key(0, &save), VimKey::Resolved(KeyPress::Action(name)) if name == "save_file"));
+
+        press(&mut editor, "i");
+        assert!(matches!(editor.vim_key(0, &tab), VimKey::PassThrough));
+        assert_eq!(text(&editor), "foo");
+    }
+}
@@ -0,0 +1,495 @@
+// WebAssembly plugins, loaded from the .wasm and .wat files in the plugins directory.
+//
+// A module sees nothing but the functions kelp imports into it, there is no file system, network or clock.
+// The imports are grouped into capabilities which a plugin has to be granted in config.json:
+//     "plugin_capabilities": { "shout": ["read", "write", "status", "commands"] }
+// Plugins that aren't listed get read, status and commands. write always has to be granted.
+//
+// Everything is imported from the "kelp" module, strings are a pointer and length in the plugin's memory:
+//     read        text_len() -> i32, read_text(ptr, len) -> i32, cursor_row() -> i32, cursor_col() -> i32
+//     write       set_text(ptr, len), insert(ptr, len), move_cursor(row, col)
+//     status      status(ptr, len)
+//     commands    register_command(ptr, len) -> i32, args_len() -> i32, read_args(ptr, len) -> i32
+// read_text and read_args copy up to len bytes and return how many they copied.
+//
+// The plugin can export any of:
+//     memory                  Needed by anything that passes strings
+//     on_load()               Run once when the plugin is loaded, commands have to be registered here
+//     run_command(id)         id is what register_command returned
+//     before_save() -> i32    Anything but 0 stops the save, with the status as the reason
+//     after_save()
+// A call that runs out of fuel or traps stops the plugin for the rest of the session.
+
+use crate::editor::plugin::{LoadedPlugin, Plugin, PluginAction};
+use crate::editor::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use wasmi::{Caller, Config, Engine, Extern, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Val};
+
+// Roughly the instructions a single call can run. Plenty for going over a big file a few times, while a loop that
+// never ends gives up in a fraction of a second
+const FUEL: u64 = 20_000_000;
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+const DEFAULT_CAPABILITIES: &[&str] = &["read", "status", "commands"];
+
+// What a plugin can see of the editor, copied in before each call and read back out after
+#[derive(Default)]
+struct HostState {
+    text: String,
+    cursor: (usize, usize),
+    args: String,
+
+    text_changed: bool,
+    cursor_moved: bool,
+    status: Option<String>,
+    commands: Vec<String>,
+
+    limits: StoreLimits,
+}
+
+struct Runtime {
+    store: Store<HostState>,
+    instance: Instance,
+    stopped: bool,
+}
+
+pub struct WasmPlugin {
+    name: String,
+    commands: Vec<String>,
+    load_status: Option<String>, // Whatever on_load said, shown once the editor is up
+    runtime: Mutex<Runtime>,
+}
+
+// Loads every WebAssembly plugin in dir, along with an error for each file that couldn't be loaded
+pub fn load_plugins(dir: &Path, capabilities: &HashMap<String, Vec<String>>) -> (Vec<LoadedPlugin>, Vec<String>) {
+    let mut plugins = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut paths: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "wasm" || extension == "wat"))
+            .collect(),
+        Err(_) => return (plugins, errors),
+    };
+    paths.sort();
+
+    for path in paths {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let granted: Vec<&str> = match capabilities.get(&name) {
+            Some(granted) => granted.iter().map(|capability| &capability[..]).collect(),
+            None => DEFAULT_CAPABILITIES.to_vec(),
+        };
+
+        match load(&path, &name, &granted) {
+            Ok(plugin) => plugins.push(LoadedPlugin::sandboxed(name, Box::new(plugin))),
+            Err(e) => errors.push(format!("Couldn't load plugin {}: {}", name, e)),
+        }
+    }
+
+    (plugins, errors)
+}
+
+fn load(path: &Path, name: &str, granted: &[&str]) -> Result<WasmPlugin, String> {
+    // wat::parse_bytes passes binary modules straight through
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    let bytes = wat::parse_bytes(&bytes).map_err(|e| e.to_string())?;
+
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, &bytes).map_err(|e| e.to_string())?;
+
+    for import in module.imports() {
+        match capability(import.module(), import.name()) {
+            Some(capability) if granted.contains(&capability) => {}
+            Some(capability) => {
+                return Err(format!(
+                    "it needs the {} capability, grant it in plugin_capabilities",
+                    capability
+                ))
+            }
+            None => return Err(format!("kelp has nothing called {}.{}", import.module(), import.name())),
+        }
+    }
+
+    let mut store = Store::new(
+        &engine,
+        HostState {
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+            ..HostState::default()
+        },
+    );
+    store.limiter(|state| &mut state.limits);
+    store.set_fuel(FUEL).map_err(|e| e.to_string())?;
+
+    let instance = linker(&engine)?
+        .instantiate(&mut store, &module)
+        .and_then(|instance| instance.start(&mut store))
+        .map_err(|e| e.to_string())?;
+
+    let mut runtime = Runtime {
+        store,
+        instance,
+        stopped: false,
+    };
+    runtime.call("on_load", &[]).map_err(|e| e.to_string())?;
+
+    let state = runtime.store.data_mut();
+    Ok(WasmPlugin {
+        name: name.to_owned(),
+        commands: std::mem::take(&mut state.commands),
+        load_status: state.status.take(),
+        runtime: Mutex::new(runtime),
+    })
+}
+
+// The capability an import belongs to, None if kelp doesn't provide it
+fn capability(module: &str, name: &str) -> Option<&'static str> {
+    if module != "kelp" {
+        return None;
+    }
+
+    match name {
+        "text_len" | "read_text" | "cursor_row" | "cursor_col" => Some("read"),
+        "set_text" | "insert" | "move_cursor" => Some("write"),
+        "status" => Some("status"),
+        "register_command" | "args_len" | "read_args" => Some("commands"),
+        _ => None,
+    }
+}
+
+fn linker(engine: &Engine) -> Result<Linker<HostState>, String> {
+    let mut linker = Linker::new(engine);
+
+    linker
+        .func_wrap("kelp", "text_len", |caller: Caller<'_, HostState>| caller.data().text.len() as i32)
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "read_text", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                let text = caller.data().text.clone();
+                write_bytes(&mut caller, ptr, len, text.as_bytes())
+            })
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "cursor_row", |caller: Caller<'_, HostState>| caller.data().cursor.0 as i32)
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "cursor_col", |caller: Caller<'_, HostState>| caller.data().cursor.1 as i32)
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "set_text", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                let text = read_string(&caller, ptr, len)?;
+                let state = caller.data_mut();
+                state.text = text;
+                state.text_changed = true;
+                Ok(())
+            })
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "insert", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                let inserted = read_string(&caller, ptr, len)?;
+                let state = caller.data_mut();
+
+                let (row, col) = state.cursor;
+                let line_start: usize = state.text.split('\n').take(row).map(|line| line.len() + 1).sum();
+                let line = state.text[line_start.min(state.text.len())..].split('\n').next().unwrap_or_default();
+                let offset = line_start + line.char_indices().nth(col).map(|(i, _)| i).unwrap_or(line.len());
+                state.text.insert_str(offset.min(state.text.len()), &inserted);
+
+                let lines: Vec<&str> = inserted.split('\n').collect();
+                let last = lines[lines.len() - 1].chars().count();
+                state.cursor = if lines.len() == 1 {
+                    (row, col + last)
+                } else {
+                    (row + lines.len() - 1, last)
+                };
+                state.text_changed = true;
+                state.cursor_moved = true;
+                Ok(())
+            })
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "move_cursor", |mut caller: Caller<'_, HostState>, row: i32, col: i32| {
+                let state = caller.data_mut();
+                state.cursor = (row.max(0) as usize, col.max(0) as usize);
+                state.cursor_moved = true;
+            })
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "status", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                let status = read_string(&caller, ptr, len)?;
+                caller.data_mut().status = Some(status);
+                Ok(())
+            })
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "register_command", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                let name = read_string(&caller, ptr, len)?;
+                let commands = &mut caller.data_mut().commands;
+                commands.push(name);
+                Ok(commands.len() as i32 - 1)
+            })
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "args_len", |caller: Caller<'_, HostState>| caller.data().args.len() as i32)
+        })
+        .and_then(|linker| {
+            linker.func_wrap("kelp", "read_args", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                let args = caller.data().args.clone();
+                write_bytes(&mut caller, ptr, len, args.as_bytes())
+            })
+        })
+        .map_err(|e| e.to_string())?;
+
+    Ok(linker)
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> Result<wasmi::Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("the plugin doesn't export its memory"))
+}
+
+// The range is checked before anything is allocated, so a made up length can't ask kelp for gigabytes
+fn read_string(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    let (ptr, len) = (ptr as u32 as usize, len.max(0) as usize);
+    let bytes = memory(caller)?
+        .data(caller)
+        .get(ptr..ptr.saturating_add(len))
+        .ok_or_else(|| wasmi::Error::new("a string runs past the end of the plugin's memory"))?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn write_bytes(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32, bytes: &[u8]) -> Result<i32, wasmi::Error> {
+    let bytes = &bytes[..bytes.len().min(len.max(0) as usize)];
+    memory(caller)?
+        .write(caller, ptr as u32 as usize, bytes)
+        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    Ok(bytes.len() as i32)
+}
+
+impl Runtime {
+    // Calls an export if the plugin has it, with a fresh tank of fuel
+    fn call(&mut self, export: &str, params: &[Val]) -> Result<Option<i32>, wasmi::Error> {
+        let func = match self.instance.get_func(&self.store, export) {
+            Some(func) => func,
+            None => return Ok(None),
+        };
+
+        self.store.set_fuel(FUEL)?;
+        let mut results = vec![Val::I32(0); func.ty(&self.store).results().len()];
+        func.call(&mut self.store, params, &mut results)?;
+
+        Ok(results.first().and_then(Val::i32))
+    }
+}
+
+impl WasmPlugin {
+    // Runs an export against a document, applying whatever the plugin changed
+    fn call(&self, editor: &mut Editor, doc: Option<usize>, export: &str, params: &[Val], args: &[String]) -> Option<i32> {
+        let mut runtime = self.runtime.lock().unwrap();
+        if runtime.stopped {
+            return None;
+        }
+
+        let doc = doc.filter(|doc| editor.docs.get(*doc).is_some_and(|doc| doc.is_text_doc()));
+        *runtime.store.data_mut() = HostState {
+            text: doc.map(|doc| editor.docs[doc].as_text_doc().contents()).unwrap_or_default(),
+            cursor: doc
+                .map(|doc| {
+                    let doc = editor.docs[doc].as_text_doc();
+                    (doc.cursor_row, doc.cursor_col)
+                })
+                .unwrap_or_default(),
+            args: args.join(" "),
+            limits: std::mem::take(&mut runtime.store.data_mut().limits),
+            ..HostState::default()
+        };
+
+        let result = match runtime.call(export, params) {
+            Ok(result) => result,
+            Err(e) => {
+                runtime.stopped = true;
+                editor.status_msg = format!("Plugin {} stopped: {}", self.name, e);
+                return None;
+            }
+        };
+
+        // The status stays in the state too, for before_save to give as its reason
+        let state = runtime.store.data_mut();
+        if let Some(status) = &state.status {
+            editor.status_msg = status.clone();
+        }
+
+        if let Some(doc) = doc {
+            let visible_rows = editor.height() - 3;
+            let doc = editor.docs[doc].as_mut_text_doc();
+
+            if state.text_changed {
+                if doc.read_only {
+                    editor.status_msg = "Document is read only".to_owned();
+                } else {
+                    doc.replace_contents(&state.text);
+                }
+            }
+
+            if state.cursor_moved {
+                let row = state.cursor.0.min(doc.rows.len().saturating_sub(1));
+                let col = state.cursor.1.min(doc.rows.get(row).map(|row| row.len()).unwrap_or(0));
+                doc.selection = None;
+                doc.jump_to(row, col, visible_rows);
+            }
+        }
+
+        result
+    }
+}
+
+impl Plugin for WasmPlugin {
+    fn on_load(&self, editor: &mut Editor) {
+        if let Some(status) = &self.load_status {
+            editor.status_msg = status.clone();
+        }
+    }
+
+    fn before_save(&self, editor: &mut Editor, doc: usize) -> Result<(), String> {
+        match self.call(editor, Some(doc), "before_save", &[], &[]) {
+            Some(0) | None => Ok(()),
+            Some(_) => {
+                let reason = self.runtime.lock().unwrap().store.data_mut().status.take();
+                Err(reason.unwrap_or_else(|| format!("{} refused to save it", self.name)))
+            }
+        }
+    }
+
+    fn after_save(&self, editor: &mut Editor, doc: usize) {
+        self.call(editor, Some(doc), "after_save", &[], &[]);
+    }
+
+    fn actions(&self) -> Vec<PluginAction> {
+        self.commands
+            .iter()
+            .map(|name| PluginAction {
+                name: name.clone(),
+                key: None,
+            })
+            .collect()
+    }
+
+    fn run_action(&self, editor: &mut Editor, name: &str, args: &[String]) {
+        if let Some(id) = self.commands.iter().position(|command| command == name) {
+            let doc = editor.open_doc;
+            self.call(editor, doc, "run_command", &[Val::I32(id as i32)], args);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_wat(name: &str, wat: &str, granted: &[&str]) -> Result<WasmPlugin, String> {
+        let dir = std::env::temp_dir().join(format!("kelp-wasm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(format!("{}.wat", name));
+        std::fs::write(&path, wat).unwrap();
+        let plugin = load(&path, name, granted);
+        let _ = std::fs::remove_file(&path);
+        plugin
+    }
+
+    fn editor_with(text: &str) -> Editor {
+        let mut editor = Editor::default();
+        editor.resize(80, 24);
+
+        let mut doc = TextDocument::new("test.txt".to_owned());
+        doc.rows = text.split('\n').map(|line| Row::from_string(line.to_owned())).collect();
+        editor.add_doc(doc);
+        editor
+    }
+
+    const SPIN: &str = r#"(module
+        (import "kelp" "register_command" (func $register (param i32 i32) (result i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 0) "spin")
+        (func (export "on_load") (drop (call $register (i32.const 0) (i32.const 4))))
+        (func (export "run_command") (param i32) (loop $forever (br $forever))))"#;
+
+    #[test]
+    fn running_out_of_fuel_stops_the_plugin() {
+        let plugin = load_wat("spin", SPIN, DEFAULT_CAPABILITIES).unwrap();
+        let mut editor = editor_with("");
+
+        plugin.run_action(&mut editor, "spin", &[]);
+        assert!(editor.status_msg.starts_with("Plugin spin stopped"), "{}", editor.status_msg);
+        assert!(editor.status_msg.contains("fuel"), "{}", editor.status_msg);
+
+        // It stays stopped rather than spinning again
+        editor.status_msg.clear();
+        plugin.run_action(&mut editor, "spin", &[]);
+        assert!(editor.status_msg.is_empty());
+    }
+
+    #[test]
+    fn running_out_of_fuel_while_loading() {
+        let wat = r#"(module (func (export "on_load") (loop $forever (br $forever))))"#;
+        let error = load_wat("spin_on_load", wat, DEFAULT_CAPABILITIES).err().unwrap();
+        assert!(error.contains("fuel"), "{}", error);
+    }
+
+    #[test]
+    fn capabilities_have_to_be_granted() {
+        let wat = r#"(module
+            (import "kelp" "set_text" (func (param i32 i32)))
+            (memory (export "memory") 1))"#;
+
+        let error = load_wat("writer", wat, DEFAULT_CAPABILITIES).err().unwrap();
+        assert_eq!(error, "it needs the write capability, grant it in plugin_capabilities");
+        assert!(load_wat("writer", wat, &["write"]).is_ok());
+
+        let wat = r#"(module (import "env" "abort" (func)))"#;
+        assert_eq!(load_wat("stranger", wat, DEFAULT_CAPABILITIES).err().unwrap(), "kelp has nothing called env.abort");
+    }
+
+    #[test]
+    fn strings_past_the_end_of_memory() {
+        let wat = r#"(module
+            (import "kelp" "status" (func $status (param i32 i32)))
+            (memory (export "memory") 1)
+            (func (export "on_load") (call $status (i32.const 65000) (i32.const 0x7fffffff))))"#;
+
+        let error = load_wat("overrun", wat, DEFAULT_CAPABILITIES).err().unwrap();
+        assert!(error.contains("past the end of the plugin's memory"), "{}", error);
+    }
+
+    #[test]
+    fn before_save_gives_its_own_reason() {
+        let wat = r#"(module
+            (import "kelp" "status" (func $status (param i32 i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "no tabs allowed")
+            (func (export "before_save") (result i32)
+                (call $status (i32.const 0) (i32.const 15))
+                (i32.const 1)))"#;
+        let plugin = load_wat("tabs", wat, DEFAULT_CAPABILITIES).unwrap();
+
+        let mut editor = editor_with("\tindented");
+        assert_eq!(plugin.before_save(&mut editor, 0), Err("no tabs allowed".to_owned()));
+
+        // Without a status of its own, whatever was in the status bar isn't taken for the reason
+        let wat = r#"(module (func (export "before_save") (result i32) (i32.const 1)))"#;
+        let plugin = load_wat("quiet", wat, DEFAULT_CAPABILITIES).unwrap();
+
+        editor.status_msg = "Saved".to_owned();
+        assert_eq!(plugin.before_save(&mut editor, 0), Err("quiet refused to save it".to_owned()));
+    }
+}
@@ -0,0 +1,248 @@
+use crate::editor::outline;
+use crate::editor::outline::Symbol;
+use crate::editor::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+// Keeps indexing from running away when started somewhere like the home directory
+const MAX_FILES: usize = 20000;
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+// Build output and dependencies, hidden directories are skipped as well
+const SKIPPED_DIRS: &[&str] = &["target", "node_modules"];
+
+// The definitions in every file under a directory, filled in by a background thread
+pub struct SymbolIndex {
+    pub root: PathBuf,
+    files: HashMap<PathBuf, Vec<Symbol>>,
+
+    updates: Receiver<(PathBuf, Vec<Symbol>)>,
+    indexing: bool,
+}
+
+impl SymbolIndex {
+    // Starts indexing every file under root that has a language config, files show up as they are done
+    pub fn build(root: PathBuf, languages: HashMap<String, FileConfig>) -> Self {
+        let (sender, updates) = channel();
+
+        let walk_root = root.clone();
+        std::thread::spawn(move || {
+            for path in source_files(&walk_root, &languages) {
+                let config = match extension(&path).and_then(|extension| languages.get(&extension)) {
+                    Some(config) => config,
+                    None => continue,
+                };
+
+                if let Ok(contents) = std::fs::read(&path) {
+                    let symbols = index_text(&String::from_utf8_lossy(&contents), config, &languages);
+                    if sender.send((path, symbols)).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        SymbolIndex {
+            root,
+            files: HashMap::new(),
+            updates,
+            indexing: true,
+        }
+    }
+
+    // Takes in whatever the background thread has finished since the last poll
+    pub fn poll(&mut self) {
+        while self.indexing {
+            match self.updates.try_recv() {
+                Ok((path, symbols)) => {
+                    self.set(path, symbols);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.indexing = false,
+            }
+        }
+    }
+
+    pub fn is_indexing(&self) -> bool {
+        self.indexing
+    }
+
+    // Reindexes a file that was just saved, files outside the root are left out
+    pub fn update(&mut self, filename: &str, text: &str, config: &FileConfig, languages: &HashMap<String, FileConfig>) {
+        let path = match std::fs::canonicalize(filename) {
+            Ok(path) if path.starts_with(&self.root) => path,
+            _ => return,
+        };
+
+        self.set(path, index_text(text, config, languages));
+    }
+
+    // Every symbol along with the file it's in, ordered by file and then position
+    pub fn symbols(&self) -> Vec<(&Path, &Symbol)> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by_key(|(path, _)| *path);
+
+        files
+            .into_iter()
+            .flat_map(|(path, symbols)| symbols.iter().map(move |symbol| (path.as_path(), symbol)))
+            .collect()
+    }
+
+    fn set(&mut self, path: PathBuf, symbols: Vec<Symbol>) {
+        if symbols.is_empty() {
+            self.files.remove(&path);
+        } else {
+            self.files.insert(path, symbols);
+        }
+    }
+}
+
+pub fn index_text(text: &str, config: &FileConfig, languages: &HashMap<String, FileConfig>) -> Vec<Symbol> {
+    let mut rows: Vec<Row> = text.lines().map(|line| Row::from_string(line.to_owned())).collect();
+    outline::symbols_from_tokens(&mut rows, config, languages)
+}
+
+// Files under root with a language config, without following symlinks
+fn source_files(root: &Path, languages: &HashMap<String, FileConfig>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+
+            if name.starts_with('.') {
+                continue;
+            }
+
+            if file_type.is_dir() {
+                if !SKIPPED_DIRS.contains(&&name[..]) {
+                    dirs.push(entry.path());
+                }
+            } else if file_type.is_file() {
+                let path = entry.path();
+                let small = entry.metadata().map(|m| m.len() <= MAX_FILE_SIZE).unwrap_or(false);
+
+                if small && extension(&path).is_some_and(|extension| languages.contains_key(&extension)) {
+                    files.push(path);
+                    if files.len() >= MAX_FILES {
+                        return files;
+                    }
+                }
+            }
+        }
+    }
+
+    files
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension().map(|extension| extension.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn languages() -> HashMap<String, FileConfig> {
+        let mut languages = HashMap::new();
+        languages.insert(
+            "rs".to_owned(),
+            FileConfig {
+                keywords: vec!["fn".to_owned(), "struct".to_owned()],
+                ..FileConfig::default()
+            },
+        );
+        languages
+    }
+
+    // A fresh directory under the temp dir, canonical like the roots the editor uses
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kelp_workspace_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::canonicalize(dir).unwrap()
+    }
+
+    fn write(root: &Path, file: &str, contents: &str) {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn names(index: &SymbolIndex) -> Vec<(String, String)> {
+        index
+            .symbols()
+            .iter()
+            .map(|(path, symbol)| (path.file_name().unwrap().to_string_lossy().into_owned(), symbol.name.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn source_files_skip_build_output_hidden_and_large_files() {
+        let root = temp_dir("sources");
+        write(&root, "lib.rs", "fn a() {}");
+        write(&root, "src/main.rs", "fn main() {}");
+        write(&root, "src/notes.txt", "fn not_indexed() {}");
+        write(&root, "src/.hidden.rs", "fn hidden() {}");
+        write(&root, "target/debug/build.rs", "fn built() {}");
+        write(&root, "node_modules/dep/index.rs", "fn dep() {}");
+        write(&root, ".git/hooks/hook.rs", "fn hook() {}");
+        write(&root, "big.rs", &"x".repeat(MAX_FILE_SIZE as usize + 1));
+        write(&root, "just_fits.rs", &"x".repeat(MAX_FILE_SIZE as usize));
+
+        let mut files: Vec<PathBuf> = source_files(&root, &languages())
+            .into_iter()
+            .map(|path| path.strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        files.sort();
+        assert_eq!(files, [PathBuf::from("just_fits.rs"), PathBuf::from("lib.rs"), PathBuf::from("src/main.rs")]);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn update_only_takes_files_under_the_root() {
+        let root = temp_dir("root");
+        let outside = temp_dir("outside");
+        write(&root, "src/main.rs", "fn main() {}");
+        write(&outside, "other.rs", "fn other() {}");
+
+        let languages = languages();
+        let config = &languages["rs"];
+        let mut index = SymbolIndex::build(root.clone(), languages.clone());
+        while index.is_indexing() {
+            index.poll();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert_eq!(names(&index), [("main.rs".to_owned(), "main".to_owned())]);
+
+        let other = outside.join("other.rs");
+        index.update(&other.to_string_lossy(), "fn other() {}", config, &languages);
+        assert_eq!(names(&index), [("main.rs".to_owned(), "main".to_owned())]);
+
+        // Saved text replaces what was indexed, and a file left without symbols drops out
+        let main = root.join("src/main.rs");
+        index.update(&main.to_string_lossy(), "struct Editor {}\nfn run() {}", config, &languages);
+        assert_eq!(
+            names(&index),
+            [("main.rs".to_owned(), "Editor".to_owned()), ("main.rs".to_owned(), "run".to_owned())]
+        );
+        index.update(&main.to_string_lossy(), "// nothing", config, &languages);
+        assert!(names(&index).is_empty());
+
+        let _ = std::fs::remove_dir_all(&root);
+        let _ = std::fs::remove_dir_all(&outside);
+    }
+}
@@ -0,0 +1,5 @@
+// The editor is a library as well so plugins can build against the same types the binary uses
+pub mod editor;
+
+#[macro_use]
+extern crate serde_derive;
@@ -1,9 +1,4 @@
-use crate::editor::prelude::Editor;
-
-mod editor;
-
-#[macro_use]
-extern crate serde_derive;
+use kelp::editor::prelude::Editor;
 
 fn main() {
     let editor = Editor::new();